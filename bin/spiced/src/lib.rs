@@ -53,6 +53,9 @@ pub enum Error {
     #[snafu(display("Unable to create data backend"))]
     UnableToCreateBackend { source: runtime::datafusion::Error },
 
+    #[snafu(display("Unable to create the query engine: {source}"))]
+    UnableToCreateDataFusion { source: runtime::datafusion::Error },
+
     #[snafu(display("Failed to start pods watcher: {source}"))]
     UnableToInitializePodsWatcher { source: runtime::NotifyError },
 }
@@ -84,7 +87,9 @@ pub struct Args {
 
 pub async fn run(args: Args) -> Result<()> {
     let current_dir = env::current_dir().unwrap_or(PathBuf::from("."));
-    let df = Arc::new(RwLock::new(runtime::datafusion::DataFusion::new()));
+    let df = runtime::datafusion::DataFusion::try_new_with_config(&args.runtime)
+        .context(UnableToCreateDataFusionSnafu)?;
+    let df = Arc::new(RwLock::new(df));
     let pods_watcher = PodsWatcher::new(current_dir.clone());
     let app: Arc<RwLock<Option<App>>> =
         match App::new(current_dir.clone()).context(UnableToConstructSpiceAppSnafu) {
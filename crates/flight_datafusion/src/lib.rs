@@ -22,7 +22,7 @@ use async_trait::async_trait;
 use flight_client::FlightClient;
 use futures::{Stream, StreamExt};
 use snafu::prelude::*;
-use sql_provider_datafusion::expr;
+use sql_provider_datafusion::{expr, metrics::RemoteQueryStream, RemoteQueryExec};
 use std::{any::Any, fmt, pin::Pin, sync::Arc, task::Poll};
 
 use arrow_flight::error::FlightError;
@@ -34,6 +34,7 @@ use datafusion::{
     execution::{context::SessionState, RecordBatchStream, TaskContext},
     logical_expr::{Expr, TableProviderFilterPushDown, TableType},
     physical_plan::{
+        metrics::{ExecutionPlanMetricsSet, MetricsSet},
         project_schema, DisplayAs, DisplayFormatType, ExecutionPlan, SendableRecordBatchStream,
     },
     sql::TableReference,
@@ -158,12 +159,13 @@ impl TableProvider for FlightTable {
 }
 
 #[derive(Clone)]
-struct FlightExec {
+pub struct FlightExec {
     projected_schema: SchemaRef,
     table_reference: OwnedTableReference,
     client: FlightClient,
     filters: Vec<Expr>,
     limit: Option<usize>,
+    metrics: ExecutionPlanMetricsSet,
 }
 
 impl FlightExec {
@@ -182,6 +184,7 @@ impl FlightExec {
             client,
             filters: filters.to_vec(),
             limit,
+            metrics: ExecutionPlanMetricsSet::new(),
         })
     }
 
@@ -218,6 +221,12 @@ impl FlightExec {
     }
 }
 
+impl RemoteQueryExec for FlightExec {
+    fn remote_sql(&self) -> Option<String> {
+        self.sql().ok()
+    }
+}
+
 impl std::fmt::Debug for FlightExec {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let sql = self.sql().unwrap_or_default();
@@ -260,9 +269,13 @@ impl ExecutionPlan for FlightExec {
         Ok(self)
     }
 
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+
     fn execute(
         &self,
-        _partition: usize,
+        partition: usize,
         _context: Arc<TaskContext>,
     ) -> DataFusionResult<SendableRecordBatchStream> {
         let sql = match self.sql().map_err(to_execution_error) {
@@ -270,10 +283,14 @@ impl ExecutionPlan for FlightExec {
             Err(error) => return Err(error),
         };
 
-        Ok(Box::pin(StreamConverter::new(
-            self.client.clone(),
-            sql.as_str(),
-            self.schema(),
+        Ok(Box::pin(RemoteQueryStream::new(
+            Box::pin(StreamConverter::new(
+                self.client.clone(),
+                sql.as_str(),
+                self.schema(),
+            )),
+            &self.metrics,
+            partition,
         )))
     }
 }
@@ -25,7 +25,7 @@ use async_trait::async_trait;
 use flight_client::tls::new_tls_flight_channel;
 use futures::{Stream, StreamExt, TryFutureExt, TryStreamExt};
 use snafu::prelude::*;
-use sql_provider_datafusion::expr;
+use sql_provider_datafusion::{expr, metrics::RemoteQueryStream, RemoteQueryExec};
 use std::{any::Any, fmt, pin::Pin, sync::Arc, task::Poll, vec};
 
 use arrow_flight::{
@@ -41,6 +41,7 @@ use datafusion::{
     execution::{context::SessionState, RecordBatchStream, TaskContext},
     logical_expr::{Expr, TableProviderFilterPushDown, TableType},
     physical_plan::{
+        metrics::{ExecutionPlanMetricsSet, MetricsSet},
         project_schema, DisplayAs, DisplayFormatType, ExecutionPlan, SendableRecordBatchStream,
     },
 };
@@ -276,12 +277,13 @@ impl TableProvider for FlightSQLTable {
 }
 
 #[derive(Clone)]
-struct FlightSqlExec {
+pub struct FlightSqlExec {
     projected_schema: SchemaRef,
     table_reference: OwnedTableReference,
     client: FlightSqlServiceClient<Channel>,
     filters: Vec<Expr>,
     limit: Option<usize>,
+    metrics: ExecutionPlanMetricsSet,
 }
 
 impl FlightSqlExec {
@@ -300,6 +302,7 @@ impl FlightSqlExec {
             client,
             filters: filters.to_vec(),
             limit,
+            metrics: ExecutionPlanMetricsSet::new(),
         })
     }
 
@@ -335,6 +338,12 @@ impl FlightSqlExec {
     }
 }
 
+impl RemoteQueryExec for FlightSqlExec {
+    fn remote_sql(&self) -> Option<String> {
+        self.sql().ok()
+    }
+}
+
 impl std::fmt::Debug for FlightSqlExec {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let sql = self.sql().unwrap_or_default();
@@ -377,9 +386,13 @@ impl ExecutionPlan for FlightSqlExec {
         Ok(self)
     }
 
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+
     fn execute(
         &self,
-        _partition: usize,
+        partition: usize,
         _context: Arc<TaskContext>,
     ) -> DataFusionResult<SendableRecordBatchStream> {
         let sql = match self.sql().map_err(to_execution_error) {
@@ -387,10 +400,14 @@ impl ExecutionPlan for FlightSqlExec {
             Err(error) => return Err(error),
         };
 
-        Ok(Box::pin(StreamConverter::new(
-            self.client.clone(),
-            sql.as_str(),
-            self.schema(),
+        Ok(Box::pin(RemoteQueryStream::new(
+            Box::pin(StreamConverter::new(
+                self.client.clone(),
+                sql.as_str(),
+                self.schema(),
+            )),
+            &self.metrics,
+            partition,
         )))
     }
 }
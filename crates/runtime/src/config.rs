@@ -15,6 +15,11 @@ limitations under the License.
 */
 
 use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::wal;
 
 #[derive(Debug, Clone, clap::Parser)]
 pub struct Config {
@@ -44,4 +49,161 @@ pub struct Config {
         action
     )]
     pub open_telemetry_bind_address: SocketAddr,
+
+    /// Directory for a write-ahead log of data received through Flight and OpenTelemetry. Disabled if not set.
+    #[arg(long = "wal_dir", value_name = "WAL_DIR", action)]
+    pub wal_dir: Option<PathBuf>,
+
+    /// When the write-ahead log is flushed to disk.
+    #[arg(
+        long = "wal_fsync",
+        value_name = "WAL_FSYNC",
+        value_enum,
+        default_value_t = wal::SyncPolicy::Always
+    )]
+    pub wal_fsync: wal::SyncPolicy,
+
+    /// Size in bytes after which a new write-ahead log segment is started.
+    #[arg(
+        long = "wal_segment_size",
+        value_name = "WAL_SEGMENT_SIZE",
+        default_value = "67108864",
+        action
+    )]
+    pub wal_segment_size: u64,
+
+    /// Number of write-ahead log segments after which the segments are compacted into one.
+    #[arg(
+        long = "wal_compact_after",
+        value_name = "WAL_COMPACT_AFTER",
+        default_value = "4",
+        action
+    )]
+    pub wal_compact_after: usize,
+
+    /// Maximum number of rows returned by a query to `/v1/sql`. Unlimited if not set. Results cut
+    /// short have the `X-Spice-Truncated: true` header.
+    #[arg(long = "sql_max_rows", value_name = "SQL_MAX_ROWS", action)]
+    pub sql_max_rows: Option<usize>,
+
+    /// Default timeout for queries over HTTP and Flight, i.e. `30s`. Unlimited if not set.
+    #[arg(long = "query_timeout", value_name = "QUERY_TIMEOUT", value_parser = parse_duration)]
+    pub query_timeout: Option<Duration>,
+
+    /// Maximum number of queries running at once. Unlimited if not set.
+    #[arg(
+        long = "max_concurrent_queries",
+        value_name = "MAX_CONCURRENT_QUERIES",
+        action
+    )]
+    pub max_concurrent_queries: Option<usize>,
+
+    /// Maximum number of queries running at once for each client, identified by its address and
+    /// `Authorization` header. The header isn't verified, so the limit is only advisory. Unlimited
+    /// if not set.
+    #[arg(
+        long = "max_concurrent_queries_per_principal",
+        value_name = "MAX_CONCURRENT_QUERIES_PER_PRINCIPAL",
+        action
+    )]
+    pub max_concurrent_queries_per_principal: Option<usize>,
+
+    /// Number of queries that can wait for a concurrency limit before new queries are rejected.
+    #[arg(
+        long = "max_queued_queries",
+        value_name = "MAX_QUEUED_QUERIES",
+        default_value = "100",
+        action
+    )]
+    pub max_queued_queries: usize,
+
+    /// Memory available to running queries, shared fairly between the operators that can spill
+    /// to disk, i.e. `4GiB`. Unlimited if not set.
+    #[arg(long = "memory_limit", value_name = "MEMORY_LIMIT", value_parser = parse_bytes)]
+    pub memory_limit: Option<usize>,
+
+    /// Directory that sorts, joins and aggregates spill to when they exceed the memory limit.
+    /// Defaults to a temporary directory.
+    #[arg(long = "spill_dir", value_name = "SPILL_DIR", action)]
+    pub spill_dir: Option<PathBuf>,
+
+    /// Number of partitions queries are executed with. Defaults to the number of CPU cores.
+    #[arg(long = "target_partitions", value_name = "TARGET_PARTITIONS", action)]
+    pub target_partitions: Option<NonZeroUsize>,
+
+    /// Number of rows in each batch produced while executing queries. Defaults to 8192.
+    #[arg(long = "batch_size", value_name = "BATCH_SIZE", action)]
+    pub batch_size: Option<NonZeroUsize>,
+
+    /// Memory used to cache the results of queries reading only accelerated datasets, i.e.
+    /// `256MB`. Results are not cached if not set.
+    #[arg(
+        long = "results_cache_max_size",
+        value_name = "RESULTS_CACHE_MAX_SIZE",
+        value_parser = parse_bytes
+    )]
+    pub results_cache_max_size: Option<usize>,
+
+    /// Load models only from the models previously downloaded to `~/.spice/models`, without
+    /// contacting their sources.
+    #[arg(long = "offline", action)]
+    pub offline: bool,
+
+    /// Total size of the models downloaded to `~/.spice/models`, beyond which the least recently
+    /// used are removed, i.e. `20GB`. Unlimited if not set.
+    #[arg(
+        long = "model_cache_max_size",
+        value_name = "MODEL_CACHE_MAX_SIZE",
+        value_parser = parse_bytes
+    )]
+    pub model_cache_max_size: Option<usize>,
+}
+
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    fundu::parse_duration(duration).map_err(|e| e.to_string())
+}
+
+/// Parses a size in bytes, i.e. `512MB` or `4GiB`.
+fn parse_bytes(size: &str) -> Result<usize, String> {
+    let size = size.trim();
+    let unit_start = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (value, unit) = size.split_at(unit_start);
+    let value: usize = value
+        .parse()
+        .map_err(|e| format!("Invalid size {size}: {e}"))?;
+
+    let multiplier: usize = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1_000,
+        "k" | "kib" => 1 << 10,
+        "mb" => 1_000_000,
+        "m" | "mib" => 1 << 20,
+        "gb" => 1_000_000_000,
+        "g" | "gib" => 1 << 30,
+        unit => return Err(format!("Invalid size {size}: unknown unit {unit}")),
+    };
+
+    match value.checked_mul(multiplier) {
+        Some(0) => Err("Size must be greater than 0".to_string()),
+        Some(bytes) => Ok(bytes),
+        None => Err(format!("Invalid size {size}: too large")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bytes() {
+        assert_eq!(parse_bytes("1024"), Ok(1024));
+        assert_eq!(parse_bytes("512MB"), Ok(512_000_000));
+        assert_eq!(parse_bytes("4GiB"), Ok(4 << 30));
+        assert_eq!(parse_bytes("2 kib"), Ok(2048));
+        assert!(parse_bytes("0").is_err());
+        assert!(parse_bytes("10XB").is_err());
+        assert!(parse_bytes("GB").is_err());
+    }
 }
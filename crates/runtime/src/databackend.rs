@@ -14,7 +14,7 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
-use crate::datapublisher::DataPublisher;
+use crate::{datapublisher::DataPublisher, wal::WriteAheadLog};
 use datafusion::execution::context::SessionContext;
 use secrets::Secret;
 use snafu::prelude::*;
@@ -31,6 +31,10 @@ pub mod postgres;
 #[cfg(feature = "sqlite")]
 pub mod sqlite;
 
+/// The table recording when each dataset persisted by a `mode: file` acceleration was last refreshed.
+#[cfg(any(feature = "duckdb", feature = "sqlite"))]
+pub(crate) const METADATA_TABLE: &str = "spice_dataset_metadata";
+
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("Invalid configuration: {msg}"))]
@@ -42,6 +46,12 @@ pub enum Error {
     },
 }
 
+/// An accelerated table persisted on disk by a previous run.
+pub struct ExistingTable {
+    /// Milliseconds since the Unix epoch at which the table was last refreshed, if recorded.
+    pub last_refresh_time: Option<u64>,
+}
+
 pub struct DataBackendBuilder {
     ctx: Arc<SessionContext>,
     name: String,
@@ -50,6 +60,7 @@ pub struct DataBackendBuilder {
     params: Arc<Option<HashMap<String, String>>>,
     primary_keys: Option<Vec<String>>,
     secret: Option<Secret>,
+    wal: Option<Arc<WriteAheadLog>>,
 }
 
 impl DataBackendBuilder {
@@ -63,6 +74,7 @@ impl DataBackendBuilder {
             params: Arc::new(None),
             primary_keys: None,
             secret: None,
+            wal: None,
         }
     }
 
@@ -96,6 +108,13 @@ impl DataBackendBuilder {
         self
     }
 
+    /// The write-ahead log of the dataset, which is truncated once the data it logged is persisted.
+    #[must_use]
+    pub fn write_ahead_log(mut self, wal: Option<Arc<WriteAheadLog>>) -> Self {
+        self.wal = wal;
+        self
+    }
+
     /// Build the data backend, panicking if it fails
     ///
     /// # Panics
@@ -110,30 +129,36 @@ impl DataBackendBuilder {
     }
 
     fn validate_arrow(&self) -> std::result::Result<(), Error> {
-        if let Some(Mode::File) = self.mode {
-            InvalidConfigurationSnafu {
-                msg: "File mode not supported for Arrow engine".to_string(),
-            }
-            .fail()?;
-        } else if self.primary_keys.is_some() {
+        if self.primary_keys.is_some() {
             InvalidConfigurationSnafu {
                 msg: "Primary keys not supported for Arrow engine".to_string(),
             }
             .fail()?;
         }
+        if matches!(self.mode, Some(Mode::File)) {
+            memtable::snapshot::Config::try_from_params(self.params.as_ref().as_ref())
+                .boxed()
+                .context(BackendCreationFailedSnafu)?;
+        }
         Ok(())
     }
 
-    fn validate(&self) -> std::result::Result<(), Error> {
-        match self.engine {
-            Some(Engine::Arrow) => self.validate_arrow(),
+    /// Checks the engine, mode and params of the backend without building it, which would create
+    /// its table.
+    pub fn validate(&self) -> std::result::Result<(), Error> {
+        #[allow(unreachable_patterns)]
+        match self.engine.clone().unwrap_or_default() {
+            Engine::Arrow => self.validate_arrow(),
             #[cfg(feature = "duckdb")]
-            Some(Engine::DuckDB) => Ok(()),
+            Engine::DuckDB => Ok(()),
             #[cfg(feature = "postgres")]
-            Some(Engine::Postgres) => Ok(()),
+            Engine::Postgres => Ok(()),
             #[cfg(feature = "sqlite")]
-            Some(Engine::Sqlite) => Ok(()),
-            _ => Ok(()),
+            Engine::Sqlite => Ok(()),
+            engine => InvalidConfigurationSnafu {
+                msg: format!("Engine {engine} is not supported by this runtime"),
+            }
+            .fail(),
         }
     }
 
@@ -143,22 +168,51 @@ impl DataBackendBuilder {
         let mode = self.mode.unwrap_or_default();
 
         match engine {
-            Engine::Arrow => Ok(Box::new(MemTableBackend::new(
-                Arc::clone(&self.ctx),
-                self.name.as_str(),
-            ))),
+            Engine::Arrow => {
+                let backend = MemTableBackend::new(Arc::clone(&self.ctx), self.name.as_str());
+                match mode {
+                    Mode::Memory => Ok(Box::new(backend)),
+                    Mode::File => {
+                        let config = memtable::snapshot::Config::try_from_params(
+                            self.params.as_ref().as_ref(),
+                        )
+                        .boxed()
+                        .context(BackendCreationFailedSnafu)?;
+                        Ok(Box::new(
+                            backend
+                                .with_snapshots(config, self.wal)
+                                .await
+                                .boxed()
+                                .context(BackendCreationFailedSnafu)?,
+                        ))
+                    }
+                }
+            }
             #[cfg(feature = "duckdb")]
-            Engine::DuckDB => Ok(Box::new(
-                DuckDBBackend::new(
+            Engine::DuckDB => {
+                let warm_start = matches!(mode, Mode::File);
+                let backend = DuckDBBackend::new(
                     Arc::clone(&self.ctx),
                     self.name.as_str(),
                     mode.into(),
                     self.params,
                     self.primary_keys,
                 )
+                .await
                 .boxed()
-                .context(BackendCreationFailedSnafu)?,
-            )),
+                .context(BackendCreationFailedSnafu)?;
+
+                if warm_start {
+                    let existing_table = backend
+                        .register_existing_table()
+                        .await
+                        .boxed()
+                        .context(BackendCreationFailedSnafu)?;
+                    mark_existing_table_stale(&self.name, existing_table);
+                }
+
+                Ok(Box::new(backend))
+            }
             #[cfg(feature = "postgres")]
             Engine::Postgres => Ok(Box::new(
                 postgres::PostgresBackend::new(
@@ -173,8 +227,9 @@ impl DataBackendBuilder {
                 .context(BackendCreationFailedSnafu)?,
             )),
             #[cfg(feature = "sqlite")]
-            Engine::Sqlite => Ok(Box::new(
-                sqlite::SqliteBackend::new(
+            Engine::Sqlite => {
+                let warm_start = matches!(mode, Mode::File);
+                let backend = sqlite::SqliteBackend::new(
                     Arc::clone(&self.ctx),
                     self.name.as_str(),
                     self.params,
@@ -183,8 +238,28 @@ impl DataBackendBuilder {
                 )
                 .await
                 .boxed()
-                .context(BackendCreationFailedSnafu)?,
-            )),
+                .context(BackendCreationFailedSnafu)?;
+
+                if warm_start {
+                    let existing_table = backend
+                        .register_existing_table()
+                        .await
+                        .boxed()
+                        .context(BackendCreationFailedSnafu)?;
+                    mark_existing_table_stale(&self.name, existing_table);
+                }
+
+                Ok(Box::new(backend))
+            }
         }
     }
 }
+
+/// Serves a table persisted by a previous run as `Ready` until its first refresh replaces it.
+#[cfg(any(feature = "duckdb", feature = "sqlite"))]
+fn mark_existing_table_stale(name: &str, existing_table: Option<ExistingTable>) {
+    if let Some(existing_table) = existing_table {
+        tracing::info!("Loaded dataset {name} from disk, refreshing in the background");
+        crate::status::mark_dataset_stale(name, existing_table.last_refresh_time);
+    }
+}
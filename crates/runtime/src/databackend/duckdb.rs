@@ -36,8 +36,11 @@ use sql_provider_datafusion::SqlTable;
 use crate::{
     datapublisher::{AddDataResult, DataPublisher},
     dataupdate::{DataUpdate, UpdateType},
+    timing::now_millis,
 };
 
+use super::{ExistingTable, METADATA_TABLE};
+
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("DbConnectionError: {source}"))]
@@ -78,7 +81,10 @@ pub struct DuckDBBackend {
             + Send
             + Sync,
     >,
+    /// Held while the table is updated, as it may be created or replaced.
     create_mutex: std::sync::Mutex<()>,
+    /// Whether the time of each refresh is recorded, for tables persisted across restarts.
+    record_refreshes: bool,
     _primary_keys: Option<Vec<String>>,
 }
 
@@ -112,6 +118,7 @@ impl DataPublisher for DuckDBBackend {
                 update_type: data_update.update_type,
                 duckdb_conn: conn,
                 create_mutex: &self.create_mutex,
+                record_refresh: self.record_refreshes,
             };
 
             duckdb_update.update()?;
@@ -128,7 +135,7 @@ impl DataPublisher for DuckDBBackend {
 
 impl DuckDBBackend {
     #[allow(clippy::needless_pass_by_value)]
-    pub fn new(
+    pub async fn new(
         ctx: Arc<SessionContext>,
         name: &str,
         mode: Mode,
@@ -137,15 +144,47 @@ impl DuckDBBackend {
     ) -> Result<Self> {
         let pool =
             DuckDbConnectionPool::new(name, &mode, &params).context(DbConnectionPoolSnafu)?;
+        let record_refreshes = matches!(mode, Mode::File);
+        if record_refreshes {
+            let conn = pool.connect().await.context(DbConnectionPoolSnafu)?;
+            let Some(conn) = conn.as_any().downcast_ref::<DuckDbConnection>() else {
+                return UnableToDowncastDbConnectionSnafu {}.fail();
+            };
+            let sql = format!(
+                r#"CREATE TABLE IF NOT EXISTS "{METADATA_TABLE}" (dataset_name VARCHAR PRIMARY KEY, last_refresh_time BIGINT)"#
+            );
+            tracing::trace!("{sql}");
+            conn.conn.execute(&sql, []).context(DuckDBSnafu)?;
+        }
+
         Ok(DuckDBBackend {
             ctx,
             name: name.to_string(),
             pool: Arc::new(pool),
             create_mutex: std::sync::Mutex::new(()),
+            record_refreshes,
             _primary_keys: primary_keys,
         })
     }
 
+    /// Registers the table persisted by a previous run, if there is one, so it can be queried
+    /// before the first refresh completes.
+    pub async fn register_existing_table(&self) -> Result<Option<ExistingTable>> {
+        let conn = self.pool.connect().await.context(DbConnectionPoolSnafu)?;
+        let Some(conn) = conn.as_any().downcast_ref::<DuckDbConnection>() else {
+            return UnableToDowncastDbConnectionSnafu {}.fail();
+        };
+
+        if !table_exists(&conn.conn, &self.name) {
+            return Ok(None);
+        }
+        let last_refresh_time = last_refresh_time(&conn.conn, &self.name);
+
+        self.initialize_datafusion().await?;
+
+        Ok(Some(ExistingTable { last_refresh_time }))
+    }
+
     async fn initialize_datafusion(&self) -> Result<()> {
         let table_exists = self
             .ctx
@@ -177,10 +216,39 @@ struct DuckDBUpdate<'a> {
     update_type: UpdateType,
     duckdb_conn: &'a mut dbconnection::duckdbconn::DuckDbConnection,
     create_mutex: &'a std::sync::Mutex<()>,
+    record_refresh: bool,
 }
 
 impl<'a> DuckDBUpdate<'a> {
+    /// Writes the update and the time of the refresh in one transaction, so a table persisted
+    /// across restarts is never recorded as refreshed with only part of its data.
     fn update(&mut self) -> Result<()> {
+        let _lock = self.create_mutex.lock().map_err(handle_poison)?;
+
+        self.duckdb_conn
+            .conn
+            .execute_batch("BEGIN TRANSACTION")
+            .context(DuckDBSnafu)?;
+        if let Err(e) = self.write() {
+            if let Err(rollback_error) = self.duckdb_conn.conn.execute_batch("ROLLBACK") {
+                tracing::error!(
+                    "Unable to roll back update to DuckDB table {}: {rollback_error}",
+                    self.name
+                );
+            }
+            return Err(e);
+        }
+        self.duckdb_conn
+            .conn
+            .execute_batch("COMMIT")
+            .context(DuckDBSnafu)?;
+
+        tracing::trace!("Processed update to DuckDB table {name}", name = self.name,);
+
+        Ok(())
+    }
+
+    fn write(&mut self) -> Result<()> {
         match self.update_type {
             UpdateType::Overwrite => self.create_table(true)?,
             UpdateType::Append => {
@@ -195,7 +263,21 @@ impl<'a> DuckDBUpdate<'a> {
             self.insert_batch(&batch)?;
         }
 
-        tracing::trace!("Processed update to DuckDB table {name}", name = self.name,);
+        if self.record_refresh {
+            self.record_refresh()?;
+        }
+
+        Ok(())
+    }
+
+    fn record_refresh(&self) -> Result<()> {
+        let sql = format!(r#"INSERT OR REPLACE INTO "{METADATA_TABLE}" VALUES (?, ?)"#);
+        tracing::trace!("{sql}");
+        let refresh_time = i64::try_from(now_millis()).unwrap_or(i64::MAX);
+        self.duckdb_conn
+            .conn
+            .execute(&sql, duckdb::params![self.name, refresh_time])
+            .context(DuckDBSnafu)?;
 
         Ok(())
     }
@@ -221,8 +303,6 @@ impl<'a> DuckDBUpdate<'a> {
     }
 
     fn create_table(&mut self, drop_if_exists: bool) -> Result<()> {
-        let _lock = self.create_mutex.lock().map_err(handle_poison)?;
-
         if self.table_exists() {
             if drop_if_exists {
                 let sql = format!(r#"DROP TABLE "{}""#, self.name);
@@ -282,23 +362,33 @@ impl<'a> DuckDBUpdate<'a> {
     }
 
     fn table_exists(&self) -> bool {
-        let sql = format!(
-            r#"SELECT EXISTS (
-              SELECT 1
-              FROM information_schema.tables 
-              WHERE table_name = '{name}'
-            )"#,
-            name = self.name
-        );
-        tracing::trace!("{sql}");
-
-        self.duckdb_conn
-            .conn
-            .query_row(&sql, [], |row| row.get::<usize, bool>(0))
-            .unwrap_or(false)
+        table_exists(&self.duckdb_conn.conn, &self.name)
     }
 }
 
+fn table_exists(conn: &duckdb::Connection, name: &str) -> bool {
+    let sql = format!(
+        r#"SELECT EXISTS (
+          SELECT 1
+          FROM information_schema.tables 
+          WHERE table_name = '{name}'
+        )"#
+    );
+    tracing::trace!("{sql}");
+
+    conn.query_row(&sql, [], |row| row.get::<usize, bool>(0))
+        .unwrap_or(false)
+}
+
+fn last_refresh_time(conn: &duckdb::Connection, name: &str) -> Option<u64> {
+    let sql = format!(r#"SELECT last_refresh_time FROM "{METADATA_TABLE}" WHERE dataset_name = ?"#);
+    tracing::trace!("{sql}");
+
+    conn.query_row(&sql, [name], |row| row.get::<usize, i64>(0))
+        .ok()
+        .and_then(|refresh_time| u64::try_from(refresh_time).ok())
+}
+
 #[allow(clippy::needless_pass_by_value)]
 fn handle_poison<T: fmt::Debug>(e: PoisonError<T>) -> Error {
     Error::LockPoisoned {
@@ -321,6 +411,7 @@ mod tests {
         let name = "test_add_data";
         let backend =
             DuckDBBackend::new(Arc::clone(&ctx), name, Mode::Memory, Arc::new(None), None)
+                .await
                 .expect("Unable to create DuckDBBackend");
 
         let schema = Arc::new(Schema::new(vec![
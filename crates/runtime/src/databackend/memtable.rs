@@ -16,13 +16,19 @@ limitations under the License.
 
 use snafu::prelude::*;
 use spicepod::component::dataset::Dataset;
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::{sync::Mutex, task::JoinHandle};
 
 use crate::{
     datapublisher::{AddDataResult, DataPublisher},
     dataupdate::{DataUpdate, UpdateType},
+    status,
+    wal::{self, WriteAheadLog},
 };
-use arrow::record_batch::RecordBatch;
+use arrow::{datatypes::SchemaRef, record_batch::RecordBatch};
 use datafusion::{
     datasource::MemTable,
     error::DataFusionError,
@@ -35,6 +41,8 @@ use datafusion::{
     },
 };
 
+pub mod snapshot;
+
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("Unable to add data: {source}"))]
@@ -47,6 +55,21 @@ pub enum Error {
 
     #[snafu(display("Invalid configuration: {msg}"))]
     InvalidConfiguration { msg: String },
+
+    #[snafu(display("Unable to read table: {source}"))]
+    UnableToReadTable { source: DataFusionError },
+
+    #[snafu(display("Unable to restore snapshot: {source}"))]
+    UnableToRestoreSnapshot { source: snapshot::Error },
+
+    #[snafu(display("Unable to take snapshot: {source}"))]
+    UnableToTakeSnapshot { source: snapshot::Error },
+
+    #[snafu(display("Unable to checkpoint the write-ahead log: {source}"))]
+    UnableToCheckpointWal { source: wal::Error },
+
+    #[snafu(display("Unable to join snapshot task: {source}"))]
+    UnableToJoinSnapshotTask { source: tokio::task::JoinError },
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
@@ -54,6 +77,19 @@ type Result<T, E = Error> = std::result::Result<T, E>;
 pub struct MemTableBackend {
     ctx: Arc<SessionContext>,
     name: String,
+    snapshots: Option<Snapshots>,
+}
+
+/// Periodically persists the table to disk for `mode: file` accelerations.
+struct Snapshots {
+    config: snapshot::Config,
+    /// Set when the table changes, cleared when a snapshot is taken.
+    dirty: Arc<AtomicBool>,
+    /// Held while a snapshot is taken, as snapshots are written through the same temporary file.
+    lock: Arc<Mutex<()>>,
+    /// The write-ahead log of the table, truncated to each snapshot.
+    wal: Option<Arc<WriteAheadLog>>,
+    task: JoinHandle<()>,
 }
 
 impl MemTableBackend {
@@ -62,10 +98,182 @@ impl MemTableBackend {
         MemTableBackend {
             ctx,
             name: name.to_owned(),
+            snapshots: None,
+        }
+    }
+
+    /// Restores the table from its last snapshot, if any, and snapshots it every `config.interval`
+    /// in which it changed.
+    ///
+    /// The restored table is queryable immediately, before the first refresh replaces it. If `wal`
+    /// is given, the updates it logged before each snapshot are dropped from it.
+    pub async fn with_snapshots(
+        mut self,
+        config: snapshot::Config,
+        wal: Option<Arc<WriteAheadLog>>,
+    ) -> Result<Self> {
+        let path = config.path(&self.name);
+        let format = config.format;
+        let restore_path = path.clone();
+        let snapshot = tokio::task::spawn_blocking(move || snapshot::read(&restore_path, format))
+            .await
+            .context(UnableToJoinSnapshotTaskSnafu)?
+            .context(UnableToRestoreSnapshotSnafu)?;
+
+        if let Some((schema, batches)) = snapshot {
+            let table = MemTable::try_new(schema, vec![batches]).context(UnableToAddDataSnafu)?;
+            self.ctx
+                .register_table(TableReference::bare(self.name.clone()), Arc::new(table))
+                .context(UnableToAddDataSnafu)?;
+            tracing::info!(
+                "Restored dataset {} from snapshot {}, refreshing in the background",
+                self.name,
+                path.display()
+            );
+            status::mark_dataset_stale(&self.name, None);
+        }
+
+        let dirty = Arc::new(AtomicBool::new(false));
+        let lock = Arc::new(Mutex::new(()));
+        let task = tokio::spawn(snapshot_periodically(
+            Arc::clone(&self.ctx),
+            self.name.clone(),
+            config.clone(),
+            Arc::clone(&dirty),
+            Arc::clone(&lock),
+            wal.clone(),
+        ));
+        self.snapshots = Some(Snapshots {
+            config,
+            dirty,
+            lock,
+            wal,
+            task,
+        });
+
+        Ok(self)
+    }
+}
+
+impl Drop for MemTableBackend {
+    fn drop(&mut self) {
+        if let Some(snapshots) = &self.snapshots {
+            snapshots.task.abort();
+        }
+    }
+}
+
+async fn snapshot_periodically(
+    ctx: Arc<SessionContext>,
+    name: String,
+    config: snapshot::Config,
+    dirty: Arc<AtomicBool>,
+    lock: Arc<Mutex<()>>,
+    wal: Option<Arc<WriteAheadLog>>,
+) {
+    let mut interval = tokio::time::interval(config.interval);
+    // The first tick completes immediately, and there is nothing new to snapshot yet.
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        match snapshot_if_dirty(&ctx, &name, &config, &dirty, &lock, wal.as_deref()).await {
+            Ok(()) => (),
+            // Retry on the next tick.
+            Err(e) => tracing::error!("Unable to snapshot dataset {name}: {e}"),
+        }
+    }
+}
+
+/// Takes a snapshot if the table changed since the last one.
+async fn snapshot_if_dirty(
+    ctx: &SessionContext,
+    name: &str,
+    config: &snapshot::Config,
+    dirty: &AtomicBool,
+    lock: &Mutex<()>,
+    wal: Option<&WriteAheadLog>,
+) -> Result<()> {
+    let _guard = lock.lock().await;
+    if !dirty.swap(false, Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    let result = take_snapshot(ctx, name, config, wal).await;
+    match &result {
+        Ok(()) => tracing::debug!("Took snapshot of dataset {name}"),
+        Err(_) => dirty.store(true, Ordering::SeqCst),
+    }
+    result
+}
+
+/// Writes the table to its snapshot, then truncates its write-ahead log to the updates applied
+/// after the table was read.
+async fn take_snapshot(
+    ctx: &SessionContext,
+    name: &str,
+    config: &snapshot::Config,
+    wal: Option<&WriteAheadLog>,
+) -> Result<()> {
+    let Some(wal) = wal else {
+        let (schema, batches) = read_table(ctx, name).await?;
+        return write_snapshot(name, config, schema, batches).await;
+    };
+
+    let (checkpoint, table) = {
+        // Writes are logged and applied under this lock, so the table read holds exactly the
+        // updates logged before the checkpoint.
+        let _writes = wal.lock_writes(name).await;
+        let checkpoint = wal
+            .begin_checkpoint(name)
+            .await
+            .context(UnableToCheckpointWalSnafu)?;
+        (checkpoint, read_table(ctx, name).await)
+    };
+
+    let written = match table {
+        Ok((schema, batches)) => write_snapshot(name, config, schema, batches).await,
+        Err(e) => Err(e),
+    };
+    match (written, checkpoint) {
+        (Ok(()), Some(position)) => wal
+            .truncate(name, position)
+            .await
+            .context(UnableToCheckpointWalSnafu),
+        (Ok(()), None) => Ok(()),
+        (Err(e), _) => {
+            wal.abort_checkpoint(name)
+                .await
+                .context(UnableToCheckpointWalSnafu)?;
+            Err(e)
         }
     }
 }
 
+async fn read_table(ctx: &SessionContext, name: &str) -> Result<(SchemaRef, Vec<RecordBatch>)> {
+    let df = ctx
+        .table(TableReference::bare(name.to_string()))
+        .await
+        .context(UnableToReadTableSnafu)?;
+    let schema: SchemaRef = Arc::new(df.schema().into());
+    let batches = df.collect().await.context(UnableToReadTableSnafu)?;
+    Ok((schema, batches))
+}
+
+async fn write_snapshot(
+    name: &str,
+    config: &snapshot::Config,
+    schema: SchemaRef,
+    batches: Vec<RecordBatch>,
+) -> Result<()> {
+    let path = config.path(name);
+    let format = config.format;
+    tokio::task::spawn_blocking(move || snapshot::write(&path, format, &schema, &batches))
+        .await
+        .context(UnableToJoinSnapshotTaskSnafu)?
+        .context(UnableToTakeSnapshotSnafu)
+}
+
 impl DataPublisher for MemTableBackend {
     fn add_data(&self, _dataset: Arc<Dataset>, data_update: DataUpdate) -> AddDataResult {
         Box::pin(async move {
@@ -82,6 +290,28 @@ impl DataPublisher for MemTableBackend {
             };
 
             table_update.update().await?;
+
+            if let Some(snapshots) = &self.snapshots {
+                snapshots.dirty.store(true, Ordering::SeqCst);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> AddDataResult {
+        Box::pin(async move {
+            if let Some(snapshots) = &self.snapshots {
+                snapshot_if_dirty(
+                    &self.ctx,
+                    &self.name,
+                    &snapshots.config,
+                    &snapshots.dirty,
+                    &snapshots.lock,
+                    snapshots.wal.as_deref(),
+                )
+                .await?;
+            }
             Ok(())
         })
     }
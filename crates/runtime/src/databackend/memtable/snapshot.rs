@@ -0,0 +1,321 @@
+/*
+Copyright 2024 The Spice.ai OSS Authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! On-disk snapshots of an Arrow-engine (`MemTable`) acceleration configured with `mode: file`.
+//!
+//! Snapshots are configured with the acceleration params:
+//! - `arrow_snapshot_dir`: the directory snapshots are written to (default `.spice/data`).
+//! - `arrow_snapshot_format`: `parquet` (default) or `arrow` for the Arrow IPC file format.
+//! - `arrow_snapshot_interval`: how often the table is snapshotted if it changed (default `60s`).
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use arrow::{
+    datatypes::SchemaRef,
+    error::ArrowError,
+    ipc::{reader::FileReader, writer::FileWriter},
+    record_batch::RecordBatch,
+};
+use datafusion::parquet::{
+    arrow::{arrow_reader::ParquetRecordBatchReaderBuilder, ArrowWriter},
+    errors::ParquetError,
+};
+use snafu::prelude::*;
+
+const DEFAULT_SNAPSHOT_DIR: &str = ".spice/data";
+const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Invalid snapshot configuration: {msg}"))]
+    InvalidConfiguration { msg: String },
+
+    #[snafu(display("Unable to create snapshot directory {}: {source}", path.display()))]
+    UnableToCreateDirectory { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Unable to write snapshot {}: {source}", path.display()))]
+    UnableToWriteSnapshot { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Unable to read snapshot {}: {source}", path.display()))]
+    UnableToReadSnapshot { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Unable to encode snapshot {}: {source}", path.display()))]
+    UnableToEncodeParquet { path: PathBuf, source: ParquetError },
+
+    #[snafu(display("Unable to decode snapshot {}: {source}", path.display()))]
+    UnableToDecodeParquet { path: PathBuf, source: ParquetError },
+
+    #[snafu(display("Unable to encode snapshot {}: {source}", path.display()))]
+    UnableToEncodeArrow { path: PathBuf, source: ArrowError },
+
+    #[snafu(display("Unable to decode snapshot {}: {source}", path.display()))]
+    UnableToDecodeArrow { path: PathBuf, source: ArrowError },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Parquet,
+    ArrowIpc,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Parquet => "parquet",
+            Format::ArrowIpc => "arrow",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub dir: PathBuf,
+    pub format: Format,
+    pub interval: Duration,
+}
+
+impl Config {
+    pub fn try_from_params(params: Option<&HashMap<String, String>>) -> Result<Self> {
+        let param = |key: &str| params.and_then(|params| params.get(key));
+
+        let dir = param("arrow_snapshot_dir")
+            .map_or_else(|| PathBuf::from(DEFAULT_SNAPSHOT_DIR), PathBuf::from);
+
+        let format = match param("arrow_snapshot_format").map(String::as_str) {
+            None | Some("parquet") => Format::Parquet,
+            Some("arrow") => Format::ArrowIpc,
+            Some(format) => InvalidConfigurationSnafu {
+                msg: format!(
+                    "Unknown arrow_snapshot_format {format}, expected \"parquet\" or \"arrow\""
+                ),
+            }
+            .fail()?,
+        };
+
+        let interval = match param("arrow_snapshot_interval") {
+            None => DEFAULT_SNAPSHOT_INTERVAL,
+            Some(interval) => match fundu::parse_duration(interval) {
+                Ok(interval) if !interval.is_zero() => interval,
+                _ => InvalidConfigurationSnafu {
+                    msg: format!("Invalid arrow_snapshot_interval {interval}"),
+                }
+                .fail()?,
+            },
+        };
+
+        Ok(Self {
+            dir,
+            format,
+            interval,
+        })
+    }
+
+    /// The path of the snapshot for the table `name`, which is always directly in `dir`.
+    #[must_use]
+    pub fn path(&self, name: &str) -> PathBuf {
+        let path = self.dir.join(format!(
+            "{}.{}",
+            encode_file_name(name),
+            self.format.extension()
+        ));
+        debug_assert_eq!(path.parent(), Some(self.dir.as_path()));
+        path
+    }
+}
+
+/// Percent-encodes the characters of `name` that aren't letters, digits, `_`, `-` or a `.` between
+/// other characters, so it can't name another directory or file and no two names share a file.
+pub(crate) fn encode_file_name(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for (i, byte) in name.bytes().enumerate() {
+        let is_inner_dot = byte == b'.' && i > 0 && i + 1 < name.len();
+        if byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-' || is_inner_dot {
+            encoded.push(char::from(byte));
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+/// Atomically replaces the snapshot at `path` with `batches`.
+///
+/// The snapshot is written to a temporary file next to `path` and renamed over it once it has been
+/// synced, so a crash mid-write leaves the previous snapshot intact.
+pub fn write(
+    path: &Path,
+    format: Format,
+    schema: &SchemaRef,
+    batches: &[RecordBatch],
+) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context(UnableToCreateDirectorySnafu { path: dir })?;
+    }
+
+    let temp_path = path.with_extension(format!("{}.tmp", format.extension()));
+    let file = File::create(&temp_path).context(UnableToWriteSnapshotSnafu { path: &temp_path })?;
+
+    let file = match format {
+        Format::Parquet => {
+            let mut writer = ArrowWriter::try_new(file, Arc::clone(schema), None)
+                .context(UnableToEncodeParquetSnafu { path: &temp_path })?;
+            for batch in batches {
+                writer
+                    .write(batch)
+                    .context(UnableToEncodeParquetSnafu { path: &temp_path })?;
+            }
+            writer
+                .into_inner()
+                .context(UnableToEncodeParquetSnafu { path: &temp_path })?
+        }
+        Format::ArrowIpc => {
+            let mut writer = FileWriter::try_new(file, schema)
+                .context(UnableToEncodeArrowSnafu { path: &temp_path })?;
+            for batch in batches {
+                writer
+                    .write(batch)
+                    .context(UnableToEncodeArrowSnafu { path: &temp_path })?;
+            }
+            writer
+                .into_inner()
+                .context(UnableToEncodeArrowSnafu { path: &temp_path })?
+        }
+    };
+
+    file.sync_all()
+        .context(UnableToWriteSnapshotSnafu { path: &temp_path })?;
+    fs::rename(&temp_path, path).context(UnableToWriteSnapshotSnafu { path })?;
+
+    Ok(())
+}
+
+/// Reads the snapshot at `path`, returning `None` if no snapshot has been taken yet.
+pub fn read(path: &Path, format: Format) -> Result<Option<(SchemaRef, Vec<RecordBatch>)>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context(UnableToReadSnapshotSnafu { path }),
+    };
+
+    let snapshot = match format {
+        Format::Parquet => {
+            let builder = ParquetRecordBatchReaderBuilder::try_new(file)
+                .context(UnableToDecodeParquetSnafu { path })?;
+            let schema = Arc::clone(builder.schema());
+            let reader = builder
+                .build()
+                .context(UnableToDecodeParquetSnafu { path })?;
+            let batches = reader
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .context(UnableToDecodeArrowSnafu { path })?;
+            (schema, batches)
+        }
+        Format::ArrowIpc => {
+            let reader =
+                FileReader::try_new(file, None).context(UnableToDecodeArrowSnafu { path })?;
+            let schema = reader.schema();
+            let batches = reader
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .context(UnableToDecodeArrowSnafu { path })?;
+            (schema, batches)
+        }
+    };
+
+    Ok(Some(snapshot))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::{
+        array::{Int64Array, StringArray},
+        datatypes::{DataType, Field, Schema},
+    };
+
+    fn test_batch() -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("name", DataType::Utf8, true),
+        ]));
+        RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int64Array::from(vec![1, 2, 3])),
+                Arc::new(StringArray::from(vec![Some("a"), None, Some("c")])),
+            ],
+        )
+        .expect("valid record batch")
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("spice_snapshot_{}", uuid::Uuid::new_v4()));
+        let batch = test_batch();
+
+        for format in [Format::Parquet, Format::ArrowIpc] {
+            let config = Config {
+                dir: dir.clone(),
+                format,
+                interval: DEFAULT_SNAPSHOT_INTERVAL,
+            };
+            let path = config.path("test");
+            assert!(read(&path, format).expect("read snapshot").is_none());
+
+            write(&path, format, &batch.schema(), &[batch.clone()]).expect("write snapshot");
+            let (schema, batches) = read(&path, format)
+                .expect("read snapshot")
+                .expect("snapshot exists");
+            assert_eq!(schema.fields(), batch.schema().fields());
+            assert_eq!(batches.len(), 1);
+            assert_eq!(batches[0].columns(), batch.columns());
+        }
+
+        fs::remove_dir_all(&dir).expect("remove snapshot dir");
+    }
+
+    #[test]
+    fn test_path_stays_in_dir() {
+        let config = Config {
+            dir: PathBuf::from("data"),
+            format: Format::Parquet,
+            interval: DEFAULT_SNAPSHOT_INTERVAL,
+        };
+
+        assert_eq!(
+            config.path("eth.recent_blocks"),
+            PathBuf::from("data/eth.recent_blocks.parquet")
+        );
+        assert_eq!(config.path(".."), PathBuf::from("data/%2E%2E.parquet"));
+        assert_eq!(
+            config.path("../../etc/passwd"),
+            PathBuf::from("data/%2E.%2F..%2Fetc%2Fpasswd.parquet")
+        );
+        assert_eq!(config.path("a\\b"), PathBuf::from("data/a%5Cb.parquet"));
+        assert_eq!(
+            config.path("/tmp/x"),
+            PathBuf::from("data/%2Ftmp%2Fx.parquet")
+        );
+    }
+}
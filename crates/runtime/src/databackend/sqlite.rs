@@ -32,8 +32,11 @@ use tokio_rusqlite::Connection;
 use crate::{
     datapublisher::{AddDataResult, DataPublisher},
     dataupdate::{DataUpdate, UpdateType},
+    timing::now_millis,
 };
 
+use super::{ExistingTable, METADATA_TABLE};
+
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("DbConnectionError: {source}"))]
@@ -62,6 +65,9 @@ pub enum Error {
 
     #[snafu(display("Unable to downcast DbConnection to SqliteConnection"))]
     UnableToDowncastDbConnection {},
+
+    #[snafu(display("Unable to read existing sqlite table: {source}"))]
+    UnableToReadExistingTable { source: tokio_rusqlite::Error },
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
@@ -71,6 +77,8 @@ pub struct SqliteBackend {
     ctx: Arc<SessionContext>,
     name: String,
     pool: Arc<dyn DbConnectionPool<Connection, &'static (dyn ToSql + Sync)> + Send + Sync>,
+    /// Whether the time of each refresh is recorded, for tables persisted across restarts.
+    record_refreshes: bool,
     _primary_keys: Option<Vec<String>>,
 }
 
@@ -97,6 +105,7 @@ impl DataPublisher for SqliteBackend {
                 data: data_update.data,
                 update_type: data_update.update_type,
                 pool,
+                record_refresh: self.record_refreshes,
             };
 
             sqlite_update.update().await?;
@@ -120,17 +129,77 @@ impl SqliteBackend {
         mode: Mode,
         primary_keys: Option<Vec<String>>,
     ) -> Result<Self> {
+        let record_refreshes = matches!(mode, Mode::File);
         let pool = SqliteConnectionPool::new(name, mode, params)
             .await
             .context(DbConnectionPoolSnafu)?;
+        if record_refreshes {
+            let conn = pool.connect().await.context(DbConnectionPoolSnafu)?;
+            let Some(conn) = conn.as_any().downcast_ref::<SqliteConnection>() else {
+                return UnableToDowncastDbConnectionSnafu {}.fail();
+            };
+            conn.conn
+                .call(|conn| {
+                    conn.execute(
+                        &format!(
+                            r#"CREATE TABLE IF NOT EXISTS "{METADATA_TABLE}" (dataset_name TEXT PRIMARY KEY, last_refresh_time INTEGER)"#
+                        ),
+                        [],
+                    )?;
+                    Ok(())
+                })
+                .await
+                .context(UpdateSnafu)?;
+        }
+
         Ok(SqliteBackend {
             ctx,
             name: name.to_string(),
             pool: Arc::new(pool),
+            record_refreshes,
             _primary_keys: primary_keys,
         })
     }
 
+    /// Registers the table persisted by a previous run, if there is one, so it can be queried
+    /// before the first refresh completes.
+    pub async fn register_existing_table(&self) -> Result<Option<ExistingTable>> {
+        let conn = self.pool.connect().await.context(DbConnectionPoolSnafu)?;
+        let Some(conn) = conn.as_any().downcast_ref::<SqliteConnection>() else {
+            return UnableToDowncastDbConnectionSnafu {}.fail();
+        };
+
+        let name = self.name.clone();
+        let existing_table = conn
+            .conn
+            .call(move |conn| {
+                let sql =
+                    r"SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type='table' AND name = ?1)";
+                let table_exists: bool = conn.query_row(sql, [&name], |row| row.get(0))?;
+                if !table_exists {
+                    return Ok(None);
+                }
+
+                let sql = format!(
+                    r#"SELECT last_refresh_time FROM "{METADATA_TABLE}" WHERE dataset_name = ?1"#
+                );
+                let last_refresh_time = conn
+                    .query_row(&sql, [&name], |row| row.get::<usize, i64>(0))
+                    .ok()
+                    .and_then(|refresh_time| u64::try_from(refresh_time).ok());
+
+                Ok(Some(ExistingTable { last_refresh_time }))
+            })
+            .await
+            .context(UnableToReadExistingTableSnafu)?;
+
+        if existing_table.is_some() {
+            self.initialize_datafusion().await?;
+        }
+
+        Ok(existing_table)
+    }
+
     async fn initialize_datafusion(&self) -> Result<()> {
         let table_exists = self
             .ctx
@@ -161,6 +230,7 @@ struct SqliteUpdate {
     data: Vec<RecordBatch>,
     update_type: UpdateType,
     pool: Arc<dyn DbConnectionPool<Connection, &'static (dyn ToSql + Sync)> + Send + Sync>,
+    record_refresh: bool,
 }
 
 impl SqliteUpdate {
@@ -196,6 +266,10 @@ impl SqliteUpdate {
                     self.insert_batch(&transaction, batch)?;
                 }
 
+                if self.record_refresh {
+                    self.record_refresh(&transaction)?;
+                }
+
                 transaction.commit()?;
 
                 tracing::trace!("Processed update to Sqlite table {name}", name = self.name,);
@@ -219,6 +293,16 @@ impl SqliteUpdate {
         Ok(())
     }
 
+    fn record_refresh(&self, transaction: &Transaction<'_>) -> tokio_rusqlite::Result<()> {
+        let refresh_time = i64::try_from(now_millis()).unwrap_or(i64::MAX);
+        transaction.execute(
+            &format!(r#"INSERT OR REPLACE INTO "{METADATA_TABLE}" VALUES (?1, ?2)"#),
+            rusqlite::params![self.name, refresh_time],
+        )?;
+
+        Ok(())
+    }
+
     fn create_table(&mut self, transaction: &Transaction<'_>) -> tokio_rusqlite::Result<()> {
         let Some(batch) = self.data.pop() else {
             return Ok(());
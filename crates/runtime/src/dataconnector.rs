@@ -111,6 +111,14 @@ pub async fn create_new_connector(
     }
 }
 
+/// Returns whether a `DataConnector` is registered for `name`.
+pub async fn is_registered(name: &str) -> bool {
+    DATA_CONNECTOR_FACTORY_REGISTRY
+        .lock()
+        .await
+        .contains_key(name)
+}
+
 pub async fn register_all() {
     tokio::join!(
         register_connector_factory("databricks", databricks::Databricks::create),
@@ -207,7 +215,7 @@ impl dyn DataConnector + '_ {
             return Box::pin(stream! {
                 loop {
                     tracing::info!("Refreshing data for {}", dataset.name);
-                    status::update_dataset(dataset.name.clone(), status::ComponentStatus::Refreshing);
+                    status::start_dataset_refresh(&dataset.name);
                     let timer = TimeMeasurement::new("load_dataset_duration_ms", vec![("dataset", dataset.name.clone())]);
                     let new_data = self.get_all_data(dataset).await;
                     status::update_dataset(dataset.name.clone(), status::ComponentStatus::Ready);
@@ -228,7 +236,7 @@ impl dyn DataConnector + '_ {
                 "load_dataset_duration_ms",
                 vec![("dataset", dataset.name.clone())],
             );
-            status::update_dataset(dataset.name.clone(), status::ComponentStatus::Refreshing);
+            status::start_dataset_refresh(&dataset.name);
             let data = self.get_all_data(dataset).await;
             status::update_dataset(dataset.name.clone(), status::ComponentStatus::Ready);
             drop(timer);
@@ -58,6 +58,37 @@ impl S3 {
     }
 }
 
+/// Configures access to the S3 `bucket` with the `region` and `endpoint` params, i.e. a local MinIO,
+/// and the `key` and `secret` of the `s3` secret. Without a secret, requests aren't signed.
+pub(crate) fn object_store_builder(
+    bucket: &str,
+    params: Option<&HashMap<String, String>>,
+    secret: Option<&Secret>,
+) -> AmazonS3Builder {
+    let mut s3_builder = AmazonS3Builder::new()
+        .with_bucket_name(bucket)
+        .with_allow_http(true);
+
+    if let Some(region) = params.and_then(|params| params.get("region")) {
+        s3_builder = s3_builder.with_region(region);
+    }
+    if let Some(endpoint) = params.and_then(|params| params.get("endpoint")) {
+        s3_builder = s3_builder.with_endpoint(endpoint);
+    }
+    if let Some(secret) = secret {
+        if let Some(key) = secret.get("key") {
+            s3_builder = s3_builder.with_access_key_id(key);
+        };
+        if let Some(secret) = secret.get("secret") {
+            s3_builder = s3_builder.with_secret_access_key(secret);
+        };
+    } else {
+        s3_builder = s3_builder.with_skip_signature(true);
+    };
+
+    s3_builder
+}
+
 impl DataConnectorFactory for S3 {
     fn create(
         secret: Option<Secret>,
@@ -98,26 +129,7 @@ impl DataConnector for S3 {
                     .into(),
                 })?;
 
-        let mut s3_builder = AmazonS3Builder::new()
-            .with_bucket_name(bucket)
-            .with_allow_http(true);
-
-        if let Some(region) = self.params.get("region") {
-            s3_builder = s3_builder.with_region(region);
-        }
-        if let Some(endpoint) = self.params.get("endpoint") {
-            s3_builder = s3_builder.with_endpoint(endpoint);
-        }
-        if let Some(secret) = &self.secret {
-            if let Some(key) = secret.get("key") {
-                s3_builder = s3_builder.with_access_key_id(key);
-            };
-            if let Some(secret) = secret.get("secret") {
-                s3_builder = s3_builder.with_secret_access_key(secret);
-            };
-        } else {
-            s3_builder = s3_builder.with_skip_signature(true);
-        };
+        let s3_builder = object_store_builder(bucket, Some(&self.params), self.secret.as_ref());
 
         let s3 = s3_builder
             .build()
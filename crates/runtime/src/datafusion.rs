@@ -19,13 +19,26 @@ use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use std::time::Duration;
 
+use crate::config;
 use crate::databackend::{self, DataBackendBuilder};
 use crate::dataconnector::DataConnector;
-use crate::datapublisher::DataPublisher;
+use crate::datapublisher::{DataPublisher, DatasetUpdate, NotifyingPublisher};
+use crate::model::Model;
+use crate::modelfunction;
+use crate::refresh;
+use crate::resultcache::{self, ResultCache};
+use crate::status;
+use crate::wal::WriteAheadLog;
+use datafusion::dataframe::DataFrame;
 use datafusion::datasource::ViewTable;
 use datafusion::error::DataFusionError;
 use datafusion::execution::context::SessionConfig;
-use datafusion::execution::{context::SessionContext, options::ParquetReadOptions};
+use datafusion::execution::disk_manager::DiskManagerConfig;
+use datafusion::execution::memory_pool::FairSpillPool;
+use datafusion::execution::runtime_env::{RuntimeConfig, RuntimeEnv};
+use datafusion::execution::{
+    context::SessionContext, options::ParquetReadOptions, SendableRecordBatchStream,
+};
 use datafusion::sql::parser;
 use datafusion::sql::parser::DFParser;
 use datafusion::sql::sqlparser;
@@ -34,7 +47,11 @@ use datafusion::sql::sqlparser::dialect::PostgreSqlDialect;
 use futures::StreamExt;
 use snafu::prelude::*;
 use spicepod::component::dataset::Dataset;
-use tokio::sync::RwLock;
+use tokio::sync::{
+    broadcast,
+    mpsc::{self, error::TrySendError},
+    RwLock,
+};
 use tokio::time::{sleep, Instant};
 use tokio::{spawn, task};
 
@@ -99,31 +116,156 @@ pub enum Error {
     #[snafu(display("Expected a SQL view statement, received nothing."))]
     ExpectedSqlView,
 
+    #[snafu(display("Dataset {name} not found or not refreshable from a data connector"))]
+    DatasetNotRefreshable {
+        name: String,
+    },
+
+    #[snafu(display("Invalid refresh filter: {source}"))]
+    InvalidRefreshFilter {
+        source: DataFusionError,
+    },
+
+    #[snafu(display("Too many refreshes are queued for dataset {name}"))]
+    RefreshQueueFull {
+        name: String,
+    },
+
+    #[snafu(display("Unable to create the query runtime: {source}"))]
+    UnableToCreateRuntime {
+        source: DataFusionError,
+    },
+
     InvalidObjectStore,
 }
 
-type PublisherList = Arc<RwLock<Vec<Arc<Box<dyn DataPublisher>>>>>;
+pub(crate) type PublisherList = Arc<RwLock<Vec<Arc<Box<dyn DataPublisher>>>>>;
 
 type DatasetAndPublishers = (Arc<Dataset>, PublisherList);
 
+/// Row count and size of a table, where they are known.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TableStatistics {
+    pub num_rows: Option<usize>,
+    /// Approximate size of the table data in memory, in bytes.
+    pub total_byte_size: Option<usize>,
+}
+
+/// The number of on-demand refreshes that can be queued per dataset.
+const REFRESH_QUEUE_CAPACITY: usize = 16;
+
+/// The number of updates to accelerated datasets a subscriber can fall behind before it misses
+/// some of them.
+const DATA_UPDATES_CAPACITY: usize = 64;
+
 pub struct DataFusion {
     pub ctx: Arc<SessionContext>,
     connectors_tasks: HashMap<String, task::JoinHandle<()>>,
     data_publishers: HashMap<String, DatasetAndPublishers>,
+    /// The backends of the accelerated datasets, flushed before they're removed.
+    accelerated_backends: HashMap<String, Arc<Box<dyn DataPublisher>>>,
+    refresh_requests: HashMap<String, mpsc::Sender<refresh::Request>>,
+    pub refreshes: Arc<refresh::Tracker>,
+    results_cache: Option<Arc<ResultCache>>,
+    data_updates: broadcast::Sender<DatasetUpdate>,
+    model_functions: modelfunction::ModelFunctions,
 }
 
 impl DataFusion {
     #[must_use]
     pub fn new() -> Self {
+        Self::with_context(SessionContext::new_with_config(Self::session_config()))
+    }
+
+    /// Creates a session with the memory limit, spill directory, partitioning and batch size set
+    /// in the runtime config.
+    pub fn try_new_with_config(config: &config::Config) -> Result<Self> {
+        let mut df_config = Self::session_config();
+        if let Some(target_partitions) = config.target_partitions {
+            df_config = df_config.with_target_partitions(target_partitions.get());
+        }
+        if let Some(batch_size) = config.batch_size {
+            df_config = df_config.with_batch_size(batch_size.get());
+        }
+
+        let mut runtime_config = RuntimeConfig::new();
+        if let Some(memory_limit) = config.memory_limit {
+            // Operators that can't spill are served first, and the remaining memory is split
+            // evenly between the operators that can.
+            runtime_config =
+                runtime_config.with_memory_pool(Arc::new(FairSpillPool::new(memory_limit)));
+        }
+        if let Some(spill_dir) = &config.spill_dir {
+            runtime_config = runtime_config
+                .with_disk_manager(DiskManagerConfig::NewSpecified(vec![spill_dir.clone()]));
+        }
+        let runtime = RuntimeEnv::new(runtime_config).context(UnableToCreateRuntimeSnafu)?;
+
+        let mut df = Self::with_context(SessionContext::new_with_config_rt(
+            df_config,
+            Arc::new(runtime),
+        ));
+        df.results_cache = config
+            .results_cache_max_size
+            .map(|max_size| Arc::new(ResultCache::new(max_size)));
+        Ok(df)
+    }
+
+    fn session_config() -> SessionConfig {
         let mut df_config = SessionConfig::new().with_information_schema(true);
         df_config.options_mut().sql_parser.dialect = "PostgreSQL".to_string();
+        df_config
+    }
+
+    fn with_context(ctx: SessionContext) -> Self {
+        let model_functions = modelfunction::ModelFunctions::new(&ctx);
         DataFusion {
-            ctx: Arc::new(SessionContext::new_with_config(df_config)),
+            ctx: Arc::new(ctx),
             connectors_tasks: HashMap::new(),
             data_publishers: HashMap::new(),
+            accelerated_backends: HashMap::new(),
+            refresh_requests: HashMap::new(),
+            refreshes: Arc::new(refresh::Tracker::new()),
+            results_cache: None,
+            data_updates: broadcast::channel(DATA_UPDATES_CAPACITY).0,
+            model_functions,
+        }
+    }
+
+    /// Receives every update written to an accelerated dataset from when this is called.
+    #[must_use]
+    pub fn subscribe_data_updates(&self) -> broadcast::Receiver<DatasetUpdate> {
+        self.data_updates.subscribe()
+    }
+
+    /// Executes `data_frame`, reusing the results of an identical earlier query if they're cached.
+    pub async fn execute_stream(
+        &self,
+        data_frame: DataFrame,
+    ) -> std::result::Result<SendableRecordBatchStream, DataFusionError> {
+        let Some(results_cache) = &self.results_cache else {
+            return data_frame.execute_stream().await;
+        };
+
+        match results_cache.lookup(data_frame.logical_plan()) {
+            resultcache::Lookup::Hit(results) => Ok(results),
+            resultcache::Lookup::Miss(key) => {
+                Ok(results_cache.cache_stream(key, data_frame.execute_stream().await?))
+            }
+            resultcache::Lookup::Uncacheable => data_frame.execute_stream().await,
         }
     }
 
+    /// Makes `model` callable from SQL with `predict`, and as a table function named after it if
+    /// it runs over windows of rows.
+    pub fn register_model(&self, model: &Model) {
+        self.model_functions.register(&self.ctx, model);
+    }
+
+    pub fn deregister_model(&self, model_name: &str) {
+        self.model_functions.deregister(&self.ctx, model_name);
+    }
+
     pub async fn register_parquet(&self, table_name: &str, path: &str) -> Result<()> {
         self.ctx
             .register_parquet(table_name, path, ParquetReadOptions::default())
@@ -131,6 +273,29 @@ impl DataFusion {
             .context(RegisterParquetSnafu { file: path })
     }
 
+    /// Records the backend accelerating `table_name`, to flush it before it's removed.
+    pub fn attach_backend(&mut self, table_name: &str, backend: Arc<Box<dyn DataPublisher>>) {
+        self.accelerated_backends
+            .insert(table_name.to_string(), backend);
+    }
+
+    /// Persists the data the backend accelerating `table_name` holds only in memory.
+    pub async fn flush_backend(&self, table_name: &str) {
+        let Some(backend) = self.accelerated_backends.get(table_name) else {
+            return;
+        };
+        if let Err(e) = backend.flush().await {
+            tracing::error!("Unable to flush dataset {table_name}: {e}");
+        }
+    }
+
+    /// Persists the data every accelerated dataset holds only in memory, i.e. before shutting down.
+    pub async fn flush_backends(&self) {
+        for table_name in self.accelerated_backends.keys() {
+            self.flush_backend(table_name).await;
+        }
+    }
+
     pub async fn attach_publisher(
         &mut self,
         table_name: &str,
@@ -155,6 +320,7 @@ impl DataFusion {
         &self,
         dataset: impl Borrow<Dataset>,
         secrets_provider: Arc<RwLock<secrets::SecretsProvider>>,
+        wal: Option<Arc<WriteAheadLog>>,
     ) -> Result<Box<dyn DataPublisher>> {
         let dataset = dataset.borrow();
         let table_name = dataset.name.to_string();
@@ -175,15 +341,31 @@ impl DataFusion {
         let backend_secret = secrets_provider.read().await.get_secret(&secret_key).await;
 
         let data_backend: Box<dyn DataPublisher> =
-            DataBackendBuilder::new(Arc::clone(&self.ctx), table_name)
+            DataBackendBuilder::new(Arc::clone(&self.ctx), table_name.clone())
                 .engine(acceleration.engine())
                 .mode(acceleration.mode())
                 .params(params)
                 .secret(backend_secret)
+                .write_ahead_log(wal.filter(|_| WriteAheadLog::should_log(dataset)))
                 .build()
                 .await
                 .context(DatasetConfigurationSnafu)?;
 
+        let data_backend: Box<dyn DataPublisher> = Box::new(NotifyingPublisher::new(
+            data_backend,
+            self.data_updates.clone(),
+        ));
+
+        // Every write to the accelerated table goes through its backend, so the backend can
+        // invalidate the cached results reading it.
+        if let Some(results_cache) = &self.results_cache {
+            results_cache.track_table(&table_name);
+            return Ok(Box::new(resultcache::InvalidatingPublisher::new(
+                data_backend,
+                Arc::clone(results_cache),
+            )));
+        }
+
         Ok(data_backend)
     }
 
@@ -207,6 +389,57 @@ impl DataFusion {
         Ok(arrow::datatypes::Schema::from(data_frame.schema()))
     }
 
+    /// Returns the statistics reported by the table's provider.
+    ///
+    /// If `count_rows` is set and the provider doesn't know its row count or size, i.e. a DuckDB or
+    /// SQLite acceleration, the table is scanned to measure them, its size being that of the rows
+    /// read into memory. This should only be used for accelerated tables, to avoid scanning remote
+    /// sources.
+    pub async fn get_table_statistics(
+        &self,
+        dataset: &str,
+        count_rows: bool,
+    ) -> Result<TableStatistics> {
+        let data_frame = self
+            .ctx
+            .table(dataset)
+            .await
+            .context(UnableToGetTableSnafu)?;
+
+        let plan = data_frame
+            .clone()
+            .create_physical_plan()
+            .await
+            .context(UnableToGetTableSnafu)?;
+        let statistics = plan.statistics().context(UnableToGetTableSnafu)?;
+
+        let mut table_statistics = TableStatistics {
+            num_rows: statistics.num_rows.get_value().copied(),
+            total_byte_size: statistics.total_byte_size.get_value().copied(),
+        };
+
+        let is_known =
+            table_statistics.num_rows.is_some() && table_statistics.total_byte_size.is_some();
+        if count_rows && !is_known {
+            let (mut num_rows, mut total_byte_size) = (0, 0);
+            let mut stream = data_frame
+                .execute_stream()
+                .await
+                .context(UnableToGetTableSnafu)?;
+            while let Some(batch) = stream.next().await {
+                let batch = batch.context(UnableToGetTableSnafu)?;
+                num_rows += batch.num_rows();
+                total_byte_size += batch.get_array_memory_size();
+            }
+            table_statistics.num_rows.get_or_insert(num_rows);
+            table_statistics
+                .total_byte_size
+                .get_or_insert(total_byte_size);
+        }
+
+        Ok(table_statistics)
+    }
+
     #[allow(clippy::needless_pass_by_value)]
     pub fn attach_connector_to_publisher(
         &mut self,
@@ -215,33 +448,100 @@ impl DataFusion {
         publisher: Arc<Box<dyn DataPublisher>>,
     ) -> Result<()> {
         let table_name = dataset.name.clone();
-        let table_exists = self.ctx.table_exist(table_name.as_str()).unwrap_or(false);
-        if table_exists {
+        // The accelerated table is already registered when it was restored from disk, i.e. from an
+        // Arrow snapshot or a `mode: file` DuckDB or SQLite database, so only a connector that's
+        // already attached is refused.
+        if self.connectors_tasks.contains_key(&table_name) {
             return TableAlreadyExistsSnafu.fail();
         }
 
+        let (refresh_tx, mut refresh_rx) = mpsc::channel(REFRESH_QUEUE_CAPACITY);
+        let ctx = Arc::clone(&self.ctx);
+        let refreshes = Arc::clone(&self.refreshes);
+        // Data written to the dataset by the runtime, i.e. through Flight `DoPut`.
+        let writers = self
+            .data_publishers
+            .get(&table_name)
+            .map(|(_, publishers)| Arc::clone(publishers));
+
         let task_handle = task::spawn(async move {
             let dataset = Arc::new(dataset);
-            let mut stream = data_connector.get_data(&dataset);
+            // The stream ends after the initial load if there is no refresh interval, but the task
+            // keeps serving on-demand refreshes until the dataset is removed.
+            let mut stream = data_connector.get_data(&dataset).fuse();
             loop {
-                let future_result = stream.next().await;
-                match future_result {
-                    Some(data_update) => {
+                tokio::select! {
+                    Some(data_update) = stream.next() => {
                         match publisher.add_data(Arc::clone(&dataset), data_update).await {
-                            Ok(()) => (),
-                            Err(e) => tracing::error!("Error adding data: {e}"),
+                            Ok(()) => {
+                                status::record_dataset_refresh(&dataset.name);
+                            }
+                            Err(e) => {
+                                tracing::error!("Error adding data: {e}");
+                                status::record_dataset_error(&dataset.name, e.to_string());
+                            }
                         }
                     }
-                    None => break,
-                };
+                    Some(request) = refresh_rx.recv() => {
+                        refresh::run(
+                            &ctx,
+                            &dataset,
+                            &*data_connector,
+                            &**publisher,
+                            writers.as_ref(),
+                            &refreshes,
+                            request,
+                        )
+                        .await;
+                    }
+                    else => break,
+                }
             }
         });
 
-        self.connectors_tasks.insert(table_name, task_handle);
+        self.connectors_tasks
+            .insert(table_name.clone(), task_handle);
+        self.refresh_requests.insert(table_name, refresh_tx);
 
         Ok(())
     }
 
+    /// Queues an immediate refresh of `dataset_name` from its data connector, optionally limited to
+    /// the rows matching the SQL filter `sql`.
+    pub async fn refresh_dataset(
+        &self,
+        dataset_name: &str,
+        sql: Option<String>,
+    ) -> Result<refresh::RefreshStatus> {
+        let Some(refresh_tx) = self.refresh_requests.get(dataset_name) else {
+            return DatasetNotRefreshableSnafu { name: dataset_name }.fail();
+        };
+
+        let filter = match &sql {
+            Some(sql) => Some(
+                refresh::parse_filter(&self.ctx, dataset_name, sql)
+                    .await
+                    .context(InvalidRefreshFilterSnafu)?,
+            ),
+            None => None,
+        };
+
+        let refresh = self.refreshes.queue(dataset_name, sql);
+        let request = refresh::Request {
+            id: refresh.id.clone(),
+            filter,
+        };
+        if let Err(e) = refresh_tx.try_send(request) {
+            self.refreshes.remove(&refresh.id);
+            return match e {
+                TrySendError::Full(_) => RefreshQueueFullSnafu { name: dataset_name }.fail(),
+                TrySendError::Closed(_) => DatasetNotRefreshableSnafu { name: dataset_name }.fail(),
+            };
+        }
+
+        Ok(refresh)
+    }
+
     #[must_use]
     pub fn table_exists(&self, dataset_name: &str) -> bool {
         self.ctx.table_exist(dataset_name).unwrap_or(false)
@@ -269,6 +569,14 @@ impl DataFusion {
             self.data_publishers.remove(dataset_name);
         }
 
+        self.accelerated_backends.remove(dataset_name);
+
+        self.refresh_requests.remove(dataset_name);
+
+        if let Some(results_cache) = &self.results_cache {
+            results_cache.untrack_table(dataset_name);
+        }
+
         Ok(())
     }
 
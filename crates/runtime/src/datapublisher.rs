@@ -18,9 +18,24 @@ use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
 
+use snafu::prelude::*;
 use spicepod::component::dataset::Dataset;
+use tokio::sync::{broadcast, RwLock};
 
-use crate::dataupdate::DataUpdate;
+use crate::{
+    dataupdate::DataUpdate,
+    flight::channels::{self, ChannelMap},
+    wal::{self, WriteAheadLog},
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Unable to write data to the write-ahead log: {source}"))]
+    UnableToWriteToWal { source: wal::Error },
+
+    #[snafu(display("Unable to add data to {publisher}: {reason}"))]
+    UnableToAddData { publisher: String, reason: String },
+}
 
 pub type AddDataResult<'a> =
     Pin<Box<dyn Future<Output = Result<(), Box<dyn std::error::Error>>> + Send + 'a>>;
@@ -28,5 +43,105 @@ pub type AddDataResult<'a> =
 pub trait DataPublisher: Send + Sync {
     fn add_data(&self, dataset: Arc<Dataset>, data_update: DataUpdate) -> AddDataResult;
 
+    /// Persists the data held only in memory, before the runtime shuts down or the dataset is
+    /// removed.
+    fn flush(&self) -> AddDataResult {
+        Box::pin(async { Ok(()) })
+    }
+
     fn name(&self) -> &str;
 }
+
+/// Writes `data_update` received by the runtime to `dataset`, i.e. through Flight `DoPut` or as
+/// model predictions.
+///
+/// The update is logged to the write-ahead log if the dataset is logged, added to each of its
+/// publishers, then published to the dataset's `do_exchange` subscribers once they've all added it.
+pub(crate) async fn write(
+    dataset: &Arc<Dataset>,
+    publishers: &RwLock<Vec<Arc<Box<dyn DataPublisher>>>>,
+    wal: Option<&WriteAheadLog>,
+    channel_map: &ChannelMap,
+    data_update: DataUpdate,
+) -> Result<(), Error> {
+    // The update is only acknowledged once it is in the write-ahead log. Logged updates are applied
+    // in the order they're logged, so a snapshot checkpointing the log sees exactly the updates
+    // logged before the checkpoint.
+    let _logged_writes = match wal.filter(|_| WriteAheadLog::should_log(dataset)) {
+        Some(wal) => {
+            let guard = wal.lock_writes(&dataset.name).await;
+            wal.append(&dataset.name, &data_update)
+                .await
+                .context(UnableToWriteToWalSnafu)?;
+            Some(guard)
+        }
+        None => None,
+    };
+
+    for publisher in publishers.read().await.iter() {
+        publisher
+            .add_data(Arc::clone(dataset), data_update.clone())
+            .await
+            .map_err(|e| Error::UnableToAddData {
+                publisher: publisher.name().to_string(),
+                reason: e.to_string(),
+            })?;
+    }
+
+    // Only applied updates are published. They're retained so subscribers can resume from a
+    // sequence number, even if there are no subscribers yet.
+    channels::get_or_create_channel(channel_map, &dataset.name)
+        .await
+        .publish(data_update);
+
+    Ok(())
+}
+
+/// A `DataUpdate` written to a dataset.
+#[derive(Debug, Clone)]
+pub struct DatasetUpdate {
+    pub dataset: String,
+    pub data_update: DataUpdate,
+}
+
+/// Sends every update written to a dataset to the subscribers of `updates`, i.e. to run a model
+/// over the new rows.
+pub struct NotifyingPublisher {
+    inner: Box<dyn DataPublisher>,
+    updates: broadcast::Sender<DatasetUpdate>,
+}
+
+impl NotifyingPublisher {
+    #[must_use]
+    pub fn new(inner: Box<dyn DataPublisher>, updates: broadcast::Sender<DatasetUpdate>) -> Self {
+        Self { inner, updates }
+    }
+}
+
+impl DataPublisher for NotifyingPublisher {
+    fn add_data(&self, dataset: Arc<Dataset>, data_update: DataUpdate) -> AddDataResult {
+        Box::pin(async move {
+            let name = dataset.name.clone();
+            // The update is moved into the inner publisher, so it's only copied if it's needed.
+            let notification = (self.updates.receiver_count() > 0).then(|| DatasetUpdate {
+                dataset: name,
+                data_update: data_update.clone(),
+            });
+
+            self.inner.add_data(dataset, data_update).await?;
+            if let Some(notification) = notification {
+                // Sending only fails if every subscriber has since been dropped.
+                let _ = self.updates.send(notification);
+            }
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> AddDataResult {
+        self.inner.flush()
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+}
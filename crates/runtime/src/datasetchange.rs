@@ -0,0 +1,406 @@
+/*
+Copyright 2024 The Spice.ai OSS Authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Changes to the loaded datasets requested through the runtime API instead of the spicepod files.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use datafusion::execution::context::SessionContext;
+use secrets::SecretsProvider;
+use serde_yaml::{Mapping, Value};
+use snafu::prelude::*;
+use spicepod::component::dataset::Dataset;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{databackend::DataBackendBuilder, dataconnector};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("No spicepod is loaded"))]
+    NoSpicepodLoaded,
+
+    #[snafu(display("Dataset {name} already exists"))]
+    DatasetAlreadyExists { name: String },
+
+    #[snafu(display("Dataset {name} not found"))]
+    DatasetNotFound { name: String },
+
+    #[snafu(display("Invalid dataset: {reason}"))]
+    InvalidDataset { reason: String },
+
+    #[snafu(display("Unable to persist dataset {name}: {reason}"))]
+    NotPersistable { name: String, reason: String },
+
+    #[snafu(display("Unable to read {}: {source}", path.display()))]
+    UnableToReadSpicepod {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Unable to parse {}: {source}", path.display()))]
+    UnableToParseSpicepod {
+        path: PathBuf,
+        source: serde_yaml::Error,
+    },
+
+    #[snafu(display("Unable to serialize dataset: {source}"))]
+    UnableToSerializeDataset { source: serde_yaml::Error },
+
+    #[snafu(display("Unable to write {}: {source}", path.display()))]
+    UnableToWriteSpicepod {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("The runtime is not accepting dataset changes"))]
+    RuntimeUnavailable,
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone)]
+pub enum DatasetChange {
+    Create(Dataset),
+    Update(Dataset),
+    Delete(String),
+}
+
+impl DatasetChange {
+    #[must_use]
+    pub fn name(&self) -> &str {
+        match self {
+            DatasetChange::Create(ds) | DatasetChange::Update(ds) => &ds.name,
+            DatasetChange::Delete(name) => name,
+        }
+    }
+}
+
+/// A `DatasetChange` sent to the runtime, which applies it in order with changes to the spicepod files.
+pub struct Request {
+    pub change: DatasetChange,
+    /// Also write the change to the root `spicepod.yaml`, which is rewritten without its comments
+    /// and formatting. Changes that aren't persisted are kept when the spicepod files change.
+    pub persist: bool,
+    pub respond_to: oneshot::Sender<Result<()>>,
+}
+
+pub type Sender = mpsc::Sender<Request>;
+pub type Receiver = mpsc::Receiver<Request>;
+
+/// Sends `change` to the runtime and waits for it to be applied.
+pub async fn apply(sender: &Sender, change: DatasetChange, persist: bool) -> Result<()> {
+    let (respond_to, response) = oneshot::channel();
+    sender
+        .send(Request {
+            change,
+            persist,
+            respond_to,
+        })
+        .await
+        .map_err(|_| Error::RuntimeUnavailable)?;
+
+    response.await.map_err(|_| Error::RuntimeUnavailable)?
+}
+
+/// Checks that `ds` can be loaded by this runtime, creating its data connector to check its params.
+pub async fn validate(ds: &Dataset, secrets_provider: &SecretsProvider) -> Result<()> {
+    validate_name(&ds.name)?;
+
+    if ds
+        .view_sql()
+        .map_err(|e| Error::InvalidDataset {
+            reason: e.to_string(),
+        })?
+        .is_some()
+    {
+        return Ok(());
+    }
+
+    if let Some(acceleration) = ds.acceleration.as_ref().filter(|acc| acc.enabled) {
+        DataBackendBuilder::new(Arc::new(SessionContext::new()), ds.name.clone())
+            .engine(acceleration.engine())
+            .mode(acceleration.mode())
+            .params(Arc::new(acceleration.params.clone()))
+            .validate()
+            .map_err(|e| Error::InvalidDataset {
+                reason: e.to_string(),
+            })?;
+    }
+
+    if let Some(refresh_interval) = ds
+        .acceleration
+        .as_ref()
+        .and_then(|acc| acc.refresh_interval.as_ref())
+    {
+        if ds.refresh_interval().is_none() {
+            return InvalidDatasetSnafu {
+                reason: format!("invalid refresh_interval {refresh_interval}"),
+            }
+            .fail();
+        }
+    }
+
+    let source = ds.source();
+    if source == "localhost" {
+        return Ok(());
+    }
+
+    let secret = secrets_provider.get_secret(&source).await;
+    match dataconnector::create_new_connector(&source, secret, Arc::new(ds.params.clone())).await {
+        Some(Ok(_)) => Ok(()),
+        Some(Err(e)) => InvalidDatasetSnafu {
+            reason: format!("unable to create data connector {source}: {e}"),
+        }
+        .fail(),
+        None => InvalidDatasetSnafu {
+            reason: format!("unknown data connector {source}"),
+        }
+        .fail(),
+    }
+}
+
+/// Dataset names are used in SQL, file names and write-ahead log keys, so they're restricted to
+/// identifiers separated by dots, e.g. `eth.recent_blocks`.
+fn validate_name(name: &str) -> Result<()> {
+    let is_identifier = |part: &str| {
+        let mut chars = part.chars();
+        chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+
+    if name.split('.').all(is_identifier) {
+        return Ok(());
+    }
+
+    InvalidDatasetSnafu {
+        reason: format!(
+            "invalid name {name:?}, expected letters, digits and underscores separated by dots, not starting with a digit"
+        ),
+    }
+    .fail()
+}
+
+/// The root spicepod file with `change` applied, to be written before the change is applied.
+pub struct PersistedSpicepod {
+    path: PathBuf,
+    contents: String,
+}
+
+impl PersistedSpicepod {
+    /// Applies `change` to the datasets defined inline in the root `spicepod.yaml` under `root_path`.
+    ///
+    /// The file is parsed and serialized again, so its comments and formatting aren't kept.
+    ///
+    /// Datasets defined by reference or in dependent spicepods can't be persisted.
+    pub fn prepare(root_path: &Path, change: &DatasetChange) -> Result<Self> {
+        let name = change.name();
+        let Some(path) = ["spicepod.yaml", "spicepod.yml"]
+            .iter()
+            .map(|file_name| root_path.join(file_name))
+            .find(|path| path.exists())
+        else {
+            return NotPersistableSnafu {
+                name,
+                reason: format!("no spicepod.yaml found in {}", root_path.display()),
+            }
+            .fail();
+        };
+
+        let contents =
+            fs::read_to_string(&path).context(UnableToReadSpicepodSnafu { path: &path })?;
+        let mut spicepod: Value =
+            serde_yaml::from_str(&contents).context(UnableToParseSpicepodSnafu { path: &path })?;
+        let Some(spicepod_mapping) = spicepod.as_mapping_mut() else {
+            return NotPersistableSnafu {
+                name,
+                reason: format!("{} is not a mapping", path.display()),
+            }
+            .fail();
+        };
+
+        if !spicepod_mapping.contains_key("datasets") {
+            spicepod_mapping.insert("datasets".into(), Value::Sequence(vec![]));
+        }
+        let Some(datasets) = spicepod_mapping
+            .get_mut("datasets")
+            .and_then(Value::as_sequence_mut)
+        else {
+            return NotPersistableSnafu {
+                name,
+                reason: format!("datasets in {} is not a list", path.display()),
+            }
+            .fail();
+        };
+
+        let position = datasets.iter().position(|ds| {
+            ds.as_mapping()
+                .and_then(|ds| ds.get("name"))
+                .and_then(Value::as_str)
+                == Some(name)
+        });
+
+        match (change, position) {
+            (DatasetChange::Create(ds), None) => datasets.push(to_value(ds)?),
+            (DatasetChange::Create(_), Some(_)) => {
+                return DatasetAlreadyExistsSnafu { name }.fail();
+            }
+            (DatasetChange::Update(ds), Some(position)) => datasets[position] = to_value(ds)?,
+            (DatasetChange::Delete(_), Some(position)) => {
+                datasets.remove(position);
+            }
+            (DatasetChange::Update(_) | DatasetChange::Delete(_), None) => {
+                return NotPersistableSnafu {
+                    name,
+                    reason: format!("the dataset is not defined in {}", path.display()),
+                }
+                .fail();
+            }
+        }
+
+        let contents = serde_yaml::to_string(&spicepod).context(UnableToSerializeDatasetSnafu)?;
+        Ok(Self { path, contents })
+    }
+
+    pub fn write(self) -> Result<()> {
+        fs::write(&self.path, self.contents).context(UnableToWriteSpicepodSnafu { path: self.path })
+    }
+}
+
+fn to_value(ds: &Dataset) -> Result<Value> {
+    let value = serde_yaml::to_value(ds).context(UnableToSerializeDatasetSnafu)?;
+    // Drop unset optional fields instead of writing them out as nulls.
+    Ok(match value {
+        Value::Mapping(mapping) => Value::Mapping(
+            mapping
+                .into_iter()
+                .filter(|(_, value)| !value.is_null())
+                .collect::<Mapping>(),
+        ),
+        value => value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dataset(yaml: &str) -> Dataset {
+        serde_yaml::from_str(yaml).expect("valid dataset")
+    }
+
+    #[test]
+    fn test_validate_name() {
+        for name in ["orders", "_orders", "eth.recent_blocks", "orders_2024"] {
+            assert!(validate_name(name).is_ok(), "{name} should be valid");
+        }
+
+        for name in [
+            "",
+            "2024_orders",
+            "orders\"; DROP TABLE orders; --",
+            "../orders",
+            "orders/2024",
+            "orders\\2024",
+            "orders..2024",
+            ".orders",
+            "orders.",
+            "orders\0",
+            "order s",
+        ] {
+            assert!(
+                matches!(validate_name(name), Err(Error::InvalidDataset { .. })),
+                "{name:?} should be rejected"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validate_rejects_invalid_acceleration() {
+        let secrets_provider = SecretsProvider::new();
+        let ds = dataset(
+            r"
+name: orders
+from: localhost
+acceleration:
+  mode: file
+  params:
+    arrow_snapshot_format: csv
+",
+        );
+
+        assert!(matches!(
+            validate(&ds, &secrets_provider).await,
+            Err(Error::InvalidDataset { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_validate_rejects_unknown_data_connector() {
+        let secrets_provider = SecretsProvider::new();
+        let ds = dataset("{name: orders, from: 'unknown:orders'}");
+
+        assert!(matches!(
+            validate(&ds, &secrets_provider).await,
+            Err(Error::InvalidDataset { .. })
+        ));
+    }
+
+    #[test]
+    fn test_persisted_spicepod() {
+        let root_path =
+            std::env::temp_dir().join(format!("spice_datasetchange_test_{}", std::process::id()));
+        fs::create_dir_all(&root_path).expect("create dir");
+        fs::write(
+            root_path.join("spicepod.yaml"),
+            "version: v1beta1\nkind: Spicepod\nname: app\ndatasets:\n  - from: localhost\n    name: orders\n",
+        )
+        .expect("write spicepod");
+
+        let customers = dataset("{name: customers, from: localhost}");
+        PersistedSpicepod::prepare(&root_path, &DatasetChange::Create(customers.clone()))
+            .expect("prepare")
+            .write()
+            .expect("write");
+        assert!(matches!(
+            PersistedSpicepod::prepare(&root_path, &DatasetChange::Create(customers)),
+            Err(Error::DatasetAlreadyExists { .. })
+        ));
+
+        PersistedSpicepod::prepare(&root_path, &DatasetChange::Delete("orders".to_string()))
+            .expect("prepare")
+            .write()
+            .expect("write");
+
+        let contents = fs::read_to_string(root_path.join("spicepod.yaml")).expect("read");
+        let spicepod: Value = serde_yaml::from_str(&contents).expect("parse");
+        let names = spicepod["datasets"]
+            .as_sequence()
+            .expect("datasets")
+            .iter()
+            .filter_map(|ds| ds["name"].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["customers"]);
+
+        fs::remove_dir_all(&root_path).expect("remove dir");
+    }
+}
@@ -0,0 +1,217 @@
+/*
+Copyright 2024 The Spice.ai OSS Authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Describes how a query is planned and, optionally, how long each part of it took to run.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use datafusion::{
+    dataframe::DataFrame,
+    error::DataFusionError,
+    physical_plan::{
+        display::DisplayableExecutionPlan, displayable, execute_stream, ExecutionPlan,
+    },
+};
+use flight_datafusion::FlightExec;
+use flightsql_datafusion::FlightSqlExec;
+use futures::StreamExt;
+use serde::Serialize;
+use sql_provider_datafusion::{RemoteQueryExec, SqlExec};
+
+#[derive(Debug, Serialize)]
+pub struct Explanation {
+    /// The optimized logical plan.
+    pub logical_plan: String,
+    /// The physical plan, including each operator's metrics if the query was run.
+    pub physical_plan: String,
+    /// The SQL pushed down to remote sources, i.e. by `SqlExec` and `FlightSqlExec`.
+    pub remote_queries: Vec<RemoteQuery>,
+    /// Each operator of the physical plan with its metrics, if the query was run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operators: Option<Vec<OperatorMetrics>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RemoteQuery {
+    pub operator: String,
+    pub sql: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OperatorMetrics {
+    pub operator: String,
+    /// How deep the operator is in the physical plan, where the root is 0.
+    pub depth: usize,
+    pub output_rows: Option<usize>,
+    pub elapsed_compute_ns: Option<usize>,
+    /// All metrics of the operator, summed over its partitions and formatted for display.
+    pub metrics: BTreeMap<String, String>,
+}
+
+/// Plans `data_frame`, and runs it to collect each operator's metrics if `analyze` is set.
+///
+/// The query's results are discarded.
+pub async fn explain(data_frame: DataFrame, analyze: bool) -> Result<Explanation, DataFusionError> {
+    let task_ctx = Arc::new(data_frame.task_ctx());
+    let logical_plan = data_frame.clone().into_optimized_plan()?;
+    let physical_plan = data_frame.create_physical_plan().await?;
+
+    let mut remote_queries = Vec::new();
+    visit(&physical_plan, 0, &mut |plan, _| {
+        if let Some(remote_query) = remote_query(plan) {
+            remote_queries.push(remote_query);
+        }
+    });
+
+    if !analyze {
+        return Ok(Explanation {
+            logical_plan: logical_plan.display_indent().to_string(),
+            physical_plan: displayable(physical_plan.as_ref()).indent(true).to_string(),
+            remote_queries,
+            operators: None,
+        });
+    }
+
+    let mut results = execute_stream(Arc::clone(&physical_plan), task_ctx)?;
+    while let Some(batch) = results.next().await {
+        batch?;
+    }
+
+    let mut operators = Vec::new();
+    visit(&physical_plan, 0, &mut |plan, depth| {
+        operators.push(operator_metrics(plan, depth));
+    });
+
+    Ok(Explanation {
+        logical_plan: logical_plan.display_indent().to_string(),
+        physical_plan: DisplayableExecutionPlan::with_metrics(physical_plan.as_ref())
+            .indent(true)
+            .to_string(),
+        remote_queries,
+        operators: Some(operators),
+    })
+}
+
+fn visit(
+    plan: &Arc<dyn ExecutionPlan>,
+    depth: usize,
+    f: &mut impl FnMut(&Arc<dyn ExecutionPlan>, usize),
+) {
+    f(plan, depth);
+    for child in plan.children() {
+        visit(&child, depth + 1, f);
+    }
+}
+
+/// The SQL of the operators that send queries to remote sources.
+fn remote_query(plan: &Arc<dyn ExecutionPlan>) -> Option<RemoteQuery> {
+    let plan = plan.as_any();
+    let (operator, exec): (&str, &dyn RemoteQueryExec) =
+        if let Some(exec) = plan.downcast_ref::<SqlExec>() {
+            ("SqlExec", exec)
+        } else if let Some(exec) = plan.downcast_ref::<FlightSqlExec>() {
+            ("FlightSqlExec", exec)
+        } else if let Some(exec) = plan.downcast_ref::<FlightExec>() {
+            ("FlightExec", exec)
+        } else {
+            return None;
+        };
+
+    Some(RemoteQuery {
+        operator: operator.to_string(),
+        // Unused clauses leave extra spaces in the generated SQL.
+        sql: exec
+            .remote_sql()?
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" "),
+    })
+}
+
+fn operator_metrics(plan: &Arc<dyn ExecutionPlan>, depth: usize) -> OperatorMetrics {
+    let line = displayable(plan.as_ref()).one_line().to_string();
+    let operator = line
+        .split(|c: char| c == ':' || c.is_whitespace())
+        .next()
+        .unwrap_or_default()
+        .to_string();
+
+    let Some(metrics) = plan.metrics() else {
+        return OperatorMetrics {
+            operator,
+            depth,
+            output_rows: None,
+            elapsed_compute_ns: None,
+            metrics: BTreeMap::new(),
+        };
+    };
+
+    let aggregated = metrics.aggregate_by_name().timestamps_removed();
+    OperatorMetrics {
+        operator,
+        depth,
+        output_rows: metrics.output_rows(),
+        elapsed_compute_ns: metrics.elapsed_compute(),
+        metrics: aggregated
+            .iter()
+            .map(|metric| {
+                (
+                    metric.value().name().to_string(),
+                    metric.value().to_string(),
+                )
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::{
+        array::Int64Array,
+        datatypes::{DataType, Field, Schema},
+        record_batch::RecordBatch,
+    };
+    use datafusion::{datasource::MemTable, execution::context::SessionContext};
+
+    #[tokio::test]
+    async fn test_explain_analyze() {
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int64, false)]));
+        let batch = RecordBatch::try_new(
+            Arc::clone(&schema),
+            vec![Arc::new(Int64Array::from(vec![1, 2, 3]))],
+        )
+        .expect("valid record batch");
+        let ctx = SessionContext::new();
+        let table = MemTable::try_new(schema, vec![vec![batch]]).expect("valid table");
+        ctx.register_table("t", Arc::new(table))
+            .expect("register table");
+
+        let data_frame = ctx
+            .sql("SELECT id FROM t WHERE id > 1")
+            .await
+            .expect("valid query");
+        let explanation = explain(data_frame, true).await.expect("query runs");
+
+        assert!(explanation.logical_plan.contains("TableScan: t"));
+        assert!(explanation.remote_queries.is_empty());
+        let operators = explanation.operators.expect("operators are analyzed");
+        assert_eq!(operators[0].depth, 0);
+        assert!(operators
+            .iter()
+            .any(|operator| operator.operator == "FilterExec" && operator.output_rows == Some(2)));
+    }
+}
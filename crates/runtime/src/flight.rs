@@ -15,8 +15,9 @@ limitations under the License.
 */
 
 use crate::datafusion::DataFusion;
-use crate::dataupdate::DataUpdate;
 use crate::measure_scope_ms;
+use crate::querylimits::{self, QueryLimits, QueryOptions};
+use crate::wal::WriteAheadLog;
 use arrow::array::RecordBatch;
 use arrow::datatypes::Schema;
 use arrow::ipc::writer::{DictionaryTracker, IpcDataGenerator};
@@ -30,14 +31,15 @@ use datafusion::sql::sqlparser::parser::ParserError;
 use futures::stream::{self, BoxStream, StreamExt};
 use futures::{Stream, TryStreamExt};
 use snafu::prelude::*;
-use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::broadcast::Sender;
+use std::time::Duration;
 use tokio::sync::RwLock;
+use tonic::metadata::MetadataValue;
 use tonic::transport::Server;
 use tonic::{Request, Response, Status, Streaming};
 
 mod actions;
+pub(crate) mod channels;
 mod do_exchange;
 mod do_get;
 mod do_put;
@@ -53,7 +55,9 @@ use arrow_flight::{
 
 pub struct Service {
     datafusion: Arc<RwLock<DataFusion>>,
-    channel_map: Arc<RwLock<HashMap<String, Arc<Sender<DataUpdate>>>>>,
+    channel_map: channels::ChannelMap,
+    wal: Option<Arc<WriteAheadLog>>,
+    query_limits: Arc<QueryLimits>,
 }
 
 #[tonic::async_trait]
@@ -167,14 +171,18 @@ impl Service {
     }
 
     async fn sql_to_flight_stream(
-        datafusion: Arc<RwLock<DataFusion>>,
+        &self,
         sql: String,
+        options: QueryOptions,
     ) -> Result<BoxStream<'static, Result<FlightData, Status>>, Status> {
-        let df = datafusion
-            .read()
+        let mut query = self
+            .query_limits
+            .start(options)
             .await
-            .ctx
-            .sql(&sql)
+            .map_err(|e| query_limits_status(&e))?;
+
+        let df = query
+            .run(async { self.datafusion.read().await.ctx.sql(&sql).await })
             .await
             .map_err(handle_datafusion_error)?;
         let schema = df.schema().clone().into();
@@ -182,8 +190,11 @@ impl Service {
         let schema_as_ipc = SchemaAsIpc::new(&schema, &options);
         let schema_flight_data = FlightData::from(schema_as_ipc);
 
-        let batches_stream: SendableRecordBatchStream =
-            df.execute_stream().await.map_err(to_tonic_err)?;
+        let batches_stream: SendableRecordBatchStream = query
+            .run(async { self.datafusion.read().await.execute_stream(df).await })
+            .await
+            .map_err(handle_datafusion_error)?;
+        let batches_stream = query.stream(batches_stream);
 
         let batches_stream = batches_stream
             .then(move |batch_result| {
@@ -203,7 +214,7 @@ impl Service {
                             flights.push(flight_batch.into());
                             Ok(flights)
                         }
-                        Err(e) => Err(Status::internal(e.to_string())),
+                        Err(e) => Err(handle_datafusion_error(e)),
                     }
                 }
             })
@@ -242,8 +253,56 @@ where
     }
 }
 
+/// Who is running the query in `request`, and the timeout requested with `grpc-timeout`.
+fn query_options<T>(request: &Request<T>) -> QueryOptions {
+    let metadata = request.metadata();
+    let principal = querylimits::principal(
+        request.remote_addr().map(|addr| addr.ip()),
+        metadata.get("authorization").map(MetadataValue::as_bytes),
+    );
+    let timeout = metadata
+        .get("grpc-timeout")
+        .and_then(|timeout| timeout.to_str().ok())
+        .and_then(parse_grpc_timeout);
+
+    QueryOptions {
+        protocol: "flight",
+        principal,
+        timeout,
+    }
+}
+
+/// Parses a `grpc-timeout` header, i.e. `30S` or `500m`.
+fn parse_grpc_timeout(timeout: &str) -> Option<Duration> {
+    if timeout.len() < 2 {
+        return None;
+    }
+    let (value, unit) = timeout.split_at(timeout.len() - 1);
+    let value: u64 = value.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(value.saturating_mul(3600))),
+        "M" => Some(Duration::from_secs(value.saturating_mul(60))),
+        "S" => Some(Duration::from_secs(value)),
+        "m" => Some(Duration::from_millis(value)),
+        "u" => Some(Duration::from_micros(value)),
+        "n" => Some(Duration::from_nanos(value)),
+        _ => None,
+    }
+}
+
+fn query_limits_status(e: &querylimits::Error) -> Status {
+    match e {
+        querylimits::Error::QueryTimedOut { .. } => Status::deadline_exceeded(e.to_string()),
+        querylimits::Error::TooManyQueries => Status::resource_exhausted(e.to_string()),
+    }
+}
+
 #[allow(clippy::needless_pass_by_value)]
 fn handle_datafusion_error(e: DataFusionError) -> Status {
+    if let Some(e) = querylimits::find_error(&e) {
+        return query_limits_status(e);
+    }
+
     match e {
         DataFusionError::Plan(err_msg) | DataFusionError::Execution(err_msg) => {
             Status::invalid_argument(err_msg)
@@ -279,10 +338,18 @@ pub enum Error {
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
-pub async fn start(bind_address: std::net::SocketAddr, df: Arc<RwLock<DataFusion>>) -> Result<()> {
+pub async fn start(
+    bind_address: std::net::SocketAddr,
+    df: Arc<RwLock<DataFusion>>,
+    wal: Option<Arc<WriteAheadLog>>,
+    query_limits: Arc<QueryLimits>,
+    channel_map: channels::ChannelMap,
+) -> Result<()> {
     let service = Service {
         datafusion: df.clone(),
-        channel_map: Arc::new(RwLock::new(HashMap::new())),
+        channel_map,
+        wal,
+        query_limits,
     };
     let svc = FlightServiceServer::new(service);
 
@@ -17,9 +17,11 @@ limitations under the License.
 use std::fmt::{self, Display, Formatter};
 
 use prost::Message;
+use serde::Deserialize;
 use tonic::{Request, Response, Status};
 
 use crate::{
+    datafusion,
     flight::{flightsql::prepared_statement_query, to_tonic_err, Service},
     timing::{TimeMeasurement, TimedStream},
 };
@@ -33,14 +35,31 @@ use arrow_flight::{
 enum ActionType {
     CreatePreparedStatement,
     ClosePreparedStatement,
+    RefreshDataset,
+    GetRefreshStatus,
     Unknown,
 }
 
+/// The JSON body of a `RefreshDataset` action.
+#[derive(Deserialize)]
+struct RefreshDatasetRequest {
+    dataset: String,
+    sql: Option<String>,
+}
+
+/// The JSON body of a `GetRefreshStatus` action.
+#[derive(Deserialize)]
+struct GetRefreshStatusRequest {
+    id: String,
+}
+
 impl ActionType {
     fn from_str(s: &str) -> Self {
         match s {
             "CreatePreparedStatement" => ActionType::CreatePreparedStatement,
             "ClosePreparedStatement" => ActionType::ClosePreparedStatement,
+            "RefreshDataset" => ActionType::RefreshDataset,
+            "GetRefreshStatus" => ActionType::GetRefreshStatus,
             _ => ActionType::Unknown,
         }
     }
@@ -49,6 +68,8 @@ impl ActionType {
         match self {
             ActionType::CreatePreparedStatement => "CreatePreparedStatement",
             ActionType::ClosePreparedStatement => "ClosePreparedStatement",
+            ActionType::RefreshDataset => "RefreshDataset",
+            ActionType::GetRefreshStatus => "GetRefreshStatus",
             ActionType::Unknown => "Unknown",
         }
     }
@@ -76,9 +97,25 @@ pub(crate) fn list() -> Response<<Service as FlightService>::ListActionsStream>
             Response Message: N/A"
             .into(),
     };
+    let refresh_dataset_action_type = FlightActionType {
+        r#type: ActionType::RefreshDataset.to_string(),
+        description: "Triggers an immediate refresh of an accelerated dataset.\n
+            Request Message: JSON {\"dataset\": string, \"sql\": optional string filter}\n
+            Response Message: JSON refresh status, including the refresh id"
+            .into(),
+    };
+    let get_refresh_status_action_type = FlightActionType {
+        r#type: ActionType::GetRefreshStatus.to_string(),
+        description: "Returns the progress and outcome of a dataset refresh.\n
+            Request Message: JSON {\"id\": string}\n
+            Response Message: JSON refresh status"
+            .into(),
+    };
     let actions: Vec<Result<FlightActionType, Status>> = vec![
         Ok(create_prepared_statement_action_type),
         Ok(close_prepared_statement_action_type),
+        Ok(refresh_dataset_action_type),
+        Ok(get_refresh_status_action_type),
     ];
 
     let output = TimedStream::new(futures::stream::iter(actions), || {
@@ -122,6 +159,51 @@ pub(crate) async fn do_action(
             tracing::trace!("do_action: ClosePreparedStatement");
             futures::stream::iter(vec![Ok(arrow_flight::Result::default())])
         }
+        ActionType::RefreshDataset => {
+            tracing::trace!("do_action: RefreshDataset");
+            let refresh_request: RefreshDatasetRequest =
+                serde_json::from_slice(&request.get_ref().body)
+                    .map_err(|e| Status::invalid_argument(e.to_string()))?;
+            let refresh = flight_svc
+                .datafusion
+                .read()
+                .await
+                .refresh_dataset(&refresh_request.dataset, refresh_request.sql)
+                .await
+                .map_err(|e| match e {
+                    datafusion::Error::DatasetNotRefreshable { .. } => {
+                        Status::not_found(e.to_string())
+                    }
+                    datafusion::Error::InvalidRefreshFilter { .. } => {
+                        Status::invalid_argument(e.to_string())
+                    }
+                    datafusion::Error::RefreshQueueFull { .. } => {
+                        Status::resource_exhausted(e.to_string())
+                    }
+                    _ => Status::internal(e.to_string()),
+                })?;
+            futures::stream::iter(vec![Ok(arrow_flight::Result {
+                body: serde_json::to_vec(&refresh).map_err(to_tonic_err)?.into(),
+            })])
+        }
+        ActionType::GetRefreshStatus => {
+            tracing::trace!("do_action: GetRefreshStatus");
+            let status_request: GetRefreshStatusRequest =
+                serde_json::from_slice(&request.get_ref().body)
+                    .map_err(|e| Status::invalid_argument(e.to_string()))?;
+            let refresh = flight_svc
+                .datafusion
+                .read()
+                .await
+                .refreshes
+                .get(&status_request.id)
+                .ok_or_else(|| {
+                    Status::not_found(format!("Refresh {} not found", status_request.id))
+                })?;
+            futures::stream::iter(vec![Ok(arrow_flight::Result {
+                body: serde_json::to_vec(&refresh).map_err(to_tonic_err)?.into(),
+            })])
+        }
         ActionType::Unknown => return Err(Status::invalid_argument("Unknown action type")),
     };
 
@@ -0,0 +1,331 @@
+/*
+Copyright 2024 The Spice.ai OSS Authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use arrow::record_batch::RecordBatch;
+use tokio::sync::{broadcast, RwLock};
+
+use crate::{dataupdate::DataUpdate, timing::now_millis};
+
+/// The number of updates a subscriber can fall behind before it is disconnected with a lag error.
+const CHANNEL_CAPACITY: usize = 100;
+
+/// The number of published updates retained per dataset for subscribers resuming from a sequence number.
+const HISTORY_CAPACITY: usize = 256;
+
+/// The memory in bytes the retained updates of a dataset can use, beyond which the oldest are dropped.
+const HISTORY_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+pub(crate) type ChannelMap = Arc<RwLock<HashMap<String, Arc<DataUpdateChannel>>>>;
+
+/// A `DataUpdate` tagged with the position it was published at.
+#[derive(Debug, Clone)]
+pub(crate) struct SequencedDataUpdate {
+    /// Monotonically increasing per dataset, starting at 1.
+    pub sequence: u64,
+    /// Milliseconds since the Unix epoch at which the update was published.
+    pub timestamp: u64,
+    pub data_update: DataUpdate,
+}
+
+/// The updates a new subscriber receives before following live updates.
+pub(crate) enum Backlog {
+    /// The retained updates after the requested position.
+    Updates(Vec<Arc<SequencedDataUpdate>>),
+    /// The requested sequence number or timestamp is no longer retained, or was never published by
+    /// this runtime.
+    Unavailable {
+        oldest_sequence: u64,
+        last_sequence: u64,
+    },
+}
+
+struct ChannelState {
+    last_sequence: u64,
+    history: VecDeque<Arc<SequencedDataUpdate>>,
+    /// The memory used by the updates in `history`.
+    history_bytes: usize,
+    /// The timestamp of the most recent update dropped from `history`, or 0 if none has been.
+    last_evicted_timestamp: u64,
+}
+
+impl ChannelState {
+    fn retain(&mut self, update: Arc<SequencedDataUpdate>) {
+        self.history_bytes += update_size(&update.data_update);
+        self.history.push_back(update);
+
+        while self.history.len() > HISTORY_CAPACITY || self.history_bytes > HISTORY_MAX_BYTES {
+            let Some(evicted) = self.history.pop_front() else {
+                break;
+            };
+            self.history_bytes = self
+                .history_bytes
+                .saturating_sub(update_size(&evicted.data_update));
+            self.last_evicted_timestamp = evicted.timestamp;
+        }
+    }
+}
+
+fn update_size(data_update: &DataUpdate) -> usize {
+    data_update
+        .data
+        .iter()
+        .map(RecordBatch::get_array_memory_size)
+        .sum()
+}
+
+/// Publishes the `DataUpdate`s of a single dataset to its `do_exchange` subscribers.
+pub(crate) struct DataUpdateChannel {
+    sender: broadcast::Sender<Arc<SequencedDataUpdate>>,
+    state: Mutex<ChannelState>,
+}
+
+impl DataUpdateChannel {
+    #[must_use]
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self {
+            sender,
+            state: Mutex::new(ChannelState {
+                last_sequence: 0,
+                history: VecDeque::new(),
+                history_bytes: 0,
+                last_evicted_timestamp: 0,
+            }),
+        }
+    }
+
+    /// Assigns the next sequence number to `data_update` and sends it to all subscribers.
+    pub fn publish(&self, data_update: DataUpdate) -> u64 {
+        let mut state = self.lock_state();
+        state.last_sequence += 1;
+
+        let update = Arc::new(SequencedDataUpdate {
+            sequence: state.last_sequence,
+            timestamp: now_millis(),
+            data_update,
+        });
+
+        state.retain(Arc::clone(&update));
+
+        // Sending under the lock keeps the order of the history and the channel the same.
+        let _ = self.sender.send(update);
+
+        state.last_sequence
+    }
+
+    /// Subscribes to live updates, returning the sequence number of the last published update.
+    pub fn subscribe(&self) -> (broadcast::Receiver<Arc<SequencedDataUpdate>>, u64) {
+        let state = self.lock_state();
+        (self.sender.subscribe(), state.last_sequence)
+    }
+
+    /// Subscribes to live updates and returns the retained updates published after `sequence`.
+    pub fn subscribe_since_sequence(
+        &self,
+        sequence: u64,
+    ) -> (broadcast::Receiver<Arc<SequencedDataUpdate>>, Backlog) {
+        let state = self.lock_state();
+        let receiver = self.sender.subscribe();
+
+        let oldest_sequence = state
+            .history
+            .front()
+            .map_or(state.last_sequence + 1, |update| update.sequence);
+        if sequence > state.last_sequence
+            || (sequence < state.last_sequence && sequence + 1 < oldest_sequence)
+        {
+            return (
+                receiver,
+                Backlog::Unavailable {
+                    oldest_sequence,
+                    last_sequence: state.last_sequence,
+                },
+            );
+        }
+
+        let updates = state
+            .history
+            .iter()
+            .filter(|update| update.sequence > sequence)
+            .cloned()
+            .collect();
+
+        (receiver, Backlog::Updates(updates))
+    }
+
+    /// Subscribes to live updates and returns the retained updates published at or after `timestamp`.
+    pub fn subscribe_since_timestamp(
+        &self,
+        timestamp: u64,
+    ) -> (broadcast::Receiver<Arc<SequencedDataUpdate>>, Backlog) {
+        let state = self.lock_state();
+        let receiver = self.sender.subscribe();
+
+        // An update published at or after `timestamp` may have been dropped.
+        if state.last_evicted_timestamp > 0 && timestamp <= state.last_evicted_timestamp {
+            let oldest_sequence = state
+                .history
+                .front()
+                .map_or(state.last_sequence + 1, |update| update.sequence);
+            return (
+                receiver,
+                Backlog::Unavailable {
+                    oldest_sequence,
+                    last_sequence: state.last_sequence,
+                },
+            );
+        }
+
+        let updates = state
+            .history
+            .iter()
+            .filter(|update| update.timestamp >= timestamp)
+            .cloned()
+            .collect();
+
+        (receiver, Backlog::Updates(updates))
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, ChannelState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl Default for DataUpdateChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the channel for `path`, creating it if no one has published or subscribed to it yet.
+pub(crate) async fn get_or_create_channel(
+    channel_map: &ChannelMap,
+    path: &str,
+) -> Arc<DataUpdateChannel> {
+    if let Some(channel) = channel_map.read().await.get(path) {
+        return Arc::clone(channel);
+    }
+
+    let mut channel_map_write = channel_map.write().await;
+    Arc::clone(
+        channel_map_write
+            .entry(path.to_string())
+            .or_insert_with(|| Arc::new(DataUpdateChannel::new())),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataupdate::UpdateType;
+
+    fn empty_update() -> DataUpdate {
+        DataUpdate {
+            data: vec![],
+            update_type: UpdateType::Append,
+        }
+    }
+
+    #[test]
+    fn test_publish_assigns_increasing_sequences() {
+        let channel = DataUpdateChannel::new();
+        let (_rx, last_sequence) = channel.subscribe();
+        assert_eq!(last_sequence, 0);
+
+        assert_eq!(channel.publish(empty_update()), 1);
+        assert_eq!(channel.publish(empty_update()), 2);
+
+        let (_rx, last_sequence) = channel.subscribe();
+        assert_eq!(last_sequence, 2);
+    }
+
+    #[test]
+    fn test_subscribe_since_sequence() {
+        let channel = DataUpdateChannel::new();
+        for _ in 0..5 {
+            channel.publish(empty_update());
+        }
+
+        let (_rx, backlog) = channel.subscribe_since_sequence(3);
+        let Backlog::Updates(updates) = backlog else {
+            panic!("Expected retained updates");
+        };
+        let sequences = updates.iter().map(|u| u.sequence).collect::<Vec<_>>();
+        assert_eq!(sequences, vec![4, 5]);
+    }
+
+    #[test]
+    fn test_subscribe_since_expired_sequence() {
+        let channel = DataUpdateChannel::new();
+        for _ in 0..HISTORY_CAPACITY + 10 {
+            channel.publish(empty_update());
+        }
+
+        let (_rx, backlog) = channel.subscribe_since_sequence(5);
+        let Backlog::Unavailable {
+            oldest_sequence,
+            last_sequence,
+        } = backlog
+        else {
+            panic!("Expected the backlog to be unavailable");
+        };
+        assert_eq!(oldest_sequence, 11);
+        assert_eq!(last_sequence, HISTORY_CAPACITY as u64 + 10);
+    }
+
+    #[test]
+    fn test_subscribe_since_timestamp() {
+        let channel = DataUpdateChannel::new();
+        channel.publish(empty_update());
+
+        let (_rx, backlog) = channel.subscribe_since_timestamp(0);
+        let Backlog::Updates(updates) = backlog else {
+            panic!("Expected retained updates");
+        };
+        assert_eq!(updates.len(), 1);
+
+        let (_rx, backlog) = channel.subscribe_since_timestamp(now_millis() + 60_000);
+        let Backlog::Updates(updates) = backlog else {
+            panic!("Expected retained updates");
+        };
+        assert!(updates.is_empty());
+    }
+
+    #[test]
+    fn test_subscribe_since_expired_timestamp() {
+        let channel = DataUpdateChannel::new();
+        for _ in 0..HISTORY_CAPACITY + 10 {
+            channel.publish(empty_update());
+        }
+
+        let (_rx, backlog) = channel.subscribe_since_timestamp(0);
+        assert!(matches!(
+            backlog,
+            Backlog::Unavailable {
+                oldest_sequence: 11,
+                ..
+            }
+        ));
+    }
+}
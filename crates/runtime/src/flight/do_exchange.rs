@@ -14,19 +14,160 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
-use std::sync::Arc;
+use std::{collections::VecDeque, sync::Arc};
 
-use arrow_flight::{flight_service_server::FlightService, FlightData, SchemaAsIpc};
+use arrow::{array::RecordBatch, datatypes::SchemaRef};
+use arrow_flight::{
+    flight_descriptor::DescriptorType, flight_service_server::FlightService, FlightData,
+    FlightDescriptor, SchemaAsIpc,
+};
 use arrow_ipc::writer::{self, DictionaryTracker, IpcDataGenerator};
-use futures::{stream, StreamExt};
-use tokio::sync::broadcast;
+use bytes::Bytes;
+use datafusion::{
+    datasource::MemTable,
+    error::DataFusionError,
+    execution::context::{SessionConfig, SessionContext},
+    sql::TableReference,
+};
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
 use tonic::{Request, Response, Status, Streaming};
 
-use crate::dataupdate::{DataUpdate, UpdateType};
+use crate::{
+    dataupdate::{DataUpdate, UpdateType},
+    timing::now_millis,
+};
 
-use super::Service;
+use super::{
+    channels::{self, Backlog, SequencedDataUpdate},
+    handle_datafusion_error, Service,
+};
+
+/// A subscription request sent as the `cmd` of a `FlightDescriptor` with type `CMD`.
+///
+/// ```json
+/// {
+///   "subscriptions": [
+///     { "path": "eth.recent_blocks", "since_sequence": 42 },
+///     { "path": "eth.recent_transactions", "sql": "SELECT hash, value FROM \"eth.recent_transactions\" WHERE value > 0" }
+///   ]
+/// }
+/// ```
+#[derive(Debug, Deserialize)]
+struct SubscriptionRequest {
+    subscriptions: Vec<Subscription>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Subscription {
+    /// The dataset to subscribe to.
+    path: String,
+
+    /// An optional query over the dataset, applied to each `DataUpdate` before it is sent.
+    /// The dataset is referenced by its name, i.e. `SELECT a, b FROM "my_dataset" WHERE a > 1`.
+    #[serde(default)]
+    sql: Option<String>,
+
+    /// Resume after the update with this sequence number, as received in the `app_metadata` of a previous subscription.
+    #[serde(default)]
+    since_sequence: Option<u64>,
+
+    /// Start with the retained updates published at or after this time, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    since_timestamp: Option<u64>,
+
+    /// Send the current contents of the dataset before following live updates.
+    /// Ignored if `since_sequence` or `since_timestamp` is set.
+    #[serde(default = "default_backfill")]
+    backfill: bool,
+}
+
+const fn default_backfill() -> bool {
+    true
+}
+
+/// Sent as JSON in the `app_metadata` of every `FlightData` message of a subscription.
+#[derive(Debug, Serialize)]
+struct UpdateMetadata<'a> {
+    path: &'a str,
+    sequence: u64,
+    timestamp: u64,
+    update_type: &'static str,
+}
+
+impl Subscription {
+    /// Runs the subscription query over the batches of a single `DataUpdate`.
+    async fn filter(&self, batches: Vec<RecordBatch>) -> Result<Vec<RecordBatch>, DataFusionError> {
+        let Some(sql) = &self.sql else {
+            return Ok(batches);
+        };
+        let Some(first_batch) = batches.first() else {
+            return Ok(batches);
+        };
+
+        let ctx = subscription_context(first_batch.schema(), &self.path, vec![batches])?;
+        ctx.sql(sql).await?.collect().await
+    }
+}
+
+/// Creates an isolated `SessionContext` that only contains the subscribed dataset, so that a
+/// subscription query can't reference other tables.
+fn subscription_context(
+    schema: SchemaRef,
+    path: &str,
+    batches: Vec<Vec<RecordBatch>>,
+) -> Result<SessionContext, DataFusionError> {
+    let mut config = SessionConfig::new();
+    config.options_mut().sql_parser.dialect = "PostgreSQL".to_string();
+    let ctx = SessionContext::new_with_config(config);
+
+    let table = MemTable::try_new(schema, batches)?;
+    ctx.register_table(TableReference::bare(path.to_string()), Arc::new(table))?;
+
+    Ok(ctx)
+}
+
+fn parse_subscriptions(flight_descriptor: &FlightDescriptor) -> Result<Vec<Subscription>, Status> {
+    match flight_descriptor.r#type() {
+        DescriptorType::Path => {
+            if flight_descriptor.path.is_empty() {
+                return Err(Status::invalid_argument(
+                    "Flight descriptor needs to specify a path to indicate which data to subscribe to",
+                ));
+            };
+
+            Ok(vec![Subscription {
+                path: flight_descriptor.path.join("."),
+                sql: None,
+                since_sequence: None,
+                since_timestamp: None,
+                backfill: default_backfill(),
+            }])
+        }
+        DescriptorType::Cmd => {
+            let request: SubscriptionRequest = serde_json::from_slice(&flight_descriptor.cmd)
+                .map_err(|e| {
+                    Status::invalid_argument(format!("Unable to parse subscription request: {e}"))
+                })?;
+
+            if request.subscriptions.is_empty() {
+                return Err(Status::invalid_argument(
+                    "Subscription request needs to specify at least one dataset to subscribe to",
+                ));
+            }
+
+            Ok(request.subscriptions)
+        }
+        DescriptorType::Unknown => Err(Status::invalid_argument(
+            "Flight descriptor type must be PATH or CMD",
+        )),
+    }
+}
 
-#[allow(clippy::too_many_lines)]
 pub(crate) async fn handle(
     flight_svc: &Service,
     request: Request<Streaming<FlightData>>,
@@ -48,20 +189,29 @@ pub(crate) async fn handle(
         }
     };
 
-    // TODO: Support multiple flight descriptors to subscribe to multiple data sources
     let Some(flight_descriptor) = subscription_request.flight_descriptor else {
         return Err(Status::invalid_argument(
             "Flight descriptor required to indicate which data to subscribe to",
         ));
     };
 
-    if flight_descriptor.path.is_empty() {
-        return Err(Status::invalid_argument(
-            "Flight descriptor needs to specify a path to indicate which data to subscribe to",
-        ));
-    };
+    let subscriptions = parse_subscriptions(&flight_descriptor)?;
 
-    let data_path = flight_descriptor.path.join(".");
+    let mut subscription_streams = Vec::with_capacity(subscriptions.len());
+    for subscription in subscriptions {
+        subscription_streams.push(subscribe(flight_svc, subscription).await?);
+    }
+
+    let response_stream = stream::select_all(subscription_streams);
+
+    Ok(Response::new(response_stream.boxed()))
+}
+
+async fn subscribe(
+    flight_svc: &Service,
+    subscription: Subscription,
+) -> Result<BoxStream<'static, Result<FlightData, Status>>, Status> {
+    let data_path = subscription.path.clone();
 
     if !flight_svc
         .datafusion
@@ -74,88 +224,239 @@ pub(crate) async fn handle(
         )));
     };
 
-    let channel_map = Arc::clone(&flight_svc.channel_map);
-    let channel_map_read = channel_map.read().await;
-    let (tx, rx) = if let Some(channel) = channel_map_read.get(&data_path) {
-        (Arc::clone(channel), channel.subscribe())
-    } else {
-        drop(channel_map_read);
-        let mut channel_map_write = channel_map.write().await;
-        let (tx, rx) = broadcast::channel(100);
-        let tx = Arc::new(tx);
-        channel_map_write.insert(data_path.clone(), Arc::clone(&tx));
-        (tx, rx)
+    // Plan the subscription query against an empty table so that invalid queries are
+    // rejected before the subscription starts.
+    if let Some(sql) = &subscription.sql {
+        let schema = flight_svc
+            .datafusion
+            .read()
+            .await
+            .get_arrow_schema(&data_path)
+            .await
+            .map_err(|e| {
+                Status::failed_precondition(format!(
+                    r#"Unable to get schema for dataset "{data_path}": {e}"#
+                ))
+            })?;
+        let ctx = subscription_context(Arc::new(schema), &data_path, vec![vec![]])
+            .map_err(handle_datafusion_error)?;
+        ctx.sql(sql).await.map_err(handle_datafusion_error)?;
+    }
+
+    let channel = channels::get_or_create_channel(&flight_svc.channel_map, &data_path).await;
+
+    // Subscribing to the channel before reading the backlog ensures that no update is missed
+    // between the backlog and the live updates.
+    let (rx, backlog, last_sequence) = match (
+        subscription.since_sequence,
+        subscription.since_timestamp,
+    ) {
+        (Some(since_sequence), _) => match channel.subscribe_since_sequence(since_sequence) {
+            (rx, Backlog::Updates(updates)) => (rx, updates, since_sequence),
+            (
+                _,
+                Backlog::Unavailable {
+                    oldest_sequence,
+                    last_sequence,
+                },
+            ) => {
+                return Err(Status::out_of_range(format!(
+                    r#"Unable to resume subscription to dataset "{data_path}" from sequence {since_sequence}, the available sequences are {oldest_sequence} to {last_sequence}. Resubscribe without since_sequence to receive the current contents of the dataset."#
+                )));
+            }
+        },
+        (None, Some(since_timestamp)) => match channel.subscribe_since_timestamp(since_timestamp) {
+            (rx, Backlog::Updates(updates)) => (rx, updates, 0),
+            (
+                _,
+                Backlog::Unavailable {
+                    oldest_sequence,
+                    last_sequence,
+                },
+            ) => {
+                return Err(Status::out_of_range(format!(
+                    r#"Unable to resume subscription to dataset "{data_path}" from timestamp {since_timestamp}, updates published since then are no longer retained, the available sequences are {oldest_sequence} to {last_sequence}. Resubscribe without since_timestamp to receive the current contents of the dataset."#
+                )));
+            }
+        },
+        (None, None) => {
+            let (rx, last_sequence) = channel.subscribe();
+            let mut backlog = vec![];
+            if subscription.backfill {
+                // Updates published while the snapshot is read may be both in the snapshot and
+                // in the live updates, so subscribers receive them at least once.
+                let data = current_contents(flight_svc, &data_path).await?;
+                if !data.is_empty() {
+                    backlog.push(Arc::new(SequencedDataUpdate {
+                        sequence: last_sequence,
+                        timestamp: now_millis(),
+                        data_update: DataUpdate {
+                            data,
+                            update_type: UpdateType::Overwrite,
+                        },
+                    }));
+                }
+            }
+            (rx, backlog, last_sequence)
+        }
+    };
+
+    Ok(subscription_stream(SubscriptionState {
+        subscription,
+        rx,
+        backlog: backlog.into(),
+        last_sequence,
+        finished: false,
+    }))
+}
+
+async fn current_contents(
+    flight_svc: &Service,
+    data_path: &str,
+) -> Result<Vec<RecordBatch>, Status> {
+    let df = flight_svc
+        .datafusion
+        .read()
+        .await
+        .ctx
+        .sql(&format!(r#"SELECT * FROM "{data_path}""#))
+        .await;
+
+    // The table doesn't exist until the first data for the dataset is received.
+    let Ok(df) = df else {
+        return Ok(vec![]);
     };
 
-    let response_stream = stream::unfold(rx, move |mut rx| {
-        let encoder = IpcDataGenerator::default();
-        let mut tracker = DictionaryTracker::new(false);
-        let write_options = writer::IpcWriteOptions::default();
-        async move {
-            match rx.recv().await {
-                Ok(data_update) => {
-                    let mut schema_sent: bool = false;
-
-                    let mut flights = vec![];
-
-                    for batch in &data_update.data {
-                        if !schema_sent {
-                            let schema = batch.schema();
-                            flights
-                                .push(FlightData::from(SchemaAsIpc::new(&schema, &write_options)));
-                            schema_sent = true;
-                        }
-                        let Ok((flight_dictionaries, flight_batch)) =
-                            encoder.encoded_batch(batch, &mut tracker, &write_options)
-                        else {
-                            panic!("Unable to encode batch")
-                        };
-
-                        flights.extend(flight_dictionaries.into_iter().map(Into::into));
-                        flights.push(flight_batch.into());
+    df.collect().await.map_err(handle_datafusion_error)
+}
+
+struct SubscriptionState {
+    subscription: Subscription,
+    rx: broadcast::Receiver<Arc<SequencedDataUpdate>>,
+    backlog: VecDeque<Arc<SequencedDataUpdate>>,
+    /// The sequence number of the last update sent to the subscriber.
+    last_sequence: u64,
+    finished: bool,
+}
+
+/// Streams the backlog and then the live `DataUpdate`s of a single subscription as `FlightData`.
+///
+/// Every message is tagged with `UpdateMetadata` in its `app_metadata`, so that clients
+/// subscribed to multiple datasets on one stream can tell them apart and can resume from the
+/// last sequence number they received.
+fn subscription_stream(state: SubscriptionState) -> BoxStream<'static, Result<FlightData, Status>> {
+    stream::unfold(state, |mut state| async move {
+        if state.finished {
+            return None;
+        }
+
+        loop {
+            let (update, from_backlog) = match state.backlog.pop_front() {
+                Some(update) => (update, true),
+                None => match state.rx.recv().await {
+                    Ok(update) => (update, false),
+                    Err(RecvError::Lagged(skipped)) => {
+                        state.finished = true;
+                        metrics::counter!("flight_do_exchange_subscriptions_lagged", "path" => state.subscription.path.clone())
+                            .increment(1);
+                        let error = Status::data_loss(format!(
+                            r#"Subscription to dataset "{}" fell behind by {skipped} updates. Resubscribe with since_sequence {} to resume."#,
+                            state.subscription.path, state.last_sequence
+                        ));
+                        return Some((stream::iter(vec![Err(error)]), state));
                     }
+                    Err(RecvError::Closed) => return None,
+                },
+            };
 
-                    metrics::counter!("flight_do_exchange_data_updates_sent")
-                        .increment(flights.len() as u64);
-                    let output = futures::stream::iter(flights.into_iter().map(Ok));
+            // Live updates that were already sent as part of the backlog are skipped.
+            if !from_backlog && update.sequence <= state.last_sequence {
+                continue;
+            }
+            state.last_sequence = state.last_sequence.max(update.sequence);
 
-                    Some((output, rx))
-                }
-                Err(_e) => {
-                    let output = futures::stream::iter(vec![].into_iter().map(Ok));
-                    Some((output, rx))
+            let batches = match state
+                .subscription
+                .filter(update.data_update.data.clone())
+                .await
+            {
+                Ok(batches) => batches,
+                Err(e) => {
+                    let output = stream::iter(vec![Err(handle_datafusion_error(e))]);
+                    return Some((output, state));
                 }
+            };
+
+            if batches.iter().all(|batch| batch.num_rows() == 0) {
+                continue;
             }
+
+            let app_metadata = match serde_json::to_vec(&UpdateMetadata {
+                path: &state.subscription.path,
+                sequence: update.sequence,
+                timestamp: update.timestamp,
+                update_type: match update.data_update.update_type {
+                    UpdateType::Append => "append",
+                    UpdateType::Overwrite => "overwrite",
+                },
+            }) {
+                Ok(app_metadata) => Bytes::from(app_metadata),
+                Err(e) => {
+                    let error = Status::internal(format!("Unable to encode update metadata: {e}"));
+                    return Some((stream::iter(vec![Err(error)]), state));
+                }
+            };
+
+            let flights = encode_batches(&batches, &app_metadata);
+            metrics::counter!("flight_do_exchange_data_updates_sent", "path" => state.subscription.path.clone())
+                .increment(flights.len() as u64);
+
+            return Some((stream::iter(flights), state));
         }
     })
-    .flat_map(|x| x);
+    .flatten()
+    .boxed()
+}
 
-    let datafusion = Arc::clone(&flight_svc.datafusion);
-    tokio::spawn(async move {
-        let Ok(df) = datafusion
-            .read()
-            .await
-            .ctx
-            .sql(&format!(r#"SELECT * FROM "{data_path}""#))
-            .await
-        else {
-            return;
-        };
-        let Ok(results) = df.collect().await else {
-            return;
-        };
-        if results.is_empty() {
-            return;
+fn encode_batches(
+    batches: &[RecordBatch],
+    app_metadata: &Bytes,
+) -> Vec<Result<FlightData, Status>> {
+    let encoder = IpcDataGenerator::default();
+    let mut tracker = DictionaryTracker::new(false);
+    let write_options = writer::IpcWriteOptions::default();
+
+    let mut schema_sent: bool = false;
+    let mut flights = vec![];
+
+    for batch in batches {
+        if !schema_sent {
+            let schema = batch.schema();
+            flights.push(Ok(FlightData::from(SchemaAsIpc::new(
+                &schema,
+                &write_options,
+            ))
+            .with_app_metadata(app_metadata.clone())));
+            schema_sent = true;
         }
 
-        for batch in &results {
-            let data_update = DataUpdate {
-                data: vec![batch.clone()],
-                update_type: UpdateType::Append,
-            };
-            let _ = tx.send(data_update);
+        match encoder.encoded_batch(batch, &mut tracker, &write_options) {
+            Ok((flight_dictionaries, flight_batch)) => {
+                flights.extend(flight_dictionaries.into_iter().map(|dictionary| {
+                    Ok(FlightData::from(dictionary).with_app_metadata(app_metadata.clone()))
+                }));
+                flights.push(Ok(
+                    FlightData::from(flight_batch).with_app_metadata(app_metadata.clone())
+                ));
+            }
+            Err(e) => {
+                flights.push(Err(Status::internal(format!(
+                    "Unable to encode batch: {e}"
+                ))));
+                break;
+            }
         }
-    });
+    }
 
-    Ok(Response::new(response_stream.boxed()))
+    flights
 }
@@ -14,8 +14,6 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
-use std::sync::Arc;
-
 use arrow_flight::{
     flight_service_server::FlightService,
     sql::{Any, Command},
@@ -24,25 +22,29 @@ use arrow_flight::{
 use prost::Message;
 use tonic::{Request, Response, Status};
 
-use crate::timing::{TimeMeasurement, TimedStream};
+use crate::{
+    querylimits::QueryOptions,
+    timing::{TimeMeasurement, TimedStream},
+};
 
-use super::{flightsql, to_tonic_err, Service};
+use super::{flightsql, query_options, to_tonic_err, Service};
 
 pub(crate) async fn handle(
     flight_svc: &Service,
     request: Request<Ticket>,
 ) -> Result<Response<<Service as FlightService>::DoGetStream>, Status> {
+    let options = query_options(&request);
     let msg: Any = match Message::decode(&*request.get_ref().ticket) {
         Ok(msg) => msg,
-        Err(_) => return do_get_simple(flight_svc, request).await,
+        Err(_) => return do_get_simple(flight_svc, request, options).await,
     };
 
     match Command::try_from(msg).map_err(to_tonic_err)? {
         Command::CommandStatementQuery(command) => {
-            flightsql::statement_query::do_get(flight_svc, command).await
+            flightsql::statement_query::do_get(flight_svc, command, options).await
         }
         Command::CommandPreparedStatementQuery(command) => {
-            flightsql::prepared_statement_query::do_get(flight_svc, command).await
+            flightsql::prepared_statement_query::do_get(flight_svc, command, options).await
         }
         Command::CommandGetCatalogs(command) => {
             flightsql::get_catalogs::do_get(flight_svc, command).await
@@ -62,14 +64,16 @@ pub(crate) async fn handle(
 async fn do_get_simple(
     flight_svc: &Service,
     request: Request<Ticket>,
+    options: QueryOptions,
 ) -> Result<Response<<Service as FlightService>::DoGetStream>, Status> {
-    let datafusion = Arc::clone(&flight_svc.datafusion);
     let ticket = request.into_inner();
     tracing::trace!("do_get_simple: {ticket:?}");
     match std::str::from_utf8(&ticket.ticket) {
         Ok(sql) => {
             let start = TimeMeasurement::new("flight_do_get_simple_duration_ms", vec![]);
-            let output = Service::sql_to_flight_stream(datafusion, sql.to_owned()).await?;
+            let output = flight_svc
+                .sql_to_flight_stream(sql.to_owned(), options)
+                .await?;
 
             let timed_output = TimedStream::new(output, move || start);
 
@@ -19,29 +19,16 @@ use std::{collections::HashMap, sync::Arc};
 use arrow_flight::{flight_service_server::FlightService, FlightData, PutResult};
 use arrow_ipc::convert::try_schema_from_flatbuffer_bytes;
 use futures::stream;
-use tokio::sync::{broadcast::Sender, RwLock};
 use tonic::{Request, Response, Status, Streaming};
 
 use crate::{
+    datapublisher,
     dataupdate::{DataUpdate, UpdateType},
     timing::{TimeMeasurement, TimedStream},
 };
 
 use super::Service;
 
-async fn get_sender_channel(
-    channel_map: Arc<RwLock<HashMap<String, Arc<Sender<DataUpdate>>>>>,
-    path: String,
-) -> Option<Arc<Sender<DataUpdate>>> {
-    let channel_map_read = channel_map.read().await;
-    if channel_map_read.contains_key(&path) {
-        let channel = channel_map_read.get(&path)?;
-        Some(Arc::clone(channel))
-    } else {
-        None
-    }
-}
-
 pub(crate) async fn handle(
     flight_svc: &Service,
     request: Request<Streaming<FlightData>>,
@@ -92,14 +79,15 @@ pub(crate) async fn handle(
     }
 
     let channel_map = Arc::clone(&flight_svc.channel_map);
+    let wal = flight_svc.wal.clone();
 
     let response_stream = stream::unfold(streaming_flight, move |mut flight| {
         let schema = Arc::clone(&schema);
         let dictionaries_by_id = Arc::clone(&dictionaries_by_id);
         let dataset = Arc::clone(&dataset);
         let data_publishers = Arc::clone(&data_publishers);
-        let path = path.clone();
         let channel_map = Arc::clone(&channel_map);
+        let wal = wal.clone();
         async move {
             match flight.message().await {
                 Ok(Some(message)) => {
@@ -121,19 +109,17 @@ pub(crate) async fn handle(
                         update_type: UpdateType::Append,
                     };
 
-                    if let Some(channel) = get_sender_channel(channel_map, path).await {
-                        let _ = channel.send(data_update.clone());
-                    };
-
-                    let data_publishers = data_publishers.read().await;
-                    for publisher in data_publishers.iter() {
-                        if let Err(err) = publisher
-                            .add_data(Arc::clone(&dataset), data_update.clone())
-                            .await
-                            .map_err(|e| Status::internal(format!("Failed to add data: {e}")))
-                        {
-                            return Some((Err(err), flight));
-                        };
+                    if let Err(e) = datapublisher::write(
+                        &dataset,
+                        &data_publishers,
+                        wal.as_deref(),
+                        &channel_map,
+                        data_update,
+                    )
+                    .await
+                    {
+                        tracing::error!("Failed to write data for {}: {e}", dataset.name);
+                        return Some((Err(Status::internal(e.to_string())), flight));
                     }
 
                     Some((Ok(PutResult::default()), flight))
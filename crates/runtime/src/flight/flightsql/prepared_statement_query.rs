@@ -26,6 +26,7 @@ use tonic::{Request, Response, Status};
 
 use crate::{
     flight::{to_tonic_err, Service},
+    querylimits::QueryOptions,
     timing::{TimeMeasurement, TimedStream},
 };
 
@@ -89,14 +90,16 @@ pub(crate) async fn get_flight_info(
 pub(crate) async fn do_get(
     flight_svc: &Service,
     query: sql::CommandPreparedStatementQuery,
+    options: QueryOptions,
 ) -> Result<Response<<Service as FlightService>::DoGetStream>, Status> {
-    let datafusion = Arc::clone(&flight_svc.datafusion);
     tracing::trace!("do_get: {query:?}");
     match std::str::from_utf8(&query.prepared_statement_handle) {
         Ok(sql) => {
             let start =
                 TimeMeasurement::new("flight_do_get_prepared_statement_query_duration_ms", vec![]);
-            let output = Service::sql_to_flight_stream(datafusion, sql.to_owned()).await?;
+            let output = flight_svc
+                .sql_to_flight_stream(sql.to_owned(), options)
+                .await?;
             let timed_output = TimedStream::new(output, move || start);
             Ok(Response::new(
                 Box::pin(timed_output) as <Service as FlightService>::DoGetStream
@@ -23,7 +23,10 @@ use tokio::{
     sync::RwLock,
 };
 
-use crate::{config, datafusion::DataFusion, model::Model};
+use crate::{
+    config, datafusion::DataFusion, datasetchange, modelversions::ModelVersions,
+    querylimits::QueryLimits,
+};
 
 mod routes;
 mod v1;
@@ -39,18 +42,29 @@ pub enum Error {
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn start<A>(
     bind_address: A,
     app: Arc<RwLock<Option<App>>>,
     df: Arc<RwLock<DataFusion>>,
-    models: Arc<RwLock<HashMap<String, Model>>>,
+    models: Arc<RwLock<HashMap<String, ModelVersions>>>,
     config: Arc<config::Config>,
     with_metrics: Option<SocketAddr>,
+    dataset_changes: datasetchange::Sender,
+    query_limits: Arc<QueryLimits>,
 ) -> Result<()>
 where
     A: ToSocketAddrs + Debug,
 {
-    let routes = routes::routes(app, df, models, config, with_metrics);
+    let routes = routes::routes(
+        app,
+        df,
+        models,
+        config,
+        with_metrics,
+        dataset_changes,
+        query_limits,
+    );
 
     let listener = TcpListener::bind(&bind_address)
         .await
@@ -59,8 +73,12 @@ where
 
     metrics::counter!("spiced_runtime_http_server_start").increment(1);
 
-    axum::serve(listener, routes)
-        .await
-        .context(UnableToStartHttpServerSnafu)?;
+    // The client address identifies the principal of queries sent without credentials.
+    axum::serve(
+        listener,
+        routes.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .context(UnableToStartHttpServerSnafu)?;
     Ok(())
 }
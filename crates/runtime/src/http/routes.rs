@@ -14,7 +14,10 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
-use crate::{config, datafusion::DataFusion, model::Model};
+use crate::{
+    config, datafusion::DataFusion, datasetchange, modelversions::ModelVersions,
+    querylimits::QueryLimits,
+};
 use app::App;
 use std::net::SocketAddr;
 use std::{collections::HashMap, sync::Arc};
@@ -25,7 +28,7 @@ use axum::{
     http::Request,
     middleware::{self, Next},
     response::IntoResponse,
-    routing::{get, post, Router},
+    routing::{get, post, put, Router},
     Extension,
 };
 use tokio::{sync::RwLock, time::Instant};
@@ -35,18 +38,38 @@ use super::v1;
 pub(crate) fn routes(
     app: Arc<RwLock<Option<App>>>,
     df: Arc<RwLock<DataFusion>>,
-    models: Arc<RwLock<HashMap<String, Model>>>,
+    models: Arc<RwLock<HashMap<String, ModelVersions>>>,
     config: Arc<config::Config>,
     with_metrics: Option<SocketAddr>,
+    dataset_changes: datasetchange::Sender,
+    query_limits: Arc<QueryLimits>,
 ) -> Router {
     Router::new()
         .route("/health", get(|| async { "ok\n" }))
         .route("/v1/sql", post(v1::query::post))
+        .route("/v1/sql/explain", post(v1::query::explain))
         .route("/v1/status", get(v1::status::get))
-        .route("/v1/datasets", get(v1::datasets::get))
+        .route(
+            "/v1/datasets",
+            get(v1::datasets::get).post(v1::datasets::post),
+        )
+        .route(
+            "/v1/datasets/:name",
+            get(v1::datasets::get_by_name)
+                .put(v1::datasets::put)
+                .delete(v1::datasets::delete),
+        )
+        .route("/v1/datasets/:name/refresh", post(v1::datasets::refresh))
+        .route(
+            "/v1/datasets/:name/refresh/:id",
+            get(v1::datasets::refresh_status),
+        )
         .route("/v1/spicepods", get(v1::spicepods::get))
         .route("/v1/models", get(v1::models::get))
+        .route("/v1/models/:name", get(v1::models::get_by_name))
         .route("/v1/models/:name/predict", get(v1::inference::get))
+        .route("/v1/models/:name/infer", post(v1::inference::infer))
+        .route("/v1/models/:name/shadow", get(v1::models::shadow_runs))
         .route("/v1/predict", post(v1::inference::post))
         .route_layer(middleware::from_fn(track_metrics))
         .layer(Extension(app))
@@ -54,6 +77,8 @@ pub(crate) fn routes(
         .layer(Extension(with_metrics))
         .layer(Extension(models))
         .layer(Extension(config))
+        .layer(Extension(dataset_changes))
+        .layer(Extension(query_limits))
 }
 
 async fn track_metrics(req: Request<Body>, next: Next) -> impl IntoResponse {
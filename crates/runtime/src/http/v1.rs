@@ -45,71 +45,806 @@ fn dataset_status(df: &DataFusion, ds: &Dataset) -> ComponentStatus {
     }
 }
 
-pub(crate) mod query {
-    use arrow::record_batch::RecordBatch;
+pub(crate) mod error {
+    use arrow_flight::error::FlightError;
     use axum::{
-        body::Bytes,
         http::StatusCode,
         response::{IntoResponse, Response},
-        Extension,
+        Json,
     };
-    use std::sync::Arc;
+    use datafusion::error::DataFusionError;
+    use once_cell::sync::Lazy;
+    use regex::Regex;
+    use serde::Serialize;
+    use std::io;
+
+    use crate::querylimits;
+
+    /// Matches the location sqlparser appends to its errors, i.e. `at Line: 1, Column 8`.
+    static SQL_POSITION: Lazy<Option<Regex>> =
+        Lazy::new(|| Regex::new(r"Line: (\d+), Column:? (\d+)").ok());
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+    #[serde(rename_all = "snake_case")]
+    pub(crate) enum ErrorCode {
+        BadRequest,
+        SyntaxError,
+        InvalidQuery,
+        TableNotFound,
+        NotFound,
+        PermissionDenied,
+        NotAcceptable,
+        Conflict,
+        TooManyRequests,
+        Timeout,
+        UpstreamError,
+        ResourcesExhausted,
+        Unavailable,
+        Internal,
+    }
+
+    impl ErrorCode {
+        fn status(self) -> StatusCode {
+            match self {
+                ErrorCode::BadRequest | ErrorCode::SyntaxError | ErrorCode::InvalidQuery => {
+                    StatusCode::BAD_REQUEST
+                }
+                ErrorCode::TableNotFound | ErrorCode::NotFound => StatusCode::NOT_FOUND,
+                ErrorCode::PermissionDenied => StatusCode::FORBIDDEN,
+                ErrorCode::NotAcceptable => StatusCode::NOT_ACCEPTABLE,
+                ErrorCode::Conflict => StatusCode::CONFLICT,
+                ErrorCode::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+                ErrorCode::Timeout => StatusCode::GATEWAY_TIMEOUT,
+                ErrorCode::UpstreamError => StatusCode::BAD_GATEWAY,
+                ErrorCode::ResourcesExhausted | ErrorCode::Unavailable => {
+                    StatusCode::SERVICE_UNAVAILABLE
+                }
+                ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            }
+        }
+
+        /// Whether the same request may succeed if it's sent again later.
+        fn is_retryable(self) -> bool {
+            matches!(
+                self,
+                ErrorCode::TooManyRequests
+                    | ErrorCode::Timeout
+                    | ErrorCode::UpstreamError
+                    | ErrorCode::ResourcesExhausted
+                    | ErrorCode::Unavailable
+            )
+        }
+    }
+
+    /// The location of a syntax error in the SQL query, starting from 1.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+    pub(crate) struct Position {
+        pub line: u64,
+        pub column: u64,
+    }
+
+    /// The error returned by all `/v1` endpoints, as `{"error": {...}}`.
+    #[derive(Debug, Serialize)]
+    pub(crate) struct ApiError {
+        pub code: ErrorCode,
+        pub message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub position: Option<Position>,
+        pub retryable: bool,
+    }
+
+    #[derive(Serialize)]
+    struct ErrorEnvelope {
+        error: ApiError,
+    }
+
+    impl ApiError {
+        pub(crate) fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+            Self {
+                code,
+                message: message.into(),
+                position: None,
+                retryable: code.is_retryable(),
+            }
+        }
+
+        pub(crate) fn from_query_limits(e: &querylimits::Error) -> Self {
+            let code = match e {
+                querylimits::Error::QueryTimedOut { .. } => ErrorCode::Timeout,
+                querylimits::Error::TooManyQueries => ErrorCode::TooManyRequests,
+            };
+            Self::new(code, e.to_string())
+        }
+
+        /// Classifies an error from planning or executing a query.
+        pub(crate) fn from_datafusion(e: &DataFusionError) -> Self {
+            if let Some(e) = querylimits::find_error(e) {
+                return Self::from_query_limits(e);
+            }
+
+            let message = e.to_string();
+            match e.find_root() {
+                DataFusionError::SQL(..) => Self {
+                    position: sql_position(&message),
+                    ..Self::new(ErrorCode::SyntaxError, message)
+                },
+                DataFusionError::Plan(msg)
+                    if msg.starts_with("table '") && msg.ends_with("' not found") =>
+                {
+                    Self::new(ErrorCode::TableNotFound, message)
+                }
+                DataFusionError::Plan(_)
+                | DataFusionError::SchemaError(..)
+                | DataFusionError::NotImplemented(_) => Self::new(ErrorCode::InvalidQuery, message),
+                DataFusionError::ResourcesExhausted(_) => {
+                    Self::new(ErrorCode::ResourcesExhausted, message)
+                }
+                DataFusionError::External(e) => {
+                    Self::new(classify_upstream_error(e.as_ref()), message)
+                }
+                DataFusionError::ObjectStore(e) => Self::new(classify_upstream_error(e), message),
+                DataFusionError::IoError(e) => Self::new(classify_upstream_error(e), message),
+                _ => Self::new(ErrorCode::Internal, message),
+            }
+        }
+    }
+
+    impl IntoResponse for ApiError {
+        fn into_response(self) -> Response {
+            (self.code.status(), Json(ErrorEnvelope { error: self })).into_response()
+        }
+    }
+
+    fn sql_position(message: &str) -> Option<Position> {
+        let captures = SQL_POSITION.as_ref()?.captures(message)?;
+        Some(Position {
+            line: captures.get(1)?.as_str().parse().ok()?,
+            column: captures.get(2)?.as_str().parse().ok()?,
+        })
+    }
+
+    /// Classifies an error from a data connector or object store by the first error in its chain of
+    /// sources with a known type.
+    fn classify_upstream_error(e: &(dyn std::error::Error + 'static)) -> ErrorCode {
+        let mut error = Some(e);
+        while let Some(e) = error {
+            if let Some(code) = upstream_error_code(e) {
+                return code;
+            }
+            error = e.source();
+        }
+        ErrorCode::UpstreamError
+    }
+
+    fn upstream_error_code(e: &(dyn std::error::Error + 'static)) -> Option<ErrorCode> {
+        if let Some(e) = e.downcast_ref::<std::io::Error>() {
+            return match e.kind() {
+                io::ErrorKind::PermissionDenied => Some(ErrorCode::PermissionDenied),
+                io::ErrorKind::TimedOut => Some(ErrorCode::Timeout),
+                _ => None,
+            };
+        }
+        if let Some(FlightError::Tonic(status)) = e.downcast_ref::<FlightError>() {
+            return status_code(status);
+        }
+        if let Some(status) = e.downcast_ref::<tonic::Status>() {
+            return status_code(status);
+        }
+        if e.is::<tokio::time::error::Elapsed>() {
+            return Some(ErrorCode::Timeout);
+        }
+        None
+    }
+
+    /// The code of a gRPC error from a Flight data connector.
+    fn status_code(status: &tonic::Status) -> Option<ErrorCode> {
+        match status.code() {
+            tonic::Code::PermissionDenied | tonic::Code::Unauthenticated => {
+                Some(ErrorCode::PermissionDenied)
+            }
+            tonic::Code::DeadlineExceeded => Some(ErrorCode::Timeout),
+            tonic::Code::Unavailable => Some(ErrorCode::Unavailable),
+            _ => None,
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_from_datafusion() {
+            let e = DataFusionError::SQL(
+                datafusion::sql::sqlparser::parser::ParserError::ParserError(
+                    "Expected an expression:, found: FROM at Line: 1, Column 8".to_string(),
+                ),
+                None,
+            );
+            let error = ApiError::from_datafusion(&e);
+            assert_eq!(error.code, ErrorCode::SyntaxError);
+            assert_eq!(error.position, Some(Position { line: 1, column: 8 }));
+            assert!(!error.retryable);
+
+            let e =
+                DataFusionError::Plan("table 'datafusion.public.missing' not found".to_string());
+            assert_eq!(ApiError::from_datafusion(&e).code, ErrorCode::TableNotFound);
+
+            let e = DataFusionError::External(Box::new(io::Error::from(io::ErrorKind::TimedOut)));
+            let error = ApiError::from_datafusion(&e);
+            assert_eq!(error.code, ErrorCode::Timeout);
+            assert!(error.retryable);
+
+            let e = DataFusionError::External(Box::new(FlightError::Tonic(
+                tonic::Status::permission_denied("permission denied for table foo"),
+            )));
+            assert_eq!(
+                ApiError::from_datafusion(&e).code,
+                ErrorCode::PermissionDenied
+            );
+
+            // Errors are classified by their type, not their message.
+            let e = DataFusionError::External("permission denied for table foo".into());
+            assert_eq!(ApiError::from_datafusion(&e).code, ErrorCode::UpstreamError);
+        }
+    }
+}
+
+pub(crate) mod query {
+    use arrow::{datatypes::SchemaRef, ipc::writer::StreamWriter, record_batch::RecordBatch};
+    use async_stream::stream;
+    use axum::{
+        body::{Body, Bytes},
+        extract::{ConnectInfo, Query},
+        http::{header, HeaderMap, HeaderValue, StatusCode},
+        response::{IntoResponse, Response},
+        Extension, Json,
+    };
+    use datafusion::{parquet::arrow::ArrowWriter, physical_plan::SendableRecordBatchStream};
+    use futures::StreamExt;
+    use serde::Deserialize;
+    use std::{net::SocketAddr, sync::Arc};
     use tokio::sync::RwLock;
 
-    use crate::datafusion::DataFusion;
+    use crate::{
+        config,
+        datafusion::DataFusion,
+        explain,
+        querylimits::{QueryLimits, QueryOptions},
+    };
+
+    use super::error::{ApiError, ErrorCode};
+
+    type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
+    /// Set to `true` on buffered results that were cut short by `max_rows` or `--sql_max_rows`.
+    pub(crate) const TRUNCATED_HEADER: &str = "x-spice-truncated";
+
+    /// Set on streamed results to the most rows they can have, as whether they were cut short isn't
+    /// known until the stream ends.
+    pub(crate) const MAX_ROWS_HEADER: &str = "x-spice-max-rows";
+
+    /// The formats a query result can be returned in.
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub(crate) enum ResultFormat {
+        /// A single JSON array of row objects.
+        #[default]
+        Json,
+        /// Newline-delimited JSON, one row object per line.
+        Ndjson,
+        Csv,
+        /// The Arrow IPC streaming format.
+        Arrow,
+        Parquet,
+    }
+
+    impl ResultFormat {
+        fn from_media_type(media_type: &str) -> Option<Self> {
+            match media_type {
+                "application/json" | "*/*" | "application/*" => Some(ResultFormat::Json),
+                "application/x-ndjson" | "application/ndjson" | "application/jsonl" => {
+                    Some(ResultFormat::Ndjson)
+                }
+                "text/csv" => Some(ResultFormat::Csv),
+                "application/vnd.apache.arrow.stream" => Some(ResultFormat::Arrow),
+                "application/vnd.apache.parquet" | "application/x-parquet" => {
+                    Some(ResultFormat::Parquet)
+                }
+                _ => None,
+            }
+        }
+
+        /// Picks the first supported media type listed in an `Accept` header.
+        fn from_accept(accept: &str) -> Option<Self> {
+            accept
+                .split(',')
+                .filter_map(|media_range| media_range.split(';').next())
+                .find_map(|media_type| Self::from_media_type(media_type.trim()))
+        }
+
+        /// Whether results in this format are streamed to the client as batches are produced,
+        /// instead of being buffered until the query completes.
+        pub(crate) fn is_streamed(self) -> bool {
+            matches!(self, ResultFormat::Ndjson | ResultFormat::Arrow)
+        }
+
+        pub(crate) fn content_type(self) -> &'static str {
+            match self {
+                ResultFormat::Json => "application/json",
+                ResultFormat::Ndjson => "application/x-ndjson",
+                ResultFormat::Csv => "text/csv",
+                ResultFormat::Arrow => "application/vnd.apache.arrow.stream",
+                ResultFormat::Parquet => "application/vnd.apache.parquet",
+            }
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub(crate) struct QueryParams {
+        /// Takes precedence over the `Accept` header.
+        format: Option<ResultFormat>,
+
+        /// Limits the result to this many rows, up to the runtime's `--sql_max_rows`.
+        max_rows: Option<usize>,
+
+        /// Overrides the runtime's `--query_timeout`, i.e. `30s`.
+        timeout: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub(crate) struct ExplainParams {
+        /// Runs the query to report each operator's row counts and timings.
+        #[serde(default)]
+        analyze: bool,
+
+        /// Overrides the runtime's `--query_timeout`, i.e. `30s`.
+        timeout: Option<String>,
+    }
+
+    /// The format requested by the `format` parameter, or else by the `Accept` header.
+    pub(crate) fn result_format(
+        format: Option<ResultFormat>,
+        headers: &HeaderMap,
+    ) -> Result<ResultFormat, ApiError> {
+        if let Some(format) = format {
+            return Ok(format);
+        }
+
+        match headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+        {
+            Some(accept) => ResultFormat::from_accept(accept).ok_or_else(|| {
+                ApiError::new(
+                    ErrorCode::NotAcceptable,
+                    format!("Unsupported result format: {accept}"),
+                )
+            }),
+            None => Ok(ResultFormat::Json),
+        }
+    }
+
+    /// Who is running the query and how long it may run for.
+    pub(crate) fn query_options(
+        headers: &HeaderMap,
+        client_addr: SocketAddr,
+        timeout: Option<&str>,
+    ) -> Result<QueryOptions, ApiError> {
+        let timeout = match timeout.map(fundu::parse_duration) {
+            Some(Ok(timeout)) => Some(timeout),
+            Some(Err(e)) => {
+                return Err(ApiError::new(
+                    ErrorCode::BadRequest,
+                    format!("Invalid timeout: {e}"),
+                ));
+            }
+            None => None,
+        };
+
+        let principal = querylimits::principal(
+            Some(client_addr.ip()),
+            headers
+                .get(header::AUTHORIZATION)
+                .map(HeaderValue::as_bytes),
+        );
+
+        Ok(QueryOptions {
+            protocol: "http",
+            principal,
+            timeout,
+        })
+    }
+
+    #[allow(clippy::too_many_lines)]
     pub(crate) async fn post(
         Extension(df): Extension<Arc<RwLock<DataFusion>>>,
+        Extension(config): Extension<Arc<config::Config>>,
+        Extension(query_limits): Extension<Arc<QueryLimits>>,
+        ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+        Query(params): Query<QueryParams>,
+        headers: HeaderMap,
         body: Bytes,
     ) -> Response {
+        let format = match result_format(params.format, &headers) {
+            Ok(format) => format,
+            Err(e) => return e.into_response(),
+        };
+
         let query = match String::from_utf8(body.to_vec()) {
             Ok(query) => query,
             Err(e) => {
                 tracing::debug!("Error reading query: {e}");
-                return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+                return ApiError::new(ErrorCode::BadRequest, e.to_string()).into_response();
             }
         };
 
-        let data_frame = match df.read().await.ctx.sql(&query).await {
+        let options = match query_options(&headers, client_addr, params.timeout.as_deref()) {
+            Ok(options) => options,
+            Err(e) => return e.into_response(),
+        };
+
+        let mut running_query = match query_limits.start(options).await {
+            Ok(running_query) => running_query,
+            Err(e) => {
+                tracing::debug!("Query not started: {e}");
+                return ApiError::from_query_limits(&e).into_response();
+            }
+        };
+
+        let data_frame = match running_query
+            .run(async { df.read().await.ctx.sql(&query).await })
+            .await
+        {
             Ok(data_frame) => data_frame,
             Err(e) => {
-                tracing::debug!("Error running query: {e}");
-                return (StatusCode::BAD_REQUEST, query.to_string()).into_response();
+                tracing::debug!("Error planning query: {e}");
+                return ApiError::from_datafusion(&e).into_response();
             }
         };
 
-        let results = match data_frame.collect().await {
-            Ok(results) => results,
+        let max_rows = match (params.max_rows, config.sql_max_rows) {
+            (Some(requested), Some(max)) => Some(requested.min(max)),
+            (requested, max) => requested.or(max),
+        };
+        // Buffered results read one more row than they return, to tell whether they're truncated.
+        let limit = match max_rows {
+            Some(max_rows) if !format.is_streamed() => Some(max_rows.saturating_add(1)),
+            max_rows => max_rows,
+        };
+        let data_frame = match limit {
+            Some(limit) => match data_frame.limit(0, Some(limit)) {
+                Ok(data_frame) => data_frame,
+                Err(e) => {
+                    tracing::debug!("Error limiting results: {e}");
+                    return ApiError::from_datafusion(&e).into_response();
+                }
+            },
+            None => data_frame,
+        };
+
+        let schema: SchemaRef = Arc::new(data_frame.schema().into());
+
+        if format.is_streamed() {
+            let mut stream = match running_query
+                .run(async { df.read().await.execute_stream(data_frame).await })
+                .await
+            {
+                Ok(stream) => running_query.stream(stream),
+                Err(e) => {
+                    tracing::debug!("Error executing query: {e}");
+                    return ApiError::from_datafusion(&e).into_response();
+                }
+            };
+
+            // Errors before the first batch can still be reported with an error status.
+            let first_batch = match stream.next().await {
+                Some(Ok(batch)) => Some(batch),
+                Some(Err(e)) => {
+                    tracing::debug!("Error collecting results: {e}");
+                    return ApiError::from_datafusion(&e).into_response();
+                }
+                None => None,
+            };
+
+            let mut response = (
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, format.content_type())],
+                stream_results(format, schema, first_batch, stream),
+            )
+                .into_response();
+            if let Some(max_rows) = max_rows {
+                response
+                    .headers_mut()
+                    .insert(MAX_ROWS_HEADER, HeaderValue::from(max_rows));
+            }
+            return response;
+        }
+
+        let results = match running_query
+            .run(async {
+                let stream = df.read().await.execute_stream(data_frame).await?;
+                datafusion::physical_plan::common::collect(stream).await
+            })
+            .await
+        {
+            Ok(results) => {
+                running_query.finish();
+                results
+            }
             Err(e) => {
                 tracing::debug!("Error collecting results: {e}");
-                return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+                return ApiError::from_datafusion(&e).into_response();
             }
         };
 
-        let buf = Vec::new();
-        let mut writer = arrow_json::ArrayWriter::new(buf);
+        let (results, truncated) = match max_rows {
+            Some(max_rows) => truncate(results, max_rows),
+            None => (results, false),
+        };
 
-        if let Err(e) =
-            writer.write_batches(results.iter().collect::<Vec<&RecordBatch>>().as_slice())
-        {
-            tracing::debug!("Error converting results to JSON: {e}");
-            return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
-        }
-        if let Err(e) = writer.finish() {
-            tracing::debug!("Error finishing JSON conversion: {e}");
-            return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        match write_results(format, &schema, &results) {
+            Ok(res) => {
+                let mut response = (
+                    StatusCode::OK,
+                    [(header::CONTENT_TYPE, format.content_type())],
+                    res,
+                )
+                    .into_response();
+                if truncated {
+                    response
+                        .headers_mut()
+                        .insert(TRUNCATED_HEADER, HeaderValue::from_static("true"));
+                }
+                response
+            }
+            Err(e) => {
+                tracing::debug!("Error converting results to {format:?}: {e}");
+                ApiError::new(ErrorCode::Internal, e.to_string()).into_response()
+            }
         }
+    }
+
+    /// Keeps the first `max_rows` rows of `results`, returning whether any were removed.
+    fn truncate(results: Vec<RecordBatch>, max_rows: usize) -> (Vec<RecordBatch>, bool) {
+        let mut remaining = max_rows;
+        let mut truncated = false;
+        let results = results
+            .into_iter()
+            .filter_map(|batch| {
+                if batch.num_rows() > remaining {
+                    truncated = true;
+                }
+                let batch = batch.slice(0, batch.num_rows().min(remaining));
+                remaining -= batch.num_rows();
+                (batch.num_rows() > 0).then_some(batch)
+            })
+            .collect();
+        (results, truncated)
+    }
+
+    /// Returns the plans of a query, and each operator's metrics if `analyze` is set.
+    pub(crate) async fn explain(
+        Extension(df): Extension<Arc<RwLock<DataFusion>>>,
+        Extension(query_limits): Extension<Arc<QueryLimits>>,
+        ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+        Query(params): Query<ExplainParams>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Response {
+        let query = match String::from_utf8(body.to_vec()) {
+            Ok(query) => query,
+            Err(e) => {
+                tracing::debug!("Error reading query: {e}");
+                return ApiError::new(ErrorCode::BadRequest, e.to_string()).into_response();
+            }
+        };
+
+        let options = match query_options(&headers, client_addr, params.timeout.as_deref()) {
+            Ok(options) => options,
+            Err(e) => return e.into_response(),
+        };
 
-        let buf = writer.into_inner();
-        let res = match String::from_utf8(buf) {
-            Ok(res) => res,
+        let mut running_query = match query_limits.start(options).await {
+            Ok(running_query) => running_query,
             Err(e) => {
-                tracing::debug!("Error converting JSON buffer to string: {e}");
-                return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+                tracing::debug!("Query not started: {e}");
+                return ApiError::from_query_limits(&e).into_response();
             }
         };
 
-        (StatusCode::OK, res).into_response()
+        let explanation = running_query
+            .run(async {
+                let data_frame = df.read().await.ctx.sql(&query).await?;
+                explain::explain(data_frame, params.analyze).await
+            })
+            .await;
+        running_query.finish();
+
+        match explanation {
+            Ok(explanation) => (StatusCode::OK, Json(explanation)).into_response(),
+            Err(e) => {
+                tracing::debug!("Error explaining query: {e}");
+                ApiError::from_datafusion(&e).into_response()
+            }
+        }
+    }
+
+    /// Encodes each batch as it's produced into a chunk of the response body.
+    ///
+    /// The status has already been sent when a later batch fails, so the error ends the body early
+    /// without its terminating chunk, which clients see as an incomplete response.
+    pub(crate) fn stream_results(
+        format: ResultFormat,
+        schema: SchemaRef,
+        first_batch: Option<RecordBatch>,
+        mut batches: SendableRecordBatchStream,
+    ) -> Body {
+        Body::from_stream(stream! {
+            let mut encoder = match BatchEncoder::try_new(format, &schema) {
+                Ok(encoder) => encoder,
+                Err(e) => {
+                    tracing::debug!("Error converting results to {format:?}: {e}");
+                    yield Err(e);
+                    return;
+                }
+            };
+
+            if let Some(batch) = first_batch {
+                yield encoder.encode(&batch);
+            }
+
+            while let Some(batch) = batches.next().await {
+                match batch {
+                    Ok(batch) => yield encoder.encode(&batch),
+                    Err(e) => {
+                        tracing::error!("Error streaming query results: {e}");
+                        yield Err(e.into());
+                        return;
+                    }
+                }
+            }
+
+            yield encoder.finish();
+        })
+    }
+
+    enum BatchEncoder {
+        Ndjson,
+        Arrow(StreamWriter<Vec<u8>>),
+    }
+
+    impl BatchEncoder {
+        fn try_new(format: ResultFormat, schema: &SchemaRef) -> Result<Self, BoxError> {
+            match format {
+                ResultFormat::Ndjson => Ok(BatchEncoder::Ndjson),
+                ResultFormat::Arrow => Ok(BatchEncoder::Arrow(StreamWriter::try_new(
+                    Vec::new(),
+                    schema,
+                )?)),
+                _ => Err(format!("{format:?} results can't be streamed").into()),
+            }
+        }
+
+        fn encode(&mut self, batch: &RecordBatch) -> Result<Bytes, BoxError> {
+            match self {
+                BatchEncoder::Ndjson => {
+                    let mut writer = arrow_json::LineDelimitedWriter::new(Vec::new());
+                    writer.write(batch)?;
+                    writer.finish()?;
+                    Ok(writer.into_inner().into())
+                }
+                BatchEncoder::Arrow(writer) => {
+                    writer.write(batch)?;
+                    Ok(std::mem::take(writer.get_mut()).into())
+                }
+            }
+        }
+
+        /// Returns anything the format writes after the last batch.
+        fn finish(&mut self) -> Result<Bytes, BoxError> {
+            match self {
+                BatchEncoder::Ndjson => Ok(Bytes::new()),
+                BatchEncoder::Arrow(writer) => {
+                    writer.finish()?;
+                    Ok(std::mem::take(writer.get_mut()).into())
+                }
+            }
+        }
+    }
+
+    pub(crate) fn write_results(
+        format: ResultFormat,
+        schema: &SchemaRef,
+        results: &[RecordBatch],
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let batches = results.iter().collect::<Vec<&RecordBatch>>();
+
+        let buf = match format {
+            ResultFormat::Json => {
+                let mut writer = arrow_json::ArrayWriter::new(Vec::new());
+                writer.write_batches(&batches)?;
+                writer.finish()?;
+                writer.into_inner()
+            }
+            ResultFormat::Ndjson => {
+                let mut writer = arrow_json::LineDelimitedWriter::new(Vec::new());
+                writer.write_batches(&batches)?;
+                writer.finish()?;
+                writer.into_inner()
+            }
+            ResultFormat::Csv => {
+                let mut writer = arrow::csv::Writer::new(Vec::new());
+                for batch in batches {
+                    writer.write(batch)?;
+                }
+                writer.into_inner()
+            }
+            ResultFormat::Arrow => {
+                let mut writer = StreamWriter::try_new(Vec::new(), schema)?;
+                for batch in batches {
+                    writer.write(batch)?;
+                }
+                writer.into_inner()?
+            }
+            ResultFormat::Parquet => {
+                let mut writer = ArrowWriter::try_new(Vec::new(), Arc::clone(schema), None)?;
+                for batch in batches {
+                    writer.write(batch)?;
+                }
+                writer.into_inner()?
+            }
+        };
+
+        Ok(buf)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_result_format_from_accept() {
+            assert_eq!(
+                ResultFormat::from_accept("application/vnd.apache.arrow.stream"),
+                Some(ResultFormat::Arrow)
+            );
+            assert_eq!(
+                ResultFormat::from_accept("text/html, text/csv;q=0.9"),
+                Some(ResultFormat::Csv)
+            );
+            assert_eq!(ResultFormat::from_accept("*/*"), Some(ResultFormat::Json));
+            assert_eq!(ResultFormat::from_accept("text/html"), None);
+        }
+
+        #[test]
+        fn test_truncate() {
+            let batch = |num_rows: i64| {
+                RecordBatch::try_from_iter([(
+                    "x",
+                    Arc::new(arrow::array::Int64Array::from_iter_values(0..num_rows))
+                        as arrow::array::ArrayRef,
+                )])
+                .expect("valid record batch")
+            };
+            let num_rows = |results: &[RecordBatch]| {
+                results
+                    .iter()
+                    .map(RecordBatch::num_rows)
+                    .collect::<Vec<_>>()
+            };
+
+            let (results, truncated) = truncate(vec![batch(2), batch(2)], 4);
+            assert_eq!(num_rows(&results), vec![2, 2]);
+            assert!(!truncated);
+
+            let (results, truncated) = truncate(vec![batch(2), batch(2)], 3);
+            assert_eq!(num_rows(&results), vec![2, 1]);
+            assert!(truncated);
+
+            let (results, truncated) = truncate(vec![batch(2), batch(2)], 2);
+            assert_eq!(num_rows(&results), vec![2]);
+            assert!(truncated);
+        }
     }
 }
 
@@ -130,6 +865,8 @@ pub(crate) mod status {
 
     use crate::{config, status::ComponentStatus};
 
+    use super::error::{ApiError, ErrorCode};
+
     #[derive(Debug, Serialize, Deserialize)]
     #[serde(rename_all = "lowercase")]
     pub struct QueryParams {
@@ -215,7 +952,7 @@ pub(crate) mod status {
                 Ok(csv) => (status::StatusCode::OK, csv).into_response(),
                 Err(e) => {
                     tracing::error!("Error converting to CSV: {e}");
-                    (status::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+                    ApiError::new(ErrorCode::Internal, e.to_string()).into_response()
                 }
             },
         }
@@ -281,19 +1018,31 @@ pub(crate) mod datasets {
 
     use app::App;
     use axum::{
-        extract::Query,
-        http::status,
+        body::Bytes,
+        extract::{Path, Query},
+        http::{header, status, HeaderMap},
         response::{IntoResponse, Response},
         Extension, Json,
     };
     use serde::{Deserialize, Serialize};
-    use spicepod::component::dataset::Dataset;
+    use spicepod::component::dataset::{
+        acceleration::{Engine, Mode},
+        Dataset,
+    };
     use tokio::sync::RwLock;
     use tract_core::tract_data::itertools::Itertools;
 
-    use crate::{datafusion::DataFusion, status::ComponentStatus};
+    use crate::{
+        datafusion::{self, DataFusion},
+        datasetchange::{self, DatasetChange},
+        status::{self as component_status, ComponentStatus},
+    };
 
-    use super::{convert_entry_to_csv, dataset_status, Format};
+    use super::{
+        convert_entry_to_csv, dataset_status,
+        error::{ApiError, ErrorCode},
+        Format,
+    };
 
     #[derive(Debug, Deserialize)]
     pub(crate) struct DatasetFilter {
@@ -325,66 +1074,336 @@ pub(crate) mod datasets {
         pub status: Option<ComponentStatus>,
     }
 
-    pub(crate) async fn get(
-        Extension(app): Extension<Arc<RwLock<Option<App>>>>,
-        Extension(df): Extension<Arc<RwLock<DataFusion>>>,
-        Query(filter): Query<DatasetFilter>,
-        Query(params): Query<DatasetQueryParams>,
+    pub(crate) async fn get(
+        Extension(app): Extension<Arc<RwLock<Option<App>>>>,
+        Extension(df): Extension<Arc<RwLock<DataFusion>>>,
+        Query(filter): Query<DatasetFilter>,
+        Query(params): Query<DatasetQueryParams>,
+    ) -> Response {
+        let app_lock = app.read().await;
+        let Some(readable_app) = &*app_lock else {
+            return ApiError::new(ErrorCode::Unavailable, "No spicepod is loaded").into_response();
+        };
+
+        let mut datasets: Vec<Dataset> = match filter.source {
+            Some(source) => readable_app
+                .datasets
+                .iter()
+                .filter(|d| d.source() == source)
+                .cloned()
+                .collect(),
+            None => readable_app.datasets.clone(),
+        };
+
+        if filter.remove_views {
+            datasets.retain(|d| !d.is_view());
+        }
+
+        let df_read = df.read().await;
+
+        let resp = datasets
+            .iter()
+            .map(|d| DatasetResponseItem {
+                from: d.from.clone(),
+                name: d.name.clone(),
+                replication_enabled: d.replication.as_ref().is_some_and(|f| f.enabled),
+                acceleration_enabled: d.acceleration.as_ref().is_some_and(|f| f.enabled),
+                depends_on: if d.depends_on.is_empty() {
+                    None
+                } else {
+                    Some(d.depends_on.join(", "))
+                },
+                status: if params.status {
+                    Some(dataset_status(&df_read, d))
+                } else {
+                    None
+                },
+            })
+            .collect_vec();
+
+        match params.format {
+            Format::Json => (status::StatusCode::OK, Json(resp)).into_response(),
+            Format::Csv => match convert_entry_to_csv(&resp) {
+                Ok(csv) => (status::StatusCode::OK, csv).into_response(),
+                Err(e) => {
+                    tracing::error!("Error converting to CSV: {e}");
+                    ApiError::new(ErrorCode::Internal, e.to_string()).into_response()
+                }
+            },
+        }
+    }
+
+    #[derive(Debug, Serialize)]
+    pub(crate) struct FieldResponse {
+        pub name: String,
+        #[serde(rename = "type")]
+        pub data_type: String,
+        pub nullable: bool,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub(crate) struct DatasetDetailResponse {
+        pub name: String,
+        pub from: String,
+        pub schema: Vec<FieldResponse>,
+        pub acceleration_enabled: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub engine: Option<Engine>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub mode: Option<Mode>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub refresh_interval: Option<String>,
+        pub row_count: Option<usize>,
+        pub size_bytes: Option<usize>,
+        /// Milliseconds since the Unix epoch.
+        pub last_refresh_time: Option<u64>,
+        pub last_refresh_duration_ms: Option<u64>,
+        pub last_error: Option<String>,
+        /// Milliseconds since the Unix epoch.
+        pub last_error_time: Option<u64>,
+        pub stale: bool,
+        pub status: ComponentStatus,
+    }
+
+    pub(crate) async fn get_by_name(
+        Extension(app): Extension<Arc<RwLock<Option<App>>>>,
+        Extension(df): Extension<Arc<RwLock<DataFusion>>>,
+        Path(name): Path<String>,
+    ) -> Response {
+        let Some(ds) = app
+            .read()
+            .await
+            .as_ref()
+            .and_then(|app| app.datasets.iter().find(|ds| ds.name == name).cloned())
+        else {
+            return ApiError::new(ErrorCode::NotFound, format!("Dataset {name} not found"))
+                .into_response();
+        };
+
+        let df_read = df.read().await;
+        let ds_status = dataset_status(&df_read, &ds);
+        let acceleration = ds.acceleration.as_ref().filter(|acc| acc.enabled);
+
+        let (schema, statistics) = if ds_status == ComponentStatus::Ready {
+            let schema = match df_read.get_arrow_schema(&ds.name).await {
+                Ok(schema) => schema
+                    .fields()
+                    .iter()
+                    .map(|field| FieldResponse {
+                        name: field.name().clone(),
+                        data_type: field.data_type().to_string(),
+                        nullable: field.is_nullable(),
+                    })
+                    .collect_vec(),
+                Err(e) => {
+                    tracing::error!("Unable to get the schema of dataset {name}: {e}");
+                    return ApiError::new(ErrorCode::Internal, e.to_string()).into_response();
+                }
+            };
+
+            // Only count rows locally, never by scanning the source of a federated dataset.
+            let statistics = df_read
+                .get_table_statistics(&ds.name, acceleration.is_some())
+                .await
+                .unwrap_or_else(|e| {
+                    tracing::warn!("Unable to get statistics for dataset {name}: {e}");
+                    datafusion::TableStatistics::default()
+                });
+
+            (schema, statistics)
+        } else {
+            (vec![], datafusion::TableStatistics::default())
+        };
+
+        let metadata = component_status::get_dataset_metadata(&ds.name).unwrap_or_default();
+
+        let resp = DatasetDetailResponse {
+            name: ds.name.clone(),
+            from: ds.from.clone(),
+            schema,
+            acceleration_enabled: acceleration.is_some(),
+            engine: acceleration.map(|acc| acc.engine()),
+            mode: acceleration.map(|acc| acc.mode()),
+            refresh_interval: acceleration.and_then(|acc| acc.refresh_interval.clone()),
+            row_count: statistics.num_rows,
+            size_bytes: statistics.total_byte_size,
+            last_refresh_time: metadata.last_refresh_time,
+            last_refresh_duration_ms: metadata.last_refresh_duration_ms,
+            last_error: metadata.last_error,
+            last_error_time: metadata.last_error_time,
+            stale: metadata.stale,
+            status: ds_status,
+        };
+
+        (status::StatusCode::OK, Json(resp)).into_response()
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    pub(crate) struct RefreshRequest {
+        /// Only refresh the rows matching this SQL filter.
+        sql: Option<String>,
+    }
+
+    pub(crate) async fn refresh(
+        Extension(df): Extension<Arc<RwLock<DataFusion>>>,
+        Path(name): Path<String>,
+        body: Bytes,
+    ) -> Response {
+        let request = if body.is_empty() {
+            RefreshRequest::default()
+        } else {
+            match serde_json::from_slice::<RefreshRequest>(&body) {
+                Ok(request) => request,
+                Err(e) => {
+                    return ApiError::new(ErrorCode::BadRequest, e.to_string()).into_response();
+                }
+            }
+        };
+
+        match df.read().await.refresh_dataset(&name, request.sql).await {
+            Ok(refresh) => (status::StatusCode::ACCEPTED, Json(refresh)).into_response(),
+            Err(e) => {
+                let code = match e {
+                    datafusion::Error::DatasetNotRefreshable { .. } => ErrorCode::NotFound,
+                    datafusion::Error::InvalidRefreshFilter { ref source } => {
+                        return ApiError::from_datafusion(source).into_response();
+                    }
+                    datafusion::Error::RefreshQueueFull { .. } => ErrorCode::TooManyRequests,
+                    _ => ErrorCode::Internal,
+                };
+                ApiError::new(code, e.to_string()).into_response()
+            }
+        }
+    }
+
+    pub(crate) async fn refresh_status(
+        Extension(df): Extension<Arc<RwLock<DataFusion>>>,
+        Path((name, id)): Path<(String, String)>,
+    ) -> Response {
+        match df.read().await.refreshes.get(&id) {
+            Some(refresh) if refresh.dataset == name => {
+                (status::StatusCode::OK, Json(refresh)).into_response()
+            }
+            _ => ApiError::new(
+                ErrorCode::NotFound,
+                format!("Refresh {id} not found for dataset {name}"),
+            )
+            .into_response(),
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub(crate) struct DatasetChangeParams {
+        /// Also write the change to the root spicepod.yaml, which is rewritten without its comments
+        /// and formatting.
+        #[serde(default)]
+        persist: bool,
+    }
+
+    pub(crate) async fn post(
+        Extension(dataset_changes): Extension<datasetchange::Sender>,
+        Query(params): Query<DatasetChangeParams>,
+        headers: HeaderMap,
+        body: Bytes,
     ) -> Response {
-        let app_lock = app.read().await;
-        let Some(readable_app) = &*app_lock else {
-            return (
-                status::StatusCode::INTERNAL_SERVER_ERROR,
-                Json::<Vec<DatasetResponseItem>>(vec![]),
-            )
-                .into_response();
+        let ds = match parse_dataset(&headers, &body) {
+            Ok(ds) => ds,
+            Err(response) => return response,
         };
 
-        let mut datasets: Vec<Dataset> = match filter.source {
-            Some(source) => readable_app
-                .datasets
-                .iter()
-                .filter(|d| d.source() == source)
-                .cloned()
-                .collect(),
-            None => readable_app.datasets.clone(),
-        };
+        apply_dataset_change(
+            &dataset_changes,
+            DatasetChange::Create(ds),
+            params.persist,
+            status::StatusCode::CREATED,
+        )
+        .await
+    }
 
-        if filter.remove_views {
-            datasets.retain(|d| !d.is_view());
+    pub(crate) async fn put(
+        Extension(dataset_changes): Extension<datasetchange::Sender>,
+        Path(name): Path<String>,
+        Query(params): Query<DatasetChangeParams>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Response {
+        let ds = match parse_dataset(&headers, &body) {
+            Ok(ds) => ds,
+            Err(response) => return response,
+        };
+        if ds.name != name {
+            return ApiError::new(
+                ErrorCode::BadRequest,
+                format!("Dataset name {} does not match {name}", ds.name),
+            )
+            .into_response();
         }
 
-        let df_read = df.read().await;
+        apply_dataset_change(
+            &dataset_changes,
+            DatasetChange::Update(ds),
+            params.persist,
+            status::StatusCode::OK,
+        )
+        .await
+    }
 
-        let resp = datasets
-            .iter()
-            .map(|d| DatasetResponseItem {
-                from: d.from.clone(),
-                name: d.name.clone(),
-                replication_enabled: d.replication.as_ref().is_some_and(|f| f.enabled),
-                acceleration_enabled: d.acceleration.as_ref().is_some_and(|f| f.enabled),
-                depends_on: if d.depends_on.is_empty() {
-                    None
-                } else {
-                    Some(d.depends_on.join(", "))
-                },
-                status: if params.status {
-                    Some(dataset_status(&df_read, d))
-                } else {
-                    None
-                },
-            })
-            .collect_vec();
+    pub(crate) async fn delete(
+        Extension(dataset_changes): Extension<datasetchange::Sender>,
+        Path(name): Path<String>,
+        Query(params): Query<DatasetChangeParams>,
+    ) -> Response {
+        apply_dataset_change(
+            &dataset_changes,
+            DatasetChange::Delete(name),
+            params.persist,
+            status::StatusCode::NO_CONTENT,
+        )
+        .await
+    }
 
-        match params.format {
-            Format::Json => (status::StatusCode::OK, Json(resp)).into_response(),
-            Format::Csv => match convert_entry_to_csv(&resp) {
-                Ok(csv) => (status::StatusCode::OK, csv).into_response(),
-                Err(e) => {
-                    tracing::error!("Error converting to CSV: {e}");
-                    (status::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
-                }
-            },
+    /// Parses a dataset definition sent as JSON, or as YAML when the content type says so.
+    fn parse_dataset(headers: &HeaderMap, body: &[u8]) -> Result<Dataset, Response> {
+        let is_yaml = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.contains("yaml"));
+
+        let parsed = if is_yaml {
+            serde_yaml::from_slice::<Dataset>(body).map_err(|e| e.to_string())
+        } else {
+            serde_json::from_slice::<Dataset>(body).map_err(|e| e.to_string())
+        };
+
+        parsed.map_err(|e| {
+            ApiError::new(
+                ErrorCode::BadRequest,
+                format!("Invalid dataset definition: {e}"),
+            )
+            .into_response()
+        })
+    }
+
+    async fn apply_dataset_change(
+        dataset_changes: &datasetchange::Sender,
+        change: DatasetChange,
+        persist: bool,
+        success: status::StatusCode,
+    ) -> Response {
+        match datasetchange::apply(dataset_changes, change, persist).await {
+            Ok(()) => success.into_response(),
+            Err(e) => {
+                let code = match e {
+                    datasetchange::Error::DatasetNotFound { .. } => ErrorCode::NotFound,
+                    datasetchange::Error::InvalidDataset { .. } => ErrorCode::BadRequest,
+                    datasetchange::Error::NoSpicepodLoaded
+                    | datasetchange::Error::DatasetAlreadyExists { .. }
+                    | datasetchange::Error::NotPersistable { .. } => ErrorCode::Conflict,
+                    datasetchange::Error::RuntimeUnavailable => ErrorCode::Unavailable,
+                    _ => ErrorCode::Internal,
+                };
+                ApiError::new(code, e.to_string()).into_response()
+            }
         }
     }
 }
@@ -401,10 +1420,13 @@ pub(crate) mod spicepods {
     };
     use itertools::Itertools;
     use serde::{Deserialize, Serialize};
-    use spicepod::Spicepod;
     use tokio::sync::RwLock;
 
-    use super::{convert_entry_to_csv, Format};
+    use super::{
+        convert_entry_to_csv,
+        error::{ApiError, ErrorCode},
+        Format,
+    };
 
     #[derive(Debug, Deserialize)]
     pub(crate) struct SpicepodQueryParams {
@@ -438,11 +1460,7 @@ pub(crate) mod spicepods {
         Query(params): Query<SpicepodQueryParams>,
     ) -> Response {
         let Some(readable_app) = &*app.read().await else {
-            return (
-                status::StatusCode::INTERNAL_SERVER_ERROR,
-                Json::<Vec<Spicepod>>(vec![]),
-            )
-                .into_response();
+            return ApiError::new(ErrorCode::Unavailable, "No spicepod is loaded").into_response();
         };
 
         match params.format {
@@ -465,7 +1483,7 @@ pub(crate) mod spicepods {
                     Ok(csv) => (status::StatusCode::OK, csv).into_response(),
                     Err(e) => {
                         tracing::error!("Error converting to CSV: {e}");
-                        (status::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+                        ApiError::new(ErrorCode::Internal, e.to_string()).into_response()
                     }
                 }
             }
@@ -476,19 +1494,31 @@ pub(crate) mod spicepods {
 pub(crate) mod models {
     use std::{collections::HashMap, sync::Arc};
 
+    use app::App;
     use axum::{
-        extract::Query,
+        extract::{Path, Query},
         http::status,
         response::{IntoResponse, Json, Response},
         Extension,
     };
     use csv::Writer;
     use serde::{Deserialize, Serialize};
+    use serde_json::Value;
     use tokio::sync::RwLock;
 
-    use crate::model::Model;
+    use crate::{
+        model,
+        modelruntime::TensorInfo,
+        modelstats::InferenceStatsSnapshot,
+        modelversions::{ModelVersions, ShadowRun},
+        status::{self as component_status, ComponentStatus},
+    };
 
-    use super::Format;
+    use super::{
+        error::{ApiError, ErrorCode},
+        query::{self, ResultFormat},
+        Format,
+    };
 
     #[derive(Debug, Deserialize)]
     pub(crate) struct ModelsQueryParams {
@@ -501,17 +1531,22 @@ pub(crate) mod models {
     pub(crate) struct ModelResponse {
         pub name: String,
         pub from: String,
+        pub version: String,
         pub datasets: Option<Vec<String>>,
+        pub traffic: Option<u32>,
+        pub shadow: bool,
     }
 
+    /// Lists each loaded version of every model.
     pub(crate) async fn get(
-        Extension(model): Extension<Arc<RwLock<HashMap<String, Model>>>>,
+        Extension(models): Extension<Arc<RwLock<HashMap<String, ModelVersions>>>>,
         Query(params): Query<ModelsQueryParams>,
     ) -> Response {
-        let resp = model
+        let resp = models
             .read()
             .await
             .values()
+            .flat_map(ModelVersions::versions)
             .map(|m| {
                 let datasets = if m.model.datasets.is_empty() {
                     None
@@ -521,7 +1556,10 @@ pub(crate) mod models {
                 ModelResponse {
                     name: m.model.name.clone(),
                     from: m.model.from.clone(),
+                    version: m.version(),
                     datasets,
+                    traffic: m.model.traffic,
+                    shadow: m.model.shadow,
                 }
             })
             .collect::<Vec<ModelResponse>>();
@@ -532,12 +1570,157 @@ pub(crate) mod models {
                 Ok(csv) => (status::StatusCode::OK, csv).into_response(),
                 Err(e) => {
                     tracing::error!("Error converting to CSV: {e}");
-                    (status::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+                    ApiError::new(ErrorCode::Internal, e.to_string()).into_response()
                 }
             },
         }
     }
 
+    #[derive(Debug, Serialize)]
+    pub(crate) struct ModelDetailResponse {
+        pub name: String,
+        pub status: ComponentStatus,
+        /// The most recent error loading a version of the model.
+        pub last_error: Option<String>,
+        /// Milliseconds since the Unix epoch.
+        pub last_error_time: Option<u64>,
+        pub versions: Vec<ModelVersionResponse>,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub(crate) struct ModelVersionResponse {
+        pub version: String,
+        pub from: String,
+        pub source: String,
+        pub traffic: Option<u32>,
+        pub shadow: bool,
+        /// Whether SQL functions and scheduled predictions use this version.
+        pub primary: bool,
+        /// Milliseconds since the Unix epoch.
+        pub loaded_at: u64,
+        pub load_duration_ms: u64,
+        pub inputs: Vec<TensorInfo>,
+        pub outputs: Vec<TensorInfo>,
+        pub stats: InferenceStatsSnapshot,
+    }
+
+    /// Describes a model and each of its loaded versions. Versions are only loaded once they've
+    /// run with zeroed inputs, so a `Ready` model can serve requests.
+    pub(crate) async fn get_by_name(
+        Extension(app): Extension<Arc<RwLock<Option<App>>>>,
+        Extension(models): Extension<Arc<RwLock<HashMap<String, ModelVersions>>>>,
+        Path(name): Path<String>,
+    ) -> Response {
+        let versions = models.read().await.get(&name).cloned();
+        let in_app = app
+            .read()
+            .await
+            .as_ref()
+            .is_some_and(|app| app.models.iter().any(|m| m.name == name));
+        if versions.is_none() && !in_app {
+            return ApiError::new(ErrorCode::NotFound, format!("Model {name} not found"))
+                .into_response();
+        }
+
+        let primary = versions
+            .as_ref()
+            .and_then(ModelVersions::primary)
+            .map(model::Model::version);
+        let versions = versions
+            .as_ref()
+            .map(ModelVersions::versions)
+            .unwrap_or_default()
+            .iter()
+            .map(|m| ModelVersionResponse {
+                version: m.version(),
+                from: m.model.from.clone(),
+                source: model::source(&m.model.from),
+                traffic: m.model.traffic,
+                shadow: m.model.shadow,
+                primary: primary.as_ref() == Some(&m.version()),
+                loaded_at: m.loaded_at(),
+                load_duration_ms: m.load_duration_ms(),
+                inputs: m.input_tensors(),
+                outputs: m.output_tensors(),
+                stats: m.stats(),
+            })
+            .collect::<Vec<_>>();
+
+        let metadata = component_status::get_model_metadata(&name);
+        let resp = ModelDetailResponse {
+            name,
+            status: metadata
+                .as_ref()
+                .map_or(ComponentStatus::Initializing, |metadata| metadata.status),
+            last_error: metadata
+                .as_ref()
+                .and_then(|metadata| metadata.last_error.clone()),
+            last_error_time: metadata.and_then(|metadata| metadata.last_error_time),
+            versions,
+        };
+        (status::StatusCode::OK, Json(resp)).into_response()
+    }
+
+    #[derive(Debug, Serialize)]
+    pub(crate) struct ShadowRunResponse {
+        pub version: String,
+        pub served_version: String,
+        pub timestamp: u64,
+        pub duration_ms: u64,
+        /// The first rows of the shadow version's outputs.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub outputs: Option<Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub error: Option<String>,
+    }
+
+    /// Returns the most recent runs of the shadow versions of a model, oldest first, to compare
+    /// with the version that served each request.
+    pub(crate) async fn shadow_runs(
+        Extension(models): Extension<Arc<RwLock<HashMap<String, ModelVersions>>>>,
+        Path(name): Path<String>,
+    ) -> Response {
+        let Some(versions) = models.read().await.get(&name).cloned() else {
+            return ApiError::new(ErrorCode::NotFound, format!("Model {name} not found"))
+                .into_response();
+        };
+
+        let resp = versions
+            .shadow_runs()
+            .into_iter()
+            .map(shadow_run_response)
+            .collect::<Vec<_>>();
+        (status::StatusCode::OK, Json(resp)).into_response()
+    }
+
+    fn shadow_run_response(run: ShadowRun) -> ShadowRunResponse {
+        let (outputs, error) = match run.result {
+            Ok(outputs) => {
+                match query::write_results(ResultFormat::Json, &outputs.schema(), &[outputs])
+                    .map_err(|e| e.to_string())
+                    .and_then(|json| {
+                        serde_json::from_slice::<Value>(&json).map_err(|e| e.to_string())
+                    }) {
+                    Ok(outputs) => (Some(outputs), None),
+                    Err(e) => (
+                        None,
+                        Some(format!("Unable to convert the outputs to JSON: {e}")),
+                    ),
+                }
+            }
+            Err(e) => (None, Some(e)),
+        };
+
+        ShadowRunResponse {
+            version: run.version,
+            served_version: run.served_version,
+            timestamp: run.timestamp,
+            duration_ms: run.duration_ms,
+            outputs,
+            error,
+        }
+    }
+
     fn convert_details_to_csv(
         models: &[ModelResponse],
     ) -> Result<String, Box<dyn std::error::Error>> {
@@ -552,22 +1735,43 @@ pub(crate) mod models {
 
 pub(crate) mod inference {
     use crate::datafusion::DataFusion;
-    use crate::model::version as model_version;
-    use crate::model::Model;
+    use crate::model::{self, InferenceMode, InferenceOptions, Model};
+    use crate::modelversions::{ModelVersions, ShadowRun, SHADOW_RUN_MAX_ROWS};
+    use crate::querylimits::QueryLimits;
+    use crate::timing::now_millis;
     use app::App;
-    use arrow::array::Float32Array;
+    use arrow::array::{FixedSizeListArray, Float32Array};
+    use arrow::compute::concat_batches;
+    use arrow::datatypes::{Schema, SchemaRef};
+    use arrow::error::ArrowError;
+    use arrow::ipc::reader::StreamReader;
+    use arrow::record_batch::RecordBatch;
+    use arrow_json::reader::{infer_json_schema_from_iterator, ReaderBuilder};
     use axum::{
-        extract::{Path, Query},
-        http::StatusCode,
+        body::Bytes,
+        extract::{ConnectInfo, Path, Query},
+        http::{header, HeaderMap, StatusCode},
         response::{IntoResponse, Response},
         Extension, Json,
     };
+    use datafusion::error::DataFusionError;
+    use datafusion::physical_plan::{
+        memory::MemoryStream, stream::RecordBatchStreamAdapter, SendableRecordBatchStream,
+    };
+    use futures::future::{FutureExt, Shared};
+    use futures::{stream::BoxStream, Future, StreamExt, TryStreamExt};
     use serde::{Deserialize, Serialize};
+    use serde_json::Value;
+    use std::io::Cursor;
+    use std::net::SocketAddr;
     use std::time::Instant;
     use std::{collections::HashMap, sync::Arc};
-    use tokio::sync::RwLock;
+    use tokio::sync::{oneshot, RwLock};
     use tract_core::tract_data::itertools::Itertools;
 
+    use super::error::{ApiError, ErrorCode};
+    use super::query::{self, ResultFormat};
+
     #[derive(Deserialize)]
     pub struct BatchPredictRequest {
         #[serde(default)]
@@ -601,11 +1805,6 @@ pub(crate) mod inference {
 
     #[derive(Serialize)]
     pub struct PredictResponse {
-        pub status: PredictStatus,
-
-        #[serde(skip_serializing_if = "Option::is_none")]
-        pub error_message: Option<String>,
-
         pub model_name: String,
 
         #[serde(skip_serializing_if = "Option::is_none")]
@@ -619,61 +1818,44 @@ pub(crate) mod inference {
         pub duration_ms: u128,
     }
 
-    #[derive(Serialize)]
-    pub enum PredictStatus {
-        Success,
-        BadRequest,
-        InternalError,
-    }
-
     pub(crate) async fn get(
         Extension(app): Extension<Arc<RwLock<Option<App>>>>,
         Extension(df): Extension<Arc<RwLock<DataFusion>>>,
         Path(model_name): Path<String>,
         Query(params): Query<PredictParams>,
-        Extension(models): Extension<Arc<RwLock<HashMap<String, Model>>>>,
+        Extension(models): Extension<Arc<RwLock<HashMap<String, ModelVersions>>>>,
     ) -> Response {
-        let model_predict_response =
-            run_inference(app, df, models, model_name, params.lookback).await;
-
-        match model_predict_response.status {
-            PredictStatus::Success => {
+        match run_inference(app, df, models, model_name, params.lookback).await {
+            Ok(model_predict_response) => {
                 (StatusCode::OK, Json(model_predict_response)).into_response()
             }
-            PredictStatus::BadRequest => {
-                (StatusCode::BAD_REQUEST, Json(model_predict_response)).into_response()
-            }
-            PredictStatus::InternalError => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(model_predict_response),
-            )
-                .into_response(),
+            Err(e) => e.into_response(),
         }
     }
 
+    /// Runs each requested prediction, failing with the error of the first one that fails.
     pub(crate) async fn post(
         Extension(app): Extension<Arc<RwLock<Option<App>>>>,
         Extension(df): Extension<Arc<RwLock<DataFusion>>>,
-        Extension(models): Extension<Arc<RwLock<HashMap<String, Model>>>>,
+        Extension(models): Extension<Arc<RwLock<HashMap<String, ModelVersions>>>>,
         Json(payload): Json<BatchPredictRequest>,
     ) -> Response {
         let start_time = Instant::now();
         let mut model_predictions = Vec::new();
-        let mut model_prediction_futures = Vec::new();
 
         for model_predict_request in payload.predictions {
-            let prediction_future = run_inference(
+            match run_inference(
                 app.clone(),
                 df.clone(),
                 models.clone(),
                 model_predict_request.model_name,
                 model_predict_request.lookback,
-            );
-            model_prediction_futures.push(prediction_future);
-        }
-
-        for prediction_future in model_prediction_futures {
-            model_predictions.push(prediction_future.await);
+            )
+            .await
+            {
+                Ok(model_prediction) => model_predictions.push(model_prediction),
+                Err(e) => return e.into_response(),
+            }
         }
 
         (
@@ -689,111 +1871,442 @@ pub(crate) mod inference {
     async fn run_inference(
         app: Arc<RwLock<Option<App>>>,
         df: Arc<RwLock<DataFusion>>,
-        models: Arc<RwLock<HashMap<String, Model>>>,
+        models: Arc<RwLock<HashMap<String, ModelVersions>>>,
         model_name: String,
         lookback: usize,
-    ) -> PredictResponse {
+    ) -> Result<PredictResponse, ApiError> {
         let start_time = Instant::now();
 
         let app_lock = app.read().await;
         let Some(readable_app) = &*app_lock else {
-            return PredictResponse {
-                status: PredictStatus::BadRequest,
-                error_message: Some("App not found".to_string()),
-                model_name,
-                model_version: None,
-                lookback,
-                prediction: vec![],
-                duration_ms: start_time.elapsed().as_millis(),
-            };
+            return Err(ApiError::new(
+                ErrorCode::Unavailable,
+                "No spicepod is loaded",
+            ));
         };
 
-        let model = readable_app.models.iter().find(|m| m.name == model_name);
-        let Some(model) = model else {
+        let model_not_found = || {
             tracing::debug!("Model {model_name} not found");
-            return PredictResponse {
-                status: PredictStatus::BadRequest,
-                error_message: Some(format!("Model {model_name} not found")),
-                model_name,
-                model_version: None,
-                lookback,
-                prediction: vec![],
-                duration_ms: start_time.elapsed().as_millis(),
-            };
+            ApiError::new(ErrorCode::NotFound, format!("Model {model_name} not found"))
+        };
+        let Some(model) = readable_app.models.iter().find(|m| m.name == model_name) else {
+            return Err(model_not_found());
+        };
+        let Some((versions, runnable)) = route(&models, &model.name).await else {
+            return Err(model_not_found());
+        };
+        let version = runnable.version();
+
+        let input = runnable.read_input(&df).await.map_err(|e| match e {
+            model::Error::NoDataset { .. } => ApiError::new(ErrorCode::BadRequest, e.to_string()),
+            model::Error::UnableToQuery { source } => ApiError::from_datafusion(&source),
+            _ => {
+                tracing::error!("Unable to read the input of model {model_name}: {e}");
+                ApiError::new(ErrorCode::Internal, e.to_string())
+            }
+        })?;
+
+        // The shadow versions run on the same rows as the served version, so their outputs can be
+        // compared even while the dataset is written to.
+        spawn_shadow_runs(&versions, &runnable, |shadow| {
+            let input = input.clone();
+            async move { shadow.run(input, lookback).map_err(|e| e.to_string()) }
+        });
+
+        let inference_result = runnable.run(input, lookback).map_err(|e| {
+            tracing::error!("Unable to run inference: {e}");
+            ApiError::new(ErrorCode::Internal, e.to_string())
+        })?;
+        let Some(column_data) = inference_result.columns().first() else {
+            tracing::error!("Inference result for model {model_name} has no outputs");
+            return Err(ApiError::new(
+                ErrorCode::Internal,
+                "Inference result has no outputs",
+            ));
         };
 
-        let loaded_models = models.read().await;
-        let Some(runnable) = loaded_models.get(&model.name) else {
-            tracing::debug!("Model {model_name} not found");
-            return PredictResponse {
-                status: PredictStatus::BadRequest,
-                error_message: Some(format!("Model {model_name} not found")),
-                model_name,
-                model_version: Some(model_version(&model.from)),
-                lookback,
-                prediction: vec![],
-                duration_ms: start_time.elapsed().as_millis(),
+        // Outputs with several values per row, i.e. a forecast, are fixed size lists.
+        let values = match column_data.as_any().downcast_ref::<FixedSizeListArray>() {
+            Some(list) => list.values(),
+            None => column_data,
+        };
+        let Some(array) = values.as_any().downcast_ref::<Float32Array>() else {
+            tracing::error!(
+                "Failed to cast inference result for model {model_name} to Float32Array"
+            );
+            tracing::debug!("Failed to cast inference result for model {model_name} to Float32Array: {column_data:?}");
+            return Err(ApiError::new(
+                ErrorCode::Internal,
+                "Unable to cast inference result to Float32Array",
+            ));
+        };
+
+        Ok(PredictResponse {
+            model_version: Some(version.clone()),
+            lookback,
+            prediction: array.values().iter().copied().collect_vec(),
+            duration_ms: start_time.elapsed().as_millis(),
+            model_name,
+        })
+    }
+
+    #[derive(Clone, Deserialize)]
+    pub(crate) struct InferParams {
+        #[serde(default)]
+        pub mode: InferenceMode,
+
+        /// Comma separated input columns returned with each prediction.
+        pub keys: Option<String>,
+
+        /// Rows in each window. Defaults to the window of the model's input.
+        pub window: Option<usize>,
+
+        /// Rows between the starts of consecutive windows.
+        #[serde(default = "default_stride")]
+        pub stride: usize,
+
+        #[serde(default = "default_batch_size")]
+        pub batch_size: usize,
+
+        pub format: Option<ResultFormat>,
+
+        /// Overrides the runtime's `--query_timeout` for the `sql` input, i.e. `30s`.
+        pub timeout: Option<String>,
+    }
+
+    fn default_stride() -> usize {
+        1
+    }
+
+    fn default_batch_size() -> usize {
+        1024
+    }
+
+    /// The rows to run inference on, either as a query or inline. Rows can also be sent as an
+    /// Arrow IPC stream instead.
+    #[derive(Deserialize)]
+    pub struct InferRequest {
+        pub sql: Option<String>,
+        pub rows: Option<Vec<Value>>,
+    }
+
+    /// Runs a model over every row of a query or of the rows in the request, returning the
+    /// requested input columns with the predictions for them.
+    pub(crate) async fn infer(
+        Extension(df): Extension<Arc<RwLock<DataFusion>>>,
+        Extension(models): Extension<Arc<RwLock<HashMap<String, ModelVersions>>>>,
+        Extension(query_limits): Extension<Arc<QueryLimits>>,
+        ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+        Path(model_name): Path<String>,
+        Query(params): Query<InferParams>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Response {
+        let format = match query::result_format(params.format, &headers) {
+            Ok(format) => format,
+            Err(e) => return e.into_response(),
+        };
+
+        // The model is cloned so it can be reloaded while the results are streamed.
+        let Some((versions, model)) = route(&models, &model_name).await else {
+            return ApiError::new(ErrorCode::NotFound, format!("Model {model_name} not found"))
+                .into_response();
+        };
+
+        let input =
+            match infer_input(&df, &query_limits, client_addr, &params, &headers, body).await {
+                Ok(input) => input,
+                Err(e) => return e.into_response(),
             };
+
+        let options = InferenceOptions {
+            mode: params.mode,
+            keys: params
+                .keys
+                .as_deref()
+                .map(|keys| keys.split(',').map(|key| key.trim().to_string()).collect())
+                .unwrap_or_default(),
+            window: params.window,
+            stride: params.stride,
+            batch_size: params.batch_size,
         };
 
-        match runnable.run(df.clone(), lookback).await {
-            Ok(inference_result) => {
-                if let Some(column_data) = inference_result.column_by_name("y") {
-                    if let Some(array) = column_data.as_any().downcast_ref::<Float32Array>() {
-                        let result = array.values().iter().copied().collect_vec();
-                        return PredictResponse {
-                            status: PredictStatus::Success,
-                            error_message: None,
-                            model_name,
-                            model_version: Some(model_version(&model.from)),
-                            lookback,
-                            prediction: result,
-                            duration_ms: start_time.elapsed().as_millis(),
-                        };
-                    }
-                    tracing::error!(
-                        "Failed to cast inference result for model {model_name} to Float32Array"
-                    );
-                    tracing::debug!("Failed to cast inference result for model {model_name} to Float32Array: {column_data:?}");
-                    return PredictResponse {
-                        status: PredictStatus::InternalError,
-                        error_message: Some(
-                            "Unable to cast inference result to Float32Array".to_string(),
-                        ),
-                        model_name,
-                        model_version: Some(model_version(&model.from)),
-                        lookback,
-                        prediction: vec![],
-                        duration_ms: start_time.elapsed().as_millis(),
-                    };
+        // Shadow versions are run on the first rows of the input once the serving version has
+        // read them, so the input is only read once.
+        let input = if versions.shadows().next().is_some() {
+            let schema = input.schema();
+            let (input, shadow_input) = tee_shadow_input(input);
+            spawn_shadow_runs(&versions, &model, |shadow| {
+                let schema = Arc::clone(&schema);
+                let shadow_input = shadow_input.clone();
+                let options = options.clone();
+                async move {
+                    let batches = shadow_input
+                        .await
+                        .map_err(|_| "The input of the request wasn't read".to_string())?;
+                    let input = memory_stream(batches, schema).map_err(|e| e.message)?;
+                    first_outputs(shadow.infer(input, options)).await
                 }
-                tracing::error!(
-                    "Unable to find column 'y' in inference result for model {model_name}"
-                );
-                PredictResponse {
-                    status: PredictStatus::InternalError,
-                    error_message: Some(
-                        "Unable to find column 'y' in inference result".to_string(),
-                    ),
-                    model_name,
-                    model_version: Some(model_version(&model.from)),
-                    lookback,
-                    prediction: vec![],
-                    duration_ms: start_time.elapsed().as_millis(),
+            });
+            input
+        } else {
+            input
+        };
+
+        let mut predictions = model.infer(input, options);
+
+        // Errors before the first batch can still be reported with an error status.
+        let first_batch = match predictions.next().await {
+            Some(Ok(batch)) => Some(batch),
+            Some(Err(e)) => {
+                tracing::debug!("Error running inference with model {model_name}: {e}");
+                return inference_error(&e).into_response();
+            }
+            None => None,
+        };
+        let schema = first_batch
+            .as_ref()
+            .map_or_else(|| Arc::new(Schema::empty()), RecordBatch::schema);
+
+        if format.is_streamed() {
+            let predictions = RecordBatchStreamAdapter::new(
+                Arc::clone(&schema),
+                predictions.map_err(|e| DataFusionError::External(Box::new(e))),
+            );
+            return (
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, format.content_type())],
+                query::stream_results(format, schema, first_batch, Box::pin(predictions)),
+            )
+                .into_response();
+        }
+
+        let mut results: Vec<RecordBatch> = first_batch.into_iter().collect();
+        while let Some(batch) = predictions.next().await {
+            match batch {
+                Ok(batch) => results.push(batch),
+                Err(e) => {
+                    tracing::debug!("Error running inference with model {model_name}: {e}");
+                    return inference_error(&e).into_response();
                 }
             }
+        }
+
+        match query::write_results(format, &schema, &results) {
+            Ok(res) => (
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, format.content_type())],
+                res,
+            )
+                .into_response(),
             Err(e) => {
-                tracing::error!("Unable to run inference: {e}");
-                PredictResponse {
-                    status: PredictStatus::InternalError,
-                    error_message: Some(e.to_string()),
-                    model_name,
-                    model_version: Some(model_version(&model.from)),
-                    lookback,
-                    prediction: vec![],
-                    duration_ms: start_time.elapsed().as_millis(),
-                }
+                tracing::debug!("Error converting results to {format:?}: {e}");
+                ApiError::new(ErrorCode::Internal, e.to_string()).into_response()
+            }
+        }
+    }
+
+    /// Picks the version of a model serving a request, returning it with all the loaded versions.
+    async fn route(
+        models: &RwLock<HashMap<String, ModelVersions>>,
+        name: &str,
+    ) -> Option<(ModelVersions, Model)> {
+        let models = models.read().await;
+        let versions = models.get(name)?;
+        let model = versions.route()?.clone();
+        Some((versions.clone(), model))
+    }
+
+    /// Runs each shadow version of a model on a request served by `served`, in the background.
+    fn spawn_shadow_runs<F, Fut>(versions: &ModelVersions, served: &Model, run: F)
+    where
+        F: Fn(Model) -> Fut,
+        Fut: Future<Output = Result<RecordBatch, String>> + Send + 'static,
+    {
+        for shadow in versions.shadows() {
+            let versions = versions.clone();
+            let version = shadow.version();
+            let served_version = served.version();
+            let outputs = run(shadow.clone());
+            tokio::spawn(async move {
+                let start_time = Instant::now();
+                let result = outputs.await;
+                versions.record_shadow_run(ShadowRun {
+                    version,
+                    served_version,
+                    timestamp: now_millis(),
+                    duration_ms: u64::try_from(start_time.elapsed().as_millis())
+                        .unwrap_or(u64::MAX),
+                    result,
+                });
+            });
+        }
+    }
+
+    /// Keeps the first `SHADOW_RUN_MAX_ROWS` rows of the input read by the serving version of a
+    /// model, sending them to its shadow versions once they're read or the input is dropped.
+    struct ShadowInput {
+        batches: Vec<RecordBatch>,
+        rows: usize,
+        tx: Option<oneshot::Sender<Vec<RecordBatch>>>,
+    }
+
+    impl ShadowInput {
+        fn push(&mut self, batch: &RecordBatch) {
+            if self.tx.is_none() {
+                return;
+            }
+            let kept = batch.num_rows().min(SHADOW_RUN_MAX_ROWS - self.rows);
+            self.batches.push(batch.slice(0, kept));
+            self.rows += kept;
+            if self.rows == SHADOW_RUN_MAX_ROWS {
+                self.send();
+            }
+        }
+
+        fn send(&mut self) {
+            if let Some(tx) = self.tx.take() {
+                let _ = tx.send(std::mem::take(&mut self.batches));
+            }
+        }
+    }
+
+    impl Drop for ShadowInput {
+        fn drop(&mut self) {
+            self.send();
+        }
+    }
+
+    /// Tees the first rows of `input` to the shadow versions of a model.
+    fn tee_shadow_input(
+        input: SendableRecordBatchStream,
+    ) -> (
+        SendableRecordBatchStream,
+        Shared<oneshot::Receiver<Vec<RecordBatch>>>,
+    ) {
+        let (tx, rx) = oneshot::channel();
+        let mut shadow_input = ShadowInput {
+            batches: Vec::new(),
+            rows: 0,
+            tx: Some(tx),
+        };
+        let schema = input.schema();
+        let input = input.inspect(move |batch| {
+            if let Ok(batch) = batch {
+                shadow_input.push(batch);
+            }
+        });
+        (
+            Box::pin(RecordBatchStreamAdapter::new(schema, input)),
+            rx.shared(),
+        )
+    }
+
+    /// Runs `predictions` to completion, keeping the first rows.
+    async fn first_outputs(
+        mut predictions: BoxStream<'static, model::Result<RecordBatch>>,
+    ) -> Result<RecordBatch, String> {
+        let mut batches = Vec::new();
+        let mut num_rows = 0;
+        while let Some(batch) = predictions.next().await {
+            let batch = batch.map_err(|e| e.to_string())?;
+            if num_rows < SHADOW_RUN_MAX_ROWS {
+                num_rows += batch.num_rows();
+                batches.push(batch);
+            }
+        }
+
+        let schema = batches
+            .first()
+            .map_or_else(|| Arc::new(Schema::empty()), RecordBatch::schema);
+        concat_batches(&schema, &batches).map_err(|e| e.to_string())
+    }
+
+    /// Reads the rows to run inference on from an Arrow IPC stream, or from the query or rows of
+    /// an `InferRequest`.
+    async fn infer_input(
+        df: &Arc<RwLock<DataFusion>>,
+        query_limits: &QueryLimits,
+        client_addr: SocketAddr,
+        params: &InferParams,
+        headers: &HeaderMap,
+        body: Bytes,
+    ) -> Result<SendableRecordBatchStream, ApiError> {
+        let is_arrow = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.starts_with("application/vnd.apache.arrow"));
+        if is_arrow {
+            let reader = StreamReader::try_new(Cursor::new(body), None)
+                .map_err(|e| ApiError::new(ErrorCode::BadRequest, e.to_string()))?;
+            let schema = reader.schema();
+            let batches = reader
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| ApiError::new(ErrorCode::BadRequest, e.to_string()))?;
+            return memory_stream(batches, schema);
+        }
+
+        let request: InferRequest = serde_json::from_slice(&body)
+            .map_err(|e| ApiError::new(ErrorCode::BadRequest, e.to_string()))?;
+        match (request.sql, request.rows) {
+            (Some(sql), None) => {
+                let options =
+                    query::query_options(headers, client_addr, params.timeout.as_deref())?;
+                let mut running_query = query_limits
+                    .start(options)
+                    .await
+                    .map_err(|e| ApiError::from_query_limits(&e))?;
+                let data_frame = running_query
+                    .run(async { df.read().await.ctx.sql(&sql).await })
+                    .await
+                    .map_err(|e| ApiError::from_datafusion(&e))?;
+                let stream = running_query
+                    .run(async { df.read().await.execute_stream(data_frame).await })
+                    .await
+                    .map_err(|e| ApiError::from_datafusion(&e))?;
+                Ok(running_query.stream(stream))
+            }
+            (None, Some(rows)) => {
+                let batch = json_rows(&rows)
+                    .map_err(|e| ApiError::new(ErrorCode::BadRequest, e.to_string()))?;
+                memory_stream(vec![batch.clone()], batch.schema())
+            }
+            _ => Err(ApiError::new(
+                ErrorCode::BadRequest,
+                "Expected either `sql` or `rows`",
+            )),
+        }
+    }
+
+    fn json_rows(rows: &[Value]) -> Result<RecordBatch, ArrowError> {
+        let schema = Arc::new(infer_json_schema_from_iterator(
+            rows.iter().map(|row| Ok(row.clone())),
+        )?);
+        let mut decoder = ReaderBuilder::new(Arc::clone(&schema)).build_decoder()?;
+        decoder.serialize(rows)?;
+        Ok(decoder
+            .flush()?
+            .unwrap_or_else(|| RecordBatch::new_empty(schema)))
+    }
+
+    fn memory_stream(
+        batches: Vec<RecordBatch>,
+        schema: SchemaRef,
+    ) -> Result<SendableRecordBatchStream, ApiError> {
+        let stream = MemoryStream::try_new(batches, schema, None)
+            .map_err(|e| ApiError::new(ErrorCode::Internal, e.to_string()))?;
+        Ok(Box::pin(stream))
+    }
+
+    fn inference_error(e: &model::Error) -> ApiError {
+        match e {
+            model::Error::UnableToQuery { source } => ApiError::from_datafusion(source),
+            model::Error::MissingWindow {}
+            | model::Error::UnableToJoinPredictions { .. }
+            | model::Error::MismatchedPredictions { .. } => {
+                ApiError::new(ErrorCode::BadRequest, e.to_string())
             }
+            _ => ApiError::new(ErrorCode::Internal, e.to_string()),
         }
     }
 }
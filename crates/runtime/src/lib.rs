@@ -23,6 +23,8 @@ use std::{collections::HashMap, sync::Arc};
 use app::App;
 use config::Config;
 use model::Model;
+use modelsource::cache::{self, CacheOptions};
+use modelversions::ModelVersions;
 pub use notify::Error as NotifyError;
 use secrets::spicepod_secret_store_type;
 use snafu::prelude::*;
@@ -31,26 +33,43 @@ use spicepod::component::dataset::Mode;
 use spicepod::component::model::Model as SpicepodModel;
 use std::time::Duration;
 use tokio::time::sleep;
-use tokio::{signal, sync::RwLock};
-
-use crate::{dataconnector::DataConnector, datafusion::DataFusion};
+use tokio::{
+    signal,
+    sync::{mpsc, Mutex, RwLock},
+    task::JoinHandle,
+};
+
+use crate::{
+    dataconnector::DataConnector, datafusion::DataFusion, datapublisher::DataPublisher,
+    datasetchange::DatasetChange,
+};
 pub mod config;
 pub mod databackend;
 pub mod dataconnector;
 pub mod datafusion;
 pub mod datapublisher;
+pub mod datasetchange;
 pub mod dataupdate;
+pub mod explain;
 mod flight;
 mod http;
 pub mod model;
 pub mod modelformat;
+pub mod modelfunction;
 pub mod modelruntime;
 pub mod modelsource;
+pub mod modelstats;
+pub mod modelversions;
 mod opentelemetry;
 pub mod podswatcher;
+pub mod predictions;
+pub mod querylimits;
+pub mod refresh;
+pub mod resultcache;
 pub mod status;
 pub mod timing;
 pub(crate) mod tracers;
+pub mod wal;
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -105,11 +124,19 @@ pub struct Runtime {
     pub app: Arc<RwLock<Option<App>>>,
     pub config: config::Config,
     pub df: Arc<RwLock<DataFusion>>,
-    pub models: Arc<RwLock<HashMap<String, Model>>>,
+    pub models: Arc<RwLock<HashMap<String, ModelVersions>>>,
     pub pods_watcher: podswatcher::PodsWatcher,
     pub secrets_provider: Arc<RwLock<secrets::SecretsProvider>>,
 
     spaced_tracer: Arc<tracers::SpacedTracer>,
+    wal: Option<Arc<wal::WriteAheadLog>>,
+    dataset_changes: datasetchange::Sender,
+    dataset_changes_rx: Option<datasetchange::Receiver>,
+    /// Datasets changed through the runtime API without being persisted, which take precedence
+    /// over the spicepod files when they're reloaded. `None` for a deleted dataset.
+    api_datasets: Mutex<HashMap<String, Option<Dataset>>>,
+    channel_map: flight::channels::ChannelMap,
+    prediction_tasks: Mutex<HashMap<String, JoinHandle<()>>>,
 }
 
 impl Runtime {
@@ -121,6 +148,8 @@ impl Runtime {
         pods_watcher: podswatcher::PodsWatcher,
     ) -> Self {
         dataconnector::register_all().await;
+        let wal = open_write_ahead_log(&config);
+        let (dataset_changes, dataset_changes_rx) = mpsc::channel(100);
         Runtime {
             app,
             config,
@@ -129,6 +158,12 @@ impl Runtime {
             pods_watcher,
             secrets_provider: Arc::new(RwLock::new(secrets::SecretsProvider::new())),
             spaced_tracer: Arc::new(tracers::SpacedTracer::new(Duration::from_secs(15))),
+            wal,
+            dataset_changes,
+            dataset_changes_rx: Some(dataset_changes_rx),
+            api_datasets: Mutex::new(HashMap::new()),
+            channel_map: flight::channels::ChannelMap::default(),
+            prediction_tasks: Mutex::new(HashMap::new()),
         }
     }
 
@@ -166,6 +201,7 @@ impl Runtime {
         let spaced_tracer = Arc::clone(&self.spaced_tracer);
         let shared_secrets_provider: Arc<RwLock<secrets::SecretsProvider>> =
             Arc::clone(&self.secrets_provider);
+        let wal = self.wal.clone();
 
         let ds = ds.clone();
 
@@ -176,6 +212,10 @@ impl Runtime {
 
         tokio::spawn(async move {
             loop {
+                // Only accelerated datasets are loaded from their source.
+                if ds.acceleration.as_ref().is_some_and(|acc| acc.enabled) {
+                    status::start_dataset_load(&ds.name);
+                }
                 let secrets_provider = shared_secrets_provider.read().await;
 
                 if !verify_dependent_tables(&ds, &existing_tables, Arc::clone(&df)).await {
@@ -198,6 +238,7 @@ impl Runtime {
                         Ok(data_connector) => data_connector,
                         Err(err) => {
                             status::update_dataset(ds.name.clone(), status::ComponentStatus::Error);
+                            status::record_dataset_error(&ds.name, err.to_string());
                             metrics::counter!("datasets_load_error").increment(1);
                             warn_spaced!(
                                 spaced_tracer,
@@ -223,12 +264,14 @@ impl Runtime {
                     &source,
                     &ds,
                     Arc::clone(&shared_secrets_provider),
+                    wal.clone(),
                 )
                 .await
                 {
                     Ok(()) => (),
                     Err(err) => {
                         status::update_dataset(ds.name.clone(), status::ComponentStatus::Error);
+                        status::record_dataset_error(&ds.name, err.to_string());
                         metrics::counter!("datasets_load_error").increment(1);
                         warn_spaced!(
                             spaced_tracer,
@@ -258,6 +301,21 @@ impl Runtime {
     }
 
     pub async fn remove_dataset(&self, ds: &Dataset) {
+        self.unload_dataset(ds).await;
+
+        // The logged data would otherwise be replayed into a dataset added later with the same name.
+        if let Some(wal) = &self.wal {
+            if let Err(e) = wal.remove(&ds.name).await {
+                tracing::warn!(
+                    "Unable to remove write-ahead log of dataset {}: {e}",
+                    &ds.name
+                );
+            }
+        }
+    }
+
+    async fn unload_dataset(&self, ds: &Dataset) {
+        self.df.read().await.flush_backend(&ds.name).await;
         let mut df = self.df.write().await;
 
         if df.table_exists(&ds.name) {
@@ -267,6 +325,7 @@ impl Runtime {
             }
         }
 
+        status::remove_dataset_metadata(&ds.name);
         tracing::info!("Unloaded dataset: {}", &ds.name);
         let engine = ds.acceleration.as_ref().map_or_else(
             || "None".to_string(),
@@ -283,7 +342,7 @@ impl Runtime {
 
     pub async fn update_dataset(&self, ds: &Dataset, all_datasets: &[Dataset]) {
         status::update_dataset(ds.name.clone(), status::ComponentStatus::Refreshing);
-        self.remove_dataset(ds).await;
+        self.unload_dataset(ds).await;
         self.load_dataset(ds, all_datasets);
     }
 
@@ -314,6 +373,7 @@ impl Runtime {
         source: &str,
         ds: impl Borrow<Dataset>,
         secrets_provider: Arc<RwLock<secrets::SecretsProvider>>,
+        wal: Option<Arc<wal::WriteAheadLog>>,
     ) -> Result<()> {
         let ds = ds.borrow();
         let view_sql = ds.view_sql().context(InvalidSQLViewSnafu)?;
@@ -344,10 +404,18 @@ impl Runtime {
         let data_backend = df
             .read()
             .await
-            .new_accelerated_backend(ds, secrets_provider)
+            .new_accelerated_backend(ds, secrets_provider, wal.clone())
             .await
             .context(UnableToCreateBackendSnafu)?;
         let data_backend = Arc::new(data_backend);
+        // The logged data is replayed before the dataset is published, so no write can be applied
+        // ahead of the writes it follows.
+        if let Some(wal) = wal.filter(|_| wal::WriteAheadLog::should_log(ds)) {
+            replay_write_ahead_log(&wal, ds, data_backend.as_ref().as_ref()).await;
+        }
+        df.write()
+            .await
+            .attach_backend(&ds.name, Arc::clone(&data_backend));
 
         if data_backend_publishing_enabled {
             df.write()
@@ -400,40 +468,62 @@ impl Runtime {
         if let Some(app) = app_lock.as_ref() {
             for model in &app.models {
                 status::update_model(model.name.clone(), status::ComponentStatus::Initializing);
-                self.load_model(model).await;
+                self.load_model(model, &app.models).await;
             }
         }
     }
 
     // Caller must set `status::update_model(...` before calling `load_model`. This function will set error/ready statues appropriately.`
-    pub async fn load_model(&self, m: &SpicepodModel) {
+    pub async fn load_model(&self, m: &SpicepodModel, all_models: &[SpicepodModel]) {
         measure_scope_ms!("load_model", "model" => m.name, "source" => model::source(&m.from));
-        tracing::info!("Loading model [{}] from {}...", m.name, m.from);
-        let mut model_map = self.models.write().await;
+        let version = model::version_of(m);
+        if let Err(e) = model::validate_version(m, all_models) {
+            metrics::counter!("models_load_error").increment(1);
+            status::update_model(m.name.clone(), status::ComponentStatus::Error);
+            status::record_model_error(&m.name, e.to_string());
+            tracing::warn!("Unable to load model {}: {e}", m.name);
+            return;
+        }
 
-        let model = m.clone();
-        let source = model::source(&model.from);
+        tracing::info!(
+            "Loading model [{}] version {version} from {}...",
+            m.name,
+            m.from
+        );
 
-        let shared_secrets_provider = Arc::clone(&self.secrets_provider);
-        let secrets_provider = shared_secrets_provider.read().await;
+        let source = model::source(&m.from);
+        let secret = {
+            let secrets_provider = self.secrets_provider.read().await;
+            secrets_provider.get_secret(source.as_str()).await
+        };
 
-        match Model::load(
-            m.clone(),
-            secrets_provider.get_secret(source.as_str()).await,
-        )
-        .await
-        {
+        // Loading can take a while, so the loaded versions keep serving until it completes.
+        match Model::load(m.clone(), secret, CacheOptions::from(&self.config)).await {
             Ok(in_m) => {
-                model_map.insert(m.name.clone(), in_m);
-                tracing::info!("Model [{}] deployed, ready for inferencing", m.name);
-                metrics::gauge!("models_count", "model" => m.name.clone(), "source" => model::source(&m.from)).increment(1.0);
-                status::update_model(model.name.clone(), status::ComponentStatus::Ready);
+                let replaced = self
+                    .models
+                    .write()
+                    .await
+                    .entry(m.name.clone())
+                    .or_default()
+                    .insert(in_m);
+                self.update_model_components(&m.name).await;
+                tracing::info!(
+                    "Model [{}] version {version} deployed, ready for inferencing",
+                    m.name
+                );
+                if replaced.is_none() {
+                    metrics::gauge!("models_count", "model" => m.name.clone(), "source" => source)
+                        .increment(1.0);
+                }
+                status::update_model(m.name.clone(), status::ComponentStatus::Ready);
             }
             Err(e) => {
                 metrics::counter!("models_load_error").increment(1);
-                status::update_model(model.name.clone(), status::ComponentStatus::Error);
+                status::update_model(m.name.clone(), status::ComponentStatus::Error);
+                status::record_model_error(&m.name, e.to_string());
                 tracing::warn!(
-                    "Unable to load runnable model from spicepod {}, error: {}",
+                    "Unable to load runnable model from spicepod {} version {version}, error: {}",
                     m.name,
                     e,
                 );
@@ -442,26 +532,73 @@ impl Runtime {
     }
 
     pub async fn remove_model(&self, m: &SpicepodModel) {
-        let mut model_map = self.models.write().await;
-        if !model_map.contains_key(&m.name) {
-            tracing::warn!(
-                "Unable to unload runnable model {}: model not found",
-                m.name,
-            );
-            return;
+        let version = model::version_of(m);
+        {
+            let mut model_map = self.models.write().await;
+            let Some(versions) = model_map.get_mut(&m.name) else {
+                tracing::warn!(
+                    "Unable to unload runnable model {}: model not found",
+                    m.name,
+                );
+                return;
+            };
+            if versions.remove(&version).is_none() {
+                tracing::warn!(
+                    "Unable to unload runnable model {} version {version}: version not found",
+                    m.name,
+                );
+                return;
+            }
+            if versions.is_empty() {
+                model_map.remove(&m.name);
+                status::remove_model_metadata(&m.name);
+            }
         }
-        model_map.remove(&m.name);
-        tracing::info!("Model [{}] has been unloaded", m.name);
+        cache::release(&m.name, &version);
+        self.update_model_components(&m.name).await;
+        tracing::info!("Model [{}] version {version} has been unloaded", m.name);
         metrics::gauge!("models_count", "model" => m.name.clone(), "source" => model::source(&m.from)).decrement(1.0);
     }
 
-    pub async fn update_model(&self, m: &SpicepodModel) {
+    /// Reloads `m`, serving requests with the loaded version until the new one replaces it.
+    pub async fn update_model(&self, m: &SpicepodModel, all_models: &[SpicepodModel]) {
         status::update_model(m.name.clone(), status::ComponentStatus::Refreshing);
-        self.remove_model(m).await;
-        self.load_model(m).await;
+        self.load_model(m, all_models).await;
+    }
+
+    /// Points the SQL functions and scheduled predictions of model `name` at its primary version.
+    async fn update_model_components(&self, name: &str) {
+        let primary = self
+            .models
+            .read()
+            .await
+            .get(name)
+            .and_then(|versions| versions.primary().cloned());
+
+        let mut prediction_tasks = self.prediction_tasks.lock().await;
+        if let Some(task) = prediction_tasks.remove(name) {
+            task.abort();
+        }
+
+        let df = self.df.read().await;
+        let Some(primary) = primary else {
+            df.deregister_model(name);
+            return;
+        };
+        df.register_model(&primary);
+        if let Some(task) = predictions::spawn(
+            &primary,
+            Arc::clone(&self.df),
+            Arc::clone(&self.channel_map),
+            self.wal.clone(),
+        ) {
+            prediction_tasks.insert(name.to_string(), task);
+        }
     }
 
     pub async fn start_servers(&mut self, with_metrics: Option<SocketAddr>) -> Result<()> {
+        let query_limits = Arc::new(querylimits::QueryLimits::new(&self.config));
+
         let http_server_future = http::start(
             self.config.http_bind_address,
             self.app.clone(),
@@ -469,11 +606,24 @@ impl Runtime {
             self.models.clone(),
             self.config.clone().into(),
             with_metrics,
+            self.dataset_changes.clone(),
+            Arc::clone(&query_limits),
         );
 
-        let flight_server_future = flight::start(self.config.flight_bind_address, self.df.clone());
-        let open_telemetry_server_future =
-            opentelemetry::start(self.config.open_telemetry_bind_address, self.df.clone());
+        let flight_server_future = flight::start(
+            self.config.flight_bind_address,
+            self.df.clone(),
+            self.wal.clone(),
+            query_limits,
+            Arc::clone(&self.channel_map),
+        );
+        let open_telemetry_server_future = opentelemetry::start(
+            self.config.open_telemetry_bind_address,
+            self.df.clone(),
+            self.wal.clone(),
+            Arc::clone(&self.channel_map),
+        );
+        let df = Arc::clone(&self.df);
         let pods_watcher_future = self.start_pods_watcher();
 
         tokio::select! {
@@ -482,6 +632,7 @@ impl Runtime {
             open_telemetry_res = open_telemetry_server_future => open_telemetry_res.context(UnableToStartOpenTelemetryServerSnafu),
             pods_watcher_res = pods_watcher_future => pods_watcher_res.context(UnableToInitializePodsWatcherSnafu),
             () = shutdown_signal() => {
+                df.read().await.flush_backends().await;
                 tracing::info!("Goodbye!");
                 Ok(())
             },
@@ -490,71 +641,159 @@ impl Runtime {
 
     pub async fn start_pods_watcher(&mut self) -> notify::Result<()> {
         let mut rx = self.pods_watcher.watch()?;
-
-        while let Some(new_app) = rx.recv().await {
-            let mut app_lock = self.app.write().await;
-            if let Some(current_app) = app_lock.as_mut() {
-                if *current_app == new_app {
-                    continue;
+        let mut dataset_changes_rx = self
+            .dataset_changes_rx
+            .take()
+            .unwrap_or_else(|| mpsc::channel(1).1);
+
+        loop {
+            tokio::select! {
+                Some(new_app) = rx.recv() => self.apply_app_update(new_app).await,
+                Some(request) = dataset_changes_rx.recv() => {
+                    let result = self
+                        .apply_dataset_change(request.change, request.persist)
+                        .await;
+                    let _ = request.respond_to.send(result);
                 }
+                else => break,
+            }
+        }
 
-                tracing::debug!("Updated pods information: {:?}", new_app);
-                tracing::debug!("Previous pods information: {:?}", current_app);
+        Ok(())
+    }
 
-                // check for new and updated datasets
-                for ds in &new_app.datasets {
-                    if let Some(current_ds) =
-                        current_app.datasets.iter().find(|d| d.name == ds.name)
-                    {
-                        if current_ds != ds {
-                            self.update_dataset(ds, &new_app.datasets).await;
-                        }
-                    } else {
-                        status::update_dataset(
-                            ds.name.clone(),
-                            status::ComponentStatus::Initializing,
-                        );
-                        self.load_dataset(ds, &new_app.datasets);
+    async fn apply_app_update(&self, mut new_app: App) {
+        for (name, ds) in self.api_datasets.lock().await.iter() {
+            new_app.datasets.retain(|d| d.name != *name);
+            if let Some(ds) = ds {
+                new_app.datasets.push(ds.clone());
+            }
+        }
+
+        let mut app_lock = self.app.write().await;
+        if let Some(current_app) = app_lock.as_mut() {
+            if *current_app == new_app {
+                return;
+            }
+
+            tracing::debug!("Updated pods information: {:?}", new_app);
+            tracing::debug!("Previous pods information: {:?}", current_app);
+
+            // check for new and updated datasets
+            for ds in &new_app.datasets {
+                if let Some(current_ds) = current_app.datasets.iter().find(|d| d.name == ds.name) {
+                    if current_ds != ds {
+                        self.update_dataset(ds, &new_app.datasets).await;
                     }
+                } else {
+                    status::update_dataset(ds.name.clone(), status::ComponentStatus::Initializing);
+                    self.load_dataset(ds, &new_app.datasets);
                 }
+            }
 
-                // check for new and updated models
-                for model in &new_app.models {
-                    if let Some(current_model) =
-                        current_app.models.iter().find(|m| m.name == model.name)
-                    {
-                        if current_model != model {
-                            self.update_model(model).await;
-                        }
-                    } else {
-                        status::update_model(
-                            model.name.clone(),
-                            status::ComponentStatus::Initializing,
-                        );
-                        self.load_model(model).await;
+            // check for new and updated models, where each version of a model is loaded separately
+            for model in &new_app.models {
+                if let Some(current_model) = current_app
+                    .models
+                    .iter()
+                    .find(|m| same_model_version(m, model))
+                {
+                    if current_model != model {
+                        self.update_model(model, &new_app.models).await;
                     }
+                } else {
+                    status::update_model(model.name.clone(), status::ComponentStatus::Initializing);
+                    self.load_model(model, &new_app.models).await;
                 }
+            }
 
-                // Remove models that are no longer in the app
-                for model in &current_app.models {
+            // Remove models that are no longer in the app
+            for model in &current_app.models {
+                if !new_app.models.iter().any(|m| same_model_version(m, model)) {
                     if !new_app.models.iter().any(|m| m.name == model.name) {
                         status::update_model(model.name.clone(), status::ComponentStatus::Disabled);
-                        self.remove_model(model).await;
                     }
+                    self.remove_model(model).await;
                 }
+            }
 
-                // Remove datasets that are no longer in the app
-                for ds in &current_app.datasets {
-                    if !new_app.datasets.iter().any(|d| d.name == ds.name) {
-                        status::update_dataset(ds.name.clone(), status::ComponentStatus::Disabled);
-                        self.remove_dataset(ds).await;
-                    }
+            // Remove datasets that are no longer in the app
+            for ds in &current_app.datasets {
+                if !new_app.datasets.iter().any(|d| d.name == ds.name) {
+                    status::update_dataset(ds.name.clone(), status::ComponentStatus::Disabled);
+                    self.remove_dataset(ds).await;
                 }
+            }
+
+            *current_app = new_app;
+        } else {
+            *app_lock = Some(new_app);
+        }
+    }
 
-                *current_app = new_app;
-            } else {
-                *app_lock = Some(new_app);
+    /// Applies a dataset change requested through the runtime API, optionally writing it to the
+    /// root spicepod file. Persisted changes are written first, so a change that can't be written
+    /// isn't applied. The pods watcher only reloads the file once the change is applied, so it sees
+    /// no difference.
+    async fn apply_dataset_change(
+        &self,
+        change: DatasetChange,
+        persist: bool,
+    ) -> datasetchange::Result<()> {
+        let mut app_lock = self.app.write().await;
+        let Some(app) = app_lock.as_mut() else {
+            return Err(datasetchange::Error::NoSpicepodLoaded);
+        };
+
+        let name = change.name().to_string();
+        let position = app.datasets.iter().position(|ds| ds.name == name);
+        match (&change, position) {
+            (DatasetChange::Create(_), Some(_)) => {
+                return Err(datasetchange::Error::DatasetAlreadyExists { name });
+            }
+            (DatasetChange::Update(_) | DatasetChange::Delete(_), None) => {
+                return Err(datasetchange::Error::DatasetNotFound { name });
             }
+            _ => (),
+        }
+
+        if let DatasetChange::Create(ds) | DatasetChange::Update(ds) = &change {
+            datasetchange::validate(ds, &*self.secrets_provider.read().await).await?;
+        }
+
+        if persist {
+            datasetchange::PersistedSpicepod::prepare(self.pods_watcher.root_path(), &change)?
+                .write()?;
+        }
+
+        match (change, position) {
+            (DatasetChange::Create(ds), _) => {
+                app.datasets.push(ds.clone());
+                status::update_dataset(ds.name.clone(), status::ComponentStatus::Initializing);
+                self.load_dataset(&ds, &app.datasets);
+            }
+            (DatasetChange::Update(ds), Some(position)) => {
+                if app.datasets[position] != ds {
+                    app.datasets[position] = ds.clone();
+                    self.update_dataset(&ds, &app.datasets).await;
+                }
+            }
+            (DatasetChange::Delete(_), Some(position)) => {
+                let ds = app.datasets.remove(position);
+                status::update_dataset(ds.name.clone(), status::ComponentStatus::Disabled);
+                self.remove_dataset(&ds).await;
+            }
+            _ => (),
+        }
+
+        let mut api_datasets = self.api_datasets.lock().await;
+        if persist {
+            api_datasets.remove(&name);
+        } else {
+            api_datasets.insert(
+                name.clone(),
+                app.datasets.iter().find(|ds| ds.name == name).cloned(),
+            );
         }
 
         Ok(())
@@ -596,6 +835,72 @@ async fn verify_dependent_tables(
     true
 }
 
+fn open_write_ahead_log(config: &Config) -> Option<Arc<wal::WriteAheadLog>> {
+    let wal_dir = config.wal_dir.as_ref()?;
+
+    let wal_config = wal::Config {
+        sync_policy: config.wal_fsync,
+        segment_size: config.wal_segment_size,
+        compact_after: config.wal_compact_after,
+    };
+
+    match wal::WriteAheadLog::open(wal_dir, wal_config) {
+        Ok(wal) => {
+            tracing::info!("Write-ahead log enabled in {}", wal_dir.display());
+            Some(Arc::new(wal))
+        }
+        Err(e) => {
+            tracing::error!("Unable to open write-ahead log, data received through Flight and OpenTelemetry will not be persisted: {e}");
+            None
+        }
+    }
+}
+
+/// Replays the data logged for `ds` into its backend, i.e. the data received after the backend's
+/// last checkpoint and before the last restart.
+async fn replay_write_ahead_log(
+    wal: &wal::WriteAheadLog,
+    ds: &Dataset,
+    data_backend: &dyn DataPublisher,
+) {
+    // Keeps a snapshot from checkpointing the log before all of it is replayed.
+    let _writes = wal.lock_writes(&ds.name).await;
+    let data_updates = match wal.replay(&ds.name).await {
+        Ok(data_updates) => data_updates,
+        Err(e) => {
+            tracing::error!(
+                "Unable to replay write-ahead log for dataset {}: {e}",
+                &ds.name
+            );
+            return;
+        }
+    };
+
+    if data_updates.is_empty() {
+        return;
+    }
+
+    let data_updates_count = data_updates.len();
+    let dataset = Arc::new(ds.clone());
+    for data_update in data_updates {
+        if let Err(e) = data_backend
+            .add_data(Arc::clone(&dataset), data_update)
+            .await
+        {
+            tracing::error!(
+                "Unable to replay write-ahead log for dataset {} into {}: {e}",
+                &ds.name,
+                data_backend.name()
+            );
+        }
+    }
+
+    tracing::info!(
+        "Replayed {data_updates_count} updates from the write-ahead log for dataset {}",
+        &ds.name
+    );
+}
+
 fn has_table_provider(data_connector: &Option<Box<dyn DataConnector>>) -> bool {
     data_connector.is_some()
         && data_connector
@@ -603,6 +908,10 @@ fn has_table_provider(data_connector: &Option<Box<dyn DataConnector>>) -> bool {
             .is_some_and(|dc| dc.has_table_provider())
 }
 
+fn same_model_version(a: &SpicepodModel, b: &SpicepodModel) -> bool {
+    a.name == b.name && model::version_of(a) == model::version_of(b)
+}
+
 async fn shutdown_signal() {
     let ctrl_c = async {
         let signal_result = signal::ctrl_c().await;
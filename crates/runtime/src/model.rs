@@ -14,19 +14,62 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
+use crate::modelformat::ModelFormat;
 use crate::modelruntime::ModelRuntime;
-use crate::modelruntime::Runnable;
+use crate::modelruntime::{Runnable, TensorInfo};
+use crate::modelsource::cache::{self, CacheOptions};
 use crate::modelsource::create_source_from;
+use crate::modelstats::{InferenceStats, InferenceStatsSnapshot, MeasuredRunnable};
+use crate::timing::now_millis;
 use crate::DataFusion;
+use arrow::compute::concat_batches;
+use arrow::datatypes::{Schema, SchemaRef};
 use arrow::record_batch::RecordBatch;
+use async_stream::try_stream;
+use datafusion::execution::SendableRecordBatchStream;
+use datafusion::sql::TableReference;
+use futures::{stream::BoxStream, StreamExt};
 use secrets::Secret;
+use serde::Deserialize;
 use snafu::prelude::*;
+use spicepod::component::model::input::ModelInput;
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::RwLock;
+use tract_core::tract_data::itertools::Itertools;
 
+#[derive(Clone)]
 pub struct Model {
-    runnable: Box<dyn Runnable>,
+    runnable: Arc<dyn Runnable>,
     pub model: spicepod::component::model::Model,
+    stats: Arc<InferenceStats>,
+    /// Milliseconds since the Unix epoch at which the model finished loading.
+    loaded_at: u64,
+    load_duration_ms: u64,
+}
+
+/// How `Model::infer` feeds the rows of its input to the model.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InferenceMode {
+    /// Every row is a sample, and the model is run over up to `batch_size` rows at a time.
+    #[default]
+    Row,
+    /// Every window of consecutive rows is a sample, i.e. to forecast a time series.
+    Window,
+}
+
+#[derive(Debug, Clone)]
+pub struct InferenceOptions {
+    pub mode: InferenceMode,
+    /// Input columns returned with each prediction.
+    pub keys: Vec<String>,
+    /// Rows in each window. Defaults to the window of the model's first input.
+    pub window: Option<usize>,
+    /// Rows between the starts of consecutive windows.
+    pub stride: usize,
+    pub batch_size: usize,
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -38,9 +81,15 @@ pub enum Error {
     #[snafu(display("Unable to load model from path: {source}"))]
     UnableToLoadModel { source: crate::modelsource::Error },
 
+    #[snafu(display("Unable to detect the format of model {path}, set `format` on the model"))]
+    UnknownModelFormat { path: String },
+
     #[snafu(display("Unable to init model: {source}"))]
     UnableToInitModel { source: crate::modelruntime::Error },
 
+    #[snafu(display("Model {name} has no dataset to read its input from"))]
+    NoDataset { name: String },
+
     #[snafu(display("Unable to query: {source}"))]
     UnableToQuery {
         source: datafusion::error::DataFusionError,
@@ -49,79 +98,499 @@ pub enum Error {
     #[snafu(display("Unable to run model: {source}"))]
     UnableToRunModel { source: crate::modelruntime::Error },
 
+    #[snafu(display("The model failed a dry run with zeroed inputs: {source}"))]
+    DryRunFailed { source: crate::modelruntime::Error },
+
+    #[snafu(display(
+        "Model {name} has more than one version downloaded by URL, so each needs a `version`"
+    ))]
+    MissingUrlVersion { name: String },
+
+    #[snafu(display(
+        "Model {name} revision {revision} isn't in the model cache, so can't be loaded offline"
+    ))]
+    ModelNotCached { name: String, revision: String },
+
     #[snafu(display("Unable to load required secrets"))]
     UnableToLoadRequiredSecrets {},
+
+    #[snafu(display("A window size is required to run inference over windows"))]
+    MissingWindow {},
+
+    #[snafu(display("Unable to join predictions with their inputs: {source}"))]
+    UnableToJoinPredictions { source: arrow::error::ArrowError },
+
+    #[snafu(display("The model returned {predictions} predictions for {samples} samples"))]
+    MismatchedPredictions { samples: usize, predictions: usize },
 }
 
 impl Model {
+    /// Pulls the model from its source, or from the model cache if the source is unreachable or
+    /// `cache_options` is offline, and loads it.
     pub async fn load(
         model: spicepod::component::model::Model,
         secret: Option<Secret>,
+        cache_options: CacheOptions,
     ) -> Result<Self> {
-        let source = source(&model.from);
-        let source = source.as_str();
-
-        let Some(secret) = secret else {
-            tracing::warn!(
-                "Unable to load model {}: unable to get secret for source {}",
-                model.name,
-                source
-            );
-            return UnableToLoadRequiredSecretsSnafu {}.fail();
+        let started = Instant::now();
+        let path = pull_cached(&model, secret, cache_options).await?;
+        let Some(format) = model
+            .format
+            .map(ModelFormat::from)
+            .or_else(|| ModelFormat::from_path(&path))
+        else {
+            return UnknownModelFormatSnafu { path }.fail();
         };
 
-        let mut params = std::collections::HashMap::new();
-        params.insert("name".to_string(), model.name.to_string());
-        params.insert("path".to_string(), path(&model.from));
-        params.insert("from".to_string(), path(&model.from));
-        params.insert("files".to_string(), model.files.join(",").to_string());
-
         let tract = crate::modelruntime::tract::Tract {
-            path: create_source_from(source)
-                .context(UnknownModelSourceSnafu)?
-                .pull(secret, Arc::new(Option::from(params)))
-                .await
-                .context(UnableToLoadModelSnafu)?
-                .clone()
-                .to_string(),
+            path,
+            inputs: inputs(&model),
+            outputs: model.outputs.clone(),
         }
-        .load()
+        .load(format)
         .context(UnableToInitModelSnafu {})?;
+        // Models that can't run aren't ready, so fail to load instead of on their first request.
+        tract.dry_run().context(DryRunFailedSnafu)?;
 
+        let stats = Arc::new(InferenceStats::default());
         Ok(Self {
-            runnable: tract,
+            runnable: Arc::new(MeasuredRunnable {
+                runnable: tract,
+                stats: Arc::clone(&stats),
+            }),
             model: model.clone(),
+            stats,
+            loaded_at: now_millis(),
+            load_duration_ms: u64::try_from(started.elapsed().as_millis()).unwrap_or(u64::MAX),
         })
     }
 
-    pub async fn run(
-        &self,
-        df: Arc<RwLock<DataFusion>>,
-        lookback_size: usize,
-    ) -> Result<RecordBatch> {
-        let data = df
-            .read()
+    /// Reads the input of the model from its dataset, ordered by the input's `order_by` column.
+    pub async fn read_input(&self, df: &RwLock<DataFusion>) -> Result<Vec<RecordBatch>> {
+        let Some(dataset) = self.model.datasets.first() else {
+            return NoDatasetSnafu {
+                name: self.model.name.clone(),
+            }
+            .fail();
+        };
+
+        df.read()
             .await
             .ctx
-            .sql(
-                &(format!(
-                    "select * from datafusion.public.{} order by ts asc",
-                    self.model.datasets[0]
-                )),
-            )
+            .sql(&input_query(&inputs(&self.model), dataset))
             .await
             .context(UnableToQuerySnafu {})?
             .collect()
             .await
-            .context(UnableToQuerySnafu {})?;
+            .context(UnableToQuerySnafu {})
+    }
 
-        let result = self
-            .runnable
+    /// Runs the model on the latest `lookback_size` rows of `data`, as read by `read_input`.
+    pub fn run(&self, data: Vec<RecordBatch>, lookback_size: usize) -> Result<RecordBatch> {
+        self.runnable
             .run(data, lookback_size)
-            .context(UnableToRunModelSnafu {})?;
+            .context(UnableToRunModelSnafu {})
+    }
+
+    /// Identifies this version among the loaded models with the same name.
+    #[must_use]
+    pub fn version(&self) -> String {
+        version_of(&self.model)
+    }
+
+    /// The input tensors of the model's graph.
+    #[must_use]
+    pub fn input_tensors(&self) -> Vec<TensorInfo> {
+        self.runnable.inputs()
+    }
+
+    /// The output tensors of the model's graph returned by inference.
+    #[must_use]
+    pub fn output_tensors(&self) -> Vec<TensorInfo> {
+        self.runnable.outputs()
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> InferenceStatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// Milliseconds since the Unix epoch at which the model finished loading.
+    #[must_use]
+    pub fn loaded_at(&self) -> u64 {
+        self.loaded_at
+    }
+
+    /// How long pulling and loading the model took.
+    #[must_use]
+    pub fn load_duration_ms(&self) -> u64 {
+        self.load_duration_ms
+    }
+
+    /// The schema of the model's predictions. Models are dry run when they're loaded, which settles
+    /// the types of outputs with symbolic dimensions, except for models with string inputs, whose
+    /// outputs are `DataType::Null` until they first run.
+    #[must_use]
+    pub fn output_schema(&self) -> SchemaRef {
+        self.runnable.output_schema()
+    }
+
+    /// The columns the model reads, in the order they're passed to it as arguments, or `None` if
+    /// it reads every column of its input.
+    #[must_use]
+    pub fn input_columns(&self) -> Option<Vec<String>> {
+        let inputs = inputs(&self.model);
+        if inputs.iter().any(|input| input.columns.is_empty()) {
+            return None;
+        }
+
+        Some(
+            inputs
+                .iter()
+                .flat_map(|input| &input.columns)
+                .unique()
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// The column the model's input rows are ordered by, i.e. the timestamp of a time series.
+    #[must_use]
+    pub fn order_by(&self) -> Option<String> {
+        inputs(&self.model)
+            .first()
+            .and_then(|input| input.order_by.clone())
+    }
+
+    /// Rows in each sample of a model run over windows of rows.
+    #[must_use]
+    pub fn window(&self) -> Option<usize> {
+        self.model.inputs.first().and_then(|input| input.window)
+    }
+
+    /// Selects the columns the model reads from `dataset`, in the order they're windowed.
+    #[must_use]
+    pub fn input_query(&self, dataset: &str) -> String {
+        input_query(&inputs(&self.model), dataset)
+    }
+
+    /// Runs the model with each row of `rows` as a sample, returning one prediction per row.
+    pub fn predict_rows(&self, rows: &RecordBatch) -> Result<RecordBatch> {
+        Samples {
+            mode: InferenceMode::Row,
+            rows: rows.num_rows(),
+            stride: rows.num_rows(),
+        }
+        .predict_sample(self.runnable.as_ref(), rows, &[])
+    }
+
+    /// Runs the model over every row of `input`, returning the `keys` columns of each sample's last
+    /// row joined with the model's predictions for it.
+    #[must_use]
+    pub fn infer(
+        &self,
+        mut input: SendableRecordBatchStream,
+        options: InferenceOptions,
+    ) -> BoxStream<'static, Result<RecordBatch>> {
+        let runnable = Arc::clone(&self.runnable);
+        let window = options.window.or_else(|| self.window());
+
+        Box::pin(try_stream! {
+            let samples = match options.mode {
+                InferenceMode::Row => Samples {
+                    mode: InferenceMode::Row,
+                    rows: window.unwrap_or(options.batch_size).max(1),
+                    stride: window.unwrap_or(options.batch_size).max(1),
+                },
+                InferenceMode::Window => Samples {
+                    mode: InferenceMode::Window,
+                    rows: window.context(MissingWindowSnafu)?.max(1),
+                    stride: options.stride.max(1),
+                },
+            };
+
+            let mut buffered: Option<RecordBatch> = None;
+            let mut keys: Option<Vec<usize>> = None;
+            loop {
+                let batch = match input.next().await {
+                    Some(batch) => Some(batch.context(UnableToQuerySnafu)?),
+                    None => None,
+                };
+                let finished = batch.is_none();
+                let rows = match (buffered.take(), batch) {
+                    (Some(buffered), Some(batch)) => {
+                        concat_batches(&batch.schema(), [&buffered, &batch])
+                            .context(UnableToJoinPredictionsSnafu)?
+                    }
+                    (None, Some(rows)) | (Some(rows), None) => rows,
+                    (None, None) => break,
+                };
+
+                if keys.is_none() {
+                    let schema = rows.schema();
+                    let indices = options
+                        .keys
+                        .iter()
+                        .map(|key| schema.index_of(key))
+                        .collect::<std::result::Result<Vec<_>, _>>()
+                        .context(UnableToJoinPredictionsSnafu)?;
+                    keys = Some(indices);
+                }
+                let key_columns = keys.clone().unwrap_or_default();
+
+                // Models are CPU bound, so they're run outside of the async runtime.
+                let runnable = Arc::clone(&runnable);
+                let (predictions, remaining) = tokio::task::spawn_blocking(move || {
+                    samples.predict(runnable.as_ref(), rows, &key_columns, finished)
+                })
+                .await
+                .map_err(|e| Error::UnableToRunModel { source: Box::new(e) })??;
+
+                if let Some(first) = predictions.first() {
+                    yield concat_batches(&first.schema(), &predictions)
+                        .context(UnableToJoinPredictionsSnafu)?;
+                }
+                if finished {
+                    break;
+                }
+                buffered = remaining;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+impl Model {
+    /// A model running `runnable`, as if it had been loaded for `model`.
+    pub(crate) fn from_runnable(
+        model: spicepod::component::model::Model,
+        runnable: Arc<dyn Runnable>,
+    ) -> Self {
+        Self {
+            runnable,
+            model,
+            stats: Arc::new(InferenceStats::default()),
+            loaded_at: 0,
+            load_duration_ms: 0,
+        }
+    }
+}
+
+/// How the rows of the input are split into the samples the model is run on.
+#[derive(Debug, Clone, Copy)]
+struct Samples {
+    mode: InferenceMode,
+    /// Rows in each sample.
+    rows: usize,
+    /// Rows between the starts of consecutive samples.
+    stride: usize,
+}
+
+impl Samples {
+    /// Runs the model on every complete sample of `rows`, returning the predictions and the rows
+    /// needed by later samples. The last rows of the input are a sample of their own in
+    /// `InferenceMode::Row`.
+    fn predict(
+        self,
+        runnable: &dyn Runnable,
+        mut rows: RecordBatch,
+        keys: &[usize],
+        finished: bool,
+    ) -> Result<(Vec<RecordBatch>, Option<RecordBatch>)> {
+        let mut predictions = Vec::new();
+        while rows.num_rows() >= self.rows
+            || (finished && self.mode == InferenceMode::Row && rows.num_rows() > 0)
+        {
+            let sample = rows.slice(0, self.rows.min(rows.num_rows()));
+            predictions.push(self.predict_sample(runnable, &sample, keys)?);
+
+            let consumed = self.stride.min(rows.num_rows());
+            rows = rows.slice(consumed, rows.num_rows() - consumed);
+        }
+
+        Ok((predictions, (rows.num_rows() > 0).then_some(rows)))
+    }
+
+    fn predict_sample(
+        self,
+        runnable: &dyn Runnable,
+        sample: &RecordBatch,
+        keys: &[usize],
+    ) -> Result<RecordBatch> {
+        let predictions = runnable
+            .run(vec![sample.clone()], sample.num_rows())
+            .context(UnableToRunModelSnafu)?;
+
+        // Each window is keyed by its last row.
+        let key_rows = match self.mode {
+            InferenceMode::Row => sample.clone(),
+            InferenceMode::Window => sample.slice(sample.num_rows() - 1, 1),
+        };
+        ensure!(
+            predictions.num_rows() == key_rows.num_rows(),
+            MismatchedPredictionsSnafu {
+                samples: key_rows.num_rows(),
+                predictions: predictions.num_rows(),
+            }
+        );
+
+        let key_rows = key_rows
+            .project(keys)
+            .context(UnableToJoinPredictionsSnafu)?;
+        let schema = Schema::new(
+            key_rows
+                .schema()
+                .fields()
+                .iter()
+                .chain(predictions.schema().fields().iter())
+                .cloned()
+                .collect::<Vec<_>>(),
+        );
+        let columns = key_rows
+            .columns()
+            .iter()
+            .chain(predictions.columns())
+            .cloned()
+            .collect();
+
+        RecordBatch::try_new(Arc::new(schema), columns).context(UnableToJoinPredictionsSnafu)
+    }
+}
+
+/// The model's inputs, which by default are every numeric column of a time series ordered by `ts`.
+fn inputs(model: &spicepod::component::model::Model) -> Vec<ModelInput> {
+    if !model.inputs.is_empty() {
+        return model.inputs.clone();
+    }
+
+    vec![ModelInput {
+        order_by: Some("ts".to_string()),
+        ..ModelInput::default()
+    }]
+}
+
+/// Selects the columns of every input from `dataset`, in the order they're windowed. The column
+/// they're ordered by is selected too, so windows can be keyed by it.
+fn input_query(inputs: &[ModelInput], dataset: &str) -> String {
+    let order_by = inputs.first().and_then(|input| input.order_by.as_ref());
+    let columns = if inputs.iter().any(|input| input.columns.is_empty()) {
+        "*".to_string()
+    } else {
+        inputs
+            .iter()
+            .flat_map(|input| &input.columns)
+            .chain(order_by)
+            .unique()
+            .map(|column| quote(column))
+            .join(", ")
+    };
+    let order_by = order_by
+        .map(|column| format!(" order by {} asc", quote(column)))
+        .unwrap_or_default();
+
+    // Datasets are registered by name, so a dotted name is a table in another schema.
+    let table = TableReference::from(dataset).resolve("datafusion", "public");
+    format!(
+        "select {columns} from {}.{}.{}{order_by}",
+        quote(&table.catalog),
+        quote(&table.schema),
+        quote(&table.table)
+    )
+}
+
+fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+/// Pulls the files of `model`, caching those downloaded from remote sources.
+async fn pull_cached(
+    model: &spicepod::component::model::Model,
+    secret: Option<Secret>,
+    cache_options: CacheOptions,
+) -> Result<String> {
+    let source = source(&model.from);
+    // Local models aren't downloaded, so aren't cached.
+    if source == "localhost" {
+        return pull(model, &source, secret).await;
+    }
+
+    let revision = version_of(model);
+    let pulled = if cache_options.offline {
+        None
+    } else {
+        Some(pull(model, &source, secret).await)
+    };
+    let path = match pulled {
+        Some(Ok(path)) => path,
+        None => cache::find_cached(&model.name, &model.from, &revision)
+            .await
+            .context(ModelNotCachedSnafu {
+                name: &model.name,
+                revision: &revision,
+            })?,
+        Some(Err(e)) => {
+            let Some(path) = cache::find_cached(&model.name, &model.from, &revision).await else {
+                return Err(e);
+            };
+            tracing::warn!(
+                "Unable to pull model {}, loading its cached revision {revision}: {e}",
+                model.name
+            );
+            metrics::counter!("models_cache_fallback", "model" => model.name.clone()).increment(1);
+            path
+        }
+    };
 
-        Ok(result)
+    if let Err(e) = cache::record_use(
+        &model.name,
+        &source,
+        &model.from,
+        &revision,
+        &path,
+        cache_options.max_size,
+    )
+    .await
+    {
+        tracing::warn!(
+            "Unable to record model {} in the model cache: {e}",
+            model.name
+        );
     }
+
+    Ok(path)
+}
+
+/// Pulls the files of `model` from its source, returning the path of the model's file.
+async fn pull(
+    model: &spicepod::component::model::Model,
+    source: &str,
+    secret: Option<Secret>,
+) -> Result<String> {
+    let secret = match secret {
+        Some(secret) => secret,
+        // Models downloaded by URL can be public, so their secrets are optional.
+        None if matches!(source, "s3" | "https") => Secret::new(HashMap::new()),
+        None => {
+            tracing::warn!(
+                "Unable to load model {}: unable to get secret for source {}",
+                model.name,
+                source
+            );
+            return UnableToLoadRequiredSecretsSnafu {}.fail();
+        }
+    };
+
+    let mut params = model.params.clone().unwrap_or_default();
+    params.insert("name".to_string(), model.name.to_string());
+    params.insert("path".to_string(), path(&model.from));
+    params.insert("from".to_string(), path(&model.from));
+    params.insert("files".to_string(), model.files.join(",").to_string());
+
+    create_source_from(source)
+        .context(UnknownModelSourceSnafu)?
+        .pull(secret, Arc::new(Option::from(params)))
+        .await
+        .context(UnableToLoadModelSnafu)
 }
 
 #[must_use]
@@ -130,6 +599,8 @@ pub(crate) fn source(from: &str) -> String {
         s if s.starts_with("spiceai:") => "spiceai".to_string(),
         s if s.starts_with("huggingface:") => "huggingface".to_string(),
         s if s.starts_with("file:/") => "localhost".to_string(),
+        s if s.starts_with("s3:") => "s3".to_string(),
+        s if s.starts_with("https:") || s.starts_with("http:") => "https".to_string(),
         _ => "spiceai".to_string(),
     }
 }
@@ -150,8 +621,223 @@ pub(crate) fn path(from: &str) -> String {
     from.to_string()
 }
 
+/// The version in the path of `from`. Models downloaded by URL are versioned by their `version`
+/// instead, so are `latest` without it.
 #[must_use]
 pub fn version(from: &str) -> String {
+    if matches!(source(from).as_str(), "s3" | "https") {
+        return "latest".to_string();
+    }
+
     let path = path(from);
     path.split(':').last().unwrap_or("").to_string()
 }
+
+/// Checks that `model` can be told apart from the other versions of it in `all_models`.
+///
+/// Models downloaded by URL are `latest` without a `version`, so a model with more than one version
+/// downloaded by URL needs them set.
+pub fn validate_version(
+    model: &spicepod::component::model::Model,
+    all_models: &[spicepod::component::model::Model],
+) -> Result<()> {
+    let is_url = |model: &spicepod::component::model::Model| {
+        matches!(source(&model.from).as_str(), "s3" | "https")
+    };
+    let url_versions = all_models
+        .iter()
+        .filter(|other| other.name == model.name && is_url(other))
+        .count();
+    ensure!(
+        model.version.is_some() || !is_url(model) || url_versions <= 1,
+        MissingUrlVersionSnafu { name: &model.name }
+    );
+    Ok(())
+}
+
+/// The version identifying `model` among the models with the same name.
+#[must_use]
+pub fn version_of(model: &spicepod::component::model::Model) -> String {
+    model
+        .version
+        .clone()
+        .unwrap_or_else(|| version(&model.from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::Int64Array;
+    use arrow::datatypes::{DataType, Field};
+
+    /// Predicts the first `x` of each sample in `InferenceMode::Window`, and `x` itself for each
+    /// row in `InferenceMode::Row`.
+    struct Echo {
+        mode: InferenceMode,
+    }
+
+    impl Runnable for Echo {
+        fn run(
+            &self,
+            input: Vec<RecordBatch>,
+            _loopback_size: usize,
+        ) -> std::result::Result<RecordBatch, crate::modelruntime::Error> {
+            let x = input[0].column(0).clone();
+            let y = match self.mode {
+                InferenceMode::Row => x,
+                InferenceMode::Window => x.slice(0, 1),
+            };
+            Ok(RecordBatch::try_new(self.output_schema(), vec![y])?)
+        }
+
+        fn output_schema(&self) -> SchemaRef {
+            Arc::new(Schema::new(vec![Field::new("y", DataType::Int64, false)]))
+        }
+
+        fn inputs(&self) -> Vec<TensorInfo> {
+            vec![]
+        }
+
+        fn outputs(&self) -> Vec<TensorInfo> {
+            vec![]
+        }
+
+        fn dry_run(&self) -> std::result::Result<(), crate::modelruntime::Error> {
+            Ok(())
+        }
+    }
+
+    fn rows(num_rows: i64) -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![Field::new("x", DataType::Int64, false)]));
+        RecordBatch::try_new(
+            schema,
+            vec![Arc::new(Int64Array::from_iter_values(0..num_rows))],
+        )
+        .expect("valid record batch")
+    }
+
+    fn column(batches: &[RecordBatch], name: &str) -> Vec<i64> {
+        batches
+            .iter()
+            .flat_map(|batch| {
+                batch
+                    .column_by_name(name)
+                    .expect("column exists")
+                    .as_any()
+                    .downcast_ref::<Int64Array>()
+                    .expect("Int64 column")
+                    .values()
+                    .to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_predict_windows_with_stride() {
+        let samples = Samples {
+            mode: InferenceMode::Window,
+            rows: 3,
+            stride: 2,
+        };
+        let runnable = Echo {
+            mode: InferenceMode::Window,
+        };
+
+        let (predictions, remaining) = samples
+            .predict(&runnable, rows(7), &[0], false)
+            .expect("predictions");
+        // Windows start at rows 0, 2 and 4, and are keyed by their last row.
+        assert_eq!(column(&predictions, "x"), vec![2, 4, 6]);
+        assert_eq!(column(&predictions, "y"), vec![0, 2, 4]);
+        // The next window starts at row 6.
+        let remaining = remaining.expect("remaining rows");
+        assert_eq!(column(&[remaining], "x"), vec![6]);
+    }
+
+    #[test]
+    fn test_predict_windows_drops_incomplete_window_when_finished() {
+        let samples = Samples {
+            mode: InferenceMode::Window,
+            rows: 3,
+            stride: 1,
+        };
+        let runnable = Echo {
+            mode: InferenceMode::Window,
+        };
+
+        let (predictions, _) = samples
+            .predict(&runnable, rows(4), &[0], true)
+            .expect("predictions");
+        assert_eq!(column(&predictions, "y"), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_predict_rows_in_batches() {
+        let samples = Samples {
+            mode: InferenceMode::Row,
+            rows: 3,
+            stride: 3,
+        };
+        let runnable = Echo {
+            mode: InferenceMode::Row,
+        };
+
+        let (predictions, remaining) = samples
+            .predict(&runnable, rows(7), &[0], false)
+            .expect("predictions");
+        assert_eq!(predictions.len(), 2);
+        assert_eq!(column(&predictions, "y"), vec![0, 1, 2, 3, 4, 5]);
+        assert!(remaining.is_some());
+
+        // The last rows are a sample of their own once the input is finished.
+        let (predictions, remaining) = samples
+            .predict(&runnable, rows(7), &[0], true)
+            .expect("predictions");
+        assert_eq!(predictions.len(), 3);
+        assert_eq!(column(&predictions, "x"), (0..7).collect::<Vec<_>>());
+        assert!(remaining.is_none());
+    }
+
+    fn spec(name: &str, from: &str, version: Option<&str>) -> spicepod::component::model::Model {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "from": from,
+            "version": version,
+        }))
+        .expect("valid model")
+    }
+
+    #[test]
+    fn test_validate_version() {
+        let models = vec![
+            spec("churn", "https://models.example.com/churn.onnx", None),
+            spec("churn", "s3://models/churn-v2.onnx", Some("v2")),
+            spec("churn", "spiceai:org/app/models/churn:v3", None),
+            spec("fraud", "https://models.example.com/fraud.onnx", None),
+        ];
+
+        assert!(validate_version(&models[0], &models).is_err());
+        assert!(validate_version(&models[1], &models).is_ok());
+        assert!(validate_version(&models[2], &models).is_ok());
+        assert!(validate_version(&models[3], &models).is_ok());
+        assert!(validate_version(&models[0], &models[..1]).is_ok());
+    }
+
+    #[test]
+    fn test_input_query_quotes_dataset() {
+        let inputs = [ModelInput {
+            columns: vec!["value".to_string()],
+            order_by: Some("ts".to_string()),
+            ..ModelInput::default()
+        }];
+
+        assert_eq!(
+            input_query(&inputs, "eth.recent_blocks"),
+            r#"select "value", "ts" from "datafusion"."eth"."recent_blocks" order by "ts" asc"#
+        );
+        assert_eq!(
+            input_query(&inputs, "select"),
+            r#"select "value", "ts" from "datafusion"."public"."select" order by "ts" asc"#
+        );
+    }
+}
@@ -14,11 +14,71 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
+pub mod nnef;
 pub mod onnx;
 
+use std::path::Path;
+
+use spicepod::component::model::ModelFormat as SpicepodModelFormat;
+
 /// A `ModelFormat` specifies the supported format of a model artifacts.
 ///
-/// Currently, only `onnx` is supported.
+/// Models are either ONNX graphs, or NNEF graphs as exported by tract, i.e. from TensorFlow models.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ModelFormat {
     Onnx(onnx::Onnx),
+    Nnef(nnef::Nnef),
+}
+
+impl ModelFormat {
+    /// Detects the format of the model at `path` from its file name, or from the files of a
+    /// directory.
+    #[must_use]
+    pub fn from_path(path: &str) -> Option<Self> {
+        let path = Path::new(path);
+        let file_name = path.file_name()?.to_str()?.to_lowercase();
+
+        if file_name.ends_with(".onnx") {
+            return Some(ModelFormat::Onnx(onnx::Onnx {}));
+        }
+        if [".nnef.tgz", ".nnef.tar.gz", ".nnef.tar"]
+            .iter()
+            .any(|extension| file_name.ends_with(extension))
+            || path.join("graph.nnef").is_file()
+        {
+            return Some(ModelFormat::Nnef(nnef::Nnef {}));
+        }
+
+        None
+    }
+}
+
+impl From<SpicepodModelFormat> for ModelFormat {
+    fn from(format: SpicepodModelFormat) -> Self {
+        match format {
+            SpicepodModelFormat::Onnx => ModelFormat::Onnx(onnx::Onnx {}),
+            SpicepodModelFormat::Nnef => ModelFormat::Nnef(nnef::Nnef {}),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(
+            ModelFormat::from_path("/models/churn/model.ONNX"),
+            Some(ModelFormat::Onnx(onnx::Onnx {}))
+        );
+        assert_eq!(
+            ModelFormat::from_path("/models/churn/model.nnef.tgz"),
+            Some(ModelFormat::Nnef(nnef::Nnef {}))
+        );
+        assert_eq!(
+            ModelFormat::from_path("/models/churn/model.safetensors"),
+            None
+        );
+    }
 }
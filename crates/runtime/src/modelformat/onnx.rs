@@ -14,4 +14,5 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Onnx {}
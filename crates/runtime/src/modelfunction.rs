@@ -0,0 +1,576 @@
+/*
+Copyright 2024 The Spice.ai OSS Authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Exposes loaded models to SQL.
+//!
+//! `predict` runs a model with each row of its arguments as a sample, i.e.
+//! `SELECT id, predict('churn', f1, f2, f3) FROM customers`. Models run over windows of rows are
+//! also table functions named after them, forecasting over a dataset, i.e.
+//! `SELECT * FROM forecast('readings')`.
+
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak},
+};
+
+use arrow::{
+    array::new_empty_array,
+    compute::cast,
+    datatypes::{DataType, Field, Schema, SchemaRef},
+    record_batch::RecordBatch,
+};
+use async_stream::try_stream;
+use datafusion::{
+    common::{exec_err, plan_err, ScalarValue},
+    datasource::{function::TableFunctionImpl, streaming::StreamingTable, TableProvider},
+    error::{DataFusionError, Result},
+    execution::{context::SessionContext, SendableRecordBatchStream, TaskContext},
+    logical_expr::{ColumnarValue, Expr, ScalarUDF, ScalarUDFImpl, Signature, Volatility},
+    physical_plan::{stream::RecordBatchStreamAdapter, streaming::PartitionStream},
+};
+use futures::{FutureExt, StreamExt};
+
+use crate::model::{InferenceMode, InferenceOptions, Model};
+
+/// The name of the scalar function running a model.
+pub const PREDICT_FUNCTION: &str = "predict";
+
+/// The loaded models callable from SQL.
+pub struct ModelFunctions {
+    models: Arc<RwLock<HashMap<String, Model>>>,
+    /// The models a table function was registered for, which can't be removed from the session.
+    table_functions: Mutex<HashSet<String>>,
+}
+
+impl ModelFunctions {
+    /// Registers `predict` in `ctx`, which runs the models registered later.
+    #[must_use]
+    pub fn new(ctx: &SessionContext) -> Self {
+        let models = Arc::new(RwLock::new(HashMap::new()));
+        ctx.register_udf(ScalarUDF::new_from_impl(PredictUdf {
+            models: Arc::clone(&models),
+            signature: Signature::variadic_any(Volatility::Stable),
+        }));
+
+        Self {
+            models,
+            table_functions: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Makes `model` callable from SQL, replacing an earlier version of it.
+    pub fn register(&self, ctx: &Arc<SessionContext>, model: &Model) {
+        let name = model.model.name.clone();
+        write(&self.models).insert(name.clone(), model.clone());
+
+        let mut table_functions = lock(&self.table_functions);
+        if model.window().is_some() {
+            ctx.register_udtf(
+                &name,
+                Arc::new(ModelTableFunction {
+                    model: model.clone(),
+                    ctx: Arc::downgrade(ctx),
+                }),
+            );
+            table_functions.insert(name);
+        } else if table_functions.remove(&name) {
+            ctx.register_udtf(&name, Arc::new(UnloadedModel::new(&name)));
+        }
+    }
+
+    /// Removes the model `name`. Its table function is replaced with one that fails when called,
+    /// as table functions can't be removed from a `SessionContext`.
+    pub fn deregister(&self, ctx: &SessionContext, name: &str) {
+        write(&self.models).remove(name);
+        if lock(&self.table_functions).remove(name) {
+            ctx.register_udtf(name, Arc::new(UnloadedModel::new(name)));
+        }
+    }
+}
+
+/// Runs the model named by its first argument with each row of the other arguments as a sample,
+/// returning the model's first output as a `DOUBLE`.
+struct PredictUdf {
+    models: Arc<RwLock<HashMap<String, Model>>>,
+    signature: Signature,
+}
+
+impl fmt::Debug for PredictUdf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PredictUdf").finish_non_exhaustive()
+    }
+}
+
+impl PredictUdf {
+    fn model(&self, args: &[ColumnarValue]) -> Result<Model> {
+        let Some(ColumnarValue::Scalar(ScalarValue::Utf8(Some(name)))) = args.first() else {
+            return plan_err!("The first argument of {PREDICT_FUNCTION} must be a model's name");
+        };
+        match read(&self.models).get(name) {
+            Some(model) => Ok(model.clone()),
+            None => plan_err!("Model {name} is not loaded"),
+        }
+    }
+}
+
+impl ScalarUDFImpl for PredictUdf {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn name(&self) -> &str {
+        PREDICT_FUNCTION
+    }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    // The model is only known from the value of the first argument, so the outputs of every model
+    // are cast to the same type.
+    fn return_type(&self, arg_types: &[DataType]) -> Result<DataType> {
+        if arg_types.len() < 2 {
+            return plan_err!("Expected {PREDICT_FUNCTION}('model', column[, ...])");
+        }
+        Ok(DataType::Float64)
+    }
+
+    fn invoke(&self, args: &[ColumnarValue]) -> Result<ColumnarValue> {
+        let model = self.model(args)?;
+        let name = &model.model.name;
+        let args = &args[1..];
+        let num_rows = args
+            .iter()
+            .find_map(|arg| match arg {
+                ColumnarValue::Array(array) => Some(array.len()),
+                ColumnarValue::Scalar(_) => None,
+            })
+            .unwrap_or(1);
+        if num_rows == 0 {
+            return Ok(ColumnarValue::Array(new_empty_array(&DataType::Float64)));
+        }
+
+        // The arguments are passed as the model's input columns, in order.
+        let columns = model.input_columns();
+        if let Some(columns) = &columns {
+            if columns.len() != args.len() {
+                return plan_err!(
+                    "Model {name} reads {} columns, but {} were passed",
+                    columns.len(),
+                    args.len()
+                );
+            }
+        }
+        let (fields, arrays): (Vec<_>, Vec<_>) = args
+            .iter()
+            .enumerate()
+            .map(|(i, arg)| {
+                let name = columns
+                    .as_ref()
+                    .and_then(|columns| columns.get(i).cloned())
+                    .unwrap_or_else(|| format!("arg{i}"));
+                let array = arg.clone().into_array(num_rows)?;
+                Ok((Field::new(name, array.data_type().clone(), true), array))
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .unzip();
+        let rows = RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays)?;
+
+        let predictions = model
+            .predict_rows(&rows)
+            .map_err(|e| DataFusionError::External(Box::new(e)))?;
+        let Some(prediction) = predictions.columns().first() else {
+            return exec_err!("Model {name} returned no predictions");
+        };
+        match cast(prediction, &DataType::Float64) {
+            Ok(prediction) => Ok(ColumnarValue::Array(prediction)),
+            Err(e) => exec_err!(
+                "The {} output of model {name} can't be returned by {PREDICT_FUNCTION}: {e}",
+                prediction.data_type()
+            ),
+        }
+    }
+}
+
+/// Forecasts over a dataset with a model run over windows of rows, as
+/// `SELECT * FROM model('dataset'[, stride])`.
+///
+/// Each forecast is keyed by the column the model's input is ordered by, from the last row of its
+/// window.
+struct ModelTableFunction {
+    model: Model,
+    /// The session holds its table functions, so only a weak reference is kept back to it.
+    ctx: Weak<SessionContext>,
+}
+
+impl TableFunctionImpl for ModelTableFunction {
+    fn call(&self, args: &[Expr]) -> Result<Arc<dyn TableProvider>> {
+        let name = &self.model.model.name;
+        let (dataset, stride) = match args {
+            [Expr::Literal(ScalarValue::Utf8(Some(dataset)))] => (dataset, 1),
+            [Expr::Literal(ScalarValue::Utf8(Some(dataset))), Expr::Literal(stride)] => {
+                match stride {
+                    ScalarValue::Int64(Some(stride)) if *stride > 0 => {
+                        (dataset, usize::try_from(*stride).unwrap_or(usize::MAX))
+                    }
+                    _ => return plan_err!("The stride of {name} must be a positive integer"),
+                }
+            }
+            _ => return plan_err!("Expected {name}('dataset') or {name}('dataset', stride)"),
+        };
+
+        let ctx = upgrade(&self.ctx)?;
+        // Only the schema of the dataset is needed to plan the forecast, which is read from the
+        // in-memory catalog without waiting; its rows are read when the forecast is executed.
+        let Some(table) = ctx.table_provider(dataset.as_str()).now_or_never() else {
+            return plan_err!("Unable to look up dataset {dataset} without blocking");
+        };
+        let table_schema = table?.schema();
+        let keys = self
+            .model
+            .order_by()
+            .and_then(|column| table_schema.field_with_name(&column).ok().cloned())
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        let schema = Arc::new(Schema::new(
+            keys.iter()
+                .cloned()
+                .chain(
+                    self.model
+                        .output_schema()
+                        .fields()
+                        .iter()
+                        .map(|f| f.as_ref().clone()),
+                )
+                .collect::<Vec<_>>(),
+        ));
+        let forecast = Forecast {
+            model: self.model.clone(),
+            ctx: Weak::clone(&self.ctx),
+            query: self.model.input_query(dataset),
+            options: InferenceOptions {
+                mode: InferenceMode::Window,
+                keys: keys.iter().map(|key| key.name().clone()).collect(),
+                window: None,
+                stride,
+                batch_size: ctx.state().config().batch_size(),
+            },
+            schema: Arc::clone(&schema),
+        };
+
+        Ok(Arc::new(StreamingTable::try_new(
+            schema,
+            vec![Arc::new(forecast)],
+        )?))
+    }
+}
+
+struct Forecast {
+    model: Model,
+    ctx: Weak<SessionContext>,
+    query: String,
+    options: InferenceOptions,
+    schema: SchemaRef,
+}
+
+impl PartitionStream for Forecast {
+    fn schema(&self) -> &SchemaRef {
+        &self.schema
+    }
+
+    fn execute(&self, _task_ctx: Arc<TaskContext>) -> SendableRecordBatchStream {
+        let model = self.model.clone();
+        let ctx = Weak::clone(&self.ctx);
+        let query = self.query.clone();
+        let options = self.options.clone();
+
+        Box::pin(RecordBatchStreamAdapter::new(
+            Arc::clone(&self.schema),
+            try_stream! {
+                let input = upgrade(&ctx)?.sql(&query).await?.execute_stream().await?;
+                let mut forecasts = model.infer(input, options);
+                while let Some(forecast) = forecasts.next().await {
+                    yield forecast.map_err(|e| DataFusionError::External(Box::new(e)))?;
+                }
+            },
+        ))
+    }
+}
+
+fn upgrade(ctx: &Weak<SessionContext>) -> Result<Arc<SessionContext>> {
+    ctx.upgrade()
+        .ok_or_else(|| DataFusionError::Execution("The runtime is shutting down".to_string()))
+}
+
+/// Stands in for the table function of a model that isn't loaded, or no longer runs over windows.
+#[derive(Debug)]
+struct UnloadedModel {
+    name: String,
+}
+
+impl UnloadedModel {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+        }
+    }
+}
+
+impl TableFunctionImpl for UnloadedModel {
+    fn call(&self, _args: &[Expr]) -> Result<Arc<dyn TableProvider>> {
+        plan_err!(
+            "Model {} is not loaded or doesn't run over windows of rows",
+            self.name
+        )
+    }
+}
+
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    match lock.read() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    match lock.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn lock<T>(lock: &Mutex<T>) -> MutexGuard<'_, T> {
+    match lock.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modelruntime::{Runnable, TensorInfo};
+    use arrow::array::{Float32Array, Float64Array, Int64Array};
+    use datafusion::datasource::MemTable;
+    use spicepod::component::model::input::ModelInput;
+
+    /// Predicts half of `x` for each row, or of the last row of each window.
+    struct Half {
+        window: bool,
+    }
+
+    impl Runnable for Half {
+        fn run(
+            &self,
+            input: Vec<RecordBatch>,
+            _loopback_size: usize,
+        ) -> std::result::Result<RecordBatch, crate::modelruntime::Error> {
+            let x = cast(
+                input[0].column_by_name("x").ok_or("no x")?,
+                &DataType::Float32,
+            )?;
+            let x = x
+                .as_any()
+                .downcast_ref::<Float32Array>()
+                .ok_or("x isn't float")?;
+            let y = if self.window {
+                Float32Array::from(vec![x.value(x.len() - 1) / 2.0])
+            } else {
+                x.iter().map(|x| x.map(|x| x / 2.0)).collect()
+            };
+            Ok(RecordBatch::try_new(
+                self.output_schema(),
+                vec![Arc::new(y)],
+            )?)
+        }
+
+        fn output_schema(&self) -> SchemaRef {
+            Arc::new(Schema::new(vec![Field::new("y", DataType::Float32, true)]))
+        }
+
+        fn inputs(&self) -> Vec<TensorInfo> {
+            vec![]
+        }
+
+        fn outputs(&self) -> Vec<TensorInfo> {
+            vec![]
+        }
+
+        fn dry_run(&self) -> std::result::Result<(), crate::modelruntime::Error> {
+            Ok(())
+        }
+    }
+
+    fn model(name: &str, window: Option<usize>) -> Model {
+        let spec = spicepod::component::model::Model {
+            from: format!("file:/models/{name}.onnx"),
+            name: name.to_string(),
+            version: None,
+            traffic: None,
+            shadow: false,
+            files: vec![],
+            datasets: vec!["readings".to_string()],
+            params: None,
+            format: None,
+            inputs: vec![ModelInput {
+                columns: vec!["x".to_string()],
+                order_by: Some("ts".to_string()),
+                window,
+                ..ModelInput::default()
+            }],
+            outputs: vec![],
+            predictions: None,
+        };
+        Model::from_runnable(
+            spec,
+            Arc::new(Half {
+                window: window.is_some(),
+            }),
+        )
+    }
+
+    fn context() -> Arc<SessionContext> {
+        let ctx = Arc::new(SessionContext::new());
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("ts", DataType::Int64, false),
+            Field::new("x", DataType::Int64, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            Arc::clone(&schema),
+            vec![
+                Arc::new(Int64Array::from(vec![1, 2, 3, 4])),
+                Arc::new(Int64Array::from(vec![2, 4, 6, 8])),
+            ],
+        )
+        .expect("valid record batch");
+        let table = MemTable::try_new(schema, vec![vec![batch]]).expect("valid memory table");
+        ctx.register_table("readings", Arc::new(table))
+            .expect("table registered");
+        ctx
+    }
+
+    async fn query(ctx: &SessionContext, sql: &str) -> Result<Vec<RecordBatch>> {
+        ctx.sql(sql).await?.collect().await
+    }
+
+    fn float64s(batches: &[RecordBatch], column: &str) -> Vec<f64> {
+        batches
+            .iter()
+            .flat_map(|batch| {
+                batch
+                    .column_by_name(column)
+                    .expect("column exists")
+                    .as_any()
+                    .downcast_ref::<Float64Array>()
+                    .expect("Float64 column")
+                    .values()
+                    .to_vec()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_predict() {
+        let ctx = context();
+        let functions = ModelFunctions::new(&ctx);
+        functions.register(&ctx, &model("half", None));
+
+        let results = query(
+            &ctx,
+            "SELECT predict('half', x) AS y FROM readings ORDER BY ts",
+        )
+        .await
+        .expect("predictions");
+        assert_eq!(float64s(&results, "y"), vec![1.0, 2.0, 3.0, 4.0]);
+
+        // Models aren't functions of their own, so can't shadow built-in functions.
+        assert!(query(&ctx, "SELECT half(x) FROM readings").await.is_err());
+
+        functions.deregister(&ctx, "half");
+        let err = query(&ctx, "SELECT predict('half', x) FROM readings")
+            .await
+            .expect_err("model isn't loaded");
+        assert!(
+            err.to_string().contains("Model half is not loaded"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_predict_requires_a_model_name() {
+        let ctx = context();
+        let functions = ModelFunctions::new(&ctx);
+        functions.register(&ctx, &model("half", None));
+
+        assert!(query(&ctx, "SELECT predict(x) FROM readings")
+            .await
+            .is_err());
+        assert!(query(&ctx, "SELECT predict('half', x, x) FROM readings")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_forecast() {
+        let ctx = context();
+        let functions = ModelFunctions::new(&ctx);
+        functions.register(&ctx, &model("forecast", Some(2)));
+
+        let results = query(&ctx, "SELECT * FROM forecast('readings', 2)")
+            .await
+            .expect("forecasts");
+        let results =
+            arrow::compute::concat_batches(&results[0].schema(), &results).expect("forecasts");
+        // Windows start at every second row, and are keyed by the `ts` of their last row.
+        let ts = results
+            .column_by_name("ts")
+            .expect("ts column")
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .expect("Int64 column");
+        assert_eq!(ts.values().to_vec(), vec![2, 4]);
+        let y = results
+            .column_by_name("y")
+            .expect("y column")
+            .as_any()
+            .downcast_ref::<Float32Array>()
+            .expect("Float32 column");
+        assert_eq!(y.values().to_vec(), vec![2.0, 4.0]);
+    }
+
+    #[tokio::test]
+    async fn test_no_table_function_without_window() {
+        let ctx = context();
+        let functions = ModelFunctions::new(&ctx);
+        functions.register(&ctx, &model("half", None));
+        assert!(query(&ctx, "SELECT * FROM half('readings')").await.is_err());
+
+        // A model that no longer runs over windows can't forecast anymore.
+        functions.register(&ctx, &model("forecast", Some(2)));
+        functions.register(&ctx, &model("forecast", None));
+        let err = query(&ctx, "SELECT * FROM forecast('readings')")
+            .await
+            .expect_err("no windows");
+        assert!(
+            err.to_string().contains("doesn't run over windows"),
+            "{err}"
+        );
+    }
+}
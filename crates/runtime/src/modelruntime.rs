@@ -14,7 +14,10 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
+use crate::modelformat::ModelFormat;
+use arrow::datatypes::SchemaRef;
 use arrow::record_batch::RecordBatch;
+use serde::Serialize;
 use std::result::Result;
 
 pub mod tract;
@@ -27,14 +30,36 @@ pub type Error = Box<dyn std::error::Error + Send + Sync>;
 pub trait Runnable: Send + Sync {
     // Run inference with the input and loaded model
     fn run(&self, input: Vec<RecordBatch>, loopback_size: usize) -> Result<RecordBatch, Error>;
+
+    // The schema of the results of `run`, where outputs with a type that isn't known until the
+    // model first runs are `DataType::Null`
+    fn output_schema(&self) -> SchemaRef;
+
+    // The input tensors of the loaded graph
+    fn inputs(&self) -> Vec<TensorInfo>;
+
+    // The output tensors of the loaded graph returned by `run`
+    fn outputs(&self) -> Vec<TensorInfo>;
+
+    // Run the graph once with zeroed inputs, to check it can run before it's used
+    fn dry_run(&self) -> Result<(), Error>;
+}
+
+/// A tensor of a loaded graph.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TensorInfo {
+    pub name: String,
+    /// The tensor's dimensions, where symbolic dimensions are `None`.
+    pub shape: Vec<Option<usize>>,
+    /// The element type, i.e. `float32`.
+    pub data_type: String,
 }
 
 /// A `ModelRuntime` loads a model into it supported `ModelFormat`.
-/// Currently only `Tract` + `Onnx` is supported
+/// Currently only `Tract` is supported, which loads `Onnx` and `Nnef` models.
 ///
 /// Implementing `load` is required, which returns a `Runnable` in a particular `ModelFormat`.
 pub trait ModelRuntime {
-    // Load the model into the runtime and return a runnable
-    // TODO: add format parameter when more formats are supported
-    fn load(&self) -> Result<Box<dyn Runnable>, Error>;
+    // Load the model in `format` into the runtime and return a runnable
+    fn load(&self, format: ModelFormat) -> Result<Box<dyn Runnable>, Error>;
 }
@@ -14,23 +14,30 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
-use super::{ModelRuntime, Runnable};
-use arrow::array::ArrayRef;
-use arrow::array::Float32Array;
-use arrow::array::Float64Array;
-use arrow::datatypes::DataType;
-use arrow::datatypes::Field;
-use arrow::datatypes::Schema;
+use super::{ModelRuntime, Runnable, TensorInfo};
+use crate::modelformat::ModelFormat;
+use arrow::array::{
+    Array, ArrayRef, BooleanArray, FixedSizeListArray, PrimitiveArray, StringArray,
+};
+use arrow::datatypes::{
+    ArrowPrimitiveType, DataType, Field, Float32Type, Float64Type, Int16Type, Int32Type, Int64Type,
+    Int8Type, Schema, SchemaRef, UInt16Type, UInt32Type, UInt64Type, UInt8Type,
+};
 use arrow::record_batch::RecordBatch;
 use snafu::prelude::*;
 use snafu::ResultExt;
-use std::sync::Arc;
+use spicepod::component::model::input::{DataType as TensorType, Layout, ModelInput};
+use spicepod::component::model::output::ModelOutput;
+use std::sync::{Arc, OnceLock};
 
 use tract_core::tract_data::itertools::Itertools;
 use tract_onnx::prelude::*;
+use tract_onnx::WithOnnx;
 
 pub struct Tract {
     pub path: String,
+    pub inputs: Vec<ModelInput>,
+    pub outputs: Vec<ModelOutput>,
 }
 
 #[derive(Debug, Snafu)]
@@ -43,108 +50,819 @@ pub enum Error {
 
     #[snafu(display("{source}"))]
     ShapeError { source: ndarray::ShapeError },
+
+    #[snafu(display("The model has no input named {name}"))]
+    UnknownInput { name: String },
+
+    #[snafu(display("Input {name} of the model is not mapped to any columns"))]
+    UnboundInput { name: String },
+
+    #[snafu(display("The model has no output named {name}"))]
+    UnknownOutput { name: String },
+
+    #[snafu(display("Input {name} has type {actual}, not {expected}"))]
+    InputTypeMismatch {
+        name: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[snafu(display(
+        "Column {column} has null values, which can't be passed to the model. Filter or fill them in the model's dataset"
+    ))]
+    NullFeature { column: String },
+
+    #[snafu(display("Input {name} has unsupported type {datum_type}"))]
+    UnsupportedInputType { name: String, datum_type: String },
+
+    #[snafu(display("Output {name} has unsupported type {datum_type}"))]
+    UnsupportedOutputType { name: String, datum_type: String },
+
+    #[snafu(display("{name} has shape {actual}, not {expected}"))]
+    ShapeMismatch {
+        name: String,
+        expected: String,
+        actual: String,
+    },
 }
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// The result column of a model's output if none are configured.
+const DEFAULT_OUTPUT_COLUMN: &str = "y";
+
 type Plan = SimplePlan<TypedFact, Box<dyn TypedOp>, Graph<TypedFact, Box<dyn TypedOp>>>;
 pub struct Model {
     model: Plan,
+    /// In the order of the graph's inputs.
+    inputs: Vec<InputMapping>,
+    outputs: Vec<OutputMapping>,
+    /// The types of the outputs' columns on the first run, for outputs whose type depends on
+    /// their symbolic dimensions.
+    output_types: OnceLock<Vec<DataType>>,
+}
+
+/// How dataset columns are arranged into one of the model's input tensors.
+struct InputMapping {
+    name: String,
+    columns: Vec<String>,
+    order_by: Option<String>,
+    datum_type: DatumType,
+    window: Option<usize>,
+    layout: Layout,
+    /// The input's shape in the graph, where symbolic dimensions are `None`.
+    shape: Vec<Option<usize>>,
+}
+
+/// A graph output returned as a result column.
+struct OutputMapping {
+    index: usize,
+    column: String,
+    /// The column's type, if the graph's output has a known element type and row shape.
+    data_type: Option<DataType>,
 }
 
 impl ModelRuntime for Tract {
-    fn load(&self) -> std::result::Result<Box<dyn Runnable>, super::Error> {
-        let model = load_tract_model(self.path.as_str()).context(TractSnafu)?;
-        Ok(Box::new(Model { model }))
+    fn load(&self, format: ModelFormat) -> std::result::Result<Box<dyn Runnable>, super::Error> {
+        let model = load_tract_model(self.path.as_str(), format).context(TractSnafu)?;
+        let inputs = resolve_inputs(&model, &self.inputs)?;
+        let outputs = resolve_outputs(&model, &self.outputs)?;
+
+        Ok(Box::new(Model {
+            model,
+            inputs,
+            outputs,
+            output_types: OnceLock::new(),
+        }))
+    }
+}
+
+fn load_tract_model(path: &str, format: ModelFormat) -> TractResult<Plan> {
+    let model = match format {
+        ModelFormat::Onnx(_) => tract_onnx::onnx().model_for_path(path)?.into_optimized()?,
+        // Graphs exported by tract may use its own operators, including those of ONNX models.
+        ModelFormat::Nnef(_) => tract_nnef::nnef()
+            .with_tract_core()
+            .with_onnx()
+            .model_for_path(path)?
+            .into_optimized()?,
+    };
+
+    model.into_runnable()
+}
+
+/// Binds `inputs` to the graph's inputs by name. A graph with a single input may be bound by an
+/// unnamed input, or by default to every column of its type.
+fn resolve_inputs(plan: &Plan, inputs: &[ModelInput]) -> Result<Vec<InputMapping>> {
+    let graph = plan.model();
+    let outlets = graph.input_outlets().context(TractSnafu)?;
+    let names = outlets
+        .iter()
+        .map(|outlet| graph.node(outlet.node).name.clone())
+        .collect_vec();
+
+    if let Some(unknown) = inputs
+        .iter()
+        .filter_map(|input| input.name.as_ref())
+        .find(|name| !names.contains(name))
+    {
+        return UnknownInputSnafu {
+            name: unknown.clone(),
+        }
+        .fail();
+    }
+
+    outlets
+        .iter()
+        .zip(names)
+        .map(|(outlet, name)| {
+            let input = match inputs
+                .iter()
+                .find(|input| input.name.as_ref() == Some(&name))
+            {
+                Some(input) => input.clone(),
+                None if outlets.len() == 1 && inputs.len() <= 1 => {
+                    inputs.first().cloned().unwrap_or_default()
+                }
+                None => return UnboundInputSnafu { name }.fail(),
+            };
+            resolve_input(
+                graph.outlet_fact(*outlet).context(TractSnafu)?,
+                name,
+                &input,
+            )
+        })
+        .collect()
+}
+
+/// Checks `input` against the graph's input, filling in what it doesn't specify from the graph.
+fn resolve_input(fact: &TypedFact, name: String, input: &ModelInput) -> Result<InputMapping> {
+    let datum_type = match input.dtype {
+        Some(dtype) => {
+            let datum_type = datum_type(dtype);
+            ensure!(
+                datum_type == fact.datum_type,
+                InputTypeMismatchSnafu {
+                    name,
+                    expected: format!("{datum_type:?}"),
+                    actual: format!("{:?}", fact.datum_type),
+                }
+            );
+            datum_type
+        }
+        None => fact.datum_type,
+    };
+    ensure!(
+        matches!(
+            datum_type,
+            DatumType::Bool
+                | DatumType::I8
+                | DatumType::I16
+                | DatumType::I32
+                | DatumType::I64
+                | DatumType::U8
+                | DatumType::U16
+                | DatumType::U32
+                | DatumType::U64
+                | DatumType::F16
+                | DatumType::F32
+                | DatumType::F64
+                | DatumType::String
+        ),
+        UnsupportedInputTypeSnafu {
+            name,
+            datum_type: format!("{datum_type:?}"),
+        }
+    );
+
+    let shape = concrete_dims(fact);
+    let features = (!input.columns.is_empty()).then_some(input.columns.len());
+    check_shape(
+        &name,
+        &expected_shape(input.layout, input.window, features),
+        &shape,
+    )?;
+
+    Ok(InputMapping {
+        name,
+        columns: input.columns.clone(),
+        order_by: input.order_by.clone(),
+        datum_type,
+        window: input.window,
+        layout: input.layout,
+        shape,
+    })
+}
+
+/// Finds each of `outputs` in the graph, defaulting to the graph's first output as column `y`.
+fn resolve_outputs(plan: &Plan, outputs: &[ModelOutput]) -> Result<Vec<OutputMapping>> {
+    let graph = plan.model();
+    let outlets = graph.output_outlets().context(TractSnafu)?;
+    let names = outlets
+        .iter()
+        .map(|outlet| {
+            graph
+                .outlet_label(*outlet)
+                .unwrap_or(graph.node(outlet.node).name.as_str())
+                .to_string()
+        })
+        .collect_vec();
+
+    // Each output is returned as the column of its name, except the default.
+    let outputs = if outputs.is_empty() {
+        let name = names.first().cloned().unwrap_or_default();
+        vec![(
+            ModelOutput {
+                name,
+                shape: Vec::new(),
+            },
+            DEFAULT_OUTPUT_COLUMN.to_string(),
+        )]
+    } else {
+        outputs
+            .iter()
+            .map(|output| (output.clone(), output.name.clone()))
+            .collect_vec()
+    };
+
+    outputs
+        .into_iter()
+        .map(|(output, column)| {
+            let index =
+                names
+                    .iter()
+                    .position(|name| *name == output.name)
+                    .context(UnknownOutputSnafu {
+                        name: output.name.clone(),
+                    })?;
+
+            let fact = graph.outlet_fact(outlets[index]).context(TractSnafu)?;
+            let shape = concrete_dims(fact);
+            if !output.shape.is_empty() {
+                let expected = output.shape.iter().copied().map(Some).collect_vec();
+                check_shape(&output.name, &expected, &shape)?;
+            }
+
+            let element_type = arrow_type(fact.datum_type).context(UnsupportedOutputTypeSnafu {
+                name: output.name.clone(),
+                datum_type: format!("{:?}", fact.datum_type),
+            })?;
+            let data_type = match shape.as_slice() {
+                [] | [_] => Some(element_type),
+                [_, row @ ..] => row
+                    .iter()
+                    .copied()
+                    .product::<Option<usize>>()
+                    .map(|width| list_type(element_type, width)),
+            };
+
+            Ok(OutputMapping {
+                index,
+                column,
+                data_type,
+            })
+        })
+        .collect()
+}
+
+fn concrete_dims(fact: &TypedFact) -> Vec<Option<usize>> {
+    fact.shape
+        .iter()
+        .map(|dim| dim.to_usize().ok())
+        .collect_vec()
+}
+
+fn datum_type(dtype: TensorType) -> DatumType {
+    match dtype {
+        TensorType::Bool => DatumType::Bool,
+        TensorType::Int8 => DatumType::I8,
+        TensorType::Int16 => DatumType::I16,
+        TensorType::Int32 => DatumType::I32,
+        TensorType::Int64 => DatumType::I64,
+        TensorType::Uint8 => DatumType::U8,
+        TensorType::Uint16 => DatumType::U16,
+        TensorType::Uint32 => DatumType::U32,
+        TensorType::Uint64 => DatumType::U64,
+        TensorType::Float16 => DatumType::F16,
+        TensorType::Float32 => DatumType::F32,
+        TensorType::Float64 => DatumType::F64,
+        TensorType::String => DatumType::String,
     }
 }
 
-fn load_tract_model(path: &str) -> TractResult<Plan> {
-    tract_onnx::onnx()
-        .model_for_path(path)?
-        .into_optimized()?
-        .into_runnable()
+/// The Arrow type of a tensor's elements. `F16` tensors are returned as `Float32`.
+fn arrow_type(datum_type: DatumType) -> Option<DataType> {
+    match datum_type {
+        DatumType::Bool => Some(DataType::Boolean),
+        DatumType::I8 => Some(DataType::Int8),
+        DatumType::I16 => Some(DataType::Int16),
+        DatumType::I32 => Some(DataType::Int32),
+        DatumType::I64 => Some(DataType::Int64),
+        DatumType::U8 => Some(DataType::UInt8),
+        DatumType::U16 => Some(DataType::UInt16),
+        DatumType::U32 => Some(DataType::UInt32),
+        DatumType::U64 => Some(DataType::UInt64),
+        DatumType::F16 | DatumType::F32 => Some(DataType::Float32),
+        DatumType::F64 => Some(DataType::Float64),
+        DatumType::String => Some(DataType::Utf8),
+        _ => None,
+    }
+}
+
+/// Outputs with more than one value per row are returned as fixed size lists.
+fn list_type(element_type: DataType, width: usize) -> DataType {
+    if width == 1 {
+        return element_type;
+    }
+    DataType::FixedSizeList(
+        Arc::new(Field::new("item", element_type, false)),
+        i32::try_from(width).unwrap_or(i32::MAX),
+    )
+}
+
+/// The shape of an input tensor, where unknown dimensions are `None`.
+fn expected_shape(
+    layout: Layout,
+    window: Option<usize>,
+    features: Option<usize>,
+) -> Vec<Option<usize>> {
+    match layout {
+        Layout::Sequence => vec![Some(1), window, features],
+        Layout::Rows => vec![window, features],
+        Layout::Flat => vec![
+            Some(1),
+            window
+                .zip(features)
+                .map(|(window, features)| window * features),
+        ],
+    }
+}
+
+/// Checks that `actual` has the rank of `expected`, and that their known dimensions match.
+fn check_shape(name: &str, expected: &[Option<usize>], actual: &[Option<usize>]) -> Result<()> {
+    let matches = expected.len() == actual.len()
+        && expected.iter().zip(actual).all(|dims| match dims {
+            (Some(expected), Some(actual)) => expected == actual,
+            _ => true,
+        });
+
+    ensure!(
+        matches,
+        ShapeMismatchSnafu {
+            name,
+            expected: format_shape(expected),
+            actual: format_shape(actual),
+        }
+    );
+    Ok(())
+}
+
+fn format_shape(shape: &[Option<usize>]) -> String {
+    let dims = shape
+        .iter()
+        .map(|dim| dim.map_or_else(|| "?".to_string(), ToString::to_string))
+        .join(", ");
+    format!("[{dims}]")
+}
+
+impl InputMapping {
+    /// Whether a column is used as a feature of this input if no columns are configured.
+    fn is_default_feature(&self, field: &Field) -> bool {
+        if Some(field.name()) == self.order_by.as_ref() {
+            return false;
+        }
+        match self.datum_type {
+            DatumType::Bool => *field.data_type() == DataType::Boolean,
+            DatumType::String => matches!(field.data_type(), DataType::Utf8 | DataType::LargeUtf8),
+            _ => field.data_type().is_numeric(),
+        }
+    }
+
+    /// The configured feature columns, or every column of the input's type.
+    fn feature_columns(&self, schema: &Schema) -> Result<Vec<usize>> {
+        if !self.columns.is_empty() {
+            return self
+                .columns
+                .iter()
+                .map(|column| schema.index_of(column).context(ArrowSnafu))
+                .collect();
+        }
+
+        Ok(schema
+            .fields()
+            .iter()
+            .enumerate()
+            .filter(|(_, field)| self.is_default_feature(field))
+            .map(|(i, _)| i)
+            .collect_vec())
+    }
+
+    /// Arranges the last `window` rows of the feature columns into the input's tensor, which are the
+    /// latest rows of input ordered by time.
+    fn tensor(&self, input: &[RecordBatch], schema: &Schema, window: usize) -> Result<Tensor> {
+        let columns = self.feature_columns(schema)?;
+
+        let shape = match self.layout {
+            Layout::Sequence => vec![1, window, columns.len()],
+            Layout::Rows => vec![window, columns.len()],
+            Layout::Flat => vec![1, window * columns.len()],
+        };
+        check_shape(
+            &self.name,
+            &self.shape,
+            &shape.iter().copied().map(Some).collect_vec(),
+        )?;
+
+        match self.datum_type {
+            DatumType::Bool => {
+                let data = column_values(input, &columns, window, &DataType::Boolean, |array| {
+                    array
+                        .as_any()
+                        .downcast_ref::<BooleanArray>()
+                        .map(|array| array.values().iter().collect_vec())
+                })?;
+                to_tensor(shape, data)
+            }
+            DatumType::String => {
+                let data = column_values(input, &columns, window, &DataType::Utf8, |array| {
+                    array.as_any().downcast_ref::<StringArray>().map(|array| {
+                        array
+                            .iter()
+                            .flatten()
+                            .map(ToString::to_string)
+                            .collect_vec()
+                    })
+                })?;
+                to_tensor(shape, data)
+            }
+            DatumType::I8 => primitive_tensor::<Int8Type>(input, &columns, window, shape),
+            DatumType::I16 => primitive_tensor::<Int16Type>(input, &columns, window, shape),
+            DatumType::I32 => primitive_tensor::<Int32Type>(input, &columns, window, shape),
+            DatumType::I64 => primitive_tensor::<Int64Type>(input, &columns, window, shape),
+            DatumType::U8 => primitive_tensor::<UInt8Type>(input, &columns, window, shape),
+            DatumType::U16 => primitive_tensor::<UInt16Type>(input, &columns, window, shape),
+            DatumType::U32 => primitive_tensor::<UInt32Type>(input, &columns, window, shape),
+            DatumType::U64 => primitive_tensor::<UInt64Type>(input, &columns, window, shape),
+            DatumType::F16 => {
+                let tensor = primitive_tensor::<Float32Type>(input, &columns, window, shape)?;
+                Ok(tensor
+                    .cast_to_dt(DatumType::F16)
+                    .context(TractSnafu)?
+                    .into_owned())
+            }
+            DatumType::F32 => primitive_tensor::<Float32Type>(input, &columns, window, shape),
+            DatumType::F64 => primitive_tensor::<Float64Type>(input, &columns, window, shape),
+            datum_type => UnsupportedInputTypeSnafu {
+                name: self.name.clone(),
+                datum_type: format!("{datum_type:?}"),
+            }
+            .fail(),
+        }
+    }
+}
+
+/// Reads the last `window` values of each column, cast to `data_type`.
+///
+/// Null values are rejected rather than skipped, so the values of each row stay together.
+fn column_values<T>(
+    input: &[RecordBatch],
+    columns: &[usize],
+    window: usize,
+    data_type: &DataType,
+    values: impl Fn(&dyn Array) -> Option<Vec<T>>,
+) -> Result<Vec<Vec<T>>> {
+    let mut data: Vec<Vec<T>> = columns.iter().map(|_| Vec::new()).collect_vec();
+
+    let num_rows = input.iter().map(RecordBatch::num_rows).sum::<usize>();
+    let mut skipped = num_rows.saturating_sub(window);
+    for batch in input {
+        if skipped >= batch.num_rows() {
+            skipped -= batch.num_rows();
+            continue;
+        }
+        let batch = batch.slice(skipped, batch.num_rows() - skipped);
+        skipped = 0;
+
+        for (column_data, &i) in data.iter_mut().zip(columns) {
+            let column = arrow::compute::cast(batch.column(i), data_type).context(ArrowSnafu)?;
+            ensure!(
+                column.null_count() == 0,
+                NullFeatureSnafu {
+                    column: batch.schema().field(i).name(),
+                }
+            );
+            let Some(col) = values(column.as_ref()) else {
+                continue;
+            };
+            column_data.extend(col);
+        }
+    }
+
+    Ok(data)
+}
+
+fn primitive_tensor<T: ArrowPrimitiveType>(
+    input: &[RecordBatch],
+    columns: &[usize],
+    window: usize,
+    shape: Vec<usize>,
+) -> Result<Tensor>
+where
+    T::Native: Datum,
+{
+    let data = column_values(input, columns, window, &T::DATA_TYPE, |array| {
+        array
+            .as_any()
+            .downcast_ref::<PrimitiveArray<T>>()
+            .map(|array| array.values().to_vec())
+    })?;
+    to_tensor(shape, data)
+}
+
+/// Interleaves the columns row by row, so each row of the window holds one value of every
+/// feature.
+fn to_tensor<T: Datum>(shape: Vec<usize>, data: Vec<Vec<T>>) -> Result<Tensor> {
+    let n_rows = data.iter().map(Vec::len).min().unwrap_or_default();
+    let mut columns = data.into_iter().map(Vec::into_iter).collect_vec();
+    let mut values = Vec::with_capacity(n_rows * columns.len());
+    for _ in 0..n_rows {
+        values.extend(columns.iter_mut().filter_map(Iterator::next));
+    }
+
+    Ok(tract_ndarray::ArrayD::from_shape_vec(shape, values)
+        .context(ShapeSnafu)?
+        .into_tensor())
+}
+
+/// Converts an output tensor into a column with a row for each entry of its first dimension.
+fn output_column(name: &str, tensor: &Tensor) -> Result<ArrayRef> {
+    let width = tensor.shape().iter().skip(1).product::<usize>();
+
+    let values: ArrayRef = match tensor.datum_type() {
+        DatumType::Bool => Arc::new(BooleanArray::from(
+            tensor.as_slice::<bool>().context(TractSnafu)?.to_vec(),
+        )),
+        DatumType::I8 => primitive_array::<Int8Type>(tensor)?,
+        DatumType::I16 => primitive_array::<Int16Type>(tensor)?,
+        DatumType::I32 => primitive_array::<Int32Type>(tensor)?,
+        DatumType::I64 => primitive_array::<Int64Type>(tensor)?,
+        DatumType::U8 => primitive_array::<UInt8Type>(tensor)?,
+        DatumType::U16 => primitive_array::<UInt16Type>(tensor)?,
+        DatumType::U32 => primitive_array::<UInt32Type>(tensor)?,
+        DatumType::U64 => primitive_array::<UInt64Type>(tensor)?,
+        DatumType::F16 => {
+            primitive_array::<Float32Type>(&tensor.cast_to::<f32>().context(TractSnafu)?)?
+        }
+        DatumType::F32 => primitive_array::<Float32Type>(tensor)?,
+        DatumType::F64 => primitive_array::<Float64Type>(tensor)?,
+        DatumType::String => Arc::new(StringArray::from_iter_values(
+            tensor.as_slice::<String>().context(TractSnafu)?,
+        )),
+        datum_type => {
+            return UnsupportedOutputTypeSnafu {
+                name,
+                datum_type: format!("{datum_type:?}"),
+            }
+            .fail()
+        }
+    };
+
+    if width == 1 {
+        return Ok(values);
+    }
+    let DataType::FixedSizeList(field, size) = list_type(values.data_type().clone(), width) else {
+        return Ok(values);
+    };
+    Ok(Arc::new(
+        FixedSizeListArray::try_new(field, size, values, None).context(ArrowSnafu)?,
+    ))
+}
+
+fn primitive_array<T: ArrowPrimitiveType>(tensor: &Tensor) -> Result<ArrayRef>
+where
+    T::Native: Datum,
+{
+    let values = tensor.as_slice::<T::Native>().context(TractSnafu)?;
+    Ok(Arc::new(PrimitiveArray::<T>::from_iter_values(
+        values.iter().copied(),
+    )))
 }
 
 impl Runnable for Model {
+    fn output_schema(&self) -> SchemaRef {
+        let output_types = self.output_types.get();
+        Arc::new(Schema::new(
+            self.outputs
+                .iter()
+                .enumerate()
+                .map(|(i, output)| {
+                    let data_type = output
+                        .data_type
+                        .clone()
+                        .or_else(|| output_types.and_then(|types| types.get(i).cloned()))
+                        .unwrap_or(DataType::Null);
+                    Field::new(output.column.as_str(), data_type, false)
+                })
+                .collect_vec(),
+        ))
+    }
+
     fn run(
         &self,
         input: Vec<RecordBatch>,
         lookback_size: usize,
     ) -> std::result::Result<RecordBatch, super::Error> {
-        {
-            let this = &self;
-            let reader: &[RecordBatch] = &input;
-            let return_schema = Schema::new(vec![Field::new("y", DataType::Float32, false)]);
+        let Some(first_record) = input.first() else {
+            return Ok(RecordBatch::new_empty(self.output_schema()));
+        };
+        let schema = first_record.schema();
+
+        let tensors = self
+            .inputs
+            .iter()
+            .map(|mapping| {
+                let window = mapping.window.unwrap_or(lookback_size);
+                Ok(mapping.tensor(&input, &schema, window)?.into())
+            })
+            .collect::<Result<TVec<TValue>>>()?;
+
+        let output = self.model.run(tensors).context(TractSnafu)?;
+        Ok(self.record_batch(&output)?)
+    }
+
+    fn inputs(&self) -> Vec<TensorInfo> {
+        self.inputs
+            .iter()
+            .map(|input| TensorInfo {
+                name: input.name.clone(),
+                shape: input.shape.clone(),
+                data_type: tensor_type_name(input.datum_type),
+            })
+            .collect()
+    }
 
-            let Some(first_record) = reader.first() else {
-                return Ok(RecordBatch::new_empty(Arc::new(return_schema)));
+    fn outputs(&self) -> Vec<TensorInfo> {
+        let graph = self.model.model();
+        self.outputs
+            .iter()
+            .filter_map(|output| {
+                let fact = graph.output_fact(output.index).ok()?;
+                Some(TensorInfo {
+                    name: output.column.clone(),
+                    shape: concrete_dims(fact),
+                    data_type: tensor_type_name(fact.datum_type),
+                })
+            })
+            .collect()
+    }
+
+    fn dry_run(&self) -> std::result::Result<(), super::Error> {
+        let mut tensors = TVec::new();
+        for input in &self.inputs {
+            // Symbolic dimensions, i.e. the number of rows, are run with one element.
+            let shape = input.shape.iter().map(|dim| dim.unwrap_or(1)).collect_vec();
+            // Tensors of strings can't be zeroed, so models with string inputs aren't dry run.
+            let Ok(tensor) = Tensor::zero_dt(input.datum_type, &shape) else {
+                return Ok(());
             };
+            tensors.push(tensor.into());
+        }
 
-            let schema = first_record.schema();
-            let fields = schema.fields();
-            let mut data: Vec<Vec<f64>> = fields.iter().map(|_| Vec::new()).collect_vec();
+        // The run also settles the types of outputs with symbolic dimensions.
+        let output = self.model.run(tensors).context(TractSnafu)?;
+        self.record_batch(&output)?;
+        Ok(())
+    }
+}
 
-            for batch in reader {
-                batch
-                    .columns()
-                    .iter()
-                    .enumerate()
-                    .for_each(|(i, a): (usize, &ArrayRef)| {
-                        if schema.field(i).name() == "ts" {
-                            // Don't process `ts`.
-                            return;
-                        }
-                        if data[i].len() >= lookback_size {
-                            // This particular column has all data.
-                            // TODO: Stop early if all columns have `lookback_size` data.
-                            return;
-                        }
-
-                        let Some(float_col) = a.as_any().downcast_ref::<Float64Array>() else {
-                            return;
-                        };
-                        let col = float_col.into_iter().flatten().collect_vec();
-                        let end_idx: usize =
-                            std::cmp::min(lookback_size - data[i].len(), col.len());
-                        data[i].append(&mut col[..end_idx].to_vec().clone());
-                    });
-            }
-            let inp: Vec<Vec<f64>> = data
+impl Model {
+    /// The result columns of the graph's `output`.
+    fn record_batch(&self, output: &[TValue]) -> Result<RecordBatch> {
+        let (fields, columns): (Vec<_>, Vec<_>) = self
+            .outputs
+            .iter()
+            .map(|mapping| {
+                let column = output_column(&mapping.column, &output[mapping.index])?;
+                let field = Field::new(mapping.column.as_str(), column.data_type().clone(), false);
+                Ok((field, column))
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .unzip();
+        self.output_types.get_or_init(|| {
+            fields
                 .iter()
-                .enumerate()
-                .filter(|(i, _)| schema.field(*i).name() != "ts") //: (usize, ArrayRef)
-                .map(|(_, x)| x.clone())
-                .collect_vec();
+                .map(|field: &Field| field.data_type().clone())
+                .collect()
+        });
 
-            let n_cols = inp.len();
+        RecordBatch::try_new(Arc::new(Schema::new(fields)), columns).context(ArrowSnafu)
+    }
+}
 
-            let small_vec: Tensor = tract_ndarray::Array3::from_shape_vec(
-                (1, lookback_size, n_cols),
-                inp.into_iter().concat(),
-            )
-            .context(ShapeSnafu)?
-            .into_tensor();
-
-            let output = this.model.run(tvec!(small_vec
-                .cast_to_dt(DatumType::F32)
-                .context(TractSnafu)?
-                .deep_clone()
-                .into()));
-
-            let result: Vec<f32> = output?[0]
-                .to_array_view::<f32>()
-                .context(TractSnafu)?
-                .iter()
-                .copied()
-                .collect_vec();
+/// The name of a tensor's element type, as it's configured on a model's inputs.
+fn tensor_type_name(datum_type: DatumType) -> String {
+    match datum_type {
+        DatumType::Bool => "bool".to_string(),
+        DatumType::I8 => "int8".to_string(),
+        DatumType::I16 => "int16".to_string(),
+        DatumType::I32 => "int32".to_string(),
+        DatumType::I64 => "int64".to_string(),
+        DatumType::U8 => "uint8".to_string(),
+        DatumType::U16 => "uint16".to_string(),
+        DatumType::U32 => "uint32".to_string(),
+        DatumType::U64 => "uint64".to_string(),
+        DatumType::F16 => "float16".to_string(),
+        DatumType::F32 => "float32".to_string(),
+        DatumType::F64 => "float64".to_string(),
+        DatumType::String => "string".to_string(),
+        other => format!("{other:?}").to_lowercase(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            let record_batch = RecordBatch::try_new(
-                Arc::new(return_schema),
-                vec![Arc::new(Float32Array::from(result))],
+    #[test]
+    fn test_check_shape() {
+        let expected = expected_shape(Layout::Sequence, Some(4), None);
+        assert!(check_shape("input", &expected, &[Some(1), Some(4), Some(2)]).is_ok());
+        assert!(check_shape("input", &expected, &[None, None, Some(2)]).is_ok());
+        assert!(check_shape("input", &expected, &[Some(1), Some(8), Some(2)]).is_err());
+        assert!(check_shape("input", &expected, &[Some(4), Some(2)]).is_err());
+
+        let expected = expected_shape(Layout::Flat, Some(4), Some(2));
+        assert_eq!(expected, vec![Some(1), Some(8)]);
+        assert_eq!(format_shape(&[None, Some(8)]), "[?, 8]");
+    }
+
+    #[test]
+    fn test_to_tensor_is_row_major() {
+        let tensor =
+            to_tensor(vec![3, 2], vec![vec![1_i64, 2, 3], vec![4, 5, 6]]).expect("valid shape");
+        assert_eq!(
+            tensor.as_slice::<i64>().expect("i64 tensor"),
+            &[1, 4, 2, 5, 3, 6]
+        );
+    }
+
+    #[test]
+    fn test_output_column() {
+        let labels = tensor1(&[1_i64, 0, 2]);
+        let column = output_column("label", &labels).expect("supported type");
+        assert_eq!(column.data_type(), &DataType::Int64);
+        assert_eq!(column.len(), 3);
+
+        let probabilities = tensor2(&[[0.1_f32, 0.9], [0.8, 0.2], [0.3, 0.7]]);
+        let column = output_column("probabilities", &probabilities).expect("supported type");
+        assert_eq!(column.data_type(), &list_type(DataType::Float32, 2));
+        assert_eq!(column.len(), 3);
+    }
+
+    #[test]
+    fn test_column_values_reads_last_rows() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("x", DataType::Int64, true),
+            Field::new("y", DataType::Float64, true),
+        ]));
+        let batch = |x: Vec<Option<i64>>, y: Vec<Option<f64>>| {
+            RecordBatch::try_new(
+                Arc::clone(&schema),
+                vec![
+                    Arc::new(arrow::array::Int64Array::from(x)),
+                    Arc::new(arrow::array::Float64Array::from(y)),
+                ],
             )
-            .context(ArrowSnafu)?;
+            .expect("valid record batch")
+        };
+        let values = |input: &[RecordBatch], window| {
+            column_values(input, &[0, 1], window, &DataType::Float32, |array| {
+                array
+                    .as_any()
+                    .downcast_ref::<PrimitiveArray<Float32Type>>()
+                    .map(|array| array.values().to_vec())
+            })
+        };
 
-            Ok(record_batch)
-        }
+        let input = [
+            batch(vec![Some(1), Some(2)], vec![Some(0.1), Some(0.2)]),
+            batch(vec![Some(3), Some(4)], vec![Some(0.3), Some(0.4)]),
+        ];
+        let data = values(&input, 3).expect("values");
+        assert_eq!(data, vec![vec![2.0, 3.0, 4.0], vec![0.2, 0.3, 0.4]]);
+
+        // A null would misalign the rows of the columns, so is rejected.
+        let input = [batch(vec![Some(1), None], vec![Some(0.1), Some(0.2)])];
+        assert!(matches!(
+            values(&input, 2),
+            Err(Error::NullFeature { column }) if column == "x"
+        ));
+        assert!(values(&input, 1).is_err());
+        // Nulls before the window aren't read.
+        let input = [batch(vec![None, Some(2)], vec![Some(0.1), Some(0.2)])];
+        assert_eq!(
+            values(&input, 1).expect("values"),
+            vec![vec![2.0], vec![0.2]]
+        );
     }
 }
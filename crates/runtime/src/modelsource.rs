@@ -18,10 +18,14 @@ use async_trait::async_trait;
 use secrets::Secret;
 use snafu::prelude::*;
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
 
+pub mod cache;
+pub mod https;
 pub mod huggingface;
 pub mod local;
+pub mod s3;
 pub mod spiceai;
 
 #[derive(Debug, Snafu)]
@@ -36,6 +40,25 @@ pub enum Error {
     #[snafu(display("Unable to download model file"))]
     UnableToDownloadModelFile {},
 
+    #[snafu(display("Unable to download {url}: {status}"))]
+    UnableToDownload {
+        url: String,
+        status: reqwest::StatusCode,
+    },
+
+    #[snafu(display("Unable to download the model from object storage: {source}"))]
+    UnableToFetchObject { source: object_store::Error },
+
+    #[snafu(display("Invalid model URL: {url}"))]
+    InvalidModelUrl { url: String },
+
+    #[snafu(display("The SHA-256 checksum of {url} is {actual}, expected {expected}"))]
+    ChecksumMismatch {
+        url: String,
+        expected: String,
+        actual: String,
+    },
+
     #[snafu(display("Unable to parse metadata"))]
     UnableToParseMetadata {},
 
@@ -45,6 +68,12 @@ pub enum Error {
     #[snafu(display("Unable to create model path: {source}"))]
     UnableToCreateModelPath { source: std::io::Error },
 
+    #[snafu(display("Unable to access the model cache: {source}"))]
+    UnableToAccessCacheIndex { source: std::io::Error },
+
+    #[snafu(display("Unable to serialize the model cache index: {source}"))]
+    UnableToSerializeCacheIndex { source: serde_json::Error },
+
     #[snafu(display("Unable to load the configuration: {reason}"))]
     UnableToLoadConfig { reason: String },
 
@@ -68,9 +97,15 @@ pub trait ModelSource {
     ) -> Result<String>;
 }
 
+/// The directory models are downloaded to, `~/.spice/models`.
+pub fn models_dir() -> Result<PathBuf> {
+    let mut models_dir = dirs::home_dir().context(UnableToFindHomeDirSnafu)?;
+    models_dir.push(".spice/models");
+    Ok(models_dir)
+}
+
 pub fn ensure_model_path(name: &str) -> Result<String> {
-    let mut model_path = dirs::home_dir().context(UnableToFindHomeDirSnafu)?;
-    model_path.push(".spice/models");
+    let mut model_path = models_dir()?;
     model_path.push(name);
 
     if !model_path.exists() {
@@ -86,11 +121,38 @@ pub fn ensure_model_path(name: &str) -> Result<String> {
     Ok(model_path.to_string())
 }
 
+fn get_param(params: &Arc<Option<HashMap<String, String>>>, key: &str) -> Option<String> {
+    params
+        .as_ref()
+        .as_ref()
+        .and_then(|params| params.get(key).cloned())
+}
+
+/// The cache of the file downloaded from the model's URL, which is its `path` param.
+fn cached_download(params: &Arc<Option<HashMap<String, String>>>) -> Result<cache::CachedDownload> {
+    let Some(name) = get_param(params, "name") else {
+        return UnableToLoadConfigSnafu {
+            reason: "Name is required",
+        }
+        .fail();
+    };
+    let Some(url) = get_param(params, "path") else {
+        return UnableToLoadConfigSnafu {
+            reason: "From is required",
+        }
+        .fail();
+    };
+
+    cache::CachedDownload::new(&name, &url, get_param(params, "sha256"))
+}
+
 pub fn create_source_from(source: &str) -> Result<Box<dyn ModelSource>> {
     match source {
         "localhost" => Ok(Box::new(local::Local {})),
         "spiceai" => Ok(Box::new(spiceai::SpiceAI {})),
         "huggingface" => Ok(Box::new(huggingface::Huggingface {})),
+        "s3" => Ok(Box::new(s3::S3 {})),
+        "https" => Ok(Box::new(https::Https {})),
         _ => UnknownModelSourceSnafu {
             model_source: source,
         }
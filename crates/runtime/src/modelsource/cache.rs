@@ -0,0 +1,508 @@
+/*
+Copyright 2024 The Spice.ai OSS Authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Caches the models downloaded to `~/.spice/models`.
+//!
+//! Files downloaded by URL are reused without a request if they match the model's `sha256` param,
+//! and are otherwise revalidated with the `ETag` they were downloaded with.
+//!
+//! Every downloaded model is recorded in an index with the checksum of its file, so a verified copy
+//! can be loaded when its source is unreachable or the runtime is offline. Once the models exceed
+//! the cache's maximum size, the least recently used are removed, except those currently loaded.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::prelude::*;
+use tokio::io::AsyncWriteExt;
+
+use super::{
+    ChecksumMismatchSnafu, InvalidModelUrlSnafu, Result, UnableToAccessCacheIndexSnafu,
+    UnableToCreateModelPathSnafu, UnableToSerializeCacheIndexSnafu,
+};
+use crate::{config::Config, timing::now_millis};
+
+/// Serializes updates to the index, which is read, modified and written back as a whole.
+static INDEX_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// The paths of the loaded models by model name and revision, which are never removed.
+static LOADED: Lazy<Mutex<HashMap<(String, String), String>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheOptions {
+    /// Load models only from the cache, without contacting their sources.
+    pub offline: bool,
+    /// The total size in bytes of the cached models, beyond which the least recently used are
+    /// removed.
+    pub max_size: Option<u64>,
+}
+
+impl From<&Config> for CacheOptions {
+    fn from(config: &Config) -> Self {
+        Self {
+            offline: config.offline,
+            max_size: config
+                .model_cache_max_size
+                .map(|size| u64::try_from(size).unwrap_or(u64::MAX)),
+        }
+    }
+}
+
+/// A downloaded model, identified by the model's name, `from` and revision.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub model: String,
+    pub source: String,
+    pub from: String,
+    pub revision: String,
+    /// The model's file. Other files of the model are in the same directory.
+    pub path: String,
+    /// The SHA-256 digest of the model's file, in hex.
+    pub checksum: String,
+    /// The size in bytes of the model's directory.
+    pub size: u64,
+    /// Milliseconds since the Unix epoch at which the model was last loaded.
+    pub last_used: u64,
+}
+
+impl CacheEntry {
+    fn is_model(&self, model: &str, from: &str, revision: &str) -> bool {
+        self.model == model && self.from == from && self.revision == revision
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheIndex {
+    entries: Vec<CacheEntry>,
+}
+
+impl CacheIndex {
+    fn path() -> Result<PathBuf> {
+        Ok(super::models_dir()?.join("index.json"))
+    }
+
+    fn load() -> Result<Self> {
+        let contents = match std::fs::read(Self::path()?) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(source) => return Err(super::Error::UnableToAccessCacheIndex { source }),
+        };
+
+        Ok(serde_json::from_slice(&contents).unwrap_or_else(|e| {
+            tracing::warn!("Unable to read the model cache index, starting a new one: {e}");
+            Self::default()
+        }))
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = Self::path()?;
+        let contents = serde_json::to_vec_pretty(self).context(UnableToSerializeCacheIndexSnafu)?;
+        let partial_path = path.with_extension("json.partial");
+        std::fs::write(&partial_path, contents).context(UnableToAccessCacheIndexSnafu)?;
+        std::fs::rename(&partial_path, &path).context(UnableToAccessCacheIndexSnafu)
+    }
+}
+
+/// The most recently used copy of a model in the cache that still matches its checksum.
+pub async fn find_cached(model: &str, from: &str, revision: &str) -> Option<String> {
+    let (model, from, revision) = (model.to_string(), from.to_string(), revision.to_string());
+    tokio::task::spawn_blocking(move || find_cached_blocking(&model, &from, &revision))
+        .await
+        .unwrap_or_else(|e| {
+            tracing::warn!("Unable to search the model cache: {e}");
+            None
+        })
+}
+
+fn find_cached_blocking(model: &str, from: &str, revision: &str) -> Option<String> {
+    let _guard = lock_index();
+    let index = match CacheIndex::load() {
+        Ok(index) => index,
+        Err(e) => {
+            tracing::warn!("Unable to read the model cache index: {e}");
+            return None;
+        }
+    };
+
+    let mut entries = index
+        .entries
+        .iter()
+        .filter(|entry| entry.is_model(model, from, revision))
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.last_used));
+    entries.into_iter().find_map(|entry| {
+        if sha256_file(&entry.path).ok()? == entry.checksum {
+            Some(entry.path.clone())
+        } else {
+            tracing::warn!("Cached model file {} is corrupted, skipping it", entry.path);
+            None
+        }
+    })
+}
+
+/// Records that a model was loaded from `path`, then removes the least recently used models
+/// beyond `max_size`.
+///
+/// The model's path is kept until it's released, replacing the path it was loaded from before.
+pub async fn record_use(
+    model: &str,
+    source: &str,
+    from: &str,
+    revision: &str,
+    path: &str,
+    max_size: Option<u64>,
+) -> Result<()> {
+    lock_loaded().insert((model.to_string(), revision.to_string()), path.to_string());
+
+    let entry = CacheEntry {
+        model: model.to_string(),
+        source: source.to_string(),
+        from: from.to_string(),
+        revision: revision.to_string(),
+        path: path.to_string(),
+        checksum: String::new(),
+        size: 0,
+        last_used: now_millis(),
+    };
+    tokio::task::spawn_blocking(move || record_use_blocking(entry, max_size))
+        .await
+        .map_err(|e| super::Error::UnableToAccessCacheIndex {
+            source: std::io::Error::other(e),
+        })?
+}
+
+fn record_use_blocking(mut entry: CacheEntry, max_size: Option<u64>) -> Result<()> {
+    entry.checksum = sha256_file(&entry.path).context(UnableToAccessCacheIndexSnafu)?;
+    entry.size = model_dir(&entry.path).map_or(0, |dir| dir_size(&dir));
+
+    let _guard = lock_index();
+    let mut index = CacheIndex::load()?;
+    // A path holds one revision of one model, so an entry with the same path is outdated.
+    index.entries.retain(|cached| {
+        cached.path != entry.path && !cached.is_model(&entry.model, &entry.from, &entry.revision)
+    });
+    index.entries.push(entry);
+
+    if let Some(max_size) = max_size {
+        let loaded = lock_loaded().values().cloned().collect::<HashSet<_>>();
+        for evicted in evictions(&index.entries, &loaded, max_size) {
+            let evicted = index.entries.remove(evicted);
+            remove_model_files(&evicted);
+        }
+    }
+
+    index.save()
+}
+
+/// Records that the revision of the model is no longer loaded, so its files can be removed.
+pub fn release(model: &str, revision: &str) {
+    lock_loaded().remove(&(model.to_string(), revision.to_string()));
+}
+
+/// The positions of the least recently used entries to remove for the rest to fit in `max_size`,
+/// in descending order. The entries for the `loaded` paths are never removed.
+fn evictions(entries: &[CacheEntry], loaded: &HashSet<String>, max_size: u64) -> Vec<usize> {
+    let mut total = entries.iter().map(|entry| entry.size).sum::<u64>();
+    let mut by_last_used = (0..entries.len())
+        .filter(|i| !loaded.contains(&entries[*i].path))
+        .collect::<Vec<_>>();
+    by_last_used.sort_by_key(|i| entries[*i].last_used);
+
+    let mut evicted = Vec::new();
+    for i in by_last_used {
+        if total <= max_size {
+            break;
+        }
+        total = total.saturating_sub(entries[i].size);
+        evicted.push(i);
+    }
+
+    evicted.sort_unstable_by(|a, b| b.cmp(a));
+    evicted
+}
+
+fn remove_model_files(entry: &CacheEntry) {
+    tracing::info!(
+        "Removing model {} revision {} from the model cache",
+        entry.model,
+        entry.revision
+    );
+    let result = match model_dir(&entry.path) {
+        Some(dir) => std::fs::remove_dir_all(dir),
+        None => std::fs::remove_file(&entry.path),
+    };
+    if let Err(e) = result {
+        tracing::warn!("Unable to remove cached model file {}: {e}", entry.path);
+    }
+}
+
+/// The directory holding one revision of a model, i.e. `~/.spice/models/<name>/<revision>`.
+///
+/// Returns `None` for files outside of the models directory, so they're never removed.
+fn model_dir(path: &str) -> Option<PathBuf> {
+    let models_dir = super::models_dir().ok()?;
+    let dir = Path::new(path).parent()?;
+    let relative = dir.strip_prefix(&models_dir).ok()?;
+    (relative.components().count() == 2).then(|| dir.to_path_buf())
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+fn lock_index() -> std::sync::MutexGuard<'static, ()> {
+    match INDEX_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn lock_loaded() -> std::sync::MutexGuard<'static, HashMap<(String, String), String>> {
+    match LOADED.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Where the model file downloaded from a URL is kept.
+pub struct CachedDownload {
+    pub url: String,
+    pub path: String,
+    /// The SHA-256 digest of the file, in hex.
+    pub checksum: Option<String>,
+}
+
+impl CachedDownload {
+    pub fn new(name: &str, url: &str, checksum: Option<String>) -> Result<Self> {
+        let parsed = url::Url::parse(url).map_err(|_| InvalidModelUrlSnafu { url }.build())?;
+        let file_name = parsed
+            .path_segments()
+            .and_then(Iterator::last)
+            .filter(|segment| !segment.is_empty())
+            .context(InvalidModelUrlSnafu { url })?;
+
+        // Files downloaded from different URLs can have the same name, as can versions of a model.
+        let directory = format!(
+            "{}/{:08x}",
+            super::ensure_model_path(name)?,
+            crc32fast::hash(url.as_bytes())
+        );
+        std::fs::create_dir_all(&directory).context(UnableToCreateModelPathSnafu)?;
+
+        Ok(Self {
+            url: url.to_string(),
+            path: format!("{directory}/{file_name}"),
+            checksum: checksum.map(|checksum| checksum.trim().to_lowercase()),
+        })
+    }
+
+    /// Whether the cached file matches the model's checksum, so doesn't need revalidating.
+    pub async fn is_verified(&self) -> bool {
+        let Some(checksum) = self.checksum.clone() else {
+            return false;
+        };
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || {
+            sha256_file(&path).is_ok_and(|actual| actual == checksum)
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    /// The `ETag` the cached file was downloaded with, to download the file only if it's changed.
+    ///
+    /// Files that don't match the model's checksum are downloaded again regardless.
+    #[must_use]
+    pub fn etag(&self) -> Option<String> {
+        if self.checksum.is_some() || !Path::new(&self.path).exists() {
+            return None;
+        }
+
+        std::fs::read_to_string(self.etag_path())
+            .ok()
+            .map(|etag| etag.trim().to_string())
+            .filter(|etag| !etag.is_empty())
+    }
+
+    /// Starts downloading the file, which is written beside the cached file until it's saved.
+    pub async fn writer(&self) -> Result<DownloadWriter> {
+        // The file is renamed into place, so an interrupted download isn't mistaken for the model.
+        let partial_path = format!("{}.partial", self.path);
+        let file = tokio::fs::File::create(&partial_path)
+            .await
+            .context(UnableToCreateModelPathSnafu)?;
+        Ok(DownloadWriter {
+            file,
+            partial_path,
+            hasher: Sha256::new(),
+        })
+    }
+
+    /// Verifies and caches the file downloaded with `writer`, returning the path of the file.
+    pub async fn save(&self, writer: DownloadWriter, etag: Option<&str>) -> Result<String> {
+        let DownloadWriter {
+            mut file,
+            partial_path,
+            hasher,
+        } = writer;
+        file.flush().await.context(UnableToCreateModelPathSnafu)?;
+        file.sync_all()
+            .await
+            .context(UnableToCreateModelPathSnafu)?;
+        drop(file);
+
+        if let Some(expected) = &self.checksum {
+            let actual = hex(&hasher.finalize());
+            if actual != *expected {
+                let _ = tokio::fs::remove_file(&partial_path).await;
+                return ChecksumMismatchSnafu {
+                    url: &self.url,
+                    expected,
+                    actual,
+                }
+                .fail();
+            }
+        }
+
+        tokio::fs::rename(&partial_path, &self.path)
+            .await
+            .context(UnableToCreateModelPathSnafu)?;
+
+        match etag {
+            Some(etag) => {
+                tokio::fs::write(self.etag_path(), etag)
+                    .await
+                    .context(UnableToCreateModelPathSnafu)?;
+            }
+            None => {
+                let _ = tokio::fs::remove_file(self.etag_path()).await;
+            }
+        }
+
+        tracing::info!("Downloaded: {}", self.path);
+        Ok(self.path.clone())
+    }
+
+    fn etag_path(&self) -> String {
+        format!("{}.etag", self.path)
+    }
+}
+
+/// A file being downloaded, hashed as it's written.
+pub struct DownloadWriter {
+    file: tokio::fs::File,
+    partial_path: String,
+    hasher: Sha256,
+}
+
+impl DownloadWriter {
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<()> {
+        self.hasher.update(chunk);
+        self.file
+            .write_all(chunk)
+            .await
+            .context(UnableToCreateModelPathSnafu)
+    }
+}
+
+/// The SHA-256 digest of the file at `path`, read in chunks rather than all at once.
+fn sha256_file(path: &str) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => break,
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, size: u64, last_used: u64) -> CacheEntry {
+        CacheEntry {
+            model: "churn".to_string(),
+            source: "s3".to_string(),
+            from: format!("s3://models/{path}"),
+            revision: "latest".to_string(),
+            path: path.to_string(),
+            checksum: String::new(),
+            size,
+            last_used,
+        }
+    }
+
+    #[test]
+    fn test_evictions() {
+        let entries = vec![
+            entry("a", 40, 3),
+            entry("b", 30, 1),
+            entry("c", 20, 2),
+            entry("d", 10, 4),
+        ];
+
+        let loaded = |paths: &[&str]| paths.iter().map(ToString::to_string).collect();
+
+        assert!(evictions(&entries, &loaded(&["d"]), 100).is_empty());
+        // The least recently used entries are removed first.
+        assert_eq!(evictions(&entries, &loaded(&["d"]), 60), vec![2, 1]);
+        // Loaded entries are kept, even if they're the least recently used.
+        assert_eq!(evictions(&entries, &loaded(&["b"]), 50), vec![2, 0]);
+        assert_eq!(evictions(&entries, &loaded(&["b"]), 0), vec![3, 2, 0]);
+        assert_eq!(evictions(&entries, &loaded(&["b", "c"]), 0), vec![3, 0]);
+    }
+
+    #[test]
+    fn test_sha256() {
+        let expected = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(hex(&Sha256::digest(b"abc")), expected);
+
+        let path = std::env::temp_dir().join(format!("spice_model_{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, b"abc").expect("write model file");
+        let path = path.to_string_lossy().to_string();
+        assert_eq!(sha256_file(&path).expect("hash model file"), expected);
+        std::fs::remove_file(&path).expect("remove model file");
+    }
+}
@@ -0,0 +1,82 @@
+/*
+Copyright 2024 The Spice.ai OSS Authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use super::ModelSource;
+use async_trait::async_trait;
+use reqwest::{header, StatusCode};
+use secrets::Secret;
+use snafu::prelude::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Downloads a model file from an HTTP(S) URL, i.e. `https://artifacts.example.com/churn.onnx`,
+/// authenticating with the `token` of the `https` secret if it's set.
+pub struct Https {}
+
+#[async_trait]
+impl ModelSource for Https {
+    async fn pull(
+        &self,
+        secret: Secret,
+        params: Arc<Option<HashMap<String, String>>>,
+    ) -> super::Result<String> {
+        let download = super::cached_download(&params)?;
+        if download.is_verified().await {
+            tracing::info!("File already exists: {}, skipping download", download.path);
+            return Ok(download.path);
+        }
+
+        tracing::info!("Downloading model: {}", download.url);
+        let mut request = reqwest::Client::new().get(&download.url);
+        if let Some(token) = secret.get("token") {
+            request = request.bearer_auth(token);
+        }
+        if let Some(etag) = download.etag() {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        let mut response = request
+            .send()
+            .await
+            .context(super::UnableToFetchModelSnafu)?;
+
+        if response.status() == StatusCode::NOT_MODIFIED {
+            tracing::info!("File already exists: {}, skipping download", download.path);
+            return Ok(download.path);
+        }
+        if !response.status().is_success() {
+            return super::UnableToDownloadSnafu {
+                url: &download.url,
+                status: response.status(),
+            }
+            .fail();
+        }
+
+        let etag = response
+            .headers()
+            .get(header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(ToString::to_string);
+        let mut writer = download.writer().await?;
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .context(super::UnableToFetchModelSnafu)?
+        {
+            writer.write(&chunk).await?;
+        }
+        download.save(writer, etag.as_deref()).await
+    }
+}
@@ -16,12 +16,12 @@ limitations under the License.
 
 use super::Error;
 use super::ModelSource;
+use crate::modelformat::ModelFormat;
 use async_trait::async_trait;
 use regex::Regex;
 use secrets::Secret;
 use snafu::prelude::*;
 use std::collections::HashMap;
-use std::io::Cursor;
 use std::sync::Arc;
 
 pub struct Huggingface {}
@@ -96,7 +96,7 @@ impl ModelSource for Huggingface {
 
         let versioned_path = format!("{local_path}/{revision}");
 
-        let mut onnx_file_name = String::new();
+        let mut model_file_name = String::new();
 
         std::fs::create_dir_all(versioned_path.clone())
             .context(super::UnableToCreateModelPathSnafu {})?;
@@ -106,6 +106,10 @@ impl ModelSource for Huggingface {
         for file in files {
             let file_name = format!("{p}/{file}");
 
+            if model_file_name.is_empty() && ModelFormat::from_path(&file_name).is_some() {
+                model_file_name = file_name.clone();
+            }
+
             if std::fs::metadata(file_name.clone()).is_ok() {
                 tracing::info!("File already exists: {}, skipping download", file_name);
 
@@ -122,10 +126,6 @@ impl ModelSource for Huggingface {
 
             tracing::info!("Downloading model: {}", download_url);
 
-            if file.to_lowercase().ends_with(".onnx") {
-                onnx_file_name = file_name.clone();
-            }
-
             let client = reqwest::Client::new();
             let response = client
                 .get(download_url)
@@ -138,15 +138,21 @@ impl ModelSource for Huggingface {
                 return Err(Error::UnableToDownloadModelFile {});
             }
 
-            let mut file = std::fs::File::create(file_name.clone())
+            // The file is renamed into place once it's complete, so an interrupted download isn't
+            // skipped as already downloaded next time.
+            let content = response
+                .bytes()
+                .await
+                .context(super::UnableToFetchModelSnafu {})?;
+            let partial_file_name = format!("{file_name}.partial");
+            std::fs::write(&partial_file_name, content)
                 .context(super::UnableToCreateModelPathSnafu {})?;
-            let mut content = Cursor::new(response.bytes().await.unwrap_or_default());
-            std::io::copy(&mut content, &mut file)
+            std::fs::rename(&partial_file_name, &file_name)
                 .context(super::UnableToCreateModelPathSnafu {})?;
 
             tracing::info!("Downloaded: {}", file_name);
         }
 
-        Ok(onnx_file_name)
+        Ok(model_file_name)
     }
 }
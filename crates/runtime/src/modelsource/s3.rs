@@ -0,0 +1,85 @@
+/*
+Copyright 2024 The Spice.ai OSS Authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use super::ModelSource;
+use crate::dataconnector::s3::object_store_builder;
+use async_trait::async_trait;
+use futures::StreamExt;
+use object_store::{path::Path, GetOptions, ObjectStore};
+use secrets::Secret;
+use snafu::prelude::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+use url::Url;
+
+/// Downloads a model file from S3 compatible object storage, i.e. `s3://bucket/models/churn.onnx`.
+///
+/// Like the S3 data connector, the `s3` secret holds the `key` and `secret`, and the `region` and
+/// `endpoint` params select the store, i.e. a local MinIO.
+pub struct S3 {}
+
+#[async_trait]
+impl ModelSource for S3 {
+    async fn pull(
+        &self,
+        secret: Secret,
+        params: Arc<Option<HashMap<String, String>>>,
+    ) -> super::Result<String> {
+        let download = super::cached_download(&params)?;
+        if download.is_verified().await {
+            tracing::info!("File already exists: {}, skipping download", download.path);
+            return Ok(download.path);
+        }
+
+        let url = Url::parse(&download.url)
+            .map_err(|_| super::InvalidModelUrlSnafu { url: &download.url }.build())?;
+        let bucket = url
+            .host_str()
+            .context(super::InvalidModelUrlSnafu { url: &download.url })?;
+        let location = Path::from_url_path(url.path())
+            .map_err(|_| super::InvalidModelUrlSnafu { url: &download.url }.build())?;
+
+        // Without both a key and a secret, public objects are downloaded without signing.
+        let secret = secret.get("key").and(secret.get("secret")).map(|_| &secret);
+        let s3_builder = object_store_builder(bucket, params.as_ref().as_ref(), secret);
+        let store = s3_builder
+            .build()
+            .context(super::UnableToFetchObjectSnafu)?;
+
+        let options = GetOptions {
+            if_none_match: download.etag(),
+            ..GetOptions::default()
+        };
+        tracing::info!("Downloading model: {}", download.url);
+        let result = match store.get_opts(&location, options).await {
+            Ok(result) => result,
+            Err(object_store::Error::NotModified { .. }) => {
+                tracing::info!("File already exists: {}, skipping download", download.path);
+                return Ok(download.path);
+            }
+            Err(source) => return Err(super::Error::UnableToFetchObject { source }),
+        };
+
+        let etag = result.meta.e_tag.clone();
+        let mut writer = download.writer().await?;
+        let mut chunks = result.into_stream();
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk.context(super::UnableToFetchObjectSnafu)?;
+            writer.write(&chunk).await?;
+        }
+        download.save(writer, etag.as_deref()).await
+    }
+}
@@ -0,0 +1,166 @@
+/*
+Copyright 2024 The Spice.ai OSS Authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Latency and throughput of the runs of a loaded model.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Instant,
+};
+
+use arrow::{datatypes::SchemaRef, record_batch::RecordBatch};
+use serde::Serialize;
+
+use crate::{
+    modelruntime::{Error, Runnable, TensorInfo},
+    timing::now_millis,
+};
+
+/// Counts the runs of a model, over every way it's run.
+#[derive(Debug, Default)]
+pub struct InferenceStats {
+    runs: AtomicU64,
+    errors: AtomicU64,
+    rows: AtomicU64,
+    total_latency_us: AtomicU64,
+    max_latency_us: AtomicU64,
+    last_run_time: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InferenceStatsSnapshot {
+    pub runs: u64,
+    pub errors: u64,
+    /// Predictions returned by the model.
+    pub rows: u64,
+    pub mean_latency_ms: Option<f64>,
+    pub max_latency_ms: Option<f64>,
+    /// Predictions returned for each second spent running the model.
+    pub rows_per_second: Option<f64>,
+    /// Milliseconds since the Unix epoch at which the model last ran.
+    pub last_run_time: Option<u64>,
+    /// The most recent error running the model.
+    pub last_error: Option<String>,
+}
+
+impl InferenceStats {
+    fn record(&self, started: Instant, result: &Result<RecordBatch, Error>) {
+        let latency_us = u64::try_from(started.elapsed().as_micros()).unwrap_or(u64::MAX);
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        self.total_latency_us
+            .fetch_add(latency_us, Ordering::Relaxed);
+        self.max_latency_us.fetch_max(latency_us, Ordering::Relaxed);
+        self.last_run_time.store(now_millis(), Ordering::Relaxed);
+
+        match result {
+            Ok(predictions) => {
+                let rows = u64::try_from(predictions.num_rows()).unwrap_or(u64::MAX);
+                self.rows.fetch_add(rows, Ordering::Relaxed);
+            }
+            Err(e) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                *self.lock_last_error() = Some(e.to_string());
+            }
+        }
+    }
+
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn snapshot(&self) -> InferenceStatsSnapshot {
+        let runs = self.runs.load(Ordering::Relaxed);
+        let rows = self.rows.load(Ordering::Relaxed);
+        let total_latency_us = self.total_latency_us.load(Ordering::Relaxed);
+        let last_run_time = self.last_run_time.load(Ordering::Relaxed);
+
+        InferenceStatsSnapshot {
+            runs,
+            errors: self.errors.load(Ordering::Relaxed),
+            rows,
+            mean_latency_ms: (runs > 0).then(|| total_latency_us as f64 / runs as f64 / 1000.0),
+            max_latency_ms: (runs > 0)
+                .then(|| self.max_latency_us.load(Ordering::Relaxed) as f64 / 1000.0),
+            rows_per_second: (total_latency_us > 0)
+                .then(|| rows as f64 / (total_latency_us as f64 / 1_000_000.0)),
+            last_run_time: (last_run_time > 0).then_some(last_run_time),
+            last_error: self.lock_last_error().clone(),
+        }
+    }
+
+    fn lock_last_error(&self) -> MutexGuard<'_, Option<String>> {
+        match self.last_error.lock() {
+            Ok(last_error) => last_error,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// Records the runs of the model it wraps.
+pub(crate) struct MeasuredRunnable {
+    pub runnable: Box<dyn Runnable>,
+    pub stats: Arc<InferenceStats>,
+}
+
+impl Runnable for MeasuredRunnable {
+    fn run(&self, input: Vec<RecordBatch>, loopback_size: usize) -> Result<RecordBatch, Error> {
+        let started = Instant::now();
+        let result = self.runnable.run(input, loopback_size);
+        self.stats.record(started, &result);
+        result
+    }
+
+    fn output_schema(&self) -> SchemaRef {
+        self.runnable.output_schema()
+    }
+
+    fn inputs(&self) -> Vec<TensorInfo> {
+        self.runnable.inputs()
+    }
+
+    fn outputs(&self) -> Vec<TensorInfo> {
+        self.runnable.outputs()
+    }
+
+    fn dry_run(&self) -> Result<(), Error> {
+        self.runnable.dry_run()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::datatypes::Schema;
+
+    #[test]
+    fn test_snapshot() {
+        let stats = InferenceStats::default();
+        assert_eq!(stats.snapshot().mean_latency_ms, None);
+
+        let predictions = RecordBatch::new_empty(Arc::new(Schema::empty()));
+        stats.record(Instant::now(), &Ok(predictions));
+        stats.record(Instant::now(), &Err("unable to run".into()));
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.runs, 2);
+        assert_eq!(snapshot.errors, 1);
+        assert_eq!(snapshot.last_error.as_deref(), Some("unable to run"));
+        assert!(snapshot.mean_latency_ms.is_some());
+        assert!(snapshot.last_run_time.is_some());
+    }
+}
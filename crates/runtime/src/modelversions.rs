@@ -0,0 +1,200 @@
+/*
+Copyright 2024 The Spice.ai OSS Authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! The loaded versions of a model, and how requests are split between them.
+//!
+//! Requests are split between the versions that aren't shadows by their `traffic`. Shadow
+//! versions run on the same requests after they've been served, and their outputs are kept for
+//! comparison instead of being returned.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use arrow::record_batch::RecordBatch;
+
+use crate::model::Model;
+
+/// The number of shadow runs kept per model.
+const SHADOW_RUNS_CAPACITY: usize = 32;
+
+/// The number of output rows kept per shadow run.
+pub const SHADOW_RUN_MAX_ROWS: usize = 1000;
+
+/// The outputs of a shadow version for a request served by another version.
+#[derive(Debug, Clone)]
+pub struct ShadowRun {
+    pub version: String,
+    /// The version that served the request.
+    pub served_version: String,
+    /// Milliseconds since the Unix epoch at which the shadow version finished running.
+    pub timestamp: u64,
+    pub duration_ms: u64,
+    /// The first rows of the outputs, or why the shadow version failed.
+    pub result: Result<RecordBatch, String>,
+}
+
+/// The loaded versions of a model. Clones share which requests were served by which version, and
+/// the recorded shadow runs.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Clone, Default)]
+pub struct ModelVersions {
+    /// In the order they were first loaded.
+    versions: Vec<Model>,
+    requests: Arc<AtomicU64>,
+    shadow_runs: Arc<Mutex<VecDeque<ShadowRun>>>,
+}
+
+impl ModelVersions {
+    /// Adds `model`, atomically replacing the loaded model with the same version.
+    pub fn insert(&mut self, model: Model) -> Option<Model> {
+        let version = model.version();
+        match self.versions.iter_mut().find(|m| m.version() == version) {
+            Some(loaded) => Some(std::mem::replace(loaded, model)),
+            None => {
+                self.versions.push(model);
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, version: &str) -> Option<Model> {
+        let position = self.versions.iter().position(|m| m.version() == version)?;
+        Some(self.versions.remove(position))
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.versions.is_empty()
+    }
+
+    #[must_use]
+    pub fn versions(&self) -> &[Model] {
+        &self.versions
+    }
+
+    /// Picks the version serving the next request, by the `traffic` of each version.
+    #[must_use]
+    pub fn route(&self) -> Option<&Model> {
+        let weights = self.weights();
+        let total = weights.iter().map(|(_, weight)| weight).sum::<u64>();
+        if total == 0 {
+            return None;
+        }
+
+        // Mixing the request count spreads each version's requests out, instead of serving runs of
+        // consecutive requests with the same version.
+        let request = self.requests.fetch_add(1, Ordering::Relaxed);
+        let mut position = mix(request) % total;
+        for (model, weight) in weights {
+            if position < weight {
+                return Some(model);
+            }
+            position -= weight;
+        }
+
+        None
+    }
+
+    /// The version serving the most requests, preferring the most recently loaded.
+    ///
+    /// SQL functions and scheduled predictions aren't split between versions, and use this one.
+    #[must_use]
+    pub fn primary(&self) -> Option<&Model> {
+        self.weights()
+            .into_iter()
+            .max_by_key(|(_, weight)| *weight)
+            .map(|(model, _)| model)
+    }
+
+    /// The versions run on requests without serving them.
+    pub fn shadows(&self) -> impl Iterator<Item = &Model> {
+        self.versions.iter().filter(|m| m.model.shadow)
+    }
+
+    pub fn record_shadow_run(&self, mut run: ShadowRun) {
+        if let Ok(outputs) = &mut run.result {
+            *outputs = outputs.slice(0, outputs.num_rows().min(SHADOW_RUN_MAX_ROWS));
+        }
+
+        let status = if run.result.is_ok() { "ok" } else { "error" };
+        metrics::counter!("model_shadow_runs", "version" => run.version.clone(), "status" => status)
+            .increment(1);
+
+        let mut shadow_runs = self.lock_shadow_runs();
+        if shadow_runs.len() == SHADOW_RUNS_CAPACITY {
+            shadow_runs.pop_front();
+        }
+        shadow_runs.push_back(run);
+    }
+
+    /// The most recent shadow runs, oldest first.
+    #[must_use]
+    pub fn shadow_runs(&self) -> Vec<ShadowRun> {
+        self.lock_shadow_runs().iter().cloned().collect()
+    }
+
+    /// The versions serving requests with their weights, omitting those serving none.
+    fn weights(&self) -> Vec<(&Model, u64)> {
+        let serving = self.versions.iter().filter(|m| !m.model.shadow);
+        let weighted = serving.clone().any(|m| m.model.traffic.is_some());
+
+        serving
+            .map(|m| {
+                let weight = if weighted {
+                    m.model.traffic.unwrap_or(0)
+                } else {
+                    1
+                };
+                (m, u64::from(weight))
+            })
+            .filter(|(_, weight)| *weight > 0)
+            .collect()
+    }
+
+    fn lock_shadow_runs(&self) -> MutexGuard<'_, VecDeque<ShadowRun>> {
+        match self.shadow_runs.lock() {
+            Ok(shadow_runs) => shadow_runs,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// The `SplitMix64` finalizer, which maps consecutive integers to well distributed ones.
+fn mix(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mix_spreads_requests() {
+        let total = 10;
+        let served = (0..1000)
+            .filter(|request| mix(*request) % total == 0)
+            .count();
+        assert!((50..150).contains(&served), "served {served} of 1000");
+    }
+}
@@ -54,8 +54,11 @@ use tonic_health::pb::health_server::Health;
 use tonic_health::pb::health_server::HealthServer;
 
 use crate::datafusion::DataFusion;
+use crate::datapublisher;
 use crate::dataupdate::DataUpdate;
 use crate::dataupdate::UpdateType;
+use crate::flight::channels::ChannelMap;
+use crate::wal::WriteAheadLog;
 use crate::{tracers::OnceTracer, warn_once};
 
 type Result<T, E = Error> = std::result::Result<T, E>;
@@ -101,6 +104,8 @@ const START_TIME_UNIX_NANO_COLUMN_NAME: &str = "start_time_unix_nano";
 pub struct Service {
     data_fusion: Arc<RwLock<DataFusion>>,
     once_tracer: OnceTracer,
+    wal: Option<Arc<WriteAheadLog>>,
+    channel_map: ChannelMap,
 }
 
 #[async_trait]
@@ -149,39 +154,29 @@ impl MetricsService for Service {
 
                                 let dataset = Arc::clone(&publishers.0);
                                 let data_publishers = Arc::clone(&publishers.1);
+                                drop(df);
 
                                 let data_update = DataUpdate {
                                     data: vec![record_batch],
                                     update_type: UpdateType::Append,
                                 };
 
-                                let mut all_publishers_failed = true;
-                                let data_publishers = data_publishers.read().await;
-                                for publisher in data_publishers.iter() {
-                                    tracing::trace!(
-                                        "Adding OpenTelemetry data for {} to publisher {}",
-                                        metric.name.as_str(),
-                                        publisher.name()
+                                // We need to await the write here in case it adds new columns to
+                                // the schema and later metrics will need to respect that schema.
+                                if let Err(e) = datapublisher::write(
+                                    &dataset,
+                                    &data_publishers,
+                                    self.wal.as_deref(),
+                                    &self.channel_map,
+                                    data_update,
+                                )
+                                .await
+                                {
+                                    // Rely on the publisher to provide a useful error message to the user.
+                                    tracing::debug!(
+                                        "Failed to add OpenTelemetry data for {}: {e}",
+                                        metric.name
                                     );
-                                    // We need to await the Future here in case it adds new columns to the schema and later metrics will need
-                                    // to respect that schema.
-                                    match publisher
-                                        .add_data(Arc::clone(&dataset), data_update.clone())
-                                        .await
-                                    {
-                                        Ok(()) => {
-                                            all_publishers_failed = false;
-                                        }
-                                        Err(e) => {
-                                            // Rely on the publisher to provide a useful error message to the user.
-                                            tracing::debug!(
-                                                "Failed to add OpenTelemetry data to backend: {e}"
-                                            );
-                                        }
-                                    }
-                                }
-
-                                if all_publishers_failed {
                                     rejected_data_points += data_points_count;
                                 }
                             }
@@ -600,10 +595,17 @@ fn append_null(
     }
 }
 
-pub async fn start(bind_address: SocketAddr, data_fusion: Arc<RwLock<DataFusion>>) -> Result<()> {
+pub async fn start(
+    bind_address: SocketAddr,
+    data_fusion: Arc<RwLock<DataFusion>>,
+    wal: Option<Arc<WriteAheadLog>>,
+    channel_map: ChannelMap,
+) -> Result<()> {
     let service = Service {
         data_fusion,
         once_tracer: OnceTracer::new(),
+        wal,
+        channel_map,
     };
     let svc = MetricsServiceServer::new(service).accept_compressed(CompressionEncoding::Gzip);
 
@@ -19,7 +19,7 @@ use notify::{
     EventKind, RecursiveMode, Watcher,
 };
 use spicepod::component::ComponentOrReference;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tokio::sync::mpsc::{channel, Receiver};
 
 use app::App;
@@ -38,6 +38,12 @@ impl PodsWatcher {
         }
     }
 
+    /// The directory containing the root `spicepod.yaml`.
+    #[must_use]
+    pub fn root_path(&self) -> &Path {
+        &self.root_path
+    }
+
     pub fn watch(&mut self) -> notify::Result<Receiver<App>> {
         let root_path = self.root_path.clone();
 
@@ -0,0 +1,362 @@
+/*
+Copyright 2024 The Spice.ai OSS Authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Runs models on a schedule or as their dataset is updated, writing the predictions to another
+//! dataset.
+//!
+//! A scheduled run predicts over all of the model's dataset, so its predictions replace those of
+//! earlier runs. The predictions for an update of the dataset are appended.
+//!
+//! Predictions are written like data received through Flight `DoPut`, with `datapublisher::write`.
+
+use std::sync::Arc;
+
+use arrow::{
+    array::{StringArray, TimestampMillisecondArray},
+    compute::concat_batches,
+    datatypes::{DataType, Field, Schema, TimeUnit},
+    error::ArrowError,
+    record_batch::RecordBatch,
+};
+use datafusion::{
+    error::DataFusionError, execution::SendableRecordBatchStream,
+    physical_plan::memory::MemoryStream,
+};
+use futures::StreamExt;
+use snafu::prelude::*;
+use spicepod::component::model::predictions::Predictions;
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        RwLock,
+    },
+    task::JoinHandle,
+    time::{Instant, Interval, MissedTickBehavior},
+};
+
+use crate::{
+    datafusion::DataFusion,
+    datapublisher::{self, DatasetUpdate},
+    dataupdate::{DataUpdate, UpdateType},
+    flight::channels::ChannelMap,
+    model::{self, InferenceMode, InferenceOptions, Model},
+    timing::now_millis,
+    wal::WriteAheadLog,
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Unable to query the model's input: {source}"))]
+    UnableToQuery { source: DataFusionError },
+
+    #[snafu(display("Unable to run the model: {source}"))]
+    UnableToRunModel { source: model::Error },
+
+    #[snafu(display("Unable to build the predictions: {source}"))]
+    UnableToBuildPredictions { source: ArrowError },
+
+    #[snafu(display("Dataset {dataset} doesn't accept writes"))]
+    DatasetNotWritable { dataset: String },
+
+    #[snafu(display("Unable to write predictions to {dataset}: {source}"))]
+    UnableToWritePredictions {
+        dataset: String,
+        source: datapublisher::Error,
+    },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Starts writing the predictions of `model`, if it's configured to, until the task is aborted.
+pub(crate) fn spawn(
+    model: &Model,
+    df: Arc<RwLock<DataFusion>>,
+    channel_map: ChannelMap,
+    wal: Option<Arc<WriteAheadLog>>,
+) -> Option<JoinHandle<()>> {
+    let name = &model.model.name;
+    let config = model.model.predictions.clone()?;
+    let Some(input_dataset) = model.model.datasets.first().cloned() else {
+        tracing::warn!("Model {name} has no dataset to run predictions on");
+        return None;
+    };
+    if input_dataset == config.dataset {
+        tracing::warn!("Model {name} can't write predictions to {input_dataset}, which it reads");
+        return None;
+    }
+    if config.every().is_none() && !config.on_update {
+        tracing::warn!("Model {name} has predictions configured without `every` or `on_update`");
+        return None;
+    }
+
+    let writer = PredictionWriter {
+        model: model.clone(),
+        config,
+        input_dataset,
+        df,
+        channel_map,
+        wal,
+    };
+    Some(tokio::spawn(writer.run()))
+}
+
+struct PredictionWriter {
+    model: Model,
+    config: Predictions,
+    input_dataset: String,
+    df: Arc<RwLock<DataFusion>>,
+    channel_map: ChannelMap,
+    wal: Option<Arc<WriteAheadLog>>,
+}
+
+impl PredictionWriter {
+    async fn run(self) {
+        // The first run waits a full interval, so the model's dataset can load first.
+        let mut interval = self.config.every().map(|every| {
+            let mut interval = tokio::time::interval_at(Instant::now() + every, every);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            interval
+        });
+        let mut updates = if self.config.on_update {
+            Some(self.df.read().await.subscribe_data_updates())
+        } else {
+            None
+        };
+        // The last rows of earlier updates, which start the windows completed by the next one.
+        let mut carried = None;
+
+        while interval.is_some() || updates.is_some() {
+            tokio::select! {
+                () = tick(&mut interval) => {
+                    self.report(self.predict_dataset().await);
+                }
+                update = next_update(&mut updates) => match update {
+                    Ok(update) if update.dataset == self.input_dataset => {
+                        self.report(self.predict_update(update.data_update, &mut carried).await);
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!(
+                            "Model {} missed {skipped} dataset updates to predict on",
+                            self.model.model.name
+                        );
+                        carried = None;
+                    }
+                    Err(RecvError::Closed) => updates = None,
+                },
+            }
+        }
+    }
+
+    /// Runs the model over all of its dataset, replacing the earlier predictions.
+    async fn predict_dataset(&self) -> Result<usize> {
+        let input = {
+            let df = self.df.read().await;
+            let data_frame = df
+                .ctx
+                .sql(&self.model.input_query(&self.input_dataset))
+                .await
+                .context(UnableToQuerySnafu)?;
+            df.execute_stream(data_frame)
+                .await
+                .context(UnableToQuerySnafu)?
+        };
+
+        self.predict(input, UpdateType::Overwrite).await
+    }
+
+    /// Runs the model over the rows of `data_update`, following the rows carried from earlier
+    /// updates.
+    async fn predict_update(
+        &self,
+        data_update: DataUpdate,
+        carried: &mut Option<RecordBatch>,
+    ) -> Result<usize> {
+        if data_update.update_type == UpdateType::Overwrite {
+            *carried = None;
+        }
+        let Some(schema) = data_update.data.first().map(RecordBatch::schema) else {
+            return Ok(0);
+        };
+
+        let rows = carried
+            .take()
+            .into_iter()
+            .chain(data_update.data)
+            .collect::<Vec<_>>();
+        let rows = concat_batches(&schema, &rows).context(UnableToBuildPredictionsSnafu)?;
+        if let Some(window) = self.model.window() {
+            let kept = rows.num_rows().min(window.saturating_sub(1));
+            *carried = Some(rows.slice(rows.num_rows() - kept, kept));
+        }
+
+        let input = MemoryStream::try_new(vec![rows], schema, None).context(UnableToQuerySnafu)?;
+        self.predict(Box::pin(input), UpdateType::Append).await
+    }
+
+    /// Runs the model over `input` and writes the predictions, returning how many were written.
+    ///
+    /// Appended predictions are written as they're made. Overwriting predictions are written once
+    /// all are made, so the dataset never holds only some of them.
+    async fn predict(
+        &self,
+        input: SendableRecordBatchStream,
+        update_type: UpdateType,
+    ) -> Result<usize> {
+        let options = InferenceOptions {
+            mode: if self.model.window().is_some() {
+                InferenceMode::Window
+            } else {
+                InferenceMode::Row
+            },
+            keys: self.config.keys.clone(),
+            window: None,
+            stride: 1,
+            batch_size: self.df.read().await.ctx.state().config().batch_size(),
+        };
+        let version = self.model.version();
+        #[allow(clippy::cast_possible_wrap)]
+        let predicted_at = now_millis() as i64;
+
+        let mut predictions = self.model.infer(input, options);
+        let mut written = 0;
+        let mut pending = vec![];
+        while let Some(batch) = predictions.next().await {
+            let batch = batch.context(UnableToRunModelSnafu)?;
+            written += batch.num_rows();
+            let batch = with_version(&batch, &version, predicted_at)?;
+            match update_type {
+                UpdateType::Append => self.write(vec![batch], UpdateType::Append).await?,
+                UpdateType::Overwrite => pending.push(batch),
+            }
+        }
+        if !pending.is_empty() {
+            self.write(pending, UpdateType::Overwrite).await?;
+        }
+
+        Ok(written)
+    }
+
+    async fn write(&self, data: Vec<RecordBatch>, update_type: UpdateType) -> Result<()> {
+        let name = &self.config.dataset;
+        let (dataset, publishers) = {
+            let df = self.df.read().await;
+            let Some((dataset, publishers)) = df.get_publishers(name) else {
+                return DatasetNotWritableSnafu { dataset: name }.fail();
+            };
+            (Arc::clone(dataset), Arc::clone(publishers))
+        };
+
+        let data_update = DataUpdate { data, update_type };
+        datapublisher::write(
+            &dataset,
+            &publishers,
+            self.wal.as_deref(),
+            &self.channel_map,
+            data_update,
+        )
+        .await
+        .context(UnableToWritePredictionsSnafu { dataset: name })
+    }
+
+    fn report(&self, result: Result<usize>) {
+        let name = self.model.model.name.clone();
+        match result {
+            Ok(written) => {
+                metrics::counter!("model_predictions_written", "model" => name)
+                    .increment(u64::try_from(written).unwrap_or(u64::MAX));
+            }
+            Err(e) => {
+                metrics::counter!("model_predictions_errors", "model" => name.clone()).increment(1);
+                tracing::warn!("Unable to write predictions of model {name}: {e}");
+            }
+        }
+    }
+}
+
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+async fn next_update(
+    updates: &mut Option<broadcast::Receiver<DatasetUpdate>>,
+) -> Result<DatasetUpdate, RecvError> {
+    match updates {
+        Some(updates) => updates.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Adds the version of the model and the time of the run to each prediction.
+fn with_version(batch: &RecordBatch, version: &str, predicted_at: i64) -> Result<RecordBatch> {
+    let num_rows = batch.num_rows();
+    let schema = batch.schema();
+    let fields = schema
+        .fields()
+        .iter()
+        .cloned()
+        .chain([
+            Arc::new(Field::new("model_version", DataType::Utf8, false)),
+            Arc::new(Field::new(
+                "predicted_at",
+                DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+                false,
+            )),
+        ])
+        .collect::<Vec<_>>();
+
+    let mut columns = batch.columns().to_vec();
+    columns.push(Arc::new(StringArray::from(vec![version; num_rows])));
+    columns.push(Arc::new(
+        TimestampMillisecondArray::from(vec![predicted_at; num_rows]).with_timezone("UTC"),
+    ));
+
+    RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)
+        .context(UnableToBuildPredictionsSnafu)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::Float32Array;
+
+    #[test]
+    fn test_with_version() {
+        let schema = Arc::new(Schema::new(vec![Field::new("y", DataType::Float32, false)]));
+        let batch =
+            RecordBatch::try_new(schema, vec![Arc::new(Float32Array::from(vec![0.5, 1.5]))])
+                .expect("valid record batch");
+
+        let batch = with_version(&batch, "v2", 1_700_000_000_000).expect("valid predictions");
+        assert_eq!(batch.num_columns(), 3);
+        assert_eq!(batch.schema().field(1).name(), "model_version");
+        let versions = batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .expect("string column");
+        assert_eq!(versions.value(1), "v2");
+        assert_eq!(
+            batch.column(2).data_type(),
+            &DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into()))
+        );
+    }
+}
@@ -0,0 +1,411 @@
+/*
+Copyright 2024 The Spice.ai OSS Authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Timeouts and concurrency limits for queries received over HTTP and Flight.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    future::Future,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use async_stream::stream;
+use datafusion::{
+    error::DataFusionError, execution::SendableRecordBatchStream,
+    physical_plan::stream::RecordBatchStreamAdapter,
+};
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use snafu::prelude::*;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+
+use crate::config;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Query timed out after {}ms", timeout.as_millis()))]
+    QueryTimedOut { timeout: Duration },
+
+    #[snafu(display("Too many queries are running or queued, try again later"))]
+    TooManyQueries,
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Who is running a query and how long it may run for.
+#[derive(Debug, Clone)]
+pub struct QueryOptions {
+    /// Either `http` or `flight`, used to label metrics.
+    pub protocol: &'static str,
+    /// Identifies the client for the per-principal concurrency limit, see `principal`.
+    pub principal: String,
+    /// Overrides the runtime's `--query_timeout` for this query.
+    pub timeout: Option<Duration>,
+}
+
+/// Identifies a client by its address and a digest of the credential it sent, if any, so the
+/// credential itself isn't kept.
+///
+/// The runtime doesn't verify the credential, so a client gets a separate limit for each one it
+/// sends: the per-principal limit is only advisory unless requests are authenticated before they
+/// reach the runtime.
+#[must_use]
+pub fn principal(client_ip: Option<IpAddr>, credential: Option<&[u8]>) -> String {
+    let mut principal = client_ip.map(|ip| ip.to_string()).unwrap_or_default();
+    if let Some(credential) = credential {
+        principal.push('/');
+        for byte in Sha256::digest(credential) {
+            let _ = write!(principal, "{byte:02x}");
+        }
+    }
+    principal
+}
+
+/// Limits shared by all queries received by the runtime.
+pub struct QueryLimits {
+    default_timeout: Option<Duration>,
+    running: Option<Arc<Semaphore>>,
+    per_principal_limit: Option<usize>,
+    principals: Mutex<HashMap<String, Arc<Semaphore>>>,
+    max_queued: usize,
+    queued: AtomicUsize,
+}
+
+impl QueryLimits {
+    #[must_use]
+    pub fn new(config: &config::Config) -> Self {
+        Self {
+            default_timeout: config.query_timeout,
+            running: config
+                .max_concurrent_queries
+                .map(|limit| Arc::new(Semaphore::new(limit))),
+            per_principal_limit: config.max_concurrent_queries_per_principal,
+            principals: Mutex::new(HashMap::new()),
+            max_queued: config.max_queued_queries,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// Waits for the query to be allowed to run, rejecting it if too many queries are already
+    /// waiting. Time spent waiting counts towards the query's timeout.
+    pub async fn start(&self, options: QueryOptions) -> Result<Query> {
+        let timeout = options.timeout.or(self.default_timeout);
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        // The principal's own limit is waited on first, so its queued queries don't hold slots
+        // that other principals could use.
+        let principal_permit = match self.principal_semaphore(&options.principal) {
+            Some(semaphore) => Some(self.acquire(semaphore, deadline, timeout, &options).await?),
+            None => None,
+        };
+        let running_permit = match &self.running {
+            Some(semaphore) => Some(
+                self.acquire(Arc::clone(semaphore), deadline, timeout, &options)
+                    .await?,
+            ),
+            None => None,
+        };
+
+        metrics::gauge!("queries_running", "protocol" => options.protocol).increment(1.0);
+
+        Ok(Query {
+            protocol: options.protocol,
+            timeout,
+            deadline,
+            outcome: Outcome::Running,
+            _permits: (principal_permit, running_permit),
+        })
+    }
+
+    fn principal_semaphore(&self, principal: &str) -> Option<Arc<Semaphore>> {
+        let limit = self.per_principal_limit?;
+        let mut principals = self.lock_principals();
+        // Permits hold a reference to their semaphore, so unreferenced semaphores are idle.
+        principals.retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+        Some(Arc::clone(
+            principals
+                .entry(principal.to_string())
+                .or_insert_with(|| Arc::new(Semaphore::new(limit))),
+        ))
+    }
+
+    async fn acquire(
+        &self,
+        semaphore: Arc<Semaphore>,
+        deadline: Option<Instant>,
+        timeout: Option<Duration>,
+        options: &QueryOptions,
+    ) -> Result<OwnedSemaphorePermit> {
+        if let Ok(permit) = Arc::clone(&semaphore).try_acquire_owned() {
+            return Ok(permit);
+        }
+
+        let Some(_queued) = Queued::try_new(&self.queued, self.max_queued, options.protocol) else {
+            metrics::counter!("queries_rejected", "protocol" => options.protocol).increment(1);
+            return TooManyQueriesSnafu.fail();
+        };
+
+        let permit = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, semaphore.acquire_owned())
+                .await
+                .ok(),
+            None => Some(semaphore.acquire_owned().await),
+        };
+
+        match (permit, timeout) {
+            (Some(Ok(permit)), _) => Ok(permit),
+            // The semaphores are never closed.
+            (Some(Err(_)), _) => TooManyQueriesSnafu.fail(),
+            (None, timeout) => {
+                metrics::counter!("queries_timed_out", "protocol" => options.protocol).increment(1);
+                QueryTimedOutSnafu {
+                    timeout: timeout.unwrap_or_default(),
+                }
+                .fail()
+            }
+        }
+    }
+
+    fn lock_principals(&self) -> MutexGuard<'_, HashMap<String, Arc<Semaphore>>> {
+        match self.principals.lock() {
+            Ok(principals) => principals,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// Counts a query waiting for a permit for as long as it's waiting, including if it's cancelled.
+struct Queued<'a> {
+    queued: &'a AtomicUsize,
+    protocol: &'static str,
+}
+
+impl<'a> Queued<'a> {
+    fn try_new(queued: &'a AtomicUsize, max_queued: usize, protocol: &'static str) -> Option<Self> {
+        if queued.fetch_add(1, Ordering::SeqCst) >= max_queued {
+            queued.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        metrics::gauge!("queries_queued", "protocol" => protocol).increment(1.0);
+        Some(Self { queued, protocol })
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        metrics::gauge!("queries_queued", "protocol" => self.protocol).decrement(1.0);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Running,
+    Finished,
+    TimedOut,
+}
+
+/// A running query, which holds its concurrency permits until it's dropped.
+///
+/// A query dropped before it finished or timed out was cancelled, i.e. because the client
+/// disconnected.
+pub struct Query {
+    protocol: &'static str,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    outcome: Outcome,
+    _permits: (Option<OwnedSemaphorePermit>, Option<OwnedSemaphorePermit>),
+}
+
+impl Query {
+    /// Runs a step of the query, i.e. planning or collecting the results, within its timeout.
+    ///
+    /// A step that fails finishes the query.
+    pub async fn run<T>(
+        &mut self,
+        f: impl Future<Output = Result<T, DataFusionError>>,
+    ) -> Result<T, DataFusionError> {
+        let result = match self.deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, f).await {
+                Ok(result) => result,
+                Err(_) => return Err(self.timed_out()),
+            },
+            None => f.await,
+        };
+
+        if result.is_err() {
+            self.finish();
+        }
+        result
+    }
+
+    /// Applies the query's timeout to its result stream, which keeps the query running until the
+    /// stream ends or is dropped.
+    #[must_use]
+    pub fn stream(self, mut batches: SendableRecordBatchStream) -> SendableRecordBatchStream {
+        let schema = batches.schema();
+        let deadline = self.deadline;
+        let mut query = self;
+
+        Box::pin(RecordBatchStreamAdapter::new(
+            schema,
+            stream! {
+                let timeout = async move {
+                    match deadline {
+                        Some(deadline) => tokio::time::sleep_until(deadline).await,
+                        None => std::future::pending().await,
+                    }
+                };
+                tokio::pin!(timeout);
+
+                loop {
+                    let batch = tokio::select! {
+                        batch = batches.next() => batch,
+                        () = &mut timeout => Some(Err(query.timed_out())),
+                    };
+                    match batch {
+                        Some(Ok(batch)) => yield Ok(batch),
+                        Some(Err(e)) => {
+                            query.finish();
+                            yield Err(e);
+                        }
+                        None => break,
+                    }
+                    if query.outcome == Outcome::TimedOut {
+                        return;
+                    }
+                }
+
+                query.finish();
+            },
+        ))
+    }
+
+    /// Marks the query as having run to completion, successfully or not.
+    pub fn finish(&mut self) {
+        if self.outcome == Outcome::Running {
+            self.outcome = Outcome::Finished;
+        }
+    }
+
+    fn timed_out(&mut self) -> DataFusionError {
+        self.outcome = Outcome::TimedOut;
+        DataFusionError::External(Box::new(Error::QueryTimedOut {
+            timeout: self.timeout.unwrap_or_default(),
+        }))
+    }
+}
+
+impl Drop for Query {
+    fn drop(&mut self) {
+        metrics::gauge!("queries_running", "protocol" => self.protocol).decrement(1.0);
+        match self.outcome {
+            Outcome::Running => {
+                metrics::counter!("queries_cancelled", "protocol" => self.protocol).increment(1);
+            }
+            Outcome::TimedOut => {
+                metrics::counter!("queries_timed_out", "protocol" => self.protocol).increment(1);
+            }
+            Outcome::Finished => {}
+        }
+    }
+}
+
+/// Returns the `querylimits::Error` a query failed with, if it was stopped by its limits.
+#[must_use]
+pub fn find_error(e: &DataFusionError) -> Option<&Error> {
+    match e.find_root() {
+        DataFusionError::External(e) => e.downcast_ref::<Error>(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(max_concurrent: usize, max_queued: usize) -> QueryLimits {
+        QueryLimits {
+            default_timeout: Some(Duration::from_millis(50)),
+            running: Some(Arc::new(Semaphore::new(max_concurrent))),
+            per_principal_limit: None,
+            principals: Mutex::new(HashMap::new()),
+            max_queued,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    fn options() -> QueryOptions {
+        QueryOptions {
+            protocol: "http",
+            principal: "test".to_string(),
+            timeout: None,
+        }
+    }
+
+    #[test]
+    fn test_principal_keeps_no_credential() {
+        let ip = Some(IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(principal(ip, None), "127.0.0.1");
+
+        let with_credential = principal(ip, Some(b"Bearer secret"));
+        assert!(with_credential.starts_with("127.0.0.1/"));
+        assert!(!with_credential.contains("secret"));
+        assert_ne!(with_credential, principal(ip, Some(b"Bearer other")));
+        assert_ne!(
+            with_credential,
+            principal(Some(IpAddr::from([127, 0, 0, 2])), Some(b"Bearer secret"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_queued_query_times_out() {
+        let limits = limits(1, 1);
+        let _running = limits.start(options()).await.expect("query starts");
+
+        let queued = limits.start(options()).await;
+        assert!(matches!(queued, Err(Error::QueryTimedOut { .. })));
+        assert_eq!(limits.queued.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_rejects_query() {
+        let limits = limits(1, 0);
+        let _running = limits.start(options()).await.expect("query starts");
+
+        let rejected = limits.start(options()).await;
+        assert!(matches!(rejected, Err(Error::TooManyQueries)));
+    }
+
+    #[tokio::test]
+    async fn test_finished_query_releases_permit() {
+        let limits = limits(1, 0);
+        let mut query = limits.start(options()).await.expect("query starts");
+        query.finish();
+        drop(query);
+
+        assert!(limits.start(options()).await.is_ok());
+    }
+}
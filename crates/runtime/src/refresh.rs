@@ -0,0 +1,489 @@
+/*
+Copyright 2024 The Spice.ai OSS Authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! On-demand refreshes of accelerated datasets, outside of their `refresh_interval`.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use arrow::{
+    datatypes::{Schema, SchemaRef},
+    record_batch::RecordBatch,
+};
+use datafusion::{
+    common::DFSchema, dataframe::DataFrame, datasource::MemTable, error::DataFusionError,
+    execution::context::SessionContext, logical_expr::Expr, sql::TableReference,
+};
+use serde::{Deserialize, Serialize};
+use snafu::prelude::*;
+use spicepod::component::dataset::Dataset;
+
+use crate::{
+    dataconnector::DataConnector,
+    datafusion::PublisherList,
+    datapublisher::DataPublisher,
+    dataupdate::{DataUpdate, UpdateType},
+    status,
+    timing::now_millis,
+};
+
+/// The number of refreshes tracked before the oldest finished refresh is forgotten. Queued and
+/// running refreshes are always tracked.
+const RETAINED_REFRESHES: usize = 1000;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Unable to read the accelerated table: {source}"))]
+    UnableToReadTable { source: DataFusionError },
+
+    #[snafu(display("Unable to apply the refresh filter: {source}"))]
+    UnableToFilterData { source: DataFusionError },
+
+    #[snafu(display("Unable to read the data connector's table: {source}"))]
+    UnableToReadSource { source: crate::dataconnector::Error },
+
+    #[snafu(display("Unable to write the refreshed data: {reason}"))]
+    UnableToWriteData { reason: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A refresh requested for a dataset, handled by the task that loads the dataset from its connector.
+pub struct Request {
+    pub id: String,
+    /// Only rows matching this filter are refreshed; all other rows are kept as they are.
+    pub filter: Option<Expr>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+}
+
+impl State {
+    #[must_use]
+    pub fn is_finished(self) -> bool {
+        matches!(self, State::Completed | State::Failed)
+    }
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshStatus {
+    pub id: String,
+    pub dataset: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sql: Option<String>,
+    pub state: State,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completed_at: Option<u64>,
+    /// The number of rows written from the source, i.e. only those matching `sql` if it's set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rows: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Default)]
+struct TrackerState {
+    refreshes: HashMap<String, RefreshStatus>,
+    order: VecDeque<String>,
+}
+
+/// Tracks the progress of requested refreshes so they can be polled by id.
+#[derive(Default)]
+pub struct Tracker {
+    state: Mutex<TrackerState>,
+}
+
+impl Tracker {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts tracking a new refresh of `dataset`.
+    #[must_use]
+    pub fn queue(&self, dataset: &str, sql: Option<String>) -> RefreshStatus {
+        let refresh = RefreshStatus {
+            id: uuid::Uuid::new_v4().to_string(),
+            dataset: dataset.to_string(),
+            sql,
+            state: State::Queued,
+            created_at: now_millis(),
+            started_at: None,
+            completed_at: None,
+            rows: None,
+            error: None,
+        };
+
+        let mut guard = self.lock_state();
+        let state = &mut *guard;
+        if state.order.len() >= RETAINED_REFRESHES {
+            let oldest_finished = state.order.iter().position(|id| {
+                state
+                    .refreshes
+                    .get(id)
+                    .map_or(true, |refresh| refresh.state.is_finished())
+            });
+            if let Some(oldest) = oldest_finished.and_then(|i| state.order.remove(i)) {
+                state.refreshes.remove(&oldest);
+            }
+        }
+        state.order.push_back(refresh.id.clone());
+        state.refreshes.insert(refresh.id.clone(), refresh.clone());
+
+        refresh
+    }
+
+    #[must_use]
+    pub fn get(&self, id: &str) -> Option<RefreshStatus> {
+        self.lock_state().refreshes.get(id).cloned()
+    }
+
+    /// Stops tracking a refresh that could not be queued.
+    pub fn remove(&self, id: &str) {
+        let mut state = self.lock_state();
+        state.refreshes.remove(id);
+        state.order.retain(|queued_id| queued_id != id);
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut RefreshStatus)) {
+        if let Some(refresh) = self.lock_state().refreshes.get_mut(id) {
+            f(refresh);
+        }
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, TrackerState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// Refreshes `dataset` from `data_connector` into `publisher`, recording its progress in `tracker`.
+///
+/// `writers` are the publishers the runtime writes other data to the dataset through, if any.
+pub(crate) async fn run(
+    ctx: &SessionContext,
+    dataset: &Arc<Dataset>,
+    data_connector: &dyn DataConnector,
+    publisher: &dyn DataPublisher,
+    writers: Option<&PublisherList>,
+    tracker: &Tracker,
+    request: Request,
+) {
+    tracing::info!("Refreshing data for {} on request", dataset.name);
+    tracker.update(&request.id, |refresh| {
+        refresh.state = State::Running;
+        refresh.started_at = Some(now_millis());
+    });
+    status::start_dataset_refresh(&dataset.name);
+
+    let result = refresh(
+        ctx,
+        dataset,
+        data_connector,
+        publisher,
+        writers,
+        request.filter.as_ref(),
+    )
+    .await;
+
+    match result {
+        Ok(rows) => {
+            status::update_dataset(dataset.name.clone(), status::ComponentStatus::Ready);
+            status::record_dataset_refresh(&dataset.name);
+            tracker.update(&request.id, |refresh| {
+                refresh.state = State::Completed;
+                refresh.completed_at = Some(now_millis());
+                refresh.rows = Some(rows);
+            });
+        }
+        Err(e) => {
+            tracing::error!("Unable to refresh dataset {}: {e}", dataset.name);
+            status::update_dataset(dataset.name.clone(), status::ComponentStatus::Error);
+            status::record_dataset_error(&dataset.name, e.to_string());
+            tracker.update(&request.id, |refresh| {
+                refresh.state = State::Failed;
+                refresh.completed_at = Some(now_millis());
+                refresh.error = Some(e.to_string());
+            });
+        }
+    }
+}
+
+/// Parses the SQL filter `sql` of a refresh of the accelerated table `dataset_name`, with the
+/// session's SQL dialect.
+pub(crate) async fn parse_filter(
+    ctx: &SessionContext,
+    dataset_name: &str,
+    sql: &str,
+) -> Result<Expr, DataFusionError> {
+    let table = ctx
+        .table(TableReference::bare(dataset_name.to_string()))
+        .await?;
+    // Unqualified, so the filter applies to the data connector's table as well.
+    let schema = DFSchema::try_from(Schema::from(table.schema()))?;
+    ctx.state().create_logical_expr(sql, &schema)
+}
+
+async fn refresh(
+    ctx: &SessionContext,
+    dataset: &Arc<Dataset>,
+    data_connector: &dyn DataConnector,
+    publisher: &dyn DataPublisher,
+    writers: Option<&PublisherList>,
+    filter: Option<&Expr>,
+) -> Result<usize> {
+    let Some(filter) = filter else {
+        let data = data_connector.get_all_data(dataset).await;
+        let rows = data.iter().map(RecordBatch::num_rows).sum();
+        overwrite(dataset, publisher, data).await?;
+        return Ok(rows);
+    };
+
+    let source = read_filtered(ctx, dataset, data_connector, filter).await?;
+    let rows = source.iter().map(RecordBatch::num_rows).sum();
+
+    // Writes through `datapublisher::write` hold a read lock on the dataset's publishers, so the
+    // rows they append can't be lost between reading the accelerated table and overwriting it.
+    let _writes = match writers {
+        Some(writers) => Some(writers.write().await),
+        None => None,
+    };
+    let data = merge_filtered(ctx, &dataset.name, source, filter).await?;
+    overwrite(dataset, publisher, data).await?;
+
+    Ok(rows)
+}
+
+async fn overwrite(
+    dataset: &Arc<Dataset>,
+    publisher: &dyn DataPublisher,
+    data: Vec<RecordBatch>,
+) -> Result<()> {
+    publisher
+        .add_data(
+            Arc::clone(dataset),
+            DataUpdate {
+                data,
+                update_type: UpdateType::Overwrite,
+            },
+        )
+        .await
+        .map_err(|e| Error::UnableToWriteData {
+            reason: e.to_string(),
+        })
+}
+
+/// Reads the rows of the source matching `filter`. The filter is pushed down to the data
+/// connector's table if it has one, otherwise all of the source is fetched and filtered here.
+async fn read_filtered(
+    ctx: &SessionContext,
+    dataset: &Dataset,
+    data_connector: &dyn DataConnector,
+    filter: &Expr,
+) -> Result<Vec<RecordBatch>> {
+    let source = if data_connector.has_table_provider() {
+        let table = data_connector
+            .get_table_provider(dataset)
+            .await
+            .context(UnableToReadSourceSnafu)?;
+        ctx.read_table(table).context(UnableToFilterDataSnafu)?
+    } else {
+        let data = data_connector.get_all_data(dataset).await;
+        let Some(schema) = data.first().map(RecordBatch::schema) else {
+            return Ok(data);
+        };
+        read_batches(ctx, schema, data)?
+    };
+
+    source
+        .filter(filter.clone())
+        .context(UnableToFilterDataSnafu)?
+        .collect()
+        .await
+        .context(UnableToFilterDataSnafu)
+}
+
+/// Replaces the rows of the accelerated table matching `filter` with `source`, the rows of the
+/// source matching it.
+async fn merge_filtered(
+    ctx: &SessionContext,
+    dataset_name: &str,
+    source: Vec<RecordBatch>,
+    filter: &Expr,
+) -> Result<Vec<RecordBatch>> {
+    let kept = ctx
+        .table(TableReference::bare(dataset_name.to_string()))
+        .await
+        .context(UnableToReadTableSnafu)?
+        .filter(filter.clone().is_not_true())
+        .context(UnableToFilterDataSnafu)?;
+
+    let merged = match source.first().map(RecordBatch::schema) {
+        Some(schema) => kept
+            .union(read_batches(ctx, schema, source)?)
+            .context(UnableToFilterDataSnafu)?,
+        None => kept,
+    };
+    merged.collect().await.context(UnableToFilterDataSnafu)
+}
+
+fn read_batches(
+    ctx: &SessionContext,
+    schema: SchemaRef,
+    batches: Vec<RecordBatch>,
+) -> Result<DataFrame> {
+    let table = MemTable::try_new(schema, vec![batches]).context(UnableToFilterDataSnafu)?;
+    ctx.read_table(Arc::new(table))
+        .context(UnableToFilterDataSnafu)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::{
+        array::{Int64Array, StringArray},
+        datatypes::{DataType, Field},
+    };
+
+    fn batch(ids: Vec<i64>, names: Vec<&str>) -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("name", DataType::Utf8, false),
+        ]));
+        RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int64Array::from(ids)),
+                Arc::new(StringArray::from(names)),
+            ],
+        )
+        .expect("valid record batch")
+    }
+
+    fn rows(batches: &[RecordBatch]) -> Vec<(i64, String)> {
+        let mut rows = vec![];
+        for batch in batches {
+            let ids = batch
+                .column(0)
+                .as_any()
+                .downcast_ref::<Int64Array>()
+                .expect("id column");
+            let names = batch
+                .column(1)
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .expect("name column");
+            for i in 0..batch.num_rows() {
+                rows.push((ids.value(i), names.value(i).to_string()));
+            }
+        }
+        rows.sort();
+        rows
+    }
+
+    fn context() -> SessionContext {
+        let ctx = SessionContext::new();
+        let current = batch(vec![1, 2, 3], vec!["a", "b", "c"]);
+        let table =
+            MemTable::try_new(current.schema(), vec![vec![current]]).expect("valid memory table");
+        ctx.register_table("test", Arc::new(table))
+            .expect("table registered");
+        ctx
+    }
+
+    #[tokio::test]
+    async fn test_merge_filtered_replaces_matching_rows() {
+        let ctx = context();
+        let filter = parse_filter(&ctx, "test", "id >= 2")
+            .await
+            .expect("valid filter");
+
+        // The source deleted row 3 and added row 4; row 1 doesn't match the filter and is kept.
+        let source = batch(vec![1, 2, 4], vec!["x", "y", "z"]);
+        let source = read_batches(&ctx, source.schema(), vec![source])
+            .expect("source table")
+            .filter(filter.clone())
+            .expect("filtered source")
+            .collect()
+            .await
+            .expect("source rows");
+
+        let merged = merge_filtered(&ctx, "test", source, &filter)
+            .await
+            .expect("merged rows");
+        assert_eq!(
+            rows(&merged),
+            vec![
+                (1, "a".to_string()),
+                (2, "y".to_string()),
+                (4, "z".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_merge_filtered_without_source_rows() {
+        let ctx = context();
+        let filter = parse_filter(&ctx, "test", "name = 'b'")
+            .await
+            .expect("valid filter");
+
+        let merged = merge_filtered(&ctx, "test", vec![], &filter)
+            .await
+            .expect("merged rows");
+        assert_eq!(
+            rows(&merged),
+            vec![(1, "a".to_string()), (3, "c".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_tracker_keeps_unfinished_refreshes() {
+        let tracker = Tracker::new();
+        let first = tracker.queue("test", None);
+        for _ in 1..RETAINED_REFRESHES {
+            let refresh = tracker.queue("test", None);
+            tracker.update(&refresh.id, |refresh| refresh.state = State::Completed);
+        }
+
+        let second = tracker.queue("test", None);
+        assert!(tracker.get(&first.id).is_some());
+        assert!(tracker.get(&second.id).is_some());
+        assert_eq!(tracker.lock_state().refreshes.len(), RETAINED_REFRESHES);
+    }
+
+    #[tokio::test]
+    async fn test_parse_filter_rejects_invalid_filters() {
+        let ctx = context();
+        assert!(parse_filter(&ctx, "test", "missing = 1").await.is_err());
+        assert!(parse_filter(&ctx, "test", "id =").await.is_err());
+    }
+}
@@ -0,0 +1,481 @@
+/*
+Copyright 2024 The Spice.ai OSS Authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Caches query results until one of the accelerated datasets they read is changed.
+//!
+//! Only queries that read nothing but accelerated datasets are cached, as the runtime can't tell
+//! when the data of a federated dataset changes at its source. Queries calling functions that
+//! aren't immutable, i.e. `now()`, `random()` or a model's `predict`, aren't cached either.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use arrow::{datatypes::SchemaRef, record_batch::RecordBatch};
+use async_stream::stream;
+use datafusion::{
+    common::tree_node::{TreeNode, VisitRecursion},
+    execution::SendableRecordBatchStream,
+    logical_expr::{
+        expr::{ScalarFunction, ScalarFunctionDefinition},
+        Exists, Expr, InSubquery, LogicalPlan, Volatility,
+    },
+    physical_plan::{memory::MemoryStream, stream::RecordBatchStreamAdapter},
+    sql::TableReference,
+};
+use futures::StreamExt;
+use spicepod::component::dataset::Dataset;
+
+use crate::{
+    datapublisher::{AddDataResult, DataPublisher},
+    dataupdate::DataUpdate,
+};
+
+struct Entry {
+    schema: SchemaRef,
+    batches: Arc<Vec<RecordBatch>>,
+    size: usize,
+    tables: HashSet<String>,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, Entry>,
+    /// Keys by when they were last used, to evict the least recently used entries first.
+    recently_used: BTreeMap<u64, String>,
+    next_use: u64,
+    size: usize,
+    /// Tables that invalidate the cache when their data changes.
+    tracked_tables: HashSet<String>,
+    /// Incremented on every invalidation, so results computed while a table changed aren't cached.
+    epoch: u64,
+}
+
+/// Identifies the cache entry for a query that missed the cache.
+pub struct CacheKey {
+    key: String,
+    tables: HashSet<String>,
+    epoch: u64,
+}
+
+pub enum Lookup {
+    Hit(SendableRecordBatchStream),
+    Miss(CacheKey),
+    Uncacheable,
+}
+
+#[allow(clippy::module_name_repetitions)]
+pub struct ResultCache {
+    max_size: usize,
+    state: Mutex<CacheState>,
+}
+
+impl ResultCache {
+    /// Creates a cache holding up to `max_size` bytes of results.
+    #[must_use]
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// Allows queries reading `table` to be cached, which requires every change to it to call
+    /// `invalidate`.
+    pub fn track_table(&self, table: &str) {
+        self.lock_state()
+            .tracked_tables
+            .insert(table_key(TableReference::from(table)));
+    }
+
+    /// Stops caching queries reading `table`, i.e. when it's removed.
+    pub fn untrack_table(&self, table: &str) {
+        self.invalidate(table);
+        self.lock_state()
+            .tracked_tables
+            .remove(&table_key(TableReference::from(table)));
+    }
+
+    /// Removes the cached results of queries reading `table`.
+    pub fn invalidate(&self, table: &str) {
+        let table = table_key(TableReference::from(table));
+        let mut state = self.lock_state();
+        state.epoch += 1;
+
+        let keys = state
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.tables.contains(&table))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in keys {
+            state.remove(&key);
+        }
+        state.report_size();
+    }
+
+    #[must_use]
+    pub fn lookup(&self, plan: &LogicalPlan) -> Lookup {
+        let mut tables = HashSet::new();
+        if !is_cacheable(plan, &mut tables) {
+            return Lookup::Uncacheable;
+        }
+
+        let mut state = self.lock_state();
+        if !tables
+            .iter()
+            .all(|table| state.tracked_tables.contains(table))
+        {
+            return Lookup::Uncacheable;
+        }
+
+        // The plan's debug format is its indented display, which ignores differences in the SQL
+        // text such as whitespace and keyword case.
+        let key = format!("{plan:?}");
+        let use_id = state.next_use();
+        if let Some(entry) = state.entries.get_mut(&key) {
+            let last_used = std::mem::replace(&mut entry.last_used, use_id);
+            let schema = Arc::clone(&entry.schema);
+            let batches = Arc::clone(&entry.batches);
+            state.recently_used.remove(&last_used);
+            state.recently_used.insert(use_id, key.clone());
+
+            if let Ok(stream) = MemoryStream::try_new(batches.to_vec(), schema, None) {
+                metrics::counter!("results_cache_hits").increment(1);
+                return Lookup::Hit(Box::pin(stream));
+            }
+        }
+
+        metrics::counter!("results_cache_misses").increment(1);
+        Lookup::Miss(CacheKey {
+            key,
+            tables,
+            epoch: state.epoch,
+        })
+    }
+
+    /// Caches the results of `batches` once the stream completes without errors.
+    #[must_use]
+    pub fn cache_stream(
+        self: &Arc<Self>,
+        key: CacheKey,
+        mut batches: SendableRecordBatchStream,
+    ) -> SendableRecordBatchStream {
+        let cache = Arc::clone(self);
+        let schema = batches.schema();
+
+        Box::pin(RecordBatchStreamAdapter::new(
+            Arc::clone(&schema),
+            stream! {
+                let mut results = Some(Vec::new());
+                let mut size = 0;
+
+                while let Some(batch) = batches.next().await {
+                    match &batch {
+                        Ok(batch) => {
+                            size += batch.get_array_memory_size();
+                            if size > cache.max_size {
+                                results = None;
+                            } else if let Some(results) = results.as_mut() {
+                                results.push(batch.clone());
+                            }
+                        }
+                        Err(_) => results = None,
+                    }
+                    yield batch;
+                }
+
+                if let Some(results) = results {
+                    cache.insert(key, schema, results, size);
+                }
+            },
+        ))
+    }
+
+    fn insert(&self, key: CacheKey, schema: SchemaRef, batches: Vec<RecordBatch>, size: usize) {
+        let mut state = self.lock_state();
+        if state.epoch != key.epoch {
+            return;
+        }
+
+        state.remove(&key.key);
+        while state.size + size > self.max_size {
+            let Some((_, evicted)) = state.recently_used.pop_first() else {
+                break;
+            };
+            state.remove(&evicted);
+            metrics::counter!("results_cache_evictions").increment(1);
+        }
+
+        let last_used = state.next_use();
+        state.recently_used.insert(last_used, key.key.clone());
+        state.size += size;
+        state.entries.insert(
+            key.key,
+            Entry {
+                schema,
+                batches: Arc::new(batches),
+                size,
+                tables: key.tables,
+                last_used,
+            },
+        );
+        state.report_size();
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, CacheState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl CacheState {
+    fn next_use(&mut self) -> u64 {
+        self.next_use += 1;
+        self.next_use
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recently_used.remove(&entry.last_used);
+            self.size -= entry.size;
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn report_size(&self) {
+        metrics::gauge!("results_cache_size_bytes").set(self.size as f64);
+    }
+}
+
+/// Collects the tables `plan` reads, including through views and subqueries, returning `false`
+/// if its results can't be cached.
+fn is_cacheable(plan: &LogicalPlan, tables: &mut HashSet<String>) -> bool {
+    let mut cacheable = true;
+
+    let _ = plan.apply(&mut |node| {
+        match node {
+            LogicalPlan::TableScan(scan) => match scan.source.get_logical_plan() {
+                Some(view) => cacheable &= is_cacheable(view, tables),
+                None => {
+                    tables.insert(table_key(scan.table_name.clone()));
+                }
+            },
+            LogicalPlan::Dml(_)
+            | LogicalPlan::Ddl(_)
+            | LogicalPlan::Copy(_)
+            | LogicalPlan::Statement(_)
+            | LogicalPlan::Explain(_)
+            | LogicalPlan::Analyze(_)
+            | LogicalPlan::DescribeTable(_) => cacheable = false,
+            _ => {}
+        }
+
+        for expr in node.expressions() {
+            let _ = expr.apply(&mut |expr| {
+                match expr {
+                    Expr::ScalarSubquery(subquery)
+                    | Expr::Exists(Exists { subquery, .. })
+                    | Expr::InSubquery(InSubquery { subquery, .. }) => {
+                        cacheable &= is_cacheable(&subquery.subquery, tables);
+                    }
+                    Expr::ScalarFunction(ScalarFunction { func_def, .. }) => {
+                        let volatility = match func_def {
+                            ScalarFunctionDefinition::BuiltIn(function) => {
+                                Some(function.volatility())
+                            }
+                            ScalarFunctionDefinition::UDF(function) => {
+                                Some(function.signature().volatility)
+                            }
+                            ScalarFunctionDefinition::Name(_) => None,
+                        };
+                        // Stable functions, i.e. `now()`, return the same result within a query but
+                        // not across queries.
+                        if volatility != Some(Volatility::Immutable) {
+                            cacheable = false;
+                        }
+                    }
+                    _ => {}
+                }
+                Ok(VisitRecursion::Continue)
+            });
+        }
+
+        Ok(if cacheable {
+            VisitRecursion::Continue
+        } else {
+            VisitRecursion::Stop
+        })
+    });
+
+    cacheable
+}
+
+/// Identifies `table` by its catalog, schema and name, as queries can refer to the same table by
+/// any suffix of them and a dotted dataset name is a table in another schema.
+fn table_key(table: TableReference) -> String {
+    table.resolve("datafusion", "public").to_string()
+}
+
+/// Invalidates the cached results reading a dataset whenever data is added to it.
+pub struct InvalidatingPublisher {
+    inner: Box<dyn DataPublisher>,
+    cache: Arc<ResultCache>,
+}
+
+impl InvalidatingPublisher {
+    #[must_use]
+    pub fn new(inner: Box<dyn DataPublisher>, cache: Arc<ResultCache>) -> Self {
+        Self { inner, cache }
+    }
+}
+
+impl DataPublisher for InvalidatingPublisher {
+    fn add_data(&self, dataset: Arc<Dataset>, data_update: DataUpdate) -> AddDataResult {
+        Box::pin(async move {
+            let name = dataset.name.clone();
+            let result = self.inner.add_data(dataset, data_update).await;
+            // Part of the update may have been written even if it failed.
+            self.cache.invalidate(&name);
+            result
+        })
+    }
+
+    fn flush(&self) -> AddDataResult {
+        self.inner.flush()
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::{
+        array::Int64Array,
+        datatypes::{DataType, Field, Schema},
+    };
+    use datafusion::{
+        catalog::schema::MemorySchemaProvider, datasource::MemTable,
+        execution::context::SessionContext,
+    };
+
+    async fn plan(ctx: &SessionContext, sql: &str) -> LogicalPlan {
+        ctx.sql(sql)
+            .await
+            .expect("valid query")
+            .logical_plan()
+            .clone()
+    }
+
+    #[tokio::test]
+    async fn test_invalidation() {
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int64, false)]));
+        let batch = RecordBatch::try_new(
+            Arc::clone(&schema),
+            vec![Arc::new(Int64Array::from(vec![1, 2, 3]))],
+        )
+        .expect("valid record batch");
+        let ctx = SessionContext::new();
+        let table =
+            MemTable::try_new(Arc::clone(&schema), vec![vec![batch.clone()]]).expect("valid table");
+        ctx.register_table("t", Arc::new(table))
+            .expect("register table");
+
+        let cache = Arc::new(ResultCache::new(1 << 20));
+        let query = plan(&ctx, "SELECT * FROM t").await;
+        assert!(matches!(cache.lookup(&query), Lookup::Uncacheable));
+
+        cache.track_table("t");
+        let Lookup::Miss(key) = cache.lookup(&query) else {
+            panic!("expected a cache miss");
+        };
+        cache.insert(key, schema, vec![batch], 1024);
+        assert!(matches!(
+            cache.lookup(&plan(&ctx, "select *   from t").await),
+            Lookup::Hit(_)
+        ));
+        assert!(matches!(
+            cache.lookup(&plan(&ctx, "SELECT now(), * FROM t").await),
+            Lookup::Uncacheable
+        ));
+
+        cache.invalidate("t");
+        assert!(matches!(cache.lookup(&query), Lookup::Miss(_)));
+    }
+
+    #[tokio::test]
+    async fn test_invalidation_by_full_name() {
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int64, false)]));
+        let ctx = SessionContext::new();
+        ctx.catalog("datafusion")
+            .expect("default catalog")
+            .register_schema("a", Arc::new(MemorySchemaProvider::new()))
+            .expect("register schema");
+        for name in ["b", "a.b"] {
+            let table = MemTable::try_new(Arc::clone(&schema), vec![vec![]]).expect("valid table");
+            ctx.register_table(name, Arc::new(table))
+                .expect("register table");
+        }
+
+        let cache = ResultCache::new(1 << 20);
+        cache.track_table("b");
+        cache.track_table("a.b");
+        let query = plan(&ctx, "SELECT * FROM a.b").await;
+        let Lookup::Miss(key) = cache.lookup(&query) else {
+            panic!("expected a cache miss");
+        };
+        cache.insert(key, Arc::clone(&schema), vec![], 0);
+
+        cache.invalidate("b");
+        assert!(matches!(cache.lookup(&query), Lookup::Hit(_)));
+        cache.invalidate("a.b");
+        assert!(matches!(cache.lookup(&query), Lookup::Miss(_)));
+    }
+
+    #[tokio::test]
+    async fn test_functions_by_volatility() {
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int64, false)]));
+        let ctx = SessionContext::new();
+        let table = MemTable::try_new(schema, vec![vec![]]).expect("valid table");
+        ctx.register_table("t", Arc::new(table))
+            .expect("register table");
+        let _models = crate::modelfunction::ModelFunctions::new(&ctx);
+
+        let cache = ResultCache::new(1 << 20);
+        cache.track_table("t");
+        assert!(matches!(
+            cache.lookup(&plan(&ctx, "SELECT abs(id) FROM t").await),
+            Lookup::Miss(_)
+        ));
+        for sql in [
+            "SELECT random(), id FROM t",
+            "SELECT id FROM t WHERE id < extract(epoch from current_date)",
+            "SELECT predict('model', id) FROM t",
+        ] {
+            assert!(
+                matches!(cache.lookup(&plan(&ctx, sql).await), Lookup::Uncacheable),
+                "{sql} is cached"
+            );
+        }
+    }
+}
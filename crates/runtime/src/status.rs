@@ -14,11 +14,24 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
-use std::fmt::Display;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
 
 use metrics::gauge;
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
+use crate::timing::now_millis;
+
+static DATASET_METADATA: Lazy<RwLock<HashMap<String, DatasetMetadata>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+static MODEL_METADATA: Lazy<RwLock<HashMap<String, ModelMetadata>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
 #[allow(clippy::module_name_repetitions)]
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
 pub enum ComponentStatus {
@@ -41,10 +54,169 @@ impl Display for ComponentStatus {
     }
 }
 
+/// Refresh metadata tracked for each loaded dataset.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DatasetMetadata {
+    /// Milliseconds since the Unix epoch at which the data was last refreshed from the source.
+    pub last_refresh_time: Option<u64>,
+
+    /// How long the last refresh took to fetch and write the data, in milliseconds.
+    pub last_refresh_duration_ms: Option<u64>,
+
+    /// The most recent error loading or refreshing the dataset.
+    pub last_error: Option<String>,
+
+    /// Milliseconds since the Unix epoch at which `last_error` occurred.
+    pub last_error_time: Option<u64>,
+
+    /// The dataset is serving data persisted by a previous run that has not yet been refreshed.
+    pub stale: bool,
+
+    #[serde(skip)]
+    refresh_started_at: Option<u64>,
+}
+
 pub fn update_dataset(ds_name: String, status: ComponentStatus) {
     gauge!("dataset/status", "dataset" => ds_name).set(f64::from(status as u32));
 }
 
+/// Records that a dataset started loading, so its initial load is timed like a refresh.
+pub fn start_dataset_load(ds_name: &str) {
+    write_dataset_metadata()
+        .entry(ds_name.to_string())
+        .or_default()
+        .refresh_started_at = Some(now_millis());
+}
+
+/// Records that a dataset started refreshing from its source and marks it `Refreshing`.
+///
+/// A refresh during the initial load is timed from the start of the load. A stale dataset is
+/// already queryable, so it's left `Ready` while its first refresh runs.
+pub fn start_dataset_refresh(ds_name: &str) {
+    let stale = {
+        let mut metadata = write_dataset_metadata();
+        let metadata = metadata.entry(ds_name.to_string()).or_default();
+        metadata.refresh_started_at.get_or_insert_with(now_millis);
+        metadata.stale
+    };
+    if !stale {
+        update_dataset(ds_name.to_string(), ComponentStatus::Refreshing);
+    }
+}
+
+/// Marks a dataset as serving data persisted by a previous run, last refreshed at `last_refresh_time`.
+pub fn mark_dataset_stale(ds_name: &str, last_refresh_time: Option<u64>) {
+    write_dataset_metadata().insert(
+        ds_name.to_string(),
+        DatasetMetadata {
+            last_refresh_time,
+            stale: true,
+            ..Default::default()
+        },
+    );
+    update_dataset(ds_name.to_string(), ComponentStatus::Ready);
+}
+
+/// Records that a dataset was refreshed from its source.
+pub fn record_dataset_refresh(ds_name: &str) {
+    let now = now_millis();
+    let mut metadata = write_dataset_metadata();
+    let metadata = metadata.entry(ds_name.to_string()).or_default();
+    metadata.last_refresh_time = Some(now);
+    metadata.last_refresh_duration_ms = metadata
+        .refresh_started_at
+        .take()
+        .map(|started_at| now.saturating_sub(started_at));
+    metadata.stale = false;
+}
+
+/// Records an error loading or refreshing a dataset.
+pub fn record_dataset_error(ds_name: &str, error: String) {
+    let mut metadata = write_dataset_metadata();
+    let metadata = metadata.entry(ds_name.to_string()).or_default();
+    metadata.last_error = Some(error);
+    metadata.last_error_time = Some(now_millis());
+    metadata.refresh_started_at = None;
+}
+
+#[must_use]
+pub fn get_dataset_metadata(ds_name: &str) -> Option<DatasetMetadata> {
+    read_dataset_metadata().get(ds_name).cloned()
+}
+
+pub fn remove_dataset_metadata(ds_name: &str) {
+    write_dataset_metadata().remove(ds_name);
+}
+
+fn read_dataset_metadata() -> RwLockReadGuard<'static, HashMap<String, DatasetMetadata>> {
+    match DATASET_METADATA.read() {
+        Ok(metadata) => metadata,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn write_dataset_metadata() -> RwLockWriteGuard<'static, HashMap<String, DatasetMetadata>> {
+    match DATASET_METADATA.write() {
+        Ok(metadata) => metadata,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Status tracked for each model, across its versions.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModelMetadata {
+    pub status: ComponentStatus,
+    /// The most recent error loading a version of the model.
+    pub last_error: Option<String>,
+    /// Milliseconds since the Unix epoch at which `last_error` occurred.
+    pub last_error_time: Option<u64>,
+}
+
 pub fn update_model(model_name: String, status: ComponentStatus) {
+    write_model_metadata()
+        .entry(model_name.clone())
+        .and_modify(|metadata| metadata.status = status)
+        .or_insert(ModelMetadata {
+            status,
+            last_error: None,
+            last_error_time: None,
+        });
     gauge!("model/status", "model" => model_name).set(f64::from(status as u32));
 }
+
+/// Records an error loading a model.
+pub fn record_model_error(model_name: &str, error: String) {
+    let mut metadata = write_model_metadata();
+    let metadata = metadata
+        .entry(model_name.to_string())
+        .or_insert(ModelMetadata {
+            status: ComponentStatus::Error,
+            last_error: None,
+            last_error_time: None,
+        });
+    metadata.last_error = Some(error);
+    metadata.last_error_time = Some(now_millis());
+}
+
+#[must_use]
+pub fn get_model_metadata(model_name: &str) -> Option<ModelMetadata> {
+    read_model_metadata().get(model_name).cloned()
+}
+
+pub fn remove_model_metadata(model_name: &str) {
+    write_model_metadata().remove(model_name);
+}
+
+fn read_model_metadata() -> RwLockReadGuard<'static, HashMap<String, ModelMetadata>> {
+    match MODEL_METADATA.read() {
+        Ok(metadata) => metadata,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn write_model_metadata() -> RwLockWriteGuard<'static, HashMap<String, ModelMetadata>> {
+    match MODEL_METADATA.write() {
+        Ok(metadata) => metadata,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
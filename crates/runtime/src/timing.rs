@@ -17,7 +17,7 @@ limitations under the License.
 use std::{
     pin::Pin,
     task::{Context, Poll},
-    time::Instant,
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
 use futures::Stream;
@@ -165,3 +165,11 @@ where
         }
     }
 }
+
+/// Returns the number of milliseconds since the Unix epoch.
+#[must_use]
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX))
+}
@@ -0,0 +1,833 @@
+/*
+Copyright 2024 The Spice.ai OSS Authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! A write-ahead log for data received through Flight `DoPut` and the OpenTelemetry
+//! `MetricsService`.
+//!
+//! Each dataset has its own directory of numbered segment files. A segment is a sequence of
+//! records, each made up of a little-endian `u32` payload length, a little-endian `u32` CRC32 of
+//! the payload, and the payload itself: a one byte record kind followed by the Arrow IPC stream
+//! of the update's record batches.
+//!
+//! Compaction rewrites all closed segments into a single segment that starts with a checkpoint
+//! record. A checkpoint supersedes every segment with a lower number, so a crash part-way
+//! through compaction never replays the same data twice.
+//!
+//! Once the updates of a dataset are persisted by its accelerated backend, i.e. in an Arrow
+//! snapshot, the log is truncated by replacing its last closed segment with a checkpoint alone.
+//! Writes to a logged dataset hold its write lock while they're logged and applied, so a
+//! checkpoint taken under the lock covers every update applied to the dataset.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{Cursor, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use arrow::{
+    error::ArrowError,
+    ipc::{reader::StreamReader, writer::StreamWriter},
+};
+use snafu::prelude::*;
+use spicepod::component::dataset::{
+    acceleration::{Engine, Mode},
+    Dataset,
+};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+use crate::{
+    databackend::memtable::snapshot,
+    dataupdate::{DataUpdate, UpdateType},
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Unable to create write-ahead log directory {}: {source}", path.display()))]
+    UnableToCreateDirectory {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Unable to list write-ahead log segments in {}: {source}", path.display()))]
+    UnableToListSegments {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Unable to open write-ahead log segment {}: {source}", path.display()))]
+    UnableToOpenSegment {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Unable to write to write-ahead log segment {}: {source}", path.display()))]
+    UnableToWriteSegment {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Unable to read write-ahead log segment {}: {source}", path.display()))]
+    UnableToReadSegment {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Unable to remove write-ahead log segment {}: {source}", path.display()))]
+    UnableToRemoveSegment {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display("Unable to remove write-ahead log directory {}: {source}", path.display()))]
+    UnableToRemoveDirectory {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[snafu(display(
+        "Unable to write an update of {size} bytes to the write-ahead log, the maximum is {} bytes",
+        u32::MAX
+    ))]
+    UpdateTooLarge { size: usize },
+
+    #[snafu(display("Unable to encode data for the write-ahead log: {source}"))]
+    UnableToEncodeUpdate { source: ArrowError },
+
+    #[snafu(display("Unable to decode data from the write-ahead log: {source}"))]
+    UnableToDecodeUpdate { source: ArrowError },
+
+    #[snafu(display("Write-ahead log task failed: {source}"))]
+    UnableToJoinTask { source: tokio::task::JoinError },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// When appended records are flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum SyncPolicy {
+    /// Flush every record before it is acknowledged.
+    #[default]
+    Always,
+    /// Flush at most once per second, when a record is appended.
+    Periodic,
+    /// Leave flushing to the operating system.
+    Never,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub sync_policy: SyncPolicy,
+    /// The size in bytes after which a segment is closed and a new one is started.
+    pub segment_size: u64,
+    /// The number of closed segments that triggers a compaction.
+    pub compact_after: usize,
+}
+
+const SEGMENT_EXTENSION: &str = "wal";
+const COMPACTING_EXTENSION: &str = "compacting";
+const RECORD_HEADER_SIZE: usize = 8;
+const PERIODIC_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecordKind {
+    Append = 0,
+    Overwrite = 1,
+    Checkpoint = 2,
+}
+
+impl RecordKind {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(RecordKind::Append),
+            1 => Some(RecordKind::Overwrite),
+            2 => Some(RecordKind::Checkpoint),
+            _ => None,
+        }
+    }
+}
+
+pub struct WriteAheadLog {
+    dir: PathBuf,
+    config: Config,
+    logs: Mutex<HashMap<String, Arc<Mutex<DatasetLog>>>>,
+    write_locks: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>,
+}
+
+impl WriteAheadLog {
+    pub fn open(dir: impl Into<PathBuf>, config: Config) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).context(UnableToCreateDirectorySnafu { path: dir.clone() })?;
+
+        Ok(Self {
+            dir,
+            config,
+            logs: Mutex::new(HashMap::new()),
+            write_locks: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Only datasets without a data connector are logged, as the data for all other datasets can be
+    /// reloaded from their source. Datasets accelerated by a database that persists every write,
+    /// i.e. DuckDB or SQLite with `mode: file`, aren't logged either.
+    #[must_use]
+    pub fn should_log(dataset: &Dataset) -> bool {
+        if dataset.source() != "localhost" {
+            return false;
+        }
+
+        match dataset.acceleration.as_ref().filter(|acc| acc.enabled) {
+            Some(acc) => match acc.engine() {
+                Engine::Arrow => true,
+                Engine::DuckDB | Engine::Sqlite => acc.mode() == Mode::Memory,
+                Engine::Postgres => false,
+            },
+            None => true,
+        }
+    }
+
+    /// Serializes the writes to `dataset`, which hold the returned guard while they're logged and
+    /// applied to the dataset.
+    pub async fn lock_writes(&self, dataset: &str) -> OwnedMutexGuard<()> {
+        let write_lock = Arc::clone(
+            lock(&self.write_locks)
+                .entry(dataset.to_string())
+                .or_default(),
+        );
+        write_lock.lock_owned().await
+    }
+
+    /// Durably appends `data_update` to the log of `dataset` according to the configured `SyncPolicy`.
+    pub async fn append(&self, dataset: &str, data_update: &DataUpdate) -> Result<()> {
+        let Some(record) = encode_update(data_update)? else {
+            return Ok(());
+        };
+
+        let log = self.dataset_log(dataset)?;
+        tokio::task::spawn_blocking(move || lock(&log).append(&record))
+            .await
+            .context(UnableToJoinTaskSnafu)?
+    }
+
+    /// Closes the active segment of `dataset`, returning the position up to which the log can be
+    /// truncated once the updates applied so far are persisted. Call under `lock_writes`.
+    pub async fn begin_checkpoint(&self, dataset: &str) -> Result<Option<u64>> {
+        let log = self.dataset_log(dataset)?;
+        tokio::task::spawn_blocking(move || {
+            let mut log = lock(&log);
+            log.close_active_segment()?;
+            log.pending_checkpoint = log.closed_segments.last().copied();
+            Ok(log.pending_checkpoint)
+        })
+        .await
+        .context(UnableToJoinTaskSnafu)?
+    }
+
+    /// Drops the updates of `dataset` logged before `position`, which have been persisted.
+    pub async fn truncate(&self, dataset: &str, position: u64) -> Result<()> {
+        let log = self.dataset_log(dataset)?;
+        tokio::task::spawn_blocking(move || lock(&log).truncate(position))
+            .await
+            .context(UnableToJoinTaskSnafu)?
+    }
+
+    /// Resumes compacting the log of `dataset` after a checkpoint whose updates couldn't be
+    /// persisted, keeping every update logged.
+    pub async fn abort_checkpoint(&self, dataset: &str) -> Result<()> {
+        let log = self.dataset_log(dataset)?;
+        // The log is locked while a record is appended and synced.
+        tokio::task::spawn_blocking(move || lock(&log).pending_checkpoint = None)
+            .await
+            .context(UnableToJoinTaskSnafu)
+    }
+
+    /// Reads all updates for `dataset` that have not been superseded by a compaction.
+    pub async fn replay(&self, dataset: &str) -> Result<Vec<DataUpdate>> {
+        let log = self.dataset_log(dataset)?;
+        tokio::task::spawn_blocking(move || lock(&log).replay())
+            .await
+            .context(UnableToJoinTaskSnafu)?
+    }
+
+    /// Deletes the log of `dataset`, i.e. when the dataset is removed, so a dataset added later
+    /// with the same name doesn't replay its updates.
+    pub async fn remove(&self, dataset: &str) -> Result<()> {
+        let _writes = self.lock_writes(dataset).await;
+        lock(&self.logs).remove(dataset);
+
+        let dir = self.dataset_dir(dataset);
+        tokio::task::spawn_blocking(move || match fs::remove_dir_all(&dir) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).context(UnableToRemoveDirectorySnafu { path: dir })
+            }
+            _ => Ok(()),
+        })
+        .await
+        .context(UnableToJoinTaskSnafu)?
+    }
+
+    /// The directory of the log of `dataset`, named so that no two datasets share one.
+    fn dataset_dir(&self, dataset: &str) -> PathBuf {
+        self.dir.join(snapshot::encode_file_name(dataset))
+    }
+
+    fn dataset_log(&self, dataset: &str) -> Result<Arc<Mutex<DatasetLog>>> {
+        let mut logs = lock(&self.logs);
+        if let Some(log) = logs.get(dataset) {
+            return Ok(Arc::clone(log));
+        }
+
+        let log = Arc::new(Mutex::new(DatasetLog::open(
+            self.dataset_dir(dataset),
+            self.config.clone(),
+        )?));
+        logs.insert(dataset.to_string(), Arc::clone(&log));
+
+        Ok(log)
+    }
+}
+
+struct ActiveSegment {
+    id: u64,
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+struct DatasetLog {
+    dir: PathBuf,
+    config: Config,
+    active: Option<ActiveSegment>,
+    closed_segments: Vec<u64>,
+    last_sync: Instant,
+    /// The position of a checkpoint that has begun but not been truncated to yet. Compaction is
+    /// paused meanwhile, as it would move the updates before it into a later segment.
+    pending_checkpoint: Option<u64>,
+}
+
+impl DatasetLog {
+    fn open(dir: PathBuf, config: Config) -> Result<Self> {
+        fs::create_dir_all(&dir).context(UnableToCreateDirectorySnafu { path: dir.clone() })?;
+
+        let mut log = Self {
+            dir,
+            config,
+            active: None,
+            closed_segments: vec![],
+            last_sync: Instant::now(),
+            pending_checkpoint: None,
+        };
+        log.closed_segments = log.list_segments()?;
+        log.remove_superseded_segments()?;
+
+        Ok(log)
+    }
+
+    fn segment_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{id:020}.{SEGMENT_EXTENSION}"))
+    }
+
+    fn list_segments(&self) -> Result<Vec<u64>> {
+        let entries =
+            fs::read_dir(&self.dir).context(UnableToListSegmentsSnafu { path: &self.dir })?;
+
+        let mut ids = vec![];
+        for entry in entries {
+            let path = entry
+                .context(UnableToListSegmentsSnafu { path: &self.dir })?
+                .path();
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some(SEGMENT_EXTENSION) => {
+                    if let Some(id) = path
+                        .file_stem()
+                        .and_then(|stem| stem.to_str())
+                        .and_then(|stem| stem.parse::<u64>().ok())
+                    {
+                        ids.push(id);
+                    }
+                }
+                // Left over from a compaction that didn't complete.
+                Some(COMPACTING_EXTENSION) => {
+                    fs::remove_file(&path).context(UnableToRemoveSegmentSnafu { path: &path })?;
+                }
+                _ => (),
+            }
+        }
+        ids.sort_unstable();
+
+        Ok(ids)
+    }
+
+    /// Removes all segments before the most recent segment that starts with a checkpoint.
+    fn remove_superseded_segments(&mut self) -> Result<()> {
+        let mut checkpoint = None;
+        for id in self.closed_segments.iter().rev() {
+            let records = read_segment(&self.segment_path(*id))?;
+            if records
+                .first()
+                .is_some_and(|(kind, _)| *kind == RecordKind::Checkpoint)
+            {
+                checkpoint = Some(*id);
+                break;
+            }
+        }
+
+        let Some(checkpoint) = checkpoint else {
+            return Ok(());
+        };
+
+        for id in self.closed_segments.iter().filter(|id| **id < checkpoint) {
+            let path = self.segment_path(*id);
+            fs::remove_file(&path).context(UnableToRemoveSegmentSnafu { path: &path })?;
+        }
+        self.closed_segments.retain(|id| *id >= checkpoint);
+
+        Ok(())
+    }
+
+    fn next_segment_id(&self) -> u64 {
+        let last_closed = self.closed_segments.last().copied().unwrap_or(0);
+        let active = self.active.as_ref().map_or(0, |active| active.id);
+        last_closed.max(active) + 1
+    }
+
+    fn append(&mut self, record: &(RecordKind, Vec<u8>)) -> Result<()> {
+        if self.active.is_none() {
+            let id = self.next_segment_id();
+            let path = self.segment_path(id);
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .context(UnableToOpenSegmentSnafu { path: &path })?;
+            self.active = Some(ActiveSegment {
+                id,
+                path,
+                file,
+                size: 0,
+            });
+        }
+
+        let sync = match self.config.sync_policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::Periodic => self.last_sync.elapsed() >= PERIODIC_SYNC_INTERVAL,
+            SyncPolicy::Never => false,
+        };
+
+        let Some(active) = self.active.as_mut() else {
+            unreachable!("active segment was just created");
+        };
+
+        let bytes = encode_record(record)?;
+        active
+            .file
+            .write_all(&bytes)
+            .context(UnableToWriteSegmentSnafu { path: &active.path })?;
+        active.size += bytes.len() as u64;
+
+        if sync {
+            active
+                .file
+                .sync_data()
+                .context(UnableToWriteSegmentSnafu { path: &active.path })?;
+            self.last_sync = Instant::now();
+        }
+
+        if active.size >= self.config.segment_size {
+            self.close_active_segment()?;
+            if self.closed_segments.len() >= self.config.compact_after
+                && self.pending_checkpoint.is_none()
+            {
+                self.compact()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn close_active_segment(&mut self) -> Result<()> {
+        if let Some(active) = self.active.take() {
+            active
+                .file
+                .sync_all()
+                .context(UnableToWriteSegmentSnafu { path: &active.path })?;
+            self.closed_segments.push(active.id);
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites the closed segments into a single segment, dropping updates that were superseded by
+    /// a later overwrite.
+    fn compact(&mut self) -> Result<()> {
+        let Some(last_closed) = self.closed_segments.last().copied() else {
+            return Ok(());
+        };
+
+        let mut records = vec![];
+        for id in &self.closed_segments {
+            records.extend(
+                read_segment(&self.segment_path(*id))?
+                    .into_iter()
+                    .filter(|(kind, _)| *kind != RecordKind::Checkpoint),
+            );
+        }
+
+        if let Some(last_overwrite) = records
+            .iter()
+            .rposition(|(kind, _)| *kind == RecordKind::Overwrite)
+        {
+            records.drain(..last_overwrite);
+        }
+
+        self.write_checkpoint_segment(last_closed, &records)?;
+        self.remove_superseded_segments()?;
+        tracing::debug!(
+            "Compacted write-ahead log {} into {} records",
+            self.dir.display(),
+            records.len()
+        );
+
+        Ok(())
+    }
+
+    /// Replaces segment `position` with a checkpoint, superseding it and every earlier segment.
+    fn truncate(&mut self, position: u64) -> Result<()> {
+        self.pending_checkpoint = None;
+        if !self.closed_segments.contains(&position) {
+            return Ok(());
+        }
+
+        self.write_checkpoint_segment(position, &[])?;
+        self.remove_superseded_segments()?;
+        tracing::debug!(
+            "Truncated write-ahead log {} through segment {position}",
+            self.dir.display()
+        );
+
+        Ok(())
+    }
+
+    /// Atomically replaces segment `id` with a checkpoint followed by `records`.
+    fn write_checkpoint_segment(&self, id: u64, records: &[(RecordKind, Vec<u8>)]) -> Result<()> {
+        let compacting_path = self.dir.join(format!("{id:020}.{COMPACTING_EXTENSION}"));
+        let mut file = File::create(&compacting_path).context(UnableToOpenSegmentSnafu {
+            path: &compacting_path,
+        })?;
+        file.write_all(&encode_record(&(RecordKind::Checkpoint, vec![]))?)
+            .context(UnableToWriteSegmentSnafu {
+                path: &compacting_path,
+            })?;
+        for record in records {
+            file.write_all(&encode_record(record)?)
+                .context(UnableToWriteSegmentSnafu {
+                    path: &compacting_path,
+                })?;
+        }
+        file.sync_all().context(UnableToWriteSegmentSnafu {
+            path: &compacting_path,
+        })?;
+
+        let segment_path = self.segment_path(id);
+        fs::rename(&compacting_path, &segment_path).context(UnableToWriteSegmentSnafu {
+            path: &segment_path,
+        })?;
+        if let Ok(dir) = File::open(&self.dir) {
+            let _ = dir.sync_all();
+        }
+
+        Ok(())
+    }
+
+    fn replay(&self) -> Result<Vec<DataUpdate>> {
+        let mut ids = self.closed_segments.clone();
+        if let Some(active) = &self.active {
+            ids.push(active.id);
+        }
+
+        let mut updates = vec![];
+        for id in ids {
+            for (kind, payload) in read_segment(&self.segment_path(id))? {
+                let update_type = match kind {
+                    RecordKind::Append => UpdateType::Append,
+                    RecordKind::Overwrite => UpdateType::Overwrite,
+                    RecordKind::Checkpoint => continue,
+                };
+                updates.push(decode_update(update_type, payload)?);
+            }
+        }
+
+        Ok(updates)
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn encode_update(data_update: &DataUpdate) -> Result<Option<(RecordKind, Vec<u8>)>> {
+    let Some(first_batch) = data_update.data.first() else {
+        return Ok(None);
+    };
+
+    let kind = match data_update.update_type {
+        UpdateType::Append => RecordKind::Append,
+        UpdateType::Overwrite => RecordKind::Overwrite,
+    };
+
+    let mut writer = StreamWriter::try_new(Vec::new(), &first_batch.schema())
+        .context(UnableToEncodeUpdateSnafu)?;
+    for batch in &data_update.data {
+        writer.write(batch).context(UnableToEncodeUpdateSnafu)?;
+    }
+    writer.finish().context(UnableToEncodeUpdateSnafu)?;
+    let payload = writer.into_inner().context(UnableToEncodeUpdateSnafu)?;
+
+    Ok(Some((kind, payload)))
+}
+
+fn decode_update(update_type: UpdateType, payload: Vec<u8>) -> Result<DataUpdate> {
+    let reader =
+        StreamReader::try_new(Cursor::new(payload), None).context(UnableToDecodeUpdateSnafu)?;
+    let data = reader
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .context(UnableToDecodeUpdateSnafu)?;
+
+    Ok(DataUpdate { data, update_type })
+}
+
+fn encode_record((kind, payload): &(RecordKind, Vec<u8>)) -> Result<Vec<u8>> {
+    let size = payload.len() + 1;
+    let len = u32::try_from(size).map_err(|_| Error::UpdateTooLarge { size })?;
+
+    let mut bytes = Vec::with_capacity(RECORD_HEADER_SIZE + size);
+    bytes.extend_from_slice(&len.to_le_bytes());
+    bytes.extend_from_slice(&[0; 4]);
+    bytes.push(*kind as u8);
+    bytes.extend_from_slice(payload);
+    let crc = crc32fast::hash(&bytes[RECORD_HEADER_SIZE..]);
+    bytes[4..RECORD_HEADER_SIZE].copy_from_slice(&crc.to_le_bytes());
+    Ok(bytes)
+}
+
+/// Reads all complete records of a segment, stopping at the first torn or corrupt record.
+fn read_segment(path: &Path) -> Result<Vec<(RecordKind, Vec<u8>)>> {
+    let bytes = fs::read(path).context(UnableToReadSegmentSnafu { path })?;
+
+    let mut records = vec![];
+    let mut offset = 0;
+    while offset + RECORD_HEADER_SIZE <= bytes.len() {
+        let len = u32::from_le_bytes([
+            bytes[offset],
+            bytes[offset + 1],
+            bytes[offset + 2],
+            bytes[offset + 3],
+        ]) as usize;
+        let crc = u32::from_le_bytes([
+            bytes[offset + 4],
+            bytes[offset + 5],
+            bytes[offset + 6],
+            bytes[offset + 7],
+        ]);
+
+        let start = offset + RECORD_HEADER_SIZE;
+        let Some(body) = bytes.get(start..start + len) else {
+            break;
+        };
+        if len == 0 || crc32fast::hash(body) != crc {
+            break;
+        }
+        let Some(kind) = RecordKind::from_byte(body[0]) else {
+            break;
+        };
+
+        records.push((kind, body[1..].to_vec()));
+        offset = start + len;
+    }
+
+    if offset < bytes.len() {
+        tracing::warn!(
+            "Ignoring {} bytes of incomplete data at the end of write-ahead log segment {}",
+            bytes.len() - offset,
+            path.display()
+        );
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use arrow::array::Int32Array;
+    use arrow::datatypes::{DataType, Field, Schema};
+    use arrow::record_batch::RecordBatch;
+
+    fn update(values: Vec<i32>, update_type: UpdateType) -> DataUpdate {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(Int32Array::from(values))])
+            .expect("Unable to create record batch");
+        DataUpdate {
+            data: vec![batch],
+            update_type,
+        }
+    }
+
+    fn values(updates: &[DataUpdate]) -> Vec<i32> {
+        updates
+            .iter()
+            .flat_map(|u| &u.data)
+            .flat_map(|batch| {
+                batch
+                    .column(0)
+                    .as_any()
+                    .downcast_ref::<Int32Array>()
+                    .expect("Expected Int32Array")
+                    .values()
+                    .to_vec()
+            })
+            .collect()
+    }
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("spice_wal_{}", uuid::Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn test_append_and_replay() {
+        let dir = temp_dir();
+        let config = Config {
+            sync_policy: SyncPolicy::Always,
+            segment_size: 1024 * 1024,
+            compact_after: 4,
+        };
+
+        let wal = WriteAheadLog::open(&dir, config.clone()).expect("Unable to open WAL");
+        wal.append("test", &update(vec![1, 2], UpdateType::Append))
+            .await
+            .expect("Unable to append");
+        wal.append("test", &update(vec![3], UpdateType::Append))
+            .await
+            .expect("Unable to append");
+        drop(wal);
+
+        let wal = WriteAheadLog::open(&dir, config).expect("Unable to open WAL");
+        let updates = wal.replay("test").await.expect("Unable to replay");
+        assert_eq!(values(&updates), vec![1, 2, 3]);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_compaction_drops_superseded_updates() {
+        let dir = temp_dir();
+        // Every record closes its segment, so every second record triggers a compaction.
+        let config = Config {
+            sync_policy: SyncPolicy::Never,
+            segment_size: 1,
+            compact_after: 2,
+        };
+
+        let wal = WriteAheadLog::open(&dir, config.clone()).expect("Unable to open WAL");
+        for u in [
+            update(vec![1], UpdateType::Append),
+            update(vec![2], UpdateType::Overwrite),
+            update(vec![3], UpdateType::Append),
+            update(vec![4], UpdateType::Append),
+        ] {
+            wal.append("test", &u).await.expect("Unable to append");
+        }
+        drop(wal);
+
+        let wal = WriteAheadLog::open(&dir, config).expect("Unable to open WAL");
+        let updates = wal.replay("test").await.expect("Unable to replay");
+        assert_eq!(values(&updates), vec![2, 3, 4]);
+        assert_eq!(updates[0].update_type, UpdateType::Overwrite);
+
+        let segments = fs::read_dir(dir.join("test"))
+            .expect("Unable to list segments")
+            .count();
+        assert_eq!(segments, 1);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_truncate_drops_persisted_updates() {
+        let dir = temp_dir();
+        let config = Config {
+            sync_policy: SyncPolicy::Never,
+            segment_size: 1024 * 1024,
+            compact_after: 4,
+        };
+
+        let wal = WriteAheadLog::open(&dir, config.clone()).expect("Unable to open WAL");
+        wal.append("test", &update(vec![1, 2], UpdateType::Append))
+            .await
+            .expect("Unable to append");
+        let position = {
+            let _writes = wal.lock_writes("test").await;
+            wal.begin_checkpoint("test")
+                .await
+                .expect("Unable to begin checkpoint")
+                .expect("Expected a checkpoint position")
+        };
+        wal.append("test", &update(vec![3], UpdateType::Append))
+            .await
+            .expect("Unable to append");
+        wal.truncate("test", position)
+            .await
+            .expect("Unable to truncate");
+        drop(wal);
+
+        let wal = WriteAheadLog::open(&dir, config).expect("Unable to open WAL");
+        let updates = wal.replay("test").await.expect("Unable to replay");
+        assert_eq!(values(&updates), vec![3]);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_remove_deletes_log() {
+        let dir = temp_dir();
+        let config = Config {
+            sync_policy: SyncPolicy::Never,
+            segment_size: 1024 * 1024,
+            compact_after: 4,
+        };
+
+        let wal = WriteAheadLog::open(&dir, config).expect("Unable to open WAL");
+        for dataset in ["a.b", "a_b"] {
+            wal.append(dataset, &update(vec![1], UpdateType::Append))
+                .await
+                .expect("Unable to append");
+        }
+        wal.remove("a.b").await.expect("Unable to remove");
+
+        let updates = wal.replay("a.b").await.expect("Unable to replay");
+        assert!(updates.is_empty());
+        let updates = wal.replay("a_b").await.expect("Unable to replay");
+        assert_eq!(values(&updates), vec![1]);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -16,12 +16,31 @@ limitations under the License.
 
 use super::WithDependsOn;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Model {
     pub from: String,
     pub name: String,
 
+    /// Identifies this version among the models with the same name. Defaults to the version in
+    /// `from`, i.e. `v2` for `spiceai:org/app/models/churn:v2`. Models downloaded by URL are
+    /// `latest` without it, so it's required if more than one version of a model is downloaded by
+    /// URL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+
+    /// The share of requests this version serves, relative to the other versions of the model
+    /// that aren't shadows. Versions share requests evenly if none of them set it, and otherwise
+    /// versions without it serve none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traffic: Option<u32>,
+
+    /// Runs this version on the requests served by the other versions of the model, recording its
+    /// outputs without returning them.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub shadow: bool,
+
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(rename = "files", default)]
     pub files: Vec<String>,
@@ -29,6 +48,37 @@ pub struct Model {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(rename = "datasets", default)]
     pub datasets: Vec<String>,
+
+    /// Options for the model's source, i.e. the `region` and `endpoint` of an `s3:` model, or
+    /// the `sha256` its downloaded file is verified with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<HashMap<String, String>>,
+
+    /// The format of the model's files. Detected from the file names if it isn't set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<ModelFormat>,
+
+    /// How the model's dataset is mapped to the model's input tensors.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<input::ModelInput>,
+
+    /// The model outputs returned by inference. Defaults to the first output of the model's graph,
+    /// returned as the column `y`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<output::ModelOutput>,
+
+    /// Runs the model on a schedule or as its dataset is updated, appending the predictions to a
+    /// dataset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub predictions: Option<predictions::Predictions>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelFormat {
+    Onnx,
+    /// A graph exported by tract, i.e. `model.nnef.tgz`.
+    Nnef,
 }
 
 impl WithDependsOn<Model> for Model {
@@ -36,8 +86,148 @@ impl WithDependsOn<Model> for Model {
         Model {
             from: self.from.clone(),
             name: self.name.clone(),
+            version: self.version.clone(),
+            traffic: self.traffic,
+            shadow: self.shadow,
             files: depends_on.to_vec(),
             datasets: depends_on.to_vec(),
+            params: self.params.clone(),
+            format: self.format,
+            inputs: self.inputs.clone(),
+            outputs: self.outputs.clone(),
+            predictions: self.predictions.clone(),
+        }
+    }
+}
+
+pub mod input {
+    use serde::{Deserialize, Serialize};
+
+    /// The element type of a tensor.
+    #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum DataType {
+        Bool,
+        Int8,
+        Int16,
+        Int32,
+        Int64,
+        Uint8,
+        Uint16,
+        Uint32,
+        Uint64,
+        Float16,
+        Float32,
+        Float64,
+        String,
+    }
+
+    /// How the rows of a window are arranged into a tensor.
+    #[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Layout {
+        /// `[1, window, features]`, i.e. a single time series.
+        #[default]
+        Sequence,
+        /// `[window, features]`, one row per sample.
+        Rows,
+        /// `[1, window * features]`.
+        Flat,
+    }
+
+    #[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+    pub struct ModelInput {
+        /// The name of the input in the model's graph. Required if the graph has several inputs.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub name: Option<String>,
+
+        /// The dataset columns used as features, in the order the model expects them. Defaults to
+        /// every column of the input's type other than `order_by`. Features can't be null.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub columns: Vec<String>,
+
+        /// The element type of the input tensor. Defaults to the type declared by the graph.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub dtype: Option<DataType>,
+
+        /// The column rows are sorted by, ascending, before they're windowed. Inputs are read from
+        /// the same rows, so only the first input's ordering is used.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub order_by: Option<String>,
+
+        /// The number of rows in each inference. Defaults to the `lookback` of the request, which
+        /// runs the model on the latest rows by `order_by`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub window: Option<usize>,
+
+        #[serde(default)]
+        pub layout: Layout,
+    }
+}
+
+pub mod output {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+    pub struct ModelOutput {
+        /// The name of the output in the model's graph, which is also the name of its result
+        /// column.
+        pub name: String,
+
+        /// The expected shape of the output, validated against the graph when the model is loaded.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub shape: Vec<usize>,
+    }
+}
+
+pub mod predictions {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Serialize};
+
+    /// Materializes the model's predictions into an accelerated dataset, which can be queried and
+    /// subscribed to like any other dataset.
+    ///
+    /// ```yaml
+    /// predictions:
+    ///   dataset: churn_predictions
+    ///   every: 1h
+    ///   on_update: true
+    ///   keys: [customer_id]
+    /// ```
+    #[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+    pub struct Predictions {
+        /// The dataset the predictions are written to. Each row has the `keys` columns, the
+        /// model's outputs, the `model_version` and the `predicted_at` timestamp.
+        pub dataset: String,
+
+        /// Runs the model over all of its dataset at this interval, i.e. `1h`. Each run replaces
+        /// the predictions in `dataset`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub every: Option<String>,
+
+        /// Runs the model over the rows of each update to its dataset. Models run over windows of
+        /// rows also see the rows of earlier updates that complete a window. These predictions are
+        /// appended to `dataset`.
+        #[serde(default)]
+        pub on_update: bool,
+
+        /// The input columns written with each prediction, i.e. to join it back to its input.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub keys: Vec<String>,
+    }
+
+    impl Predictions {
+        #[must_use]
+        pub fn every(&self) -> Option<Duration> {
+            let every = self.every.as_ref()?;
+            match fundu::parse_duration(every) {
+                Ok(duration) => Some(duration),
+                Err(_) => {
+                    tracing::warn!("Unable to parse prediction interval: {every}");
+                    None
+                }
+            }
         }
     }
 }
@@ -19,7 +19,7 @@ limitations under the License.
 use async_trait::async_trait;
 use db_connection_pool::dbconnection::{get_schema, query_arrow};
 use db_connection_pool::DbConnectionPool;
-use futures::TryStreamExt;
+use futures::{future::BoxFuture, FutureExt, TryStreamExt};
 use snafu::prelude::*;
 use std::{any::Any, fmt, sync::Arc};
 
@@ -31,12 +31,15 @@ use datafusion::{
     execution::{context::SessionState, TaskContext},
     logical_expr::{Expr, TableProviderFilterPushDown, TableType},
     physical_plan::{
-        project_schema, stream::RecordBatchStreamAdapter, DisplayAs, DisplayFormatType,
-        ExecutionPlan, SendableRecordBatchStream,
+        metrics::{ExecutionPlanMetricsSet, MetricsSet},
+        project_schema,
+        stream::RecordBatchStreamAdapter,
+        DisplayAs, DisplayFormatType, ExecutionPlan, SendableRecordBatchStream,
     },
 };
 
 pub mod expr;
+pub mod metrics;
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -54,6 +57,13 @@ pub enum Error {
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// An execution plan that reads its data by sending a query to a remote source, which can be found
+/// by downcasting the plan to its type.
+pub trait RemoteQueryExec {
+    /// The SQL sent to the remote source, if it can be generated from the plan's filters.
+    fn remote_sql(&self) -> Option<String>;
+}
+
 pub struct SqlTable<T: 'static, P: 'static> {
     pool: Arc<dyn DbConnectionPool<T, P> + Send + Sync>,
     schema: SchemaRef,
@@ -101,11 +111,13 @@ impl<T, P> SqlTable<T, P> {
         filters: &[Expr],
         limit: Option<usize>,
     ) -> DataFusionResult<Arc<dyn ExecutionPlan>> {
+        let pool = Arc::clone(&self.pool);
+        let query = Arc::new(move |sql: String| get_stream(Arc::clone(&pool), sql).boxed());
         Ok(Arc::new(SqlExec::new(
             projections,
             schema,
             &self.table_reference,
-            Arc::clone(&self.pool),
+            query,
             filters,
             limit,
         )?))
@@ -152,21 +164,28 @@ impl<T, P> TableProvider for SqlTable<T, P> {
     }
 }
 
+/// Runs a query on a connection from the table's pool, which keeps `SqlExec` independent of the
+/// pool's connection types.
+type Query = Arc<
+    dyn Fn(String) -> BoxFuture<'static, DataFusionResult<SendableRecordBatchStream>> + Send + Sync,
+>;
+
 #[derive(Clone)]
-struct SqlExec<T, P> {
+pub struct SqlExec {
     projected_schema: SchemaRef,
     table_reference: OwnedTableReference,
-    pool: Arc<dyn DbConnectionPool<T, P> + Send + Sync>,
+    query: Query,
     filters: Vec<Expr>,
     limit: Option<usize>,
+    metrics: ExecutionPlanMetricsSet,
 }
 
-impl<T, P> SqlExec<T, P> {
+impl SqlExec {
     fn new(
         projections: Option<&Vec<usize>>,
         schema: &SchemaRef,
         table_reference: &OwnedTableReference,
-        pool: Arc<dyn DbConnectionPool<T, P> + Send + Sync>,
+        query: Query,
         filters: &[Expr],
         limit: Option<usize>,
     ) -> DataFusionResult<Self> {
@@ -174,9 +193,10 @@ impl<T, P> SqlExec<T, P> {
         Ok(Self {
             projected_schema,
             table_reference: table_reference.clone(),
-            pool,
+            query,
             filters: filters.to_vec(),
             limit,
+            metrics: ExecutionPlanMetricsSet::new(),
         })
     }
 
@@ -213,21 +233,27 @@ impl<T, P> SqlExec<T, P> {
     }
 }
 
-impl<T, P> std::fmt::Debug for SqlExec<T, P> {
+impl RemoteQueryExec for SqlExec {
+    fn remote_sql(&self) -> Option<String> {
+        self.sql().ok()
+    }
+}
+
+impl std::fmt::Debug for SqlExec {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let sql = self.sql().unwrap_or_default();
         write!(f, "SqlExec sql={sql}")
     }
 }
 
-impl<T, P> DisplayAs for SqlExec<T, P> {
+impl DisplayAs for SqlExec {
     fn fmt_as(&self, _t: DisplayFormatType, f: &mut fmt::Formatter) -> std::fmt::Result {
         let sql = self.sql().unwrap_or_default();
         write!(f, "SqlExec sql={sql}")
     }
 }
 
-impl<T: 'static, P: 'static> ExecutionPlan for SqlExec<T, P> {
+impl ExecutionPlan for SqlExec {
     fn as_any(&self) -> &dyn Any {
         self
     }
@@ -255,19 +281,27 @@ impl<T: 'static, P: 'static> ExecutionPlan for SqlExec<T, P> {
         Ok(self)
     }
 
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+
     fn execute(
         &self,
-        _partition: usize,
+        partition: usize,
         _context: Arc<TaskContext>,
     ) -> DataFusionResult<SendableRecordBatchStream> {
         let sql = self.sql().map_err(to_execution_error)?;
         tracing::debug!("SqlExec sql: {sql}");
 
-        let fut = get_stream(Arc::clone(&self.pool), sql);
+        let fut = (self.query)(sql);
 
         let stream = futures::stream::once(fut).try_flatten();
         let schema = self.schema().clone();
-        Ok(Box::pin(RecordBatchStreamAdapter::new(schema, stream)))
+        Ok(Box::pin(metrics::RemoteQueryStream::new(
+            Box::pin(RecordBatchStreamAdapter::new(schema, stream)),
+            &self.metrics,
+            partition,
+        )))
     }
 }
 
@@ -0,0 +1,84 @@
+/*
+Copyright 2024 The Spice.ai OSS Authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+     https://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Metrics for execution plans that run queries on a remote source, shown by `EXPLAIN ANALYZE`.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use datafusion::{
+    arrow::{datatypes::SchemaRef, record_batch::RecordBatch},
+    error::Result as DataFusionResult,
+    execution::RecordBatchStream,
+    physical_plan::{
+        metrics::{BaselineMetrics, ExecutionPlanMetricsSet, MetricBuilder, Time},
+        SendableRecordBatchStream,
+    },
+};
+use futures::{Stream, StreamExt};
+
+/// Records the rows returned by a remote query, and how long it took to return all of them.
+pub struct RemoteQueryStream {
+    inner: SendableRecordBatchStream,
+    baseline_metrics: BaselineMetrics,
+    remote_query_time: Time,
+    started: Option<Instant>,
+}
+
+impl RemoteQueryStream {
+    #[must_use]
+    pub fn new(
+        inner: SendableRecordBatchStream,
+        metrics: &ExecutionPlanMetricsSet,
+        partition: usize,
+    ) -> Self {
+        Self {
+            inner,
+            baseline_metrics: BaselineMetrics::new(metrics, partition),
+            remote_query_time: MetricBuilder::new(metrics)
+                .subset_time("remote_query_time", partition),
+            started: None,
+        }
+    }
+}
+
+impl Stream for RemoteQueryStream {
+    type Item = DataFusionResult<RecordBatch>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // The remote query is sent when the stream is first polled, not when it's created.
+        let started = *self.started.get_or_insert_with(Instant::now);
+
+        let elapsed_compute = self.baseline_metrics.elapsed_compute().clone();
+        let timer = elapsed_compute.timer();
+        let poll = self.inner.poll_next_unpin(cx);
+        timer.done();
+
+        if matches!(poll, Poll::Ready(None | Some(Err(_)))) {
+            self.remote_query_time.add_elapsed(started);
+        }
+        self.baseline_metrics.record_poll(poll)
+    }
+}
+
+impl RecordBatchStream for RemoteQueryStream {
+    fn schema(&self) -> SchemaRef {
+        self.inner.schema()
+    }
+}
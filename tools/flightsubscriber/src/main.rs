@@ -22,6 +22,7 @@ use arrow_flight::{
 };
 use clap::Parser;
 use futures::{stream, StreamExt};
+use serde_json::json;
 use tonic::transport::Channel;
 use tracing_subscriber::filter::Directive;
 
@@ -35,8 +36,17 @@ pub struct Args {
     )]
     pub flight_endpoint: String,
 
+    /// The dataset to subscribe to. Can be specified multiple times to subscribe to several datasets.
     #[arg(long, value_name = "DATASET_PATH", default_value = "test")]
-    pub path: String,
+    pub path: Vec<String>,
+
+    /// An optional query to filter the updates of the subscribed dataset. Only valid with a single `--path`.
+    #[arg(long, value_name = "SQL")]
+    pub sql: Option<String>,
+
+    /// Resume a previous subscription after this sequence number instead of receiving the current contents first.
+    #[arg(long, value_name = "SEQUENCE")]
+    pub since_sequence: Option<u64>,
 }
 
 /// Reads a Parquet file and sends it via DoPut to an Apache Arrow Flight endpoint.
@@ -51,7 +61,30 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .await?;
     let mut client = FlightServiceClient::new(channel);
 
-    let flight_descriptor = FlightDescriptor::new_path(vec![args.path]);
+    if args.sql.is_some() && args.path.len() > 1 {
+        return Err("--sql can only be used when subscribing to a single --path".into());
+    }
+
+    let flight_descriptor =
+        if args.path.len() == 1 && args.sql.is_none() && args.since_sequence.is_none() {
+            FlightDescriptor::new_path(args.path)
+        } else {
+            let subscriptions = args
+                .path
+                .iter()
+                .map(|path| {
+                    let mut subscription = json!({ "path": path });
+                    if let Some(sql) = &args.sql {
+                        subscription["sql"] = json!(sql);
+                    }
+                    if let Some(since_sequence) = args.since_sequence {
+                        subscription["since_sequence"] = json!(since_sequence);
+                    }
+                    subscription
+                })
+                .collect::<Vec<_>>();
+            FlightDescriptor::new_cmd(json!({ "subscriptions": subscriptions }).to_string())
+        };
     let subscription_request =
         stream::iter(vec![FlightData::new().with_descriptor(flight_descriptor)].into_iter());
 
@@ -70,7 +103,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     tracing::trace!("SCHEMA");
                 }
                 DecodedPayload::RecordBatch(batch) => {
-                    tracing::info!("RECORD BATCH: num_rows={}", batch.num_rows());
+                    let metadata = String::from_utf8_lossy(&msg.inner.app_metadata);
+                    tracing::info!(
+                        "RECORD BATCH: metadata={metadata} num_rows={}",
+                        batch.num_rows()
+                    );
                 }
                 DecodedPayload::None => {
                     tracing::trace!("NONE");